@@ -0,0 +1,279 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use diesel::prelude::*;
+use cashcontracts::{Address, tx_hash_to_hex, tx_hex_to_hash};
+use slpdexdb_base::{Result, SLPDEXConfig, ExtendedPubKey};
+use slpdexdb_db::{Db, TxSource, TxFilter, TxHistory, Confirmedness, ResyncTask, ResyncJobState,
+                  TradeFeeQuery, resync, reindex_raw_txs, PRIORITY_DEFAULT};
+use slpdexdb_endpoint::events;
+
+const USAGE: &str = "\
+usage: slpdexdb-admin <command>
+
+commands:
+    status                                  last update of every sync subject
+    resync all|tokens|exch                  resync from the remote sources
+    resync address <cash_address>
+    resync token <token_id>
+    resync token-stats <token_id>
+    resync account <xpub>                   derive and resync the addresses of an HD account
+    rebuild-utxos all|exch                  rebuild the UTXO tables from the stored txs
+    rebuild-utxos address <cash_address>
+    rebuild-utxos token <token_id>
+    tx <tx_hash>                            decode a tx, including its SLP and offer details
+    addresses                               list the active addresses
+    deactivate <cash_address>               stop tracking an address
+    purge <cash_address>                    deactivate an address and drop its UTXOs and sync state
+    reindex [batch_size]                    rebuild the tx index from the stored raw txs
+    check [--repair]                        report inconsistencies, optionally repairing them
+    jobs [pending|running|done|failed]      list the resync jobs queued for the indexer
+    jobs add tokens|exch                    queue a resync for the indexer
+    jobs add address <cash_address>
+    jobs add token|token-stats <token_id>
+    jobs add account <xpub>
+    jobs retry <job_id>|failed              queue failed jobs again
+    fees [--from <timestamp>] [--to <timestamp>] [--token <token_id>] [--csv]
+                                            collected trade fees per token, day and fee address
+
+The database is given by DATABASE_URL, the config by the environment like for the indexer.";
+
+const REINDEX_BATCH_SIZE: i64 = 1000;
+const JOBS_LIMIT: i64 = 100;
+
+// resyncs and reindexing notify subscribers like the indexer does
+fn connect_db() -> Result<Db> {
+    let connection_str = std::env::var("DATABASE_URL")?;
+    Ok(Db::new(PgConnection::establish(&connection_str)?)
+        .with_outbox_event_builder(Arc::new(events::OutboxEvents)))
+}
+
+fn parse_address(address: Option<&String>) -> Result<Address> {
+    let address = address.ok_or("missing cash address")?;
+    Ok(Address::from_cash_addr(address.clone()).map_err(|_| "invalid cash address")?)
+}
+
+fn parse_hash(hash: Option<&String>) -> Result<[u8; 32]> {
+    Ok(hash.and_then(|hash| tx_hex_to_hash(hash)).ok_or("missing or invalid hash")?)
+}
+
+fn status(db: &Db) -> Result<()> {
+    for (update, timestamp) in db.update_subjects()? {
+        let subject = &update.subject;
+        let subject_hash = subject.hash.as_ref().map(|hash| {
+            match hash.len() {
+                20 => Address::from_bytes(cashcontracts::AddressType::P2PKH,
+                                          slpdexdb_db::address_hash_from_slice(hash)).cash_addr(),
+                _ => hex::encode(hash),
+            }
+        });
+        println!("{:?} {} {}: height {}, {}, at {}",
+                 subject.subject_type,
+                 subject_hash.unwrap_or_default(),
+                 if subject.is_confirmed { "confirmed" } else { "unconfirmed" },
+                 update.last_height,
+                 if update.completed { "completed" } else { "in progress" },
+                 timestamp);
+    }
+    Ok(())
+}
+
+fn parse_task(args: &[String]) -> Result<ResyncTask> {
+    match args.get(0).map(String::as_str) {
+        Some("tokens") => Ok(ResyncTask::Tokens),
+        Some("exch") => Ok(ResyncTask::Exch),
+        Some("address") => Ok(ResyncTask::Address(parse_address(args.get(1))?)),
+        Some("token") => Ok(ResyncTask::TokenUtxos(parse_hash(args.get(1))?)),
+        Some("token-stats") => Ok(ResyncTask::TokenStats(parse_hash(args.get(1))?)),
+        Some("account") => {
+            let xpub = args.get(1).ok_or("missing xpub")?;
+            ExtendedPubKey::from_base58(xpub)?;
+            Ok(ResyncTask::Account(xpub.clone()))
+        },
+        _ => Err(USAGE.into()),
+    }
+}
+
+fn resync_subject(db: &Db, config: &SLPDEXConfig, args: &[String]) -> Result<()> {
+    match args.get(0).map(String::as_str) {
+        Some("all") => resync::resync(db, config),
+        _ => resync::run_resync_task(db, config, &parse_task(args)?),
+    }
+}
+
+fn jobs(db: &Db, args: &[String]) -> Result<()> {
+    let state = match args.get(0).map(String::as_str) {
+        None => None,
+        Some("pending") => Some(ResyncJobState::Pending),
+        Some("running") => Some(ResyncJobState::Running),
+        Some("done") => Some(ResyncJobState::Done),
+        Some("failed") => Some(ResyncJobState::Failed),
+        Some("add") => {
+            let job = db.enqueue_resync_job(&parse_task(&args[1..])?, PRIORITY_DEFAULT, 0)?;
+            println!("queued as job {}", job.id);
+            return Ok(());
+        },
+        Some("retry") => {
+            let id = match args.get(1).map(String::as_str) {
+                Some("failed") => None,
+                Some(id) => Some(id.parse()?),
+                None => return Err(USAGE.into()),
+            };
+            println!("{} jobs queued again", db.retry_resync_jobs(id)?);
+            return Ok(());
+        },
+        Some(_) => return Err(USAGE.into()),
+    };
+    for job in db.resync_jobs(state, JOBS_LIMIT)? {
+        println!("{} {} {:?}: priority {}, {} attempts, run after {}, created {}{}",
+                 job.id,
+                 job.task,
+                 job.state,
+                 job.priority,
+                 job.attempts,
+                 job.run_after,
+                 job.created,
+                 job.last_error.map(|err| format!(", last error: {}", err)).unwrap_or_default());
+    }
+    for (state, count) in db.resync_job_counts()? {
+        println!("{:?}: {}", state, count);
+    }
+    Ok(())
+}
+
+fn rebuild_utxos(db: &Db, args: &[String]) -> Result<()> {
+    match args.get(0).map(String::as_str) {
+        Some("all") => {
+            for address in db.active_addresses()? {
+                db.update_utxo_set(&address)?;
+            }
+            db.update_utxo_set_exch()?;
+            for token_hash in db.utxo_slp_token_hashes()? {
+                db.update_utxo_set_slp(&token_hash)?;
+            }
+        },
+        Some("exch") => db.update_utxo_set_exch()?,
+        Some("address") => db.update_utxo_set(&parse_address(args.get(1))?)?,
+        Some("token") => db.update_utxo_set_slp(&parse_hash(args.get(1))?)?,
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+fn inspect_tx(db: &Db, config: &SLPDEXConfig, tx_hash: &[u8; 32]) -> Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let tx_entries = TxSource::new().request_txs(&[TxFilter::TxHash(*tx_hash)], config,
+                                                 Confirmedness::Both)?;
+    let history = TxHistory::from_entries(&tx_entries, timestamp, config, db)?;
+    if history.txs.len() == 0 {
+        return Err(format!("tx {} not found", tx_hash_to_hex(tx_hash)).into());
+    }
+    for (idx, tx) in history.txs.iter().enumerate() {
+        print!("{}", tx);
+        if let Some(trade_offer) = history.trade_offers.get(&idx) {
+            println!("trade_offer: {:#?}", trade_offer);
+        }
+    }
+    match db.txs(std::iter::once(*tx_hash))?.get(tx_hash) {
+        Some(tx) => println!("indexed as tx {}", tx.id),
+        None => println!("not indexed"),
+    }
+    Ok(())
+}
+
+fn check(db: &Db, repair: bool) -> Result<()> {
+    let inconsistencies = db.check_consistency()?;
+    for inconsistency in inconsistencies.iter() {
+        println!("{}", inconsistency);
+    }
+    println!("{} inconsistencies found", inconsistencies.len());
+    if repair && inconsistencies.len() > 0 {
+        db.repair_inconsistencies(&inconsistencies)?;
+        println!("repaired");
+    }
+    Ok(())
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn fees(db: &Db, args: &[String]) -> Result<()> {
+    let mut query = TradeFeeQuery::default();
+    let mut csv = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => query.min_timestamp = Some(args.next().ok_or(USAGE)?.parse()?),
+            "--to" => query.max_timestamp = Some(args.next().ok_or(USAGE)?.parse()?),
+            "--token" => query.token_hash = Some(parse_hash(args.next())?),
+            "--csv" => csv = true,
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let fees = db.trade_fee_report(&query)?;
+    if csv {
+        println!("day,tokenIdHex,symbol,feeAddress,tradeCount,feeSatoshis");
+    }
+    for fee in fees.iter() {
+        let symbol = fee.symbol.as_ref().map(String::as_str).unwrap_or("");
+        if csv {
+            println!("{},{},{},{},{},{}",
+                     fee.day, tx_hash_to_hex(&fee.token_hash), csv_field(symbol),
+                     fee.fee_address.cash_addr(), fee.trade_count, fee.fee_satoshis);
+        } else {
+            println!("{} {} {} {}: {} trades, {} sat",
+                     fee.day, tx_hash_to_hex(&fee.token_hash), symbol,
+                     fee.fee_address.cash_addr(), fee.trade_count, fee.fee_satoshis);
+        }
+    }
+    if !csv {
+        println!("total: {} sat", fees.iter().map(|fee| fee.fee_satoshis).sum::<u64>());
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let command = match args.get(0) {
+        Some(command) => command.as_str(),
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        },
+    };
+    let db = connect_db()?;
+    let config = SLPDEXConfig::from_env();
+    match command {
+        "status" => status(&db)?,
+        "resync" => resync_subject(&db, &config, &args[1..])?,
+        "rebuild-utxos" => rebuild_utxos(&db, &args[1..])?,
+        "tx" => inspect_tx(&db, &config, &parse_hash(args.get(1))?)?,
+        "addresses" => {
+            for address in db.active_addresses()? {
+                println!("{}", address.cash_addr());
+            }
+        },
+        "deactivate" => db.set_address_active(&parse_address(args.get(1))?, false)?,
+        "purge" => db.purge_address(&parse_address(args.get(1))?)?,
+        "reindex" => {
+            let batch_size = match args.get(1) {
+                Some(batch_size) => batch_size.parse()?,
+                None => REINDEX_BATCH_SIZE,
+            };
+            let num_reindexed = reindex_raw_txs(&db, &config, batch_size)?;
+            println!("reindexed {} txs from raw_tx", num_reindexed);
+        },
+        "check" => check(&db, args.get(1).map(String::as_str) == Some("--repair"))?,
+        "jobs" => jobs(&db, &args[1..])?,
+        "fees" => fees(&db, &args[1..])?,
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        },
+    }
+    Ok(())
+}
@@ -1,3 +1,15 @@
+use crate::errors::{ErrorKind, Result, TradeOfferError};
+
+pub const DEFAULT_FEE_PROFILE: &str = "default";
+
+/// Fee settings an offer's contract can commit to, e.g. those of a partner frontend.
+#[derive(Clone, Debug)]
+pub struct FeeProfile {
+    pub name: String,
+    pub fee_address: cashcontracts::Address,
+    pub fee_divisor: u64,
+}
+
 #[derive(Clone, Debug)]
 pub struct SLPDEXConfig {
     pub fee_address: cashcontracts::Address,
@@ -5,7 +17,17 @@ pub struct SLPDEXConfig {
     pub dust_limit: u64,
     pub exch_lokad: &'static str,
     pub exch_lokad_b64: String,
-    pub exch_version: i32,
+    pub exch_version: i32,  // contract version of offers we build; all known versions are parsed
+    pub slp_utxo_index: bool,  // maintain utxo_slp for every token, not only active addresses
+    pub slp_utxo_tokens: Vec<[u8; 32]>,  // limits the SLP UTXO index to these tokens if not empty
+    pub store_raw_txs: bool,  // keep raw tx bytes in raw_tx, for reindexing without network
+    pub hd_gap_limit: i32,  // consecutive unused addresses derived per chain of an xpub account
+    pub tx_fee_per_byte: u64,  // fee rate of txs built for wallets
+    pub fee_profiles: Vec<FeeProfile>,  // recognized besides our own `fee_address`/`fee_divisor`
+    pub event_outbox_retention_secs: i64,  // how long clients can resume their event stream
+    pub resync_job_max_attempts: i32,
+    pub resync_job_retry_delay_secs: i64,  // before the first retry, doubling with every attempt
+    pub resync_job_max_retry_delay_secs: i64,
 }
 
 impl Default for SLPDEXConfig {
@@ -19,6 +41,151 @@ impl Default for SLPDEXConfig {
             exch_lokad: "EXCH",
             exch_lokad_b64: base64::encode("EXCH"),
             exch_version: 2,
+            slp_utxo_index: false,
+            slp_utxo_tokens: Vec::new(),
+            store_raw_txs: false,
+            hd_gap_limit: 20,
+            tx_fee_per_byte: 1,
+            fee_profiles: Vec::new(),
+            event_outbox_retention_secs: 7 * 24 * 60 * 60,
+            resync_job_max_attempts: 8,
+            resync_job_retry_delay_secs: 30,
+            resync_job_max_retry_delay_secs: 60 * 60,
         }
     }
 }
+
+impl SLPDEXConfig {
+    /// Default config with the settings given by the SLP_UTXO_INDEX, SLP_UTXO_TOKENS,
+    /// STORE_RAW_TXS and FEE_PROFILES environment variables. SLP_UTXO_INDEX, if set, enables
+    /// the SLP UTXO index for every token; SLP_UTXO_TOKENS lists token ids separated by `,`
+    /// and enables it for only those.
+    pub fn from_env() -> SLPDEXConfig {
+        let fee_profiles = match std::env::var("FEE_PROFILES") {
+            Ok(fee_profiles) => SLPDEXConfig::parse_fee_profiles(&fee_profiles)
+                .expect("FEE_PROFILES must be `name,cash_address,fee_divisor;...`"),
+            Err(_) => Vec::new(),
+        };
+        let slp_utxo_tokens = match std::env::var("SLP_UTXO_TOKENS") {
+            Ok(token_ids) => SLPDEXConfig::parse_token_ids(&token_ids)
+                .expect("SLP_UTXO_TOKENS must be `token_id,...`"),
+            Err(_) => Vec::new(),
+        };
+        SLPDEXConfig {
+            slp_utxo_index: std::env::var("SLP_UTXO_INDEX").is_ok() || slp_utxo_tokens.len() > 0,
+            slp_utxo_tokens,
+            store_raw_txs: std::env::var("STORE_RAW_TXS").is_ok(),
+            fee_profiles,
+            event_outbox_retention_secs: std::env::var("EVENT_OUTBOX_RETENTION_SECS").ok()
+                .map(|secs| secs.parse().expect("EVENT_OUTBOX_RETENTION_SECS must be a number"))
+                .unwrap_or(SLPDEXConfig::default().event_outbox_retention_secs),
+            ..SLPDEXConfig::default()
+        }
+    }
+
+    /// Whether the SLP UTXO index tracks the token.
+    pub fn is_slp_utxo_indexed(&self, token_hash: &[u8; 32]) -> bool {
+        self.slp_utxo_index &&
+            (self.slp_utxo_tokens.len() == 0 || self.slp_utxo_tokens.contains(token_hash))
+    }
+
+    /// Parses token ids in hex separated by `,`.
+    pub fn parse_token_ids(s: &str) -> Option<Vec<[u8; 32]>> {
+        s.split(',')
+            .filter(|token_id| !token_id.trim().is_empty())
+            .map(|token_id| cashcontracts::tx_hex_to_hash(token_id.trim()))
+            .collect()
+    }
+
+    /// Our own fee settings as `DEFAULT_FEE_PROFILE`, followed by the additional profiles.
+    pub fn all_fee_profiles(&self) -> Vec<FeeProfile> {
+        let own_profile = FeeProfile {
+            name: DEFAULT_FEE_PROFILE.to_string(),
+            fee_address: self.fee_address.clone(),
+            fee_divisor: self.fee_divisor,
+        };
+        std::iter::once(own_profile).chain(self.fee_profiles.iter().cloned()).collect()
+    }
+
+    /// Fee addresses of all fee profiles, e.g. to recognize fee outputs.
+    pub fn fee_addresses(&self) -> Vec<cashcontracts::Address> {
+        self.all_fee_profiles().into_iter().map(|profile| profile.fee_address).collect()
+    }
+
+    /// This config with the fee settings of profile `name`; no name keeps our own.
+    pub fn with_fee_profile(&self, name: Option<&str>) -> Result<SLPDEXConfig> {
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(self.clone()),
+        };
+        let profile = self.all_fee_profiles().into_iter()
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| ErrorKind::InvalidTradeOffer(
+                TradeOfferError::UnknownFeeProfile(name.to_string())
+            ))?;
+        let mut config = self.clone();
+        config.fee_address = profile.fee_address;
+        config.fee_divisor = profile.fee_divisor;
+        Ok(config)
+    }
+
+    /// Parses profiles given as `name,cash_address,fee_divisor` separated by `;`.
+    pub fn parse_fee_profiles(s: &str) -> Option<Vec<FeeProfile>> {
+        s.split(';')
+            .filter(|profile| !profile.trim().is_empty())
+            .map(|profile| {
+                let mut parts = profile.trim().splitn(3, ',');
+                let name = parts.next()?.to_string();
+                let fee_address = cashcontracts::Address::from_cash_addr(parts.next()?.to_string()).ok()?;
+                let fee_divisor = parts.next()?.parse().ok()?;
+                Some(FeeProfile { name, fee_address, fee_divisor })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partner_config() -> SLPDEXConfig {
+        SLPDEXConfig {
+            fee_profiles: SLPDEXConfig::parse_fee_profiles(
+                "partner,bitcoincash:qrhea03074073ff3zv9whh0nggxc7k03ssh8jv9mkx,250"
+            ).unwrap(),
+            ..SLPDEXConfig::default()
+        }
+    }
+
+    #[test]
+    fn with_fee_profile_known() {
+        let config = partner_config();
+        let partner = config.with_fee_profile(Some("partner")).unwrap();
+        assert_eq!(partner.fee_address, config.fee_profiles[0].fee_address);
+        assert_eq!(partner.fee_divisor, 250);
+        let own = config.with_fee_profile(Some(DEFAULT_FEE_PROFILE)).unwrap();
+        assert_eq!(own.fee_address, config.fee_address);
+        assert_eq!(own.fee_divisor, config.fee_divisor);
+        assert_eq!(config.with_fee_profile(None).unwrap().fee_divisor, config.fee_divisor);
+    }
+
+    #[test]
+    fn with_fee_profile_unknown() {
+        assert!(partner_config().with_fee_profile(Some("unknown")).is_err());
+    }
+
+    #[test]
+    fn slp_utxo_index_tokens() {
+        let config = SLPDEXConfig::default();
+        assert!(!config.is_slp_utxo_indexed(&[1; 32]));
+        let config = SLPDEXConfig { slp_utxo_index: true, ..SLPDEXConfig::default() };
+        assert!(config.is_slp_utxo_indexed(&[1; 32]));
+        let config = SLPDEXConfig {
+            slp_utxo_index: true,
+            slp_utxo_tokens: vec![[1; 32]],
+            ..SLPDEXConfig::default()
+        };
+        assert!(config.is_slp_utxo_indexed(&[1; 32]));
+        assert!(!config.is_slp_utxo_indexed(&[2; 32]));
+    }
+}
@@ -27,6 +27,21 @@ pub fn pg_numeric_to_rational(numeric: &PgNumeric) -> Result<Rational> {
     Ok(result)
 }
 
+pub fn str_to_rational(s: &str) -> Result<Rational> {
+    let invalid = || ErrorKind::NumericError(NumericError::InvalidDecimal(s.to_string()));
+    let (integer_part, fract_part) = match s.find(".") {
+        Some(dot_idx) => (&s[..dot_idx], &s[dot_idx + 1..]),
+        None => (s, ""),
+    };
+    let digits = format!("{}{}", integer_part, fract_part);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid().into());
+    }
+    let numerator = digits.parse::<Integer>().map_err(|_| invalid())?;
+    let denominator = Integer::from(10u8).pow(fract_part.len() as u32);
+    Ok(Rational::from((numerator, denominator)))
+}
+
 pub fn pg_numeric_to_i128(numeric: &PgNumeric) -> Result<i128> {
     let (is_signed, weight, digits) = match *numeric {
         PgNumeric::Positive {weight, ref digits, ..} => (false, weight, digits),
@@ -172,3 +187,24 @@ fn fmt_rational(rational: Rational, f: &mut Formatter) -> fmt::Result {
     f.pad_integral(is_nonnegative, "", &string)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn str_to_rational_parses_decimals() {
+        assert_eq!(str_to_rational("1000").unwrap(), 1000);
+        assert_eq!(str_to_rational("0.00000546").unwrap(), Rational::from((546, 100_000_000)));
+        assert_eq!(str_to_rational("12.5").unwrap(), Rational::from((25, 2)));
+        assert_eq!(str_to_rational(".5").unwrap(), Rational::from((1, 2)));
+        assert_eq!(str_to_rational("7.").unwrap(), 7);
+    }
+
+    #[test]
+    fn str_to_rational_rejects_invalid() {
+        for s in &["", ".", "-1", "1e5", "1.2.3", " 1", "0x10"] {
+            assert!(str_to_rational(s).is_err(), "{:?} should be rejected", s);
+        }
+    }
+}
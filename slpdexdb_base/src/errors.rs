@@ -1,4 +1,5 @@
 use diesel::data_types::PgNumeric;
+use crate::slp_amount::SLPAmount;
 
 #[derive(Debug)]
 pub enum SLPError {
@@ -16,12 +17,16 @@ pub enum NumericError {
     NaN,
     NotInteger(PgNumeric),  // expected integer numeric, got fractional
     TooManyDigits(String),  // decimal number has too many fractional digits
+    InvalidDecimal(String),
 }
 
 #[derive(Debug)]
 pub enum TradeOfferError {
     InvalidPrice(Vec<u8>),
     InvalidPower(Vec<u8>),
+    UnknownVersion(i32),
+    InvalidDecimals(i32),  // token decimals outside of 0..=9
+    UnknownFeeProfile(String),  // not among the configured fee profiles
 }
 
 #[derive(Debug)]
@@ -31,6 +36,83 @@ pub enum TokenError {
     InvalidTokenHex(String),
 }
 
+#[derive(Debug)]
+pub enum HdKeyError {
+    InvalidBase58,
+    InvalidLength(usize),
+    InvalidVersion(u32),
+    InvalidPublicKey,
+    HardenedDerivation(u32),  // hardened children can't be derived from a public key
+    InvalidChild(u32),
+    MaxDepth,  // depth is a single byte, children of depth 255 keys can't be serialized
+}
+
+#[derive(Debug)]
+pub enum TxBuildError {
+    InsufficientFunds { needed: u64, available: u64 },
+    InsufficientTokens { needed: String, available: String },
+    WrongToken(String),  // funding utxo carries a different token
+    UnknownUtxo(String),
+    UnsupportedInput(String),  // wallet can only sign P2PKH inputs
+    PriceOutOfRange(String),  // price not representable as 32 bit script price
+    PaymentOutOfRange { amount: u64, script_price: u64 },  // payment doesn't fit into 64 bits
+    MissingQuoteTarget,  // quote requested without a token amount or satoshis
+}
+
+#[derive(Clone, Debug)]
+pub enum BurnReason {
+    NoSend,  // spent by a tx that doesn't SEND the input's token
+    WrongToken,  // carries a different token than the tx sends
+    MintBaton,  // mint baton spent without a MINT
+    Underspent,  // token outputs sum to less than the token inputs
+    UnknownInput,  // spent output unknown to the tx source and the index, may carry tokens
+}
+
+#[derive(Clone, Debug)]
+pub struct SLPInputCheck {
+    pub input_idx: usize,
+    pub tx_hash: [u8; 32],
+    pub vout: i32,
+    pub token_hash: Option<[u8; 32]>,
+    pub value_token: SLPAmount,
+    pub is_baton: bool,
+    pub burn: Option<BurnReason>,
+}
+
+#[derive(Debug)]
+pub enum TxRejection {
+    InvalidHex,
+    Malformed(String),
+    InvalidSLP(String),
+    InsufficientSLPInputs,  // token outputs exceed the valid token inputs
+    InvalidTradeOffer(String),
+    BurnsTokens(Vec<SLPInputCheck>),
+    NoPeers,  // no connected node to relay the tx to
+}
+
+impl TxRejection {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TxRejection::InvalidHex => "InvalidHex",
+            TxRejection::Malformed(_) => "Malformed",
+            TxRejection::InvalidSLP(_) => "InvalidSLP",
+            TxRejection::InsufficientSLPInputs => "InsufficientSLPInputs",
+            TxRejection::InvalidTradeOffer(_) => "InvalidTradeOffer",
+            TxRejection::BurnsTokens(_) => "BurnsTokens",
+            TxRejection::NoPeers => "NoPeers",
+        }
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            TxRejection::Malformed(detail) |
+            TxRejection::InvalidSLP(detail) |
+            TxRejection::InvalidTradeOffer(detail) => Some(detail),
+            _ => None,
+        }
+    }
+}
+
 error_chain! {
     foreign_links {
         Fmt(std::fmt::Error);
@@ -64,5 +146,35 @@ error_chain! {
             description("Invalid trade offer")
             display("Invalid trade offer: {:?}", trade_offer_error)
         }
+
+        TxBuild(tx_build_error: TxBuildError) {
+            description("Can't build tx")
+            display("Can't build tx: {:?}", tx_build_error)
+        }
+
+        TxRejected(rejection: TxRejection) {
+            description("Tx rejected")
+            display("Tx rejected: {:?}", rejection)
+        }
+
+        InvalidHdKey(hd_key_error: HdKeyError) {
+            description("Invalid extended public key")
+            display("Invalid extended public key: {:?}", hd_key_error)
+        }
+
+        IndexerRequest(reason: String) {
+            description("Indexer request failed")
+            display("Indexer request failed: {}", reason)
+        }
+
+        SlpUtxoIndexDisabled(token_id: String) {
+            description("SLP UTXO index disabled for token")
+            display("SLP UTXO index disabled for token {}", token_id)
+        }
+
+        ResyncJobFailed(job_id: i32, reason: String) {
+            description("Resync job failed")
+            display("Resync job {} failed: {}", job_id, reason)
+        }
     }
 }
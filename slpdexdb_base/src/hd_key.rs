@@ -0,0 +1,172 @@
+use crate::errors::{Result, ErrorKind, HdKeyError};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use secp256k1::{Secp256k1, PublicKey};
+use cashcontracts::{Address, AddressType};
+use std::io;
+
+const XPUB_VERSION: u32 = 0x0488_B21E;
+const HARDENED_OFFSET: u32 = 0x8000_0000;
+
+pub const RECEIVE_CHAIN: u32 = 0;
+pub const CHANGE_CHAIN: u32 = 1;
+
+/// BIP32 extended public key; only supports non-hardened (public) derivation.
+#[derive(Clone, Debug)]
+pub struct ExtendedPubKey {
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub public_key: PublicKey,
+}
+
+impl ExtendedPubKey {
+    pub fn from_base58(s: &str) -> Result<Self> {
+        let data = bs58::decode(s).with_check(None).into_vec()
+            .map_err(|_| ErrorKind::InvalidHdKey(HdKeyError::InvalidBase58))?;
+        if data.len() != 78 {
+            return Err(ErrorKind::InvalidHdKey(HdKeyError::InvalidLength(data.len())).into());
+        }
+        let mut cur = io::Cursor::new(&data);
+        let version = cur.read_u32::<BigEndian>()?;
+        if version != XPUB_VERSION {
+            return Err(ErrorKind::InvalidHdKey(HdKeyError::InvalidVersion(version)).into());
+        }
+        let depth = cur.read_u8()?;
+        let mut parent_fingerprint = [0; 4];
+        parent_fingerprint.copy_from_slice(&data[5..9]);
+        cur.set_position(9);
+        let child_number = cur.read_u32::<BigEndian>()?;
+        let mut chain_code = [0; 32];
+        chain_code.copy_from_slice(&data[13..45]);
+        let public_key = PublicKey::from_slice(&data[45..78])
+            .map_err(|_| ErrorKind::InvalidHdKey(HdKeyError::InvalidPublicKey))?;
+        Ok(ExtendedPubKey { depth, parent_fingerprint, child_number, chain_code, public_key })
+    }
+
+    pub fn to_base58(&self) -> String {
+        let mut data = Vec::with_capacity(78);
+        data.write_u32::<BigEndian>(XPUB_VERSION).unwrap();
+        data.push(self.depth);
+        data.extend_from_slice(&self.parent_fingerprint);
+        data.write_u32::<BigEndian>(self.child_number).unwrap();
+        data.extend_from_slice(&self.chain_code);
+        data.extend_from_slice(&self.public_key.serialize());
+        bs58::encode(data).with_check().into_string()
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self> {
+        if index >= HARDENED_OFFSET {
+            return Err(ErrorKind::InvalidHdKey(HdKeyError::HardenedDerivation(index)).into());
+        }
+        let depth = self.depth.checked_add(1)
+            .ok_or_else(|| ErrorKind::InvalidHdKey(HdKeyError::MaxDepth))?;
+        let mut data = self.public_key.serialize().to_vec();
+        data.write_u32::<BigEndian>(index)?;
+        let mut mac = Hmac::<Sha512>::new_varkey(&self.chain_code)
+            .expect("HMAC accepts keys of any length");
+        mac.input(&data);
+        let result = mac.result().code();
+        let secp = Secp256k1::verification_only();
+        let mut public_key = self.public_key.clone();
+        public_key.add_exp_assign(&secp, &result[..32])
+            .map_err(|_| ErrorKind::InvalidHdKey(HdKeyError::InvalidChild(index)))?;
+        let mut chain_code = [0; 32];
+        chain_code.copy_from_slice(&result[32..]);
+        Ok(ExtendedPubKey {
+            depth,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code,
+            public_key,
+        })
+    }
+
+    pub fn fingerprint(&self) -> [u8; 4] {
+        let mut fingerprint = [0; 4];
+        fingerprint.copy_from_slice(&cashcontracts::hash160(&self.public_key.serialize())[..4]);
+        fingerprint
+    }
+
+    /// P2PKH address of the child key at `index`, i.e. `self.derive_child(index)`.
+    pub fn derive_address(&self, index: u32) -> Result<Address> {
+        let key = self.derive_child(index)?;
+        Ok(Address::from_serialized_pub_key(
+            "bitcoincash",
+            AddressType::P2PKH,
+            &key.public_key.serialize(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_child(parent: &str, index: u32, child: &str) {
+        let key = ExtendedPubKey::from_base58(parent).unwrap();
+        assert_eq!(key.to_base58(), parent);
+        assert_eq!(key.derive_child(index).unwrap().to_base58(), child);
+    }
+
+    // BIP32 test vector 1, public derivation steps
+    #[test]
+    fn bip32_test_vector_1() {
+        assert_child(
+            "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw",
+            1,
+            "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ",
+        );
+        assert_child(
+            "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5",
+            2,
+            "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV",
+        );
+        assert_child(
+            "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV",
+            1_000_000_000,
+            "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy",
+        );
+    }
+
+    // BIP32 test vector 2, public derivation steps
+    #[test]
+    fn bip32_test_vector_2() {
+        assert_child(
+            "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB",
+            0,
+            "xpub69H7F5d8KSRgmmdJg2KhpAK8SR3DjMwAdkxj3ZuxV27CprR9LgpeyGmXUbC6wb7ERfvrnKZjXoUmmDznezpbZb7ap6r1D3tgFxHmwMkQTPH",
+        );
+        assert_child(
+            "xpub6ASAVgeehLbnwdqV6UKMHVzgqAG8Gr6riv3Fxxpj8ksbH9ebxaEyBLZ85ySDhKiLDBrQSARLq1uNRts8RuJiHjaDMBU4Zn9h8LZNnBC5y4a",
+            1,
+            "xpub6DF8uhdarytz3FWdA8TvFSvvAh8dP3283MY7p2V4SeE2wyWmG5mg5EwVvmdMVCQcoNJxGoWaU9DCWh89LojfZ537wTfunKau47EL2dhHKon",
+        );
+        assert_child(
+            "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL",
+            2,
+            "xpub6FnCn6nSzZAw5Tw7cgR9bi15UV96gLZhjDstkXXxvCLsUXBGXPdSnLFbdpq8p9HmGsApME5hQTZ3emM2rnY5agb9rXpVGyy3bdW6EEgAtqt",
+        );
+    }
+
+    #[test]
+    fn hardened_derivation_is_rejected() {
+        let key = ExtendedPubKey::from_base58(
+            "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB"
+        ).unwrap();
+        assert!(key.derive_child(HARDENED_OFFSET).is_err());
+    }
+
+    #[test]
+    fn max_depth_is_rejected() {
+        let mut key = ExtendedPubKey::from_base58(
+            "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB"
+        ).unwrap();
+        key.depth = 254;
+        assert_eq!(key.derive_child(0).unwrap().depth, 255);
+        key.depth = 255;
+        assert!(key.derive_child(0).is_err());
+    }
+}
@@ -8,11 +8,14 @@ pub mod convert_numeric;
 mod errors;
 mod slp_amount;
 mod block;
+mod hd_key;
 
 pub use config::*;
-pub use errors::{Error, ErrorKind, TradeOfferError, NumericError, SLPError, TokenError, Result};
+pub use errors::{Error, ErrorKind, TradeOfferError, NumericError, SLPError, TokenError, HdKeyError,
+                 TxBuildError, TxRejection, BurnReason, SLPInputCheck, Result};
 pub use slp_amount::*;
 pub use block::*;
+pub use hd_key::*;
 
 #[cfg(test)]
 mod tests {
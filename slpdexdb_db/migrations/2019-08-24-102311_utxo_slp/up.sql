@@ -0,0 +1,11 @@
+CREATE TABLE utxo_slp (
+    "tx"          BIGINT NOT NULL,
+    "idx"         INT NOT NULL,
+    "address"     BYTEA,
+    "token"       INT NOT NULL REFERENCES token ("id") ON DELETE CASCADE,
+    PRIMARY KEY ("tx", "idx"),
+    FOREIGN KEY ("tx", "idx") REFERENCES tx_output ("tx", "idx") ON DELETE CASCADE
+);
+
+CREATE INDEX utxo_slp_token_idx ON utxo_slp ("token");
+CREATE INDEX utxo_slp_address_idx ON utxo_slp ("address");
@@ -0,0 +1,2 @@
+DROP TABLE hd_account_address;
+DROP TABLE hd_account;
@@ -0,0 +1,15 @@
+CREATE TABLE hd_account (
+    "id"          SERIAL PRIMARY KEY,
+    "xpub"        VARCHAR(120) NOT NULL UNIQUE,
+    "gap_limit"   INT NOT NULL
+);
+
+CREATE TABLE hd_account_address (
+    "account"     INT NOT NULL REFERENCES hd_account ("id") ON DELETE CASCADE,
+    "is_change"   BOOLEAN NOT NULL,
+    "idx"         INT NOT NULL,
+    "address"     BYTEA NOT NULL,
+    PRIMARY KEY ("account", "is_change", "idx")
+);
+
+CREATE INDEX hd_account_address_address_idx ON hd_account_address ("address");
@@ -0,0 +1 @@
+ALTER TABLE trade_offer DROP COLUMN "power";
@@ -0,0 +1 @@
+ALTER TABLE trade_offer ADD COLUMN "power" SMALLINT NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+DROP TABLE trade_offer_state_history;
+DROP INDEX trade_offer_input_idx;
+ALTER TABLE trade_offer DROP COLUMN "spent_tx";
+ALTER TABLE trade_offer DROP COLUMN "state";
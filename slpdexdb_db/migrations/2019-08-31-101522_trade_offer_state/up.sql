@@ -0,0 +1,40 @@
+ALTER TABLE trade_offer ADD COLUMN "state" SMALLINT NOT NULL DEFAULT 1;
+ALTER TABLE trade_offer ADD COLUMN "spent_tx" BYTEA;
+
+CREATE TABLE trade_offer_state_history (
+    "id"          BIGSERIAL PRIMARY KEY,
+    "trade_offer" INT NOT NULL REFERENCES trade_offer ("id") ON DELETE CASCADE,
+    "state"       SMALLINT NOT NULL,
+    "tx"          BYTEA,
+    "timestamp"   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX trade_offer_state_history_trade_offer_idx ON trade_offer_state_history ("trade_offer");
+CREATE INDEX trade_offer_input_idx ON trade_offer ("input_tx", "input_idx");
+
+-- offers without a matching contract output never opened
+UPDATE trade_offer SET "state" = 5 WHERE "output_idx" IS NULL;
+
+-- already spent offers: filled (or pending while unconfirmed) if the spender paid the maker
+-- what the contract demands, cancelled otherwise; the same rule as the indexer's
+UPDATE trade_offer SET
+    "spent_tx" = spender."hash",
+    "state" = CASE WHEN EXISTS (
+        SELECT 1 FROM tx_output
+        WHERE tx_output."tx" = spender."id" AND
+              tx_output."address" = trade_offer."receiving_address" AND
+              tx_output."value_satoshis" >= CASE
+                  WHEN trade_offer."is_inverted"
+                  THEN CEIL(trade_offer."sell_amount_token_base" / NULLIF(trade_offer."script_price", 0))
+                  ELSE trade_offer."sell_amount_token_base" * trade_offer."script_price"
+              END
+    ) THEN (CASE WHEN spender."height" IS NULL THEN 2 ELSE 3 END) ELSE 4 END
+FROM tx offer_tx, tx_input, tx spender
+WHERE
+    offer_tx."id" = trade_offer."tx" AND
+    tx_input."output_tx" = offer_tx."hash" AND
+    tx_input."output_idx" = trade_offer."output_idx" AND
+    spender."id" = tx_input."tx";
+
+INSERT INTO trade_offer_state_history ("trade_offer", "state", "tx")
+SELECT "id", "state", "spent_tx" FROM trade_offer;
@@ -0,0 +1,12 @@
+CREATE TABLE trade_fee (
+    "trade_offer"    INT PRIMARY KEY REFERENCES trade_offer ("id") ON DELETE CASCADE,
+    "tx"             BIGINT NOT NULL,
+    "idx"            INT NOT NULL,
+    "fee_address"    BYTEA NOT NULL,
+    "value_satoshis" BIGINT NOT NULL,
+    "token"          INT NOT NULL REFERENCES token ("id") ON DELETE CASCADE,
+    "timestamp"      BIGINT NOT NULL,
+    FOREIGN KEY ("tx", "idx") REFERENCES tx_output ("tx", "idx") ON DELETE CASCADE
+);
+
+CREATE INDEX trade_fee_timestamp_idx ON trade_fee ("timestamp");
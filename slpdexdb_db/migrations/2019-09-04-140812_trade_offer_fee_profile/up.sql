@@ -0,0 +1,4 @@
+ALTER TABLE trade_offer ADD COLUMN "fee_profile" VARCHAR(64);
+
+-- offers matched before profiles existed were matched against our own fee settings
+UPDATE trade_offer SET "fee_profile" = 'default' WHERE "output_idx" IS NOT NULL;
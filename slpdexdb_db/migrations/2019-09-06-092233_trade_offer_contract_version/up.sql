@@ -0,0 +1,2 @@
+ALTER TABLE trade_offer ADD COLUMN "contract_version" SMALLINT NOT NULL DEFAULT 2;
+ALTER TABLE trade_offer ALTER COLUMN "contract_version" DROP DEFAULT;
@@ -0,0 +1,4 @@
+CREATE TABLE raw_tx (
+    "hash" BYTEA PRIMARY KEY,
+    "raw"  BYTEA NOT NULL
+);
@@ -0,0 +1,10 @@
+CREATE TABLE event_outbox (
+    "seq"        BIGSERIAL PRIMARY KEY,
+    "subject"    TEXT NOT NULL,
+    "event_type" VARCHAR(32) NOT NULL,
+    "payload"    TEXT NOT NULL,
+    "timestamp"  TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX event_outbox_subject_idx ON event_outbox ("subject", "seq");
+CREATE INDEX event_outbox_timestamp_idx ON event_outbox ("timestamp");
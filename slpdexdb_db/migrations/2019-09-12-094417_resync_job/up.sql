@@ -0,0 +1,17 @@
+CREATE TABLE resync_job (
+    "id"         SERIAL PRIMARY KEY,
+    "job_type"   INT NOT NULL,
+    "subject"    BYTEA,
+    "priority"   INT NOT NULL,
+    "state"      INT NOT NULL,
+    "attempts"   INT NOT NULL DEFAULT 0,
+    "run_after"  TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "last_error" TEXT,
+    "created"    TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updated"    TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- at most one pending job per subject; state 1 is ResyncJobState::Pending
+CREATE UNIQUE INDEX resync_job_pending_idx ON resync_job ("job_type", COALESCE("subject", ''::BYTEA))
+    WHERE "state" = 1;
+CREATE INDEX resync_job_claim_idx ON resync_job ("priority" DESC, "id") WHERE "state" = 1;
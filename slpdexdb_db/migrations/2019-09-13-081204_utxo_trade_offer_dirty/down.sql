@@ -0,0 +1,5 @@
+DROP TABLE utxo_trade_offer_dirty;
+
+-- cursors written since don't follow the old incremental update; without one it rebuilds
+-- utxo_trade_offer in full, as after the up migration
+DELETE FROM update_history WHERE "subject_type" = 7;
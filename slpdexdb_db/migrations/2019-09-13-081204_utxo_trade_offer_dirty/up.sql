@@ -0,0 +1,9 @@
+-- txs added since the last incremental update of utxo_trade_offer
+CREATE TABLE utxo_trade_offer_dirty (
+    "tx" BIGINT PRIMARY KEY
+);
+
+-- The dropped rows only record up to where utxo_trade_offer was last updated, a cursor the
+-- dirty set replaces. utxo_trade_offer is derived from the tx index, and without a cursor
+-- the next update rebuilds it in full, so nothing is lost.
+DELETE FROM update_history WHERE "subject_type" = 7;
@@ -0,0 +1,3 @@
+-- offers stored before the power column defaulted to 0; a full EXCH resync re-parses them
+-- and corrects their power
+DELETE FROM update_history WHERE "subject_type" = 2;
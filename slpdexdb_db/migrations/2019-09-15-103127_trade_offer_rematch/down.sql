@@ -0,0 +1,2 @@
+-- the resync can't be undone
+SELECT 1;
@@ -0,0 +1,3 @@
+-- offers of fee profiles configured only later were stored without a contract output; a full
+-- EXCH resync re-parses them and matches them against every configured profile
+DELETE FROM update_history WHERE "subject_type" = 2;
@@ -0,0 +1,4 @@
+-- P2SH jobs can't be told apart from P2PKH jobs without the type
+DELETE FROM resync_job WHERE "job_type" = 3 AND get_byte("subject", 0) = 2;
+UPDATE resync_job SET "subject" = substring("subject" FROM 2)
+WHERE "job_type" = 3 AND length("subject") = 21;
@@ -0,0 +1,4 @@
+-- address subjects carry the OutputType id of the address before its hash; queued jobs were
+-- always resynced as P2PKH, 3 is OutputType::Address(P2PKH)
+UPDATE resync_job SET "subject" = '\x03'::BYTEA || "subject"
+WHERE "job_type" = 3 AND length("subject") = 20;
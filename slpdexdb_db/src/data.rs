@@ -1,5 +1,8 @@
-use cashcontracts::Address;
+use cashcontracts::{Address, AddressType, tx_hash_to_hex, tx_hex_to_hash};
 use slpdexdb_base::SLPAmount;
+use rug::{Rational, Integer};
+use crate::token::Token;
+use crate::tx_history::TradeOffer;
 
 #[derive(Clone, Debug)]
 pub struct Utxo {
@@ -27,6 +30,191 @@ pub enum NewUtxo {
         tx_hash: [u8; 32],
         vout: i32,
     },
+    Slp {
+        tx_hash: [u8; 32],
+        vout: i32,
+        address: Option<Address>,
+        token_hash: [u8; 32],
+    },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UtxoSetDiff {
+    pub missing: Vec<(i64, i32)>,
+    pub extra: Vec<(i64, i32)>,
+}
+
+impl UtxoSetDiff {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// What a client subscribes to; every outbox event belongs to exactly one subject.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EventSubject {
+    Address(Address),
+    Account(String),  // xpub
+    Token([u8; 32]),
+}
+
+impl EventSubject {
+    pub fn key(&self) -> String {
+        match self {
+            EventSubject::Address(address) => format!("address:{}", address.cash_addr()),
+            EventSubject::Account(xpub) => format!("account:{}", xpub),
+            EventSubject::Token(token_hash) => format!("token:{}", tx_hash_to_hex(token_hash)),
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        let mut parts = key.splitn(2, ':');
+        match (parts.next()?, parts.next()?) {
+            ("address", address) => Address::from_cash_addr(address.to_string())
+                .ok()
+                .map(EventSubject::Address),
+            ("account", xpub) => Some(EventSubject::Account(xpub.to_string())),
+            ("token", token_hex) => tx_hex_to_hash(token_hex).map(EventSubject::Token),
+            _ => None,
+        }
+    }
+}
+
+/// An event for `event_outbox`; `payload` is the JSON sent to the subject's subscribers.
+#[derive(Clone, Debug)]
+pub struct NewOutboxEvent {
+    pub subject: EventSubject,
+    pub event_type: &'static str,
+    pub payload: String,
+}
+
+/// A stored event, ordered by its sequence number.
+#[derive(Clone, Debug)]
+pub struct OutboxEvent {
+    pub seq: i64,
+    pub subject: EventSubject,
+    pub event_type: String,
+    pub payload: String,
+    pub timestamp: i64,
+}
+
+/// An invariant violation found by `Db::check_consistency`.
+#[derive(Clone, Debug)]
+pub enum Inconsistency {
+    /// `utxo_address` row of a spent output.
+    ExtraUtxoAddress { tx: i64, idx: i32 },
+    /// Unspent output of an active address without `utxo_address` row.
+    MissingUtxoAddress { tx: i64, idx: i32, address: Vec<u8> },
+    /// `utxo_trade_offer` row which isn't an unspent contract output.
+    ExtraUtxoTradeOffer { tx: i64, idx: i32 },
+    /// Unspent contract output without `utxo_trade_offer` row.
+    MissingUtxoTradeOffer { tx: i64, idx: i32 },
+    SlpTxWithoutToken { tx: i64 },
+    /// Offer which isn't invalidated but whose contract output doesn't exist.
+    MissingContractOutput { trade_offer: i32, tx: i64, output_idx: Option<i32> },
+    /// Completed update whose height is below that of an earlier completed update of the same
+    /// subject, so the next resync would refetch from there.
+    UpdateCursorRegressed { update: i64, subject_type: i32, subject_hash: Option<Vec<u8>>,
+                            is_confirmed: bool, last_height: i32, previous_height: i32 },
+    /// Heights missing in `blocks`.
+    BlockHeightGap { from_height: i32, to_height: i32 },
+}
+
+impl std::fmt::Display for Inconsistency {
+    fn fmt<'a>(&self, f: &mut std::fmt::Formatter<'a>) -> Result<(), std::fmt::Error> {
+        use self::Inconsistency::*;
+        match self {
+            ExtraUtxoAddress { tx, idx } =>
+                write!(f, "utxo_address {}:{} is spent", tx, idx),
+            MissingUtxoAddress { tx, idx, address } =>
+                write!(f, "utxo_address {}:{} of {} is missing", tx, idx, hex::encode(address)),
+            ExtraUtxoTradeOffer { tx, idx } =>
+                write!(f, "utxo_trade_offer {}:{} isn't an unspent contract output", tx, idx),
+            MissingUtxoTradeOffer { tx, idx } =>
+                write!(f, "utxo_trade_offer {}:{} is missing", tx, idx),
+            SlpTxWithoutToken { tx } =>
+                write!(f, "slp_tx {} has no token", tx),
+            MissingContractOutput { trade_offer, tx, output_idx } =>
+                write!(f, "trade_offer {} has no contract output {}:{:?}", trade_offer, tx, output_idx),
+            UpdateCursorRegressed { update, subject_type, subject_hash, is_confirmed, last_height,
+                                    previous_height } =>
+                write!(f, "update_history {} of subject {} {} (confirmed: {}) went back to height {} \
+                           from {}",
+                       update, subject_type, subject_hash.as_ref().map(hex::encode).unwrap_or_default(),
+                       is_confirmed, last_height, previous_height),
+            BlockHeightGap { from_height, to_height } =>
+                write!(f, "blocks {} to {} are missing", from_height, to_height),
+        }
+    }
+}
+
+/// An open trade offer together with what's needed to spend its contract output.
+#[derive(Clone, Debug)]
+pub struct TradeOfferUtxo {
+    pub trade_offer: TradeOffer,
+    pub token_hash: [u8; 32],
+    pub value_satoshis: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TradeOfferState {
+    Open = 1,
+    PendingTake = 2,  // spent by an unconfirmed tx paying the maker
+    Filled = 3,
+    Cancelled = 4,
+    Invalidated = 5,  // never had a contract output, or its tx was double spent
+}
+
+impl TradeOfferState {
+    pub fn from_id(id: i16) -> Option<Self> {
+        match id {
+            1 => Some(TradeOfferState::Open),
+            2 => Some(TradeOfferState::PendingTake),
+            3 => Some(TradeOfferState::Filled),
+            4 => Some(TradeOfferState::Cancelled),
+            5 => Some(TradeOfferState::Invalidated),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> i16 {
+        *self as i16
+    }
+
+    pub fn is_final(&self) -> bool {
+        match self {
+            TradeOfferState::Filled | TradeOfferState::Cancelled => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TradeOfferStateChange {
+    pub offer_tx: [u8; 32],
+    pub offer_vout: Option<i32>,
+    pub token_hash: Option<[u8; 32]>,
+    pub receiving_address: Address,
+    pub state: TradeOfferState,
+    pub spent_tx: Option<[u8; 32]>,
+    pub timestamp: i64,
+}
+
+/// An offer an unconfirmed tx paying the maker spent.
+#[derive(Clone, Debug)]
+pub struct PendingTake {
+    pub trade_offer: i32,
+    pub offer_tx: [u8; 32],
+    pub spent_tx: [u8; 32],
+    pub since: i64,
+}
+
+impl PendingTake {
+    /// Whether the offer is open again: `grace_secs` after it went pending, its taker tx is
+    /// neither mined nor in the mempool, i.e. it was evicted, double spent or reorged out.
+    pub fn is_reverted(&self, is_spent_tx_known: bool, now: i64, grace_secs: i64) -> bool {
+        !is_spent_tx_known && now - self.since >= grace_secs
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -42,6 +230,218 @@ pub struct TxDelta {
     pub delta_token: SLPAmount,
     pub token_hash: Option<[u8; 32]>,
     pub timestamp: i64,
+    pub height: Option<i32>,
+    // balances after the tx; only paged history has them, pushed deltas leave them `None`
+    pub running_balance_satoshis: Option<i64>,
+    pub running_balance_token: Option<SLPAmount>,
+}
+
+impl TxDelta {
+    pub fn is_confirmed(&self) -> bool {
+        self.height.is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxDeltaKind {
+    All = 0,
+    Bch = 1,
+    Slp = 2,
+}
+
+/// Position of a delta in the history, ordered by height (unconfirmed last),
+/// timestamp and `tx.id`, which follows the in-block spend order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxDeltaCursor {
+    pub sort_height: i32,
+    pub timestamp: i64,
+    pub tx_id: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct TxDeltaQuery {
+    pub cursor: Option<TxDeltaCursor>,
+    pub limit: Option<i64>,
+    pub token_hash: Option<[u8; 32]>,
+    pub kind: TxDeltaKind,
+    pub min_height: Option<i32>,
+    pub max_height: Option<i32>,
+    pub min_timestamp: Option<i64>,
+    pub max_timestamp: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TxDeltaPage {
+    pub tx_deltas: Vec<TxDelta>,
+    pub next_cursor: Option<TxDeltaCursor>,
+}
+
+impl Default for TxDeltaQuery {
+    fn default() -> Self {
+        TxDeltaQuery {
+            cursor: None,
+            limit: None,
+            token_hash: None,
+            kind: TxDeltaKind::All,
+            min_height: None,
+            max_height: None,
+            min_timestamp: None,
+            max_timestamp: None,
+        }
+    }
+}
+
+impl std::fmt::Display for TxDeltaCursor {
+    fn fmt<'a>(&self, f: &mut std::fmt::Formatter<'a>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}-{}-{}", self.sort_height, self.timestamp, self.tx_id)
+    }
+}
+
+impl std::str::FromStr for TxDeltaCursor {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, '-');
+        let mut next = || parts.next().unwrap_or("");
+        Ok(TxDeltaCursor {
+            sort_height: next().parse()?,
+            timestamp: next().parse()?,
+            tx_id: next().parse()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TradeRole {
+    Maker,
+    Taker,
+}
+
+/// Position of a trade in an address's history, ordered by the time of its last action.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TradeHistoryCursor {
+    pub timestamp: i64,
+    pub offer_id: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TradeHistoryEntry {
+    pub role: TradeRole,
+    pub trade_offer: TradeOffer,
+    pub token_hash: [u8; 32],
+    pub state: TradeOfferState,
+    pub spent_tx: Option<[u8; 32]>,
+    pub created_timestamp: i64,
+    pub spent_timestamp: Option<i64>,
+    pub payment_satoshis: u64,
+    pub fee_satoshis: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct TradeHistoryPage {
+    pub trades: Vec<TradeHistoryEntry>,
+    pub next_cursor: Option<TradeHistoryCursor>,
+}
+
+impl std::fmt::Display for TradeHistoryCursor {
+    fn fmt<'a>(&self, f: &mut std::fmt::Formatter<'a>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}-{}", self.timestamp, self.offer_id)
+    }
+}
+
+impl std::str::FromStr for TradeHistoryCursor {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '-');
+        let mut next = || parts.next().unwrap_or("");
+        Ok(TradeHistoryCursor {
+            timestamp: next().parse()?,
+            offer_id: next().parse()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TradeFeeQuery {
+    pub min_timestamp: Option<i64>,
+    pub max_timestamp: Option<i64>,
+    pub token_hash: Option<[u8; 32]>,
+}
+
+/// Fees collected for one token on one (UTC) day at one fee address.
+#[derive(Clone, Debug)]
+pub struct TradeFeeSummary {
+    pub token_hash: [u8; 32],
+    pub symbol: Option<String>,
+    pub day: String,
+    pub fee_address: Address,
+    pub trade_count: i64,
+    pub fee_satoshis: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenBalance {
+    pub token: Token,
+    pub confirmed: SLPAmount,
+    pub unconfirmed: SLPAmount,
+}
+
+#[derive(Clone, Debug)]
+pub struct AddressBalance {
+    pub addresses: Vec<Address>,
+    pub satoshis_confirmed: u64,
+    pub satoshis_unconfirmed: u64,
+    pub tokens: Vec<TokenBalance>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenHolder {
+    pub address: Option<Address>,
+    pub amount: SLPAmount,
+    pub share: Rational,
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenDistribution {
+    pub token: Token,
+    pub height: i32,
+    pub circulating_supply: SLPAmount,
+    pub holders: Vec<TokenHolder>,
+}
+
+impl TokenDistribution {
+    /// The distribution at `height` of the holders' amounts, largest first.
+    pub fn new(token: Token, height: i32, amounts: Vec<(Option<Address>, SLPAmount)>) -> Self {
+        let decimals = token.decimals as u32;
+        let circulating_supply = amounts.iter()
+            .fold(SLPAmount::new(0, decimals), |acc, (_, amount)| acc + *amount);
+        let holders = amounts.into_iter()
+            .map(|(address, amount)| TokenHolder {
+                address,
+                amount,
+                share: if circulating_supply.base_amount() > 0 {
+                    Rational::from((Integer::from(amount.base_amount()),
+                                    Integer::from(circulating_supply.base_amount())))
+                } else {
+                    Rational::new()
+                },
+            })
+            .collect();
+        TokenDistribution { token, height, circulating_supply, holders }
+    }
+
+    pub fn holder_count(&self) -> usize {
+        self.holders.len()
+    }
+
+    /// Share of the circulating supply held by the `n` largest holders.
+    pub fn top_concentration(&self, n: usize) -> Rational {
+        self.holders.iter()
+            .take(n)
+            .map(|holder| &holder.share)
+            .fold(Rational::new(), |acc, share| acc + share)
+    }
 }
 
 pub fn tx_hash_from_slice(slice: &[u8]) -> [u8; 32] {
@@ -55,3 +455,138 @@ pub fn address_hash_from_slice(slice: &[u8]) -> [u8; 20] {
     hash.copy_from_slice(&slice);
     hash
 }
+
+pub fn address_from_output_type(output_type: i32, slice: &[u8]) -> Option<Address> {
+    let addr_type = match output_type {
+        2 => AddressType::P2SH,
+        3 => AddressType::P2PKH,
+        _ => return None,
+    };
+    Address::from_slice(addr_type, slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tx_delta_cursor_round_trip() {
+        let cursor = TxDeltaCursor { sort_height: 600123, timestamp: 1568000000, tx_id: 42 };
+        assert_eq!(cursor.to_string(), "600123-1568000000-42");
+        assert_eq!(cursor.to_string().parse::<TxDeltaCursor>(), Ok(cursor));
+    }
+
+    #[test]
+    fn tx_delta_cursor_invalid() {
+        assert!("".parse::<TxDeltaCursor>().is_err());
+        assert!("600123-1568000000".parse::<TxDeltaCursor>().is_err());
+        assert!("600123-1568000000-42-1".parse::<TxDeltaCursor>().is_err());
+        assert!("a-b-c".parse::<TxDeltaCursor>().is_err());
+    }
+
+    #[test]
+    fn trade_history_cursor_round_trip() {
+        let cursor = TradeHistoryCursor { timestamp: 1568000000, offer_id: 42 };
+        assert_eq!(cursor.to_string(), "1568000000-42");
+        assert_eq!(cursor.to_string().parse::<TradeHistoryCursor>(), Ok(cursor));
+    }
+
+    #[test]
+    fn trade_history_cursor_invalid() {
+        assert!("".parse::<TradeHistoryCursor>().is_err());
+        assert!("1568000000".parse::<TradeHistoryCursor>().is_err());
+        assert!("1568000000-42-1".parse::<TradeHistoryCursor>().is_err());
+        assert!("a-b".parse::<TradeHistoryCursor>().is_err());
+    }
+
+    fn pending_take(since: i64) -> PendingTake {
+        PendingTake { trade_offer: 1, offer_tx: [1; 32], spent_tx: [2; 32], since }
+    }
+
+    #[test]
+    fn pending_take_reverts_once_taker_tx_is_gone() {
+        assert!(pending_take(1000).is_reverted(false, 1600, 600));
+        assert!(pending_take(1000).is_reverted(false, 2000, 600));
+    }
+
+    #[test]
+    fn pending_take_kept_while_taker_tx_known_or_recent() {
+        assert!(!pending_take(1000).is_reverted(true, 2000, 600));
+        // the tx source may not have seen a just relayed tx yet
+        assert!(!pending_take(1000).is_reverted(false, 1599, 600));
+    }
+
+    const ADDRESS: &str = "bitcoincash:qrhea03074073ff3zv9whh0nggxc7k03ssh8jv9mkx";
+    const XPUB: &str = "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1\
+                        VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw";
+
+    #[test]
+    fn event_subject_key_round_trip() {
+        let subjects = vec![
+            EventSubject::Address(Address::from_cash_addr(ADDRESS.to_string()).unwrap()),
+            EventSubject::Account(XPUB.to_string()),
+            EventSubject::Token([0xab; 32]),
+        ];
+        for subject in subjects {
+            assert_eq!(EventSubject::from_key(&subject.key()), Some(subject));
+        }
+        assert_eq!(EventSubject::Account(XPUB.to_string()).key(), format!("account:{}", XPUB));
+    }
+
+    #[test]
+    fn event_subject_invalid_key() {
+        assert_eq!(EventSubject::from_key("address"), None);
+        assert_eq!(EventSubject::from_key("address:bitcoincash:qqq"), None);
+        assert_eq!(EventSubject::from_key("block:00"), None);
+    }
+
+    fn token(decimals: i32) -> Token {
+        Token {
+            hash: [1; 32],
+            decimals,
+            timestamp: 0,
+            version_type: 1,
+            document_uri: None,
+            symbol: None,
+            name: None,
+            document_hash: None,
+            initial_supply: SLPAmount::new(0, decimals as u32),
+            current_supply: SLPAmount::new(0, decimals as u32),
+            block_created_height: 0,
+        }
+    }
+
+    #[test]
+    fn token_distribution_shares() {
+        let address = |byte| Some(Address::from_bytes(AddressType::P2PKH, [byte; 20]));
+        let distribution = TokenDistribution::new(token(2), 600000, vec![
+            (address(1), SLPAmount::new(600, 2)),
+            (None, SLPAmount::new(300, 2)),  // output without a known address
+            (address(2), SLPAmount::new(100, 2)),
+        ]);
+        assert_eq!(distribution.circulating_supply.base_amount(), 1000);
+        assert_eq!(distribution.holder_count(), 3);
+        let shares = distribution.holders.iter()
+            .map(|holder| holder.share.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(shares, vec![Rational::from((3, 5)), Rational::from((3, 10)),
+                                Rational::from((1, 10))]);
+        assert_eq!(distribution.top_concentration(0), Rational::new());
+        assert_eq!(distribution.top_concentration(1), Rational::from((3, 5)));
+        assert_eq!(distribution.top_concentration(2), Rational::from((9, 10)));
+        assert_eq!(distribution.top_concentration(10), Rational::from(1));
+    }
+
+    #[test]
+    fn token_distribution_without_supply() {
+        let distribution = TokenDistribution::new(token(0), 600000, vec![]);
+        assert_eq!(distribution.circulating_supply.base_amount(), 0);
+        assert_eq!(distribution.top_concentration(3), Rational::new());
+
+        let distribution = TokenDistribution::new(token(0), 600000, vec![
+            (None, SLPAmount::new(0, 0)),
+        ]);
+        assert_eq!(distribution.holders[0].share, Rational::new());
+        assert_eq!(distribution.top_concentration(1), Rational::new());
+    }
+}
@@ -4,27 +4,105 @@ use diesel::prelude::*;
 
 use cashcontracts::{Address, AddressType};
 use slpdexdb_base::{BlockHeader, GENESIS};
-use slpdexdb_base::SLPAmount;
+use slpdexdb_base::{SLPAmount, SLPDEXConfig};
 use slpdexdb_base::convert_numeric::{rational_to_pg_numeric, pg_numeric_to_rational};
 use crate::tx_history::{TxHistory, TxType, TradeOffer};
-use crate::update_history::{UpdateHistory, UpdateSubject};
+use crate::update_history::{UpdateHistory, UpdateSubject, UpdateSubjectType};
 use crate::token::Token;
+use crate::tx_builder::{FundingUtxo, script_payment, offer_payment_and_fee};
 use crate::{models, schema::*};
 use crate::convert::pg_safe_string;
+use crate::protocol::{ProtocolParsers, ProtocolRecord};
 use crate::data::{Utxo, NewUtxo, SpentUtxo, TxDelta, tx_hash_from_slice, address_hash_from_slice,
-                  TradeOfferFilter};
+                  TradeOfferFilter, UtxoSetDiff,
+                  TxDeltaQuery, TxDeltaPage, TxDeltaCursor, AddressBalance, TokenBalance,
+                  address_from_output_type, TradeOfferUtxo, TradeOfferState,
+                  TradeOfferStateChange, TradeRole, TradeHistoryEntry, TradeHistoryPage,
+                  TradeHistoryCursor, TradeFeeQuery, TradeFeeSummary, Inconsistency,
+                  EventSubject, NewOutboxEvent, OutboxEvent, PendingTake};
+use crate::resync_job::{ResyncJob, ResyncJobState, ResyncTask};
 
 use std::collections::{HashMap, HashSet, BTreeSet};
+use std::sync::Arc;
 
 const PRICE_DIGITS: u16 = 26;
+const OUTBOX_LOCK_ID: i64 = 0x6f7574626f78;  // "outbox"
+
+/// Channel `Db::notify_outbox` notifies API instances on, with the newest seq as payload.
+pub const OUTBOX_CHANNEL: &str = "event_outbox";
+
+const RESYNC_JOB_COLUMNS: &str = "\
+    id, job_type, subject, priority, state, attempts,
+    EXTRACT(EPOCH FROM run_after)::BIGINT AS run_after, last_error,
+    EXTRACT(EPOCH FROM created)::BIGINT AS created";
+// another pending job for the same subject as the `resync_job` row of the outer query
+const RESYNC_JOB_PENDING_DUPLICATE: &str = "\
+    EXISTS (
+        SELECT 1 FROM resync_job AS duplicate
+        WHERE
+            duplicate.state = 1 AND
+            duplicate.id <> resync_job.id AND
+            duplicate.job_type = resync_job.job_type AND
+            COALESCE(duplicate.subject, ''::BYTEA) = COALESCE(resync_job.subject, ''::BYTEA)
+    )";
+
+// columns `Db::_trade_offer_from_columns` builds a `TradeOffer` from
+type TradeOfferColumns = (Vec<u8>, Option<i32>, Vec<u8>, i32, i64, PgNumeric, Vec<u8>, PgNumeric, bool,
+                          i32, i16, Option<String>, i16);
+
+struct TradeOfferStateRow {
+    id: i32,
+    tx_hash: [u8; 32],
+    output_idx: Option<i32>,
+    input_tx: [u8; 32],
+    input_idx: i32,
+    state: TradeOfferState,
+    spent_tx: Option<[u8; 32]>,
+    payment: u64,
+    receiving_address: Vec<u8>,
+    token_hash: Option<[u8; 32]>,
+}
+
+/// Builds the outbox events of newly indexed txs; the events' payloads are the endpoint's,
+/// so it injects its builder with `Db::with_outbox_event_builder`.
+pub trait OutboxEventBuilder: Send + Sync {
+    fn outbox_events(&self,
+                     db: &Db,
+                     history: &TxHistory,
+                     trade_offer_states: &[TradeOfferStateChange],
+                     relevant_addresses: &HashSet<Address>,
+                     now: i64) -> slpdexdb_base::Result<Vec<NewOutboxEvent>>;
+}
 
 pub struct Db {
     connection: PgConnection,
+    protocol_parsers: Arc<ProtocolParsers>,
+    outbox_event_builder: Option<Arc<dyn OutboxEventBuilder>>,
 }
 
 impl Db {
     pub fn new(connection: PgConnection) -> Self {
-        Db { connection }
+        Db::with_protocol_parsers(connection, Arc::new(ProtocolParsers::default()))
+    }
+
+    pub fn with_protocol_parsers(connection: PgConnection, protocol_parsers: Arc<ProtocolParsers>)
+            -> Self {
+        Db { connection, protocol_parsers, outbox_event_builder: None }
+    }
+
+    /// Without a builder, indexing writes no outbox events.
+    pub fn with_outbox_event_builder(self, outbox_event_builder: Arc<dyn OutboxEventBuilder>)
+            -> Self {
+        Db { outbox_event_builder: Some(outbox_event_builder), ..self }
+    }
+
+    pub fn protocol_parsers(&self) -> &ProtocolParsers {
+        &self.protocol_parsers
+    }
+
+    /// For protocol parsers storing records in their own tables.
+    pub fn connection(&self) -> &PgConnection {
+        &self.connection
     }
 
     pub fn add_headers(&self, headers: &[BlockHeader]) -> QueryResult<()> {
@@ -101,7 +179,70 @@ impl Db {
         Ok(())
     }
 
-    pub fn add_tx_history(&self, tx_history: &TxHistory) -> QueryResult<()> {
+    pub fn add_hd_account(&self, xpub: &str, gap_limit: i32) -> QueryResult<i32> {
+        diesel::insert_into(hd_account::table)
+            .values(models::NewHdAccount { xpub: xpub.to_string(), gap_limit })
+            .on_conflict(hd_account::xpub)
+            .do_update()
+            .set(hd_account::gap_limit.eq(gap_limit))
+            .returning(hd_account::id)
+            .get_result(&self.connection)
+    }
+
+    pub fn add_hd_account_address(&self, account: i32, is_change: bool, idx: i32,
+                                  address: &Address) -> QueryResult<()> {
+        diesel::insert_into(hd_account_address::table)
+            .values(models::HdAccountAddress {
+                account,
+                is_change,
+                idx,
+                address: address.bytes().to_vec(),
+            })
+            .on_conflict_do_nothing()
+            .execute(&self.connection)?;
+        Ok(())
+    }
+
+    pub fn hd_account(&self, xpub: &str) -> QueryResult<Option<i32>> {
+        hd_account::table
+            .filter(hd_account::xpub.eq(xpub))
+            .select(hd_account::id)
+            .first(&self.connection)
+            .optional()
+    }
+
+    pub fn hd_account_addresses(&self, account: i32) -> QueryResult<Vec<Address>> {
+        let addresses = hd_account_address::table
+            .filter(hd_account_address::account.eq(account))
+            .order((hd_account_address::is_change, hd_account_address::idx))
+            .select(hd_account_address::address)
+            .load::<Vec<u8>>(&self.connection)?;
+        Ok(addresses.into_iter()
+            .map(|address| {
+                Address::from_bytes(AddressType::P2PKH, address_hash_from_slice(&address))
+            })
+            .collect())
+    }
+
+    /// Whether any known tx pays to or spends from the address.
+    pub fn is_address_used(&self, address: &Address) -> QueryResult<bool> {
+        use diesel::dsl::exists;
+        let address = address.bytes().to_vec();
+        let has_output = diesel::select(exists(
+            tx_output::table.filter(tx_output::address.eq(address.clone()))
+        )).get_result::<bool>(&self.connection)?;
+        if has_output {
+            return Ok(true);
+        }
+        diesel::select(exists(
+            tx_input::table.filter(tx_input::address.eq(address))
+        )).get_result(&self.connection)
+    }
+
+    /// Stores the txs and trade offers of `tx_history` and returns the offers it moved to a
+    /// new state.
+    pub fn add_tx_history(&self, tx_history: &TxHistory)
+            -> QueryResult<Vec<TradeOfferStateChange>> {
         self.connection.transaction(|| {
             let token_hashes = tx_history.txs.iter()
                 .filter_map(|tx| {
@@ -111,7 +252,9 @@ impl Db {
                     }
                 })
                 .collect::<HashSet<_>>();
-            let new_txs = tx_history.txs.iter().map(|tx| {
+            let insertion_order = tx_history.insertion_order();
+            let new_txs = insertion_order.iter().map(|&idx| {
+                let tx = &tx_history.txs[idx];
                 models::NewTx {
                     hash: tx.hash.to_vec(),
                     height: tx.height,
@@ -124,7 +267,7 @@ impl Db {
                 .filter(token::hash.eq_any(token_hashes))
                 .load(&self.connection)?;
             let token_ids = tokens.into_iter().collect::<HashMap<_, _>>();
-            let tx_ids = diesel::insert_into(tx::table)
+            let inserted_ids = diesel::insert_into(tx::table)
                 .values(&new_txs)
                 .on_conflict(tx::hash)
                 .do_update().set((tx::height.eq(tx::height),
@@ -132,6 +275,11 @@ impl Db {
                                   tx::timestamp.eq(tx::timestamp)))
                 .returning(tx::id)
                 .get_results::<i64>(&self.connection)?;
+            let mut tx_ids = vec![0; inserted_ids.len()];
+            for (&idx, id) in insertion_order.iter().zip(inserted_ids) {
+                tx_ids[idx] = id;
+            }
+            self._mark_exch_utxos_dirty(&tx_ids)?;
             let new_slp_txs = tx_history.txs
                 .iter()
                 .zip(tx_ids.iter().cloned())
@@ -202,14 +350,409 @@ impl Db {
                         script_price: trade_offer.script_price,
                         sell_amount_token_base: trade_offer.sell_amount_token.into(),
                         receiving_address: trade_offer.receiving_address.bytes().to_vec(),
+                        power: trade_offer.power as i16,
+                        fee_profile: trade_offer.fee_profile.clone(),
+                        contract_version: trade_offer.contract_version as i16,
                     }
                 })
                 .collect::<Vec<_>>();
-            diesel::insert_into(trade_offer::table)
+            let mut new_offers = diesel::insert_into(trade_offer::table)
                 .values(&new_trade_offers)
                 .on_conflict_do_nothing()
+                .returning((trade_offer::id, trade_offer::tx, trade_offer::output_idx))
+                .get_results::<(i32, i64, Option<i32>)>(&self.connection)?;
+            self._refresh_trade_offer_power(&new_trade_offers)?;
+            let rematched_offers = self._rematch_trade_offers(&new_trade_offers)?;
+            new_offers.extend(rematched_offers.iter().cloned());
+            for parser in self.protocol_parsers.iter() {
+                let tx_ids = &tx_ids;
+                let records = tx_history.records.iter()
+                    .flat_map(|(tx_idx, records)| {
+                        records.iter().map(move |record| (tx_ids[*tx_idx], record.as_ref()))
+                    })
+                    .filter(|(_, record)| record.lokad_id() == parser.lokad_id())
+                    .collect::<Vec<(i64, &dyn ProtocolRecord)>>();
+                if records.len() > 0 {
+                    parser.store(self, &records)?;
+                }
+            }
+            let mut changes = self._update_trade_offer_states(tx_history, &tx_ids, &new_offers)?;
+            changes.extend(self._update_rematched_offer_states(&rematched_offers)?);
+            Ok(changes)
+        })
+    }
+
+    /// Corrects the power of offers stored before it was recorded, which defaulted to 0.
+    fn _refresh_trade_offer_power(&self, trade_offers: &[models::NewTradeOffer]) -> QueryResult<()> {
+        use diesel::sql_types::{Array, BigInt, SmallInt};
+        if trade_offers.is_empty() {
+            return Ok(());
+        }
+        diesel::sql_query("\
+            UPDATE trade_offer
+            SET power = new_offer.power
+            FROM UNNEST($1::BIGINT[], $2::SMALLINT[]) AS new_offer (tx, power)
+            WHERE trade_offer.tx = new_offer.tx AND trade_offer.power <> new_offer.power
+        ").bind::<Array<BigInt>, _>(trade_offers.iter().map(|offer| offer.tx).collect::<Vec<_>>())
+            .bind::<Array<SmallInt>, _>(trade_offers.iter().map(|offer| offer.power).collect::<Vec<_>>())
+            .execute(&self.connection)?;
+        Ok(())
+    }
+
+    /// Matches offers stored without a contract output, e.g. before their fee profile was
+    /// configured, to the contract output found now; returns them like newly inserted offers.
+    fn _rematch_trade_offers(&self, trade_offers: &[models::NewTradeOffer])
+            -> QueryResult<Vec<(i32, i64, Option<i32>)>> {
+        use super::schema::trade_offer as t;
+        let matched = trade_offers.iter()
+            .filter(|offer| offer.output_idx.is_some())
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            return Ok(Vec::new());
+        }
+        let unmatched = trade_offer::table
+            .filter(t::tx.eq_any(matched.iter().map(|offer| offer.tx).collect::<Vec<_>>()))
+            .filter(t::output_idx.is_null())
+            .select((t::id, t::tx))
+            .load::<(i32, i64)>(&self.connection)?;
+        let mut rematched = Vec::new();
+        for (id, tx) in unmatched {
+            let offer = match matched.iter().find(|offer| offer.tx == tx) {
+                Some(offer) => offer,
+                None => continue,
+            };
+            diesel::update(trade_offer::table.filter(t::id.eq(id)))
+                .set((t::output_idx.eq(offer.output_idx),
+                      t::sell_amount_token_base.eq(offer.sell_amount_token_base.clone()),
+                      t::fee_profile.eq(offer.fee_profile.clone())))
                 .execute(&self.connection)?;
-            Ok(())
+            rematched.push((id, tx, offer.output_idx));
+        }
+        Ok(rematched)
+    }
+
+    /// Moves rematched offers whose contract a stored tx already spent to pending, filled or
+    /// cancelled, by the same rule as `_update_trade_offer_states`.
+    fn _update_rematched_offer_states(&self, rematched_offers: &[(i32, i64, Option<i32>)])
+            -> QueryResult<Vec<TradeOfferStateChange>> {
+        let mut changes = Vec::new();
+        for &(id, offer_tx_id, output_idx) in rematched_offers {
+            let output_idx = match output_idx {
+                Some(output_idx) => output_idx,
+                None => continue,
+            };
+            let offer_hash = tx::table
+                .filter(tx::id.eq(offer_tx_id))
+                .select(tx::hash)
+                .first::<Vec<u8>>(&self.connection)?;
+            let offer = match self._trade_offer_states(vec![offer_hash.clone()])?
+                    .into_iter()
+                    .find(|offer| offer.id == id) {
+                Some(offer) => offer,
+                None => continue,
+            };
+            let spender = tx_input::table
+                .inner_join(tx::table)
+                .filter(tx_input::output_tx.eq(offer_hash))
+                .filter(tx_input::output_idx.eq(output_idx))
+                .select((tx::id, tx::hash, tx::height, tx::timestamp))
+                .first::<(i64, Vec<u8>, Option<i32>, i64)>(&self.connection)
+                .optional()?;
+            let (spender_id, spender_hash, height, timestamp) = match spender {
+                Some(spender) => spender,
+                None => continue,
+            };
+            let pays_maker = offer.payment <= std::i64::MAX as u64 && diesel::select(
+                diesel::dsl::exists(
+                    tx_output::table
+                        .filter(tx_output::tx.eq(spender_id))
+                        .filter(tx_output::address.eq(offer.receiving_address.clone()))
+                        .filter(tx_output::value_satoshis.ge(offer.payment as i64))
+                )
+            ).get_result::<bool>(&self.connection)?;
+            let state = match (pays_maker, height) {
+                (true, Some(_)) => TradeOfferState::Filled,
+                (true, None) => TradeOfferState::PendingTake,
+                (false, _) => TradeOfferState::Cancelled,
+            };
+            let spent_tx = tx_hash_from_slice(&spender_hash);
+            self._set_trade_offer_state(id, state, Some(spent_tx))?;
+            changes.push(TradeOfferStateChange {
+                offer_tx: offer.tx_hash,
+                offer_vout: offer.output_idx,
+                token_hash: offer.token_hash,
+                receiving_address: Address::from_bytes(
+                    AddressType::P2PKH, address_hash_from_slice(&offer.receiving_address)
+                ),
+                state,
+                spent_tx: Some(spent_tx),
+                timestamp,
+            });
+        }
+        Ok(changes)
+    }
+
+    fn _trade_offer_states(&self, hashes: Vec<Vec<u8>>) -> QueryResult<Vec<TradeOfferStateRow>> {
+        use super::schema::trade_offer as t;
+        Ok(trade_offer::table
+            .inner_join(tx::table)
+            .left_join(slp_tx::table.on(tx::id.eq(slp_tx::tx)))
+            .left_join(token::table.on(slp_tx::token.eq(token::id)))
+            .filter(tx::hash.eq_any(&hashes).or(t::input_tx.eq_any(&hashes)))
+            .select((t::id, tx::hash, t::output_idx, t::input_tx, t::input_idx, t::state,
+                     t::spent_tx, t::script_price, t::is_inverted, t::sell_amount_token_base,
+                     t::receiving_address, token::hash.nullable()))
+            .load::<(i32, Vec<u8>, Option<i32>, Vec<u8>, i32, i16, Option<Vec<u8>>, i64, bool,
+                     PgNumeric, Vec<u8>, Option<Vec<u8>>)>(&self.connection)?
+            .into_iter()
+            .map(|(id, tx_hash, output_idx, input_tx, input_idx, state, spent_tx, script_price,
+                   is_inverted, sell_amount_token_base, receiving_address, token_hash)| {
+                let amount = SLPAmount::from_numeric_decimals(&sell_amount_token_base, 0);
+                TradeOfferStateRow {
+                    id,
+                    tx_hash: tx_hash_from_slice(&tx_hash),
+                    output_idx,
+                    input_tx: tx_hash_from_slice(&input_tx),
+                    input_idx,
+                    state: TradeOfferState::from_id(state).unwrap_or(TradeOfferState::Open),
+                    spent_tx: spent_tx.map(|spent_tx| tx_hash_from_slice(&spent_tx)),
+                    // no output can pay an out of range payment
+                    payment: script_payment(amount.base_amount() as u64, script_price as u64,
+                                            is_inverted).unwrap_or(std::u64::MAX),
+                    receiving_address,
+                    token_hash: token_hash.map(|token_hash| tx_hash_from_slice(&token_hash)),
+                }
+            })
+            .collect())
+    }
+
+    fn _set_trade_offer_state(&self, id: i32, state: TradeOfferState, spent_tx: Option<[u8; 32]>)
+            -> QueryResult<()> {
+        use super::schema::trade_offer as t;
+        diesel::update(trade_offer::table.filter(t::id.eq(id)))
+            .set((t::state.eq(state.id()), t::spent_tx.eq(spent_tx.map(|tx| tx.to_vec()))))
+            .execute(&self.connection)?;
+        diesel::insert_into(trade_offer_state_history::table)
+            .values(&models::NewTradeOfferStateHistory {
+                trade_offer: id,
+                state: state.id(),
+                tx: spent_tx.map(|tx| tx.to_vec()),
+            })
+            .execute(&self.connection)?;
+        Ok(())
+    }
+
+    /// Opens new offers and moves offers spent by `tx_history` to pending, filled or cancelled;
+    /// offers whose own tx got double spent are invalidated.
+    fn _update_trade_offer_states(&self, tx_history: &TxHistory, tx_ids: &[i64],
+                                  new_offers: &[(i32, i64, Option<i32>)])
+            -> QueryResult<Vec<TradeOfferStateChange>> {
+        let mut changes = Vec::new();
+        for &(id, tx_id, output_idx) in new_offers {
+            let (tx, trade_offer) = match tx_ids.iter().position(|&id| id == tx_id) {
+                Some(tx_idx) => match tx_history.trade_offers.get(&tx_idx) {
+                    Some(trade_offer) => (&tx_history.txs[tx_idx], trade_offer),
+                    None => continue,
+                },
+                None => continue,
+            };
+            let state = if output_idx.is_some() { TradeOfferState::Open }
+                        else { TradeOfferState::Invalidated };
+            self._set_trade_offer_state(id, state, None)?;
+            changes.push(TradeOfferStateChange {
+                offer_tx: tx.hash,
+                offer_vout: output_idx,
+                token_hash: tx.tx_type.token_hash().cloned(),
+                receiving_address: trade_offer.receiving_address.clone(),
+                state,
+                spent_tx: None,
+                timestamp: tx.timestamp,
+            });
+        }
+        let spent_hashes = tx_history.txs.iter()
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.output_tx.to_vec()))
+            .collect::<HashSet<_>>();
+        if spent_hashes.len() == 0 { return Ok(changes) }
+        let mut offers = self._trade_offer_states(spent_hashes.into_iter().collect())?;
+        for (tx_idx, tx) in tx_history.txs.iter().enumerate() {
+            // a tx no node validated may never be mined, so it can at most make an offer pending
+            let is_unverified = tx_history.unverified.contains(&tx_idx);
+            for input in tx.inputs.iter() {
+                for offer in offers.iter_mut() {
+                    let spends_contract = offer.tx_hash == input.output_tx &&
+                        offer.output_idx == Some(input.output_idx);
+                    let double_spends = offer.input_tx == input.output_tx &&
+                        offer.input_idx == input.output_idx &&
+                        offer.tx_hash != tx.hash;
+                    let state = if spends_contract {
+                        let pays_maker = tx.outputs.iter().any(|output| {
+                            output.value_satoshis >= offer.payment &&
+                                output.output.address().map(|address| address.bytes().to_vec()) ==
+                                    Some(offer.receiving_address.clone())
+                        });
+                        if offer.state.is_final() && tx.height.is_none() { continue }
+                        match (pays_maker, tx.height) {
+                            (true, Some(_)) => TradeOfferState::Filled,
+                            (true, None) => TradeOfferState::PendingTake,
+                            (false, _) if is_unverified => continue,
+                            (false, _) => TradeOfferState::Cancelled,
+                        }
+                    } else if double_spends && !is_unverified {
+                        TradeOfferState::Invalidated
+                    } else {
+                        continue
+                    };
+                    if offer.state == state && offer.spent_tx == Some(tx.hash) { continue }
+                    self._set_trade_offer_state(offer.id, state, Some(tx.hash))?;
+                    offer.state = state;
+                    offer.spent_tx = Some(tx.hash);
+                    changes.push(TradeOfferStateChange {
+                        offer_tx: offer.tx_hash,
+                        offer_vout: offer.output_idx,
+                        token_hash: offer.token_hash,
+                        receiving_address: Address::from_bytes(
+                            AddressType::P2PKH, address_hash_from_slice(&offer.receiving_address)
+                        ),
+                        state,
+                        spent_tx: Some(tx.hash),
+                        timestamp: tx.timestamp,
+                    });
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Whether `tx_history` spends the contract or the funding input of a known offer.
+    pub fn spends_trade_offers(&self, tx_history: &TxHistory) -> QueryResult<bool> {
+        let spent_hashes = tx_history.txs.iter()
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.output_tx.to_vec()))
+            .collect::<HashSet<_>>();
+        if spent_hashes.len() == 0 { return Ok(false) }
+        let offers = self._trade_offer_states(spent_hashes.into_iter().collect())?;
+        Ok(tx_history.txs.iter().flat_map(|tx| tx.inputs.iter()).any(|input| {
+            offers.iter().any(|offer| {
+                (offer.tx_hash == input.output_tx && offer.output_idx == Some(input.output_idx)) ||
+                    (offer.input_tx == input.output_tx && offer.input_idx == input.output_idx)
+            })
+        }))
+    }
+
+    /// Transitions of the offer whose contract sits at `outpoint`, oldest first.
+    pub fn trade_offer_state_history(&self, outpoint: &SpentUtxo)
+            -> QueryResult<Vec<(TradeOfferState, Option<[u8; 32]>, i64)>> {
+        use super::schema::trade_offer as t;
+        use super::schema::trade_offer_state_history as h;
+        Ok(trade_offer_state_history::table
+            .inner_join(trade_offer::table)
+            .inner_join(tx::table.on(tx::id.eq(t::tx)))
+            .filter(tx::hash.eq(outpoint.tx_hash.to_vec()))
+            .filter(t::output_idx.eq(outpoint.vout))
+            .order(h::id.asc())
+            .select((h::state, h::tx, h::timestamp))
+            .load::<(i16, Option<Vec<u8>>, chrono::DateTime<chrono::Utc>)>(&self.connection)?
+            .into_iter()
+            .filter_map(|(state, tx, timestamp)| Some((
+                TradeOfferState::from_id(state)?,
+                tx.map(|tx| tx_hash_from_slice(&tx)),
+                timestamp.timestamp(),
+            )))
+            .collect())
+    }
+
+    /// Offers pending a take, with when they went pending.
+    pub fn pending_takes(&self) -> QueryResult<Vec<PendingTake>> {
+        use super::schema::trade_offer as t;
+        use super::schema::trade_offer_state_history as h;
+        let pending = trade_offer::table
+            .inner_join(tx::table)
+            .filter(t::state.eq(TradeOfferState::PendingTake.id()))
+            .select((t::id, tx::hash, t::spent_tx))
+            .load::<(i32, Vec<u8>, Option<Vec<u8>>)>(&self.connection)?;
+        let ids = pending.iter().map(|&(id, _, _)| id).collect::<Vec<_>>();
+        let mut since = HashMap::new();
+        for (id, timestamp) in trade_offer_state_history::table
+                .filter(h::trade_offer.eq_any(&ids))
+                .filter(h::state.eq(TradeOfferState::PendingTake.id()))
+                .select((h::trade_offer, h::timestamp))
+                .load::<(i32, chrono::DateTime<chrono::Utc>)>(&self.connection)? {
+            let since = since.entry(id).or_insert(timestamp.timestamp());
+            *since = (*since).max(timestamp.timestamp());
+        }
+        Ok(pending.into_iter()
+            .filter_map(|(id, tx_hash, spent_tx)| Some(PendingTake {
+                trade_offer: id,
+                offer_tx: tx_hash_from_slice(&tx_hash),
+                spent_tx: tx_hash_from_slice(&spent_tx?),
+                since: *since.get(&id)?,
+            }))
+            .collect())
+    }
+
+    /// Drops the taker tx of `take`, if it's still unconfirmed, and opens the offer again.
+    /// Returns the change and the active addresses the taker tx touched, whose UTXOs are
+    /// rebuilt.
+    pub fn revert_pending_take(&self, take: &PendingTake, now: i64)
+            -> QueryResult<Option<(TradeOfferStateChange, HashSet<Address>)>> {
+        self.connection.transaction(|| {
+            let offer = match self._trade_offer_states(vec![take.offer_tx.to_vec()])?
+                    .into_iter()
+                    .find(|offer| offer.id == take.trade_offer) {
+                Some(ref offer) if offer.state != TradeOfferState::PendingTake ||
+                                   offer.spent_tx != Some(take.spent_tx) => return Ok(None),
+                Some(offer) => offer,
+                None => return Ok(None),
+            };
+            let spent_tx_id = match tx::table
+                    .filter(tx::hash.eq(take.spent_tx.to_vec()))
+                    .filter(tx::height.is_null())
+                    .select(tx::id)
+                    .first::<i64>(&self.connection)
+                    .optional()? {
+                Some(spent_tx_id) => spent_tx_id,
+                // mined in the meantime
+                None => return Ok(None),
+            };
+            let touched_addresses = tx_input::table
+                .filter(tx_input::tx.eq(spent_tx_id))
+                .select(tx_input::address)
+                .load::<Option<Vec<u8>>>(&self.connection)?
+                .into_iter()
+                .chain(tx_output::table
+                    .filter(tx_output::tx.eq(spent_tx_id))
+                    .select(tx_output::address)
+                    .load::<Option<Vec<u8>>>(&self.connection)?)
+                .filter_map(|address| Some(
+                    Address::from_bytes(AddressType::P2PKH, address_hash_from_slice(&address?))
+                ))
+                .collect::<Vec<_>>();
+            // its outputs, fee rows and offers go with it; what it spent is unspent again
+            diesel::delete(tx::table.filter(tx::id.eq(spent_tx_id)))
+                .execute(&self.connection)?;
+            self._set_trade_offer_state(offer.id, TradeOfferState::Open, None)?;
+            let offer_tx_id = tx::table
+                .filter(tx::hash.eq(take.offer_tx.to_vec()))
+                .select(tx::id)
+                .first::<i64>(&self.connection)?;
+            self._mark_exch_utxos_dirty(&[offer_tx_id])?;
+            let active_addresses = self.active_addresses_among(&touched_addresses)?;
+            for address in active_addresses.iter() {
+                self.update_utxo_set(address)?;
+            }
+            Ok(Some((
+                TradeOfferStateChange {
+                    offer_tx: offer.tx_hash,
+                    offer_vout: offer.output_idx,
+                    token_hash: offer.token_hash,
+                    receiving_address: Address::from_bytes(
+                        AddressType::P2PKH, address_hash_from_slice(&offer.receiving_address)
+                    ),
+                    state: TradeOfferState::Open,
+                    spent_tx: None,
+                    timestamp: now,
+                },
+                active_addresses,
+            )))
         })
     }
 
@@ -257,6 +800,33 @@ impl Db {
         Ok(())
     }
 
+    /// The last update of every subject, together with when it happened.
+    pub fn update_subjects(&self)
+            -> QueryResult<Vec<(UpdateHistory, chrono::DateTime<chrono::Utc>)>> {
+        use num_traits::FromPrimitive;
+        Ok(update_history::table
+            .distinct_on((update_history::subject_type, update_history::subject_hash,
+                          update_history::is_confirmed))
+            .order((update_history::subject_type, update_history::subject_hash,
+                    update_history::is_confirmed, update_history::timestamp.desc()))
+            .load::<models::UpdateHistory>(&self.connection)?
+            .into_iter()
+            .filter_map(|update| {
+                let subject_type = UpdateSubjectType::from_i32(update.subject_type)?;
+                Some((UpdateHistory {
+                    last_height: update.last_height,
+                    last_tx_hash: update.last_tx_hash,
+                    completed: update.completed,
+                    subject: UpdateSubject {
+                        subject_type,
+                        hash: update.subject_hash,
+                        is_confirmed: update.is_confirmed,
+                    },
+                }, update.timestamp))
+            })
+            .collect())
+    }
+
     pub fn add_tokens(&self, tokens: &[Token]) -> QueryResult<()> {
         diesel::insert_into(token::table)
             .values(&tokens.iter()
@@ -283,28 +853,38 @@ impl Db {
         Ok(())
     }
 
+    /// Sets the current supply of a known token, e.g. after a MINT or burn.
+    pub fn update_token_supply(&self, token: &Token) -> QueryResult<usize> {
+        let current_supply: PgNumeric = token.current_supply.into();
+        diesel::update(token::table.filter(token::hash.eq(token.hash.to_vec())))
+            .set(token::current_supply.eq(current_supply))
+            .execute(&self.connection)
+    }
+
     pub fn token(&self, token_hash: &[u8; 32]) -> QueryResult<Option<Token>> {
         let token: Option<models::Token> = token::table
             .filter(token::hash.eq(token_hash.to_vec()))
             .first::<models::Token>(&self.connection)
             .optional()?;
-        Ok(token.map(|token| {
-            Token {
-                hash: tx_hash_from_slice(&token.hash),
-                decimals: token.decimals,
-                timestamp: token.timestamp,
-                version_type: token.version_type,
-                document_uri: token.document_uri,
-                symbol: token.symbol,
-                name: token.name,
-                document_hash: token.document_hash,
-                initial_supply: SLPAmount::from_numeric_decimals(&token.initial_supply,
-                                                                 token.decimals as u32),
-                current_supply: SLPAmount::from_numeric_decimals(&token.current_supply,
-                                                                 token.decimals as u32),
-                block_created_height: token.block_created_height,
-            }
-        }))
+        Ok(token.map(Self::_token_from_model))
+    }
+
+    fn _token_from_model(token: models::Token) -> Token {
+        Token {
+            hash: tx_hash_from_slice(&token.hash),
+            decimals: token.decimals,
+            timestamp: token.timestamp,
+            version_type: token.version_type,
+            document_uri: token.document_uri,
+            symbol: token.symbol,
+            name: token.name,
+            document_hash: token.document_hash,
+            initial_supply: SLPAmount::from_numeric_decimals(&token.initial_supply,
+                                                             token.decimals as u32),
+            current_supply: SLPAmount::from_numeric_decimals(&token.current_supply,
+                                                             token.decimals as u32),
+            block_created_height: token.block_created_height,
+        }
     }
 
     pub fn update_utxo_set(&self, address: &cashcontracts::Address) -> QueryResult<()> {
@@ -331,6 +911,9 @@ impl Db {
         })
     }
 
+    /// Rebuilds `utxo_trade_offer` in full from the tx index. Incremental updates fall back to
+    /// it while there's no cursor yet, i.e. on a new db and after the dirty set migration;
+    /// otherwise it only runs to repair the set, after a reindex and from the admin tool.
     pub fn update_utxo_set_exch(&self) -> QueryResult<()> {
         use diesel::dsl::*;
         self.connection.transaction(|| {
@@ -354,11 +937,348 @@ impl Db {
                 )
                 .on_conflict_do_nothing()  // this shouldn't happen
                 .execute(&self.connection)?;
+            diesel::sql_query("DELETE FROM utxo_trade_offer_dirty").execute(&self.connection)?;
+            self._set_exch_utxo_cursor()?;
+            Ok(())
+        })
+    }
+
+    fn _exch_utxo_subject() -> UpdateSubject {
+        UpdateSubject {
+            subject_type: UpdateSubjectType::ExchUtxos,
+            hash: None,
+            is_confirmed: true,
+        }
+    }
+
+    /// Records a full rebuild, which incremental updates build upon.
+    fn _set_exch_utxo_cursor(&self) -> QueryResult<Option<i64>> {
+        let max_tx_id: Option<i64> = tx::table
+            .select(diesel::dsl::max(tx::id))
+            .first(&self.connection)?;
+        let max_tx_id = match max_tx_id {
+            Some(max_tx_id) => max_tx_id,
+            None => return Ok(None),
+        };
+        let last_tx_hash: Vec<u8> = tx::table
+            .select(tx::hash)
+            .filter(tx::id.eq(max_tx_id))
+            .first(&self.connection)?;
+        self.add_update_history(&UpdateHistory {
+            last_height: self.header_tip()?.map(|(_, height)| height).unwrap_or(0),
+            last_tx_hash: Some(last_tx_hash),
+            subject: Self::_exch_utxo_subject(),
+            completed: true,
+        })?;
+        Ok(Some(max_tx_id))
+    }
+
+    /// Queues txs for the next incremental `utxo_trade_offer` update; their offers or the
+    /// offers they spend may have changed.
+    fn _mark_exch_utxos_dirty(&self, tx_ids: &[i64]) -> QueryResult<()> {
+        use diesel::sql_types::{Array, BigInt};
+        if tx_ids.len() == 0 { return Ok(()) }
+        diesel::sql_query("\
+            INSERT INTO utxo_trade_offer_dirty (tx)
+            SELECT UNNEST($1::BIGINT[])
+            ON CONFLICT DO NOTHING
+        ").bind::<Array<BigInt>, _>(tx_ids.to_vec())
+            .execute(&self.connection)?;
+        Ok(())
+    }
+
+    /// Applies only the spends and new offers of the txs added since the last
+    /// `utxo_trade_offer` update, whatever their id or commit order; falls back to a full
+    /// rebuild if there is none yet.
+    pub fn update_utxo_set_exch_incremental(&self) -> QueryResult<()> {
+        use diesel::sql_types::{Array, BigInt};
+        self.connection.transaction(|| {
+            if self.last_update(Self::_exch_utxo_subject())?.is_none() {
+                return self.update_utxo_set_exch();
+            }
+            // rows added concurrently stay for the next update
+            let dirty_tx_ids: Vec<i64> = diesel::sql_query("\
+                SELECT tx FROM utxo_trade_offer_dirty FOR UPDATE
+            ").load::<models::TxIdRow>(&self.connection)?
+                .into_iter()
+                .map(|row| row.tx)
+                .collect();
+            if dirty_tx_ids.len() == 0 { return Ok(()) }
+            diesel::sql_query("\
+                DELETE FROM utxo_trade_offer
+                USING tx, tx_input
+                WHERE
+                    tx.id = utxo_trade_offer.tx AND
+                    tx_input.output_tx = tx.hash AND
+                    tx_input.output_idx = utxo_trade_offer.idx AND
+                    tx_input.tx = ANY($1)
+            ").bind::<Array<BigInt>, _>(dirty_tx_ids.clone())
+                .execute(&self.connection)?;
+            diesel::sql_query("\
+                INSERT INTO utxo_trade_offer (tx, idx)
+                SELECT tx_output.tx, tx_output.idx
+                FROM tx_output
+                    INNER JOIN tx          ON (tx.id = tx_output.tx)
+                    INNER JOIN trade_offer ON (trade_offer.tx = tx_output.tx AND
+                                               trade_offer.output_idx = tx_output.idx)
+                    LEFT JOIN tx_input     ON (tx_input.output_tx = tx.hash AND
+                                               tx_input.output_idx = tx_output.idx)
+                WHERE
+                    tx_input.tx IS NULL AND
+                    tx_output.tx = ANY($1)
+                ON CONFLICT DO NOTHING
+            ").bind::<Array<BigInt>, _>(dirty_tx_ids.clone())
+                .execute(&self.connection)?;
+            diesel::sql_query("DELETE FROM utxo_trade_offer_dirty WHERE tx = ANY($1)")
+                .bind::<Array<BigInt>, _>(dirty_tx_ids)
+                .execute(&self.connection)?;
+            Ok(())
+        })
+    }
+
+    /// Compares `utxo_trade_offer` against the full rebuild query without modifying it.
+    pub fn check_utxo_set_exch(&self) -> QueryResult<UtxoSetDiff> {
+        use diesel::dsl::*;
+        let expected = tx_output::table
+            .left_join(tx::table)
+            .inner_join(trade_offer::table.on(
+                tx_output::tx.eq(trade_offer::tx)
+                    .and(tx_output::idx.nullable().eq(trade_offer::output_idx))
+                    .and(not(trade_offer::output_idx.is_null()))
+            ))
+            .left_outer_join(tx_input::table.on(
+                tx::hash.eq(tx_input::output_tx)
+                    .and(tx_output::idx.eq(tx_input::output_idx))
+            ))
+            .filter(tx_input::tx.is_null())
+            .select((tx_output::tx, tx_output::idx))
+            .load::<(i64, i32)>(&self.connection)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let actual = utxo_trade_offer::table
+            .select((utxo_trade_offer::tx, utxo_trade_offer::idx))
+            .load::<(i64, i32)>(&self.connection)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        Ok(UtxoSetDiff {
+            missing: expected.difference(&actual).cloned().collect(),
+            extra: actual.difference(&expected).cloned().collect(),
+        })
+    }
+
+    /// Checks the invariants between the tx index, the UTXO tables, update history and block
+    /// headers, without modifying anything.
+    pub fn check_consistency(&self) -> QueryResult<Vec<Inconsistency>> {
+        let mut inconsistencies = Vec::new();
+        let extra_utxos_address = utxo_address::table
+            .inner_join(tx::table.on(tx::id.eq(utxo_address::tx)))
+            .inner_join(tx_input::table.on(
+                tx::hash.eq(tx_input::output_tx)
+                    .and(utxo_address::idx.eq(tx_input::output_idx))
+            ))
+            .select((utxo_address::tx, utxo_address::idx))
+            .distinct()
+            .load::<(i64, i32)>(&self.connection)?;
+        inconsistencies.extend(extra_utxos_address.into_iter().map(|(tx, idx)| {
+            Inconsistency::ExtraUtxoAddress { tx, idx }
+        }));
+        let missing_utxos_address = tx_output::table
+            .inner_join(tx::table)
+            .inner_join(active_address::table.on(
+                tx_output::address.eq(active_address::address.nullable())
+            ))
+            .left_outer_join(tx_input::table.on(
+                tx::hash.eq(tx_input::output_tx)
+                    .and(tx_output::idx.eq(tx_input::output_idx))
+            ))
+            .left_outer_join(utxo_address::table.on(
+                tx_output::tx.eq(utxo_address::tx)
+                    .and(tx_output::idx.eq(utxo_address::idx))
+            ))
+            .filter(tx_input::tx.is_null())
+            .filter(utxo_address::tx.is_null())
+            .select((tx_output::tx, tx_output::idx, active_address::address))
+            .load::<(i64, i32, Vec<u8>)>(&self.connection)?;
+        inconsistencies.extend(missing_utxos_address.into_iter().map(|(tx, idx, address)| {
+            Inconsistency::MissingUtxoAddress { tx, idx, address }
+        }));
+        let diff = self.check_utxo_set_exch()?;
+        inconsistencies.extend(diff.extra.into_iter().map(|(tx, idx)| {
+            Inconsistency::ExtraUtxoTradeOffer { tx, idx }
+        }));
+        inconsistencies.extend(diff.missing.into_iter().map(|(tx, idx)| {
+            Inconsistency::MissingUtxoTradeOffer { tx, idx }
+        }));
+        let slp_txs_without_token = slp_tx::table
+            .left_outer_join(token::table.on(token::id.eq(slp_tx::token)))
+            .filter(token::id.is_null())
+            .select(slp_tx::tx)
+            .load::<i64>(&self.connection)?;
+        inconsistencies.extend(slp_txs_without_token.into_iter().map(|tx| {
+            Inconsistency::SlpTxWithoutToken { tx }
+        }));
+        let offers_without_output = trade_offer::table
+            .left_outer_join(tx_output::table.on(
+                tx_output::tx.eq(trade_offer::tx)
+                    .and(tx_output::idx.nullable().eq(trade_offer::output_idx))
+            ))
+            .filter(trade_offer::state.ne(TradeOfferState::Invalidated.id()))
+            .filter(tx_output::tx.is_null())
+            .select((trade_offer::id, trade_offer::tx, trade_offer::output_idx))
+            .load::<(i32, i64, Option<i32>)>(&self.connection)?;
+        inconsistencies.extend(offers_without_output.into_iter().map(|(trade_offer, tx, output_idx)| {
+            Inconsistency::MissingContractOutput { trade_offer, tx, output_idx }
+        }));
+        let regressed_updates = diesel::sql_query("\
+            SELECT id, subject_type, subject_hash, is_confirmed, last_height, previous_height
+            FROM (
+                SELECT
+                    id, subject_type, subject_hash, is_confirmed, last_height,
+                    MAX(last_height) OVER (
+                        PARTITION BY subject_type, subject_hash, is_confirmed
+                        ORDER BY id
+                        ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING
+                    ) AS previous_height
+                FROM update_history
+                WHERE completed
+            ) AS updates
+            WHERE last_height < previous_height
+            ORDER BY id
+        ").load::<models::UpdateRegressionRow>(&self.connection)?;
+        inconsistencies.extend(regressed_updates.into_iter().map(|row| {
+            Inconsistency::UpdateCursorRegressed {
+                update: row.id,
+                subject_type: row.subject_type,
+                subject_hash: row.subject_hash,
+                is_confirmed: row.is_confirmed,
+                last_height: row.last_height,
+                previous_height: row.previous_height,
+            }
+        }));
+        let block_gaps = diesel::sql_query("\
+            SELECT height + 1 AS from_height, next_height - 1 AS to_height
+            FROM (
+                SELECT height, LEAD(height) OVER (ORDER BY height) AS next_height
+                FROM (SELECT DISTINCT height FROM blocks) AS heights
+            ) AS heights
+            WHERE next_height > height + 1
+            ORDER BY height
+        ").load::<models::BlockGapRow>(&self.connection)?;
+        inconsistencies.extend(block_gaps.into_iter().map(|row| {
+            Inconsistency::BlockHeightGap { from_height: row.from_height, to_height: row.to_height }
+        }));
+        Ok(inconsistencies)
+    }
+
+    /// Repairs inconsistencies found by `check_consistency` in one transaction: UTXO rows are
+    /// added or removed, offers without contract output invalidated, regressed updates
+    /// deleted so the subject resumes from the earlier cursor, and the headers above the first
+    /// height gap deleted so the node downloads them again.
+    pub fn repair_inconsistencies(&self, inconsistencies: &[Inconsistency]) -> QueryResult<()> {
+        use self::Inconsistency::*;
+        self.connection.transaction(|| {
+            let mut min_gap_height = None;
+            for inconsistency in inconsistencies {
+                match inconsistency {
+                    ExtraUtxoAddress { tx, idx } => {
+                        diesel::delete(utxo_address::table)
+                            .filter(utxo_address::tx.eq(*tx).and(utxo_address::idx.eq(*idx)))
+                            .execute(&self.connection)?;
+                    },
+                    MissingUtxoAddress { tx, idx, address } => {
+                        diesel::insert_into(utxo_address::table)
+                            .values(models::UtxoAddress {
+                                tx: *tx,
+                                idx: *idx,
+                                address: Some(address.clone()),
+                            })
+                            .on_conflict_do_nothing()
+                            .execute(&self.connection)?;
+                    },
+                    ExtraUtxoTradeOffer { tx, idx } => {
+                        diesel::delete(utxo_trade_offer::table)
+                            .filter(utxo_trade_offer::tx.eq(*tx).and(utxo_trade_offer::idx.eq(*idx)))
+                            .execute(&self.connection)?;
+                    },
+                    MissingUtxoTradeOffer { tx, idx } => {
+                        diesel::insert_into(utxo_trade_offer::table)
+                            .values(models::Utxo { tx: *tx, idx: *idx })
+                            .on_conflict_do_nothing()
+                            .execute(&self.connection)?;
+                    },
+                    SlpTxWithoutToken { tx: tx_id } => {
+                        diesel::delete(slp_tx::table.filter(slp_tx::tx.eq(*tx_id)))
+                            .execute(&self.connection)?;
+                        diesel::update(tx::table.filter(tx::id.eq(*tx_id)))
+                            .set(tx::tx_type.eq(TxType::Default.id()))
+                            .execute(&self.connection)?;
+                    },
+                    MissingContractOutput { trade_offer: id, .. } => {
+                        diesel::update(trade_offer::table.filter(trade_offer::id.eq(*id)))
+                            .set(trade_offer::output_idx.eq(None::<i32>))
+                            .execute(&self.connection)?;
+                        self._set_trade_offer_state(*id, TradeOfferState::Invalidated, None)?;
+                    },
+                    UpdateCursorRegressed { update, .. } => {
+                        diesel::delete(update_history::table.filter(update_history::id.eq(*update)))
+                            .execute(&self.connection)?;
+                    },
+                    BlockHeightGap { from_height, .. } => {
+                        min_gap_height = Some(match min_gap_height {
+                            Some(height) if height < *from_height => height,
+                            _ => *from_height,
+                        });
+                    },
+                }
+            }
+            if let Some(min_gap_height) = min_gap_height {
+                diesel::delete(blocks::table.filter(blocks::height.ge(min_gap_height)))
+                    .execute(&self.connection)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn update_utxo_set_slp(&self, token_hash: &[u8; 32]) -> QueryResult<()> {
+        use diesel::dsl::sql;
+        use diesel::sql_types::Bool;
+        self.connection.transaction(|| {
+            let token_id: i32 = token::table
+                .select(token::id)
+                .filter(token::hash.eq(token_hash.to_vec()))
+                .first(&self.connection)?;
+            diesel::delete(utxo_slp::table)
+                .filter(utxo_slp::token.eq(token_id))
+                .execute(&self.connection)?;
+            diesel::insert_into(utxo_slp::table)
+                .values(
+                    tx_output::table
+                        .left_join(tx::table)
+                        .inner_join(slp_tx::table.on(tx_output::tx.eq(slp_tx::tx)))
+                        .left_outer_join(tx_input::table.on(
+                            tx::hash.eq(tx_input::output_tx)
+                                .and(tx_output::idx.eq(tx_input::output_idx))
+                        ))
+                        .filter(tx_input::tx.is_null())
+                        .filter(slp_tx::token.eq(token_id))
+                        .filter(sql::<Bool>("tx_output.value_token_base > 0"))
+                        .select((tx_output::tx, tx_output::idx, tx_output::address, slp_tx::token))
+                )
+                .execute(&self.connection)?;
             Ok(())
         })
     }
 
-    pub fn utxos_address(&self, address: &Address) -> QueryResult<Vec<Utxo>> {
+    pub fn token_hashes(&self) -> QueryResult<Vec<[u8; 32]>> {
+        Ok(token::table
+            .select(token::hash)
+            .load::<Vec<u8>>(&self.connection)?
+            .into_iter()
+            .map(|token_hash| tx_hash_from_slice(&token_hash))
+            .collect())
+    }
+
+    pub fn utxos_address(&self, addresses: &[Address]) -> QueryResult<Vec<Utxo>> {
         let result = tx_output::table
             .inner_join(utxo_address::table.on(
                 tx_output::tx.eq(utxo_address::tx).and(tx_output::idx.eq(utxo_address::idx))
@@ -366,7 +1286,9 @@ impl Db {
             .inner_join(tx::table)
             .left_join(slp_tx::table.on(tx::id.eq(slp_tx::tx)))
             .left_join(token::table.on(slp_tx::token.eq(token::id)))
-            .filter(utxo_address::address.eq(address.bytes().to_vec()))
+            .filter(utxo_address::address.eq_any(
+                addresses.iter().map(|address| address.bytes().to_vec()).collect::<Vec<_>>()
+            ))
             .select((tx::hash,
                      tx_output::idx,
                      tx_output::value_satoshis,
@@ -392,118 +1314,545 @@ impl Db {
             .collect())
     }
 
-    pub fn address_tx_deltas(&self, address: &Address) -> QueryResult<Vec<TxDelta>> {
+    /// Looks up outpoints, e.g. funding coins supplied by a wallet; unknown and spent ones
+    /// are skipped.
+    pub fn funding_utxos(&self, outpoints: &[SpentUtxo]) -> QueryResult<Vec<FundingUtxo>> {
+        self._stored_outputs(outpoints, true)
+    }
+
+    /// Looks up outpoints whether or not they're spent, e.g. the inputs of a tx; unknown ones
+    /// are skipped.
+    pub fn stored_outputs(&self, outpoints: &[SpentUtxo]) -> QueryResult<Vec<FundingUtxo>> {
+        self._stored_outputs(outpoints, false)
+    }
+
+    fn _stored_outputs(&self, outpoints: &[SpentUtxo], unspent_only: bool)
+            -> QueryResult<Vec<FundingUtxo>> {
+        let mut funding_utxos = Vec::new();
+        for outpoint in outpoints {
+            let row = tx_output::table
+                .inner_join(tx::table)
+                .left_outer_join(tx_input::table.on(
+                    tx::hash.eq(tx_input::output_tx)
+                        .and(tx_output::idx.eq(tx_input::output_idx))
+                ))
+                .left_join(slp_tx::table.on(tx::id.eq(slp_tx::tx)))
+                .left_join(token::table.on(slp_tx::token.eq(token::id)))
+                .filter(tx::hash.eq(outpoint.tx_hash.to_vec()))
+                .filter(tx_output::idx.eq(outpoint.vout))
+                .select((tx_output::value_satoshis,
+                         tx_output::value_token_base,
+                         tx_output::address,
+                         tx_output::output_type,
+                         token::hash.nullable(),
+                         token::decimals.nullable(),
+                         tx_input::tx.nullable()))
+                .first::<(i64, PgNumeric, Option<Vec<u8>>, i32, Option<Vec<u8>>, Option<i32>, Option<i64>)>(
+                    &self.connection
+                )
+                .optional()?;
+            let (value_satoshis, value_token_base, address, output_type, token_hash, decimals, spent_by) =
+                match row {
+                    Some(row) => row,
+                    None => continue,
+                };
+            if unspent_only && spent_by.is_some() {
+                continue;
+            }
+            let address = match address.and_then(|address| {
+                address_from_output_type(output_type, &address)
+            }) {
+                Some(address) => address,
+                None => continue,
+            };
+            let value_token = decimals.map(
+                |decimals| SLPAmount::from_numeric_decimals(&value_token_base, decimals as u32)
+            ).unwrap_or(SLPAmount::new(0, 0));
+            funding_utxos.push(FundingUtxo {
+                utxo: Utxo {
+                    tx_hash: outpoint.tx_hash,
+                    vout: outpoint.vout,
+                    value_satoshis: value_satoshis as u64,
+                    value_token,
+                    token_hash: token_hash
+                        .filter(|_| value_token.base_amount() > 0)
+                        .map(|token_hash| tx_hash_from_slice(&token_hash)),
+                },
+                address,
+            });
+        }
+        Ok(funding_utxos)
+    }
+
+    /// Sums the UTXOs of `addresses`. Confirmed/unconfirmed is split on the height of the
+    /// UTXO's own tx, so confirmed outputs already spent by a mempool tx still count as
+    /// confirmed until that tx is mined.
+    pub fn address_balance(&self, addresses: &[Address]) -> QueryResult<AddressBalance> {
+        let rows = tx_output::table
+            .inner_join(utxo_address::table.on(
+                tx_output::tx.eq(utxo_address::tx).and(tx_output::idx.eq(utxo_address::idx))
+            ))
+            .inner_join(tx::table)
+            .left_join(slp_tx::table.on(tx::id.eq(slp_tx::tx)))
+            .left_join(token::table.on(slp_tx::token.eq(token::id)))
+            .filter(utxo_address::address.eq_any(
+                addresses.iter().map(|address| address.bytes().to_vec()).collect::<Vec<_>>()
+            ))
+            .select((tx::height,
+                     tx_output::value_satoshis,
+                     tx_output::value_token_base,
+                     token::all_columns.nullable()))
+            .load::<(Option<i32>, i64, PgNumeric, Option<models::Token>)>(&self.connection)?;
+        let mut balance = AddressBalance {
+            addresses: addresses.to_vec(),
+            satoshis_confirmed: 0,
+            satoshis_unconfirmed: 0,
+            tokens: Vec::new(),
+        };
+        let mut token_indices = HashMap::new();
+        for (height, value_satoshis, value_token_base, token) in rows {
+            let is_confirmed = height.is_some();
+            if is_confirmed {
+                balance.satoshis_confirmed += value_satoshis as u64;
+            } else {
+                balance.satoshis_unconfirmed += value_satoshis as u64;
+            }
+            let token = match token {
+                Some(token) => token,
+                None => continue,
+            };
+            let token_hash = tx_hash_from_slice(&token.hash);
+            let token_idx = match token_indices.get(&token_hash) {
+                Some(token_idx) => *token_idx,
+                None => {
+                    let token = Self::_token_from_model(token);
+                    let zero = SLPAmount::new(0, token.decimals as u32);
+                    balance.tokens.push(TokenBalance { token, confirmed: zero, unconfirmed: zero });
+                    token_indices.insert(token_hash, balance.tokens.len() - 1);
+                    balance.tokens.len() - 1
+                },
+            };
+            let token_balance = &mut balance.tokens[token_idx];
+            let amount = SLPAmount::from_numeric_decimals(&value_token_base,
+                                                          token_balance.token.decimals as u32);
+            if is_confirmed {
+                token_balance.confirmed += amount;
+            } else {
+                token_balance.unconfirmed += amount;
+            }
+        }
+        balance.tokens.retain(|token_balance| {
+            token_balance.confirmed.base_amount() != 0 || token_balance.unconfirmed.base_amount() != 0
+        });
+        Ok(balance)
+    }
+
+    pub fn utxos_slp_token(&self, token_hash: &[u8; 32]) -> QueryResult<Vec<Utxo>> {
+        let result = tx_output::table
+            .inner_join(utxo_slp::table.on(
+                tx_output::tx.eq(utxo_slp::tx).and(tx_output::idx.eq(utxo_slp::idx))
+            ))
+            .inner_join(tx::table)
+            .inner_join(token::table.on(utxo_slp::token.eq(token::id)))
+            .filter(token::hash.eq(token_hash.to_vec()))
+            .select((tx::hash,
+                     tx_output::idx,
+                     tx_output::value_satoshis,
+                     tx_output::value_token_base,
+                     token::hash,
+                     token::decimals))
+            .load::<(Vec<u8>, i32, i64, PgNumeric, Vec<u8>, i32)>(&self.connection)?;
+        Ok(result.into_iter().map(Self::_slp_utxo_from_row).collect())
+    }
+
+    pub fn utxos_slp_address(&self, address: &Address) -> QueryResult<Vec<Utxo>> {
+        let result = tx_output::table
+            .inner_join(utxo_slp::table.on(
+                tx_output::tx.eq(utxo_slp::tx).and(tx_output::idx.eq(utxo_slp::idx))
+            ))
+            .inner_join(tx::table)
+            .inner_join(token::table.on(utxo_slp::token.eq(token::id)))
+            .filter(utxo_slp::address.eq(address.bytes().to_vec()))
+            .select((tx::hash,
+                     tx_output::idx,
+                     tx_output::value_satoshis,
+                     tx_output::value_token_base,
+                     token::hash,
+                     token::decimals))
+            .load::<(Vec<u8>, i32, i64, PgNumeric, Vec<u8>, i32)>(&self.connection)?;
+        Ok(result.into_iter().map(Self::_slp_utxo_from_row).collect())
+    }
+
+    /// The token and the SLP UTXO amount of each of its holders, largest first, for
+    /// `TokenDistribution::new`.
+    pub fn token_holder_amounts(&self, token_hash: &[u8; 32])
+            -> QueryResult<Option<(Token, Vec<(Option<Address>, SLPAmount)>)>> {
         use diesel::sql_types::Binary;
-        let input_query = diesel::sql_query("\
+        let token = match self.token(token_hash)? {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let decimals = token.decimals as u32;
+        let rows = diesel::sql_query("\
             SELECT
-                tx.id AS tx_id,
-                tx.hash AS tx_hash,
-                tx.timestamp AS timestamp,
-                SUM(tx_input_output.value_satoshis)::NUMERIC AS input_value_satoshis,
-                SUM(tx_input_output.value_token_base) AS input_value_token_base,
-                token.hash AS token_hash,
-                token.decimals AS decimals
-            FROM tx
-                LEFT JOIN slp_tx                       ON (tx.id = slp_tx.tx)
-                LEFT JOIN token                        ON (token.id = slp_tx.token)
-                LEFT JOIN tx_input                     ON (tx.id = tx_input.tx AND
-                                                           tx_input.address = $1)
-                LEFT JOIN tx        AS tx_input_tx     ON (tx_input_tx.hash = tx_input.output_tx)
-                LEFT JOIN tx_output AS tx_input_output ON (tx_input_tx.id = tx_input_output.tx AND
-                                                           tx_input.output_idx = tx_input_output.idx)
+                tx_output.address AS address,
+                tx_output.output_type AS output_type,
+                SUM(tx_output.value_token_base) AS amount
+            FROM utxo_slp
+                INNER JOIN tx_output ON (tx_output.tx = utxo_slp.tx AND
+                                         tx_output.idx = utxo_slp.idx)
+                INNER JOIN token     ON (token.id = utxo_slp.token)
             WHERE
-                tx_input.address = $1
-            GROUP BY tx.id, tx.hash, token.hash, token.decimals
-        ").bind::<Binary, _>(address.bytes().to_vec());
-        let output_query = diesel::sql_query("\
-            SELECT
-                tx.id AS tx_id,
-                tx.hash AS tx_hash,
-                tx.timestamp AS timestamp,
-                SUM(tx_output.value_satoshis)::NUMERIC AS output_value_satoshis,
-                SUM(tx_output.value_token_base) AS output_value_token_base,
-                token.hash AS token_hash,
-                token.decimals AS decimals
-            FROM tx
-                LEFT JOIN slp_tx                       ON (tx.id = slp_tx.tx)
-                LEFT JOIN token                        ON (token.id = slp_tx.token)
-                LEFT JOIN tx_output                    ON (tx.id = tx_output.tx AND
-                                                           tx_output.address = $1)
+                token.hash = $1
+            GROUP BY tx_output.address, tx_output.output_type
+            ORDER BY amount DESC
+        ").bind::<Binary, _>(token_hash.to_vec())
+            .load::<models::TokenHolderRow>(&self.connection)?;
+        let amounts = rows.into_iter()
+            .map(|row| (
+                row.address.and_then(|address| address_from_output_type(row.output_type, &address)),
+                SLPAmount::from_numeric_decimals(&row.amount, decimals),
+            ))
+            .collect();
+        Ok(Some((token, amounts)))
+    }
+
+    fn _slp_utxo_from_row((tx_hash, vout, value_satoshis, value_token_base, token_hash, decimals):
+                              (Vec<u8>, i32, i64, PgNumeric, Vec<u8>, i32)) -> Utxo {
+        Utxo {
+            tx_hash: tx_hash_from_slice(&tx_hash),
+            vout,
+            value_satoshis: value_satoshis as u64,
+            value_token: SLPAmount::from_numeric_decimals(&value_token_base, decimals as u32),
+            token_hash: Some(tx_hash_from_slice(&token_hash)),
+        }
+    }
+
+    pub fn address_tx_deltas(&self, addresses: &[Address], query: &TxDeltaQuery)
+            -> QueryResult<TxDeltaPage> {
+        use diesel::sql_types::{Array, Binary, Integer, BigInt, Nullable};
+        let rows = diesel::sql_query("\
+            WITH deltas AS (
+                SELECT
+                    tx.id AS tx_id,
+                    tx.hash AS tx_hash,
+                    tx.height AS height,
+                    COALESCE(tx.height, 2147483647) AS sort_height,
+                    tx.timestamp AS timestamp,
+                    token.hash AS token_hash,
+                    token.decimals AS decimals,
+                    (COALESCE(outputs.value_satoshis, 0) -
+                     COALESCE(inputs.value_satoshis, 0))::BIGINT AS delta_satoshis,
+                    COALESCE(outputs.value_token_base, 0) -
+                     COALESCE(inputs.value_token_base, 0) AS delta_token_base
+                FROM tx
+                    LEFT JOIN slp_tx ON (tx.id = slp_tx.tx)
+                    LEFT JOIN token  ON (token.id = slp_tx.token)
+                    LEFT JOIN (
+                        SELECT
+                            tx_output.tx AS tx,
+                            SUM(tx_output.value_satoshis) AS value_satoshis,
+                            SUM(tx_output.value_token_base) AS value_token_base
+                        FROM tx_output
+                        WHERE tx_output.address = ANY($1)
+                        GROUP BY tx_output.tx
+                    ) outputs ON (outputs.tx = tx.id)
+                    LEFT JOIN (
+                        SELECT
+                            tx_input.tx AS tx,
+                            SUM(tx_input_output.value_satoshis) AS value_satoshis,
+                            SUM(tx_input_output.value_token_base) AS value_token_base
+                        FROM tx_input
+                            LEFT JOIN tx        AS tx_input_tx     ON (tx_input_tx.hash = tx_input.output_tx)
+                            LEFT JOIN tx_output AS tx_input_output ON (tx_input_tx.id = tx_input_output.tx AND
+                                                                       tx_input.output_idx = tx_input_output.idx)
+                        WHERE tx_input.address = ANY($1)
+                        GROUP BY tx_input.tx
+                    ) inputs ON (inputs.tx = tx.id)
+                WHERE
+                    outputs.tx IS NOT NULL OR inputs.tx IS NOT NULL
+            ), balances AS (
+                SELECT
+                    deltas.*,
+                    (SUM(delta_satoshis) OVER (
+                        ORDER BY sort_height, timestamp, tx_id
+                    ))::BIGINT AS running_satoshis,
+                    SUM(delta_token_base) OVER (
+                        PARTITION BY token_hash ORDER BY sort_height, timestamp, tx_id
+                    ) AS running_token_base
+                FROM deltas
+            )
+            SELECT * FROM balances
             WHERE
-                tx_output.address = $1
-            GROUP BY tx.id, tx.hash, token.hash, token.decimals
-        ").bind::<Binary, _>(address.bytes().to_vec());
-        let mut result_input = input_query
-            .load::<models::TxDeltaInput>(&self.connection)?
-            .into_iter()
-            .map(|delta_input| (delta_input.tx_id, delta_input))
-            .collect::<HashMap<_, _>>();
-        let mut result_output = output_query
-            .load::<models::TxDeltaOutput>(&self.connection)?
-            .into_iter()
-            .map(|delta_output| (delta_output.tx_id, delta_output))
-            .collect::<HashMap<_, _>>();
-        let tx_ids = result_input.keys().cloned()
-            .chain(result_output.keys().cloned())
-            .collect::<HashSet<_>>();
-        Ok(tx_ids.into_iter()
-            .map(|tx_id| {
-                let delta_input = result_input.remove(&tx_id);
-                let delta_output = result_output.remove(&tx_id);
-                let (decimals, token_hash) = delta_input.as_ref()
-                    .and_then(
-                        |delta_input| Some((delta_input.decimals?,
-                                            Some(delta_input.token_hash.clone()?)))
-                    )
-                    .or_else(|| delta_output.as_ref().and_then(|delta_output| {
-                        Some((delta_output.decimals?, Some(delta_output.token_hash.clone()?)))
-                    }))
-                    .unwrap_or((0, None));
-                let decimals = decimals as u32;
-                let zero = SLPAmount::new(0, decimals);
-                let map_numeric = |numeric: &Option<PgNumeric>| numeric.as_ref()
-                    .map(|numeric| SLPAmount::from_numeric_decimals(numeric, decimals))
-                    .unwrap_or(zero);
-                let (input_value_satoshis, input_value_token) = delta_input.as_ref()
-                    .map(|delta_input| (
-                        map_numeric(&delta_input.input_value_satoshis).base_amount(),
-                        map_numeric(&delta_input.input_value_token_base),
-                    ))
-                    .unwrap_or((0, zero));
-                let (output_value_satoshis, output_value_token) = delta_output.as_ref()
-                    .map(|delta_output| (
-                        map_numeric(&delta_output.output_value_satoshis).base_amount(),
-                        map_numeric(&delta_output.output_value_token_base),
-                    ))
-                    .unwrap_or((0, zero));
-                let delta_satoshis = output_value_satoshis - input_value_satoshis;
-                let delta_token = output_value_token - input_value_token;
+                ($2::BYTEA IS NULL OR token_hash = $2) AND
+                ($3 = 0 OR ($3 = 1 AND delta_token_base = 0) OR ($3 = 2 AND delta_token_base <> 0)) AND
+                ($4::INT IS NULL OR sort_height >= $4) AND
+                ($5::INT IS NULL OR sort_height <= $5) AND
+                ($6::BIGINT IS NULL OR timestamp >= $6) AND
+                ($7::BIGINT IS NULL OR timestamp <= $7) AND
+                ($8::INT IS NULL OR (sort_height, timestamp, tx_id) < ($8, $9, $10))
+            ORDER BY sort_height DESC, timestamp DESC, tx_id DESC
+            LIMIT $11
+        ").bind::<Array<Binary>, _>(
+                addresses.iter().map(|address| address.bytes().to_vec()).collect::<Vec<_>>()
+            )
+            .bind::<Nullable<Binary>, _>(query.token_hash.map(|token_hash| token_hash.to_vec()))
+            .bind::<Integer, _>(query.kind as i32)
+            .bind::<Nullable<Integer>, _>(query.min_height)
+            .bind::<Nullable<Integer>, _>(query.max_height)
+            .bind::<Nullable<BigInt>, _>(query.min_timestamp)
+            .bind::<Nullable<BigInt>, _>(query.max_timestamp)
+            .bind::<Nullable<Integer>, _>(query.cursor.map(|cursor| cursor.sort_height))
+            .bind::<Nullable<BigInt>, _>(query.cursor.map(|cursor| cursor.timestamp))
+            .bind::<Nullable<BigInt>, _>(query.cursor.map(|cursor| cursor.tx_id))
+            .bind::<Nullable<BigInt>, _>(query.limit)
+            .load::<models::TxDeltaRow>(&self.connection)?;
+        let next_cursor = match (rows.last(), query.limit) {
+            (Some(row), Some(limit)) if rows.len() as i64 >= limit => Some(TxDeltaCursor {
+                sort_height: row.sort_height,
+                timestamp: row.timestamp,
+                tx_id: row.tx_id,
+            }),
+            _ => None,
+        };
+        let tx_deltas = rows.into_iter()
+            .map(|row| {
+                let decimals = row.decimals.unwrap_or(0) as u32;
+                let delta_token = SLPAmount::from_numeric_decimals(&row.delta_token_base, decimals);
+                let token_hash = row.token_hash.as_ref().map(|token_hash| tx_hash_from_slice(token_hash));
                 TxDelta {
-                    tx_hash: tx_hash_from_slice(
-                        &delta_output.as_ref()
-                            .map(|delta_output| delta_output.tx_hash.clone())
-                            .unwrap_or_else(|| delta_input.as_ref().unwrap().tx_hash.clone())
-                    ),
-                    token_hash: token_hash.filter(|_| delta_token.base_amount() != 0)
-                        .map(|token_hash| tx_hash_from_slice(&token_hash)),
-                    delta_satoshis: delta_satoshis as i64,
+                    tx_hash: tx_hash_from_slice(&row.tx_hash),
+                    token_hash: token_hash.filter(|_| delta_token.base_amount() != 0),
+                    delta_satoshis: row.delta_satoshis,
                     delta_token,
-                    timestamp: delta_output.as_ref()
-                        .map(|delta_output| delta_output.timestamp)
-                        .unwrap_or_else(|| delta_input.as_ref().unwrap().timestamp),
+                    timestamp: row.timestamp,
+                    height: row.height,
+                    running_balance_satoshis: Some(row.running_satoshis),
+                    running_balance_token: token_hash.map(|_| {
+                        SLPAmount::from_numeric_decimals(&row.running_token_base, decimals)
+                    }),
                 }
             })
+            .collect();
+        Ok(TxDeltaPage { tx_deltas, next_cursor })
+    }
+
+    /// Offers made by `addresses` in any state, plus offers they took as a taker, most recent
+    /// action first.
+    pub fn address_trade_history(&self, addresses: &[Address], cursor: Option<TradeHistoryCursor>,
+                                 limit: i64, config: &SLPDEXConfig)
+            -> QueryResult<TradeHistoryPage> {
+        use diesel::sql_types::{Array, Binary, Integer, BigInt, Nullable};
+        let rows = diesel::sql_query("\
+            WITH trades AS (
+                SELECT
+                    trade_offer.id AS offer_id,
+                    trade_offer.receiving_address = ANY($1) AS is_maker,
+                    offer_tx.hash AS tx_hash,
+                    trade_offer.output_idx,
+                    trade_offer.input_tx,
+                    trade_offer.input_idx,
+                    trade_offer.price_per_token,
+                    trade_offer.script_price,
+                    trade_offer.is_inverted,
+                    trade_offer.sell_amount_token_base,
+                    trade_offer.receiving_address,
+                    trade_offer.power,
+                    trade_offer.fee_profile,
+                    trade_offer.contract_version,
+                    trade_offer.state,
+                    trade_offer.spent_tx,
+                    token.hash AS token_hash,
+                    token.decimals,
+                    offer_tx.timestamp AS created_timestamp,
+                    spent_tx.timestamp AS spent_timestamp,
+                    COALESCE(spent_tx.timestamp, offer_tx.timestamp) AS sort_timestamp
+                FROM trade_offer
+                    INNER JOIN tx AS offer_tx ON (offer_tx.id = trade_offer.tx)
+                    INNER JOIN slp_tx         ON (slp_tx.tx = offer_tx.id)
+                    INNER JOIN token          ON (token.id = slp_tx.token)
+                    LEFT JOIN tx AS spent_tx  ON (spent_tx.hash = trade_offer.spent_tx)
+                WHERE
+                    trade_offer.receiving_address = ANY($1) OR (
+                        trade_offer.state IN (2, 3) AND
+                        EXISTS (
+                            SELECT 1 FROM tx_input
+                            WHERE tx_input.tx = spent_tx.id AND tx_input.address = ANY($1)
+                        )
+                    )
+            )
+            SELECT * FROM trades
+            WHERE ($2::BIGINT IS NULL OR (sort_timestamp, offer_id) < ($2, $3))
+            ORDER BY sort_timestamp DESC, offer_id DESC
+            LIMIT $4
+        ").bind::<Array<Binary>, _>(
+                addresses.iter().map(|address| address.bytes().to_vec()).collect::<Vec<_>>()
+            )
+            .bind::<Nullable<BigInt>, _>(cursor.map(|cursor| cursor.timestamp))
+            .bind::<Nullable<Integer>, _>(cursor.map(|cursor| cursor.offer_id))
+            .bind::<BigInt, _>(limit)
+            .load::<models::TradeHistoryRow>(&self.connection)?;
+        let next_cursor = match rows.last() {
+            Some(row) if rows.len() as i64 >= limit => Some(TradeHistoryCursor {
+                timestamp: row.sort_timestamp,
+                offer_id: row.offer_id,
+            }),
+            _ => None,
+        };
+        let trades = rows.into_iter()
+            .filter_map(|row| {
+                let trade_offer = TradeOffer {
+                    tx: tx_hash_from_slice(&row.tx_hash),
+                    output_idx: row.output_idx,
+                    input_tx: tx_hash_from_slice(&row.input_tx),
+                    input_idx: row.input_idx,
+                    price_per_token: pg_numeric_to_rational(&row.price_per_token).ok()?,
+                    script_price: row.script_price,
+                    is_inverted: row.is_inverted,
+                    sell_amount_token: SLPAmount::from_numeric_decimals(&row.sell_amount_token_base,
+                                                                        row.decimals as u32),
+                    receiving_address: Address::from_bytes(
+                        AddressType::P2PKH, address_hash_from_slice(&row.receiving_address)
+                    ),
+                    power: row.power as u8,
+                    fee_profile: row.fee_profile,
+                    contract_version: row.contract_version as i32,
+                };
+                let (payment_satoshis, fee_satoshis) = offer_payment_and_fee(&trade_offer, config).ok()?;
+                Some(TradeHistoryEntry {
+                    role: if row.is_maker { TradeRole::Maker } else { TradeRole::Taker },
+                    trade_offer,
+                    token_hash: tx_hash_from_slice(&row.token_hash),
+                    state: TradeOfferState::from_id(row.state)?,
+                    spent_tx: row.spent_tx.as_ref().map(|spent_tx| tx_hash_from_slice(spent_tx)),
+                    created_timestamp: row.created_timestamp,
+                    spent_timestamp: row.spent_timestamp,
+                    payment_satoshis,
+                    fee_satoshis,
+                })
+            })
+            .collect();
+        Ok(TradeHistoryPage { trades, next_cursor })
+    }
+
+    /// Records the fee output of every filled offer's taker tx not yet in `trade_fee`. The fee
+    /// output directly follows the maker's payment, as laid out by the contract, and must pay
+    /// the offer's own fee profile at least the fee its contract demands.
+    pub fn update_trade_fees(&self, config: &SLPDEXConfig) -> QueryResult<usize> {
+        use super::schema::trade_offer as t;
+        use diesel::sql_types::{Array, Binary, BigInt, Integer};
+        let candidates = trade_offer::table
+            .inner_join(tx::table)
+            .inner_join(slp_tx::table.on(tx::id.eq(slp_tx::tx)))
+            .inner_join(token::table.on(slp_tx::token.eq(token::id)))
+            .left_join(trade_fee::table.on(trade_fee::trade_offer.eq(t::id)))
+            .filter(t::state.eq(TradeOfferState::Filled.id()))
+            .filter(t::fee_profile.is_not_null())
+            .filter(trade_fee::trade_offer.nullable().is_null())
+            .select((t::id, (tx::hash, t::output_idx, t::input_tx, t::input_idx, t::script_price,
+                             t::sell_amount_token_base, t::receiving_address, t::price_per_token,
+                             t::is_inverted, token::decimals, t::power, t::fee_profile,
+                             t::contract_version)))
+            .load::<(i32, TradeOfferColumns)>(&self.connection)?;
+        let mut offer_ids = Vec::new();
+        let mut fee_addresses = Vec::new();
+        let mut payments = Vec::new();
+        let mut fees = Vec::new();
+        for (id, columns) in candidates {
+            let trade_offer = match Self::_trade_offer_from_columns(columns) {
+                Some(trade_offer) => trade_offer,
+                None => continue,
+            };
+            let (payment, fee) = match offer_payment_and_fee(&trade_offer, config) {
+                Ok((_, 0)) | Err(_) => continue,
+                Ok(payment_and_fee) => payment_and_fee,
+            };
+            // offers of a fee profile no longer configured can't be attributed
+            let fee_config = match config.with_fee_profile(trade_offer.fee_profile.as_ref().map(String::as_str)) {
+                Ok(fee_config) => fee_config,
+                Err(_) => continue,
+            };
+            offer_ids.push(id);
+            fee_addresses.push(fee_config.fee_address.bytes().to_vec());
+            payments.push(payment as i64);
+            fees.push(fee as i64);
+        }
+        if offer_ids.len() == 0 { return Ok(0) }
+        diesel::sql_query("\
+            INSERT INTO trade_fee (trade_offer, tx, idx, fee_address, value_satoshis, token, timestamp)
+            SELECT DISTINCT ON (trade_offer.id)
+                trade_offer.id,
+                taker_tx.id,
+                fee_output.idx,
+                fee_output.address,
+                fee_output.value_satoshis,
+                slp_tx.token,
+                taker_tx.timestamp
+            FROM UNNEST($1::INT[], $2::BYTEA[], $3::BIGINT[], $4::BIGINT[])
+                    AS expected (trade_offer, fee_address, payment, fee)
+                INNER JOIN trade_offer           ON (trade_offer.id = expected.trade_offer)
+                INNER JOIN tx AS taker_tx        ON (taker_tx.hash = trade_offer.spent_tx)
+                INNER JOIN slp_tx                ON (slp_tx.tx = trade_offer.tx)
+                INNER JOIN tx_output AS payment  ON (payment.tx = taker_tx.id AND
+                                                     payment.address = trade_offer.receiving_address AND
+                                                     payment.value_satoshis >= expected.payment)
+                INNER JOIN tx_output AS fee_output ON (fee_output.tx = taker_tx.id AND
+                                                       fee_output.idx = payment.idx + 1 AND
+                                                       fee_output.address = expected.fee_address AND
+                                                       fee_output.value_satoshis >= expected.fee)
+            ORDER BY trade_offer.id, payment.idx
+            ON CONFLICT DO NOTHING
+        ").bind::<Array<Integer>, _>(offer_ids)
+            .bind::<Array<Binary>, _>(fee_addresses)
+            .bind::<Array<BigInt>, _>(payments)
+            .bind::<Array<BigInt>, _>(fees)
+            .execute(&self.connection)
+    }
+
+    /// Collected fees grouped by token, UTC day and fee address, oldest day first.
+    pub fn trade_fee_report(&self, query: &TradeFeeQuery) -> QueryResult<Vec<TradeFeeSummary>> {
+        use diesel::sql_types::{Binary, BigInt, Nullable};
+        Ok(diesel::sql_query("\
+            SELECT
+                token.hash AS token_hash,
+                token.symbol,
+                to_char(to_timestamp(trade_fee.timestamp) AT TIME ZONE 'UTC', 'YYYY-MM-DD') AS day,
+                trade_fee.fee_address,
+                COUNT(*) AS trade_count,
+                SUM(trade_fee.value_satoshis)::BIGINT AS fee_satoshis
+            FROM trade_fee
+                INNER JOIN token ON (token.id = trade_fee.token)
+            WHERE
+                ($1::BIGINT IS NULL OR trade_fee.timestamp >= $1) AND
+                ($2::BIGINT IS NULL OR trade_fee.timestamp <= $2) AND
+                ($3::BYTEA IS NULL OR token.hash = $3)
+            GROUP BY token.hash, token.symbol, day, trade_fee.fee_address
+            ORDER BY day, token.hash, trade_fee.fee_address
+        ").bind::<Nullable<BigInt>, _>(query.min_timestamp)
+            .bind::<Nullable<BigInt>, _>(query.max_timestamp)
+            .bind::<Nullable<Binary>, _>(query.token_hash.map(|token_hash| token_hash.to_vec()))
+            .load::<models::TradeFeeRow>(&self.connection)?
+            .into_iter()
+            .map(|row| TradeFeeSummary {
+                token_hash: tx_hash_from_slice(&row.token_hash),
+                symbol: row.symbol,
+                day: row.day,
+                fee_address: Address::from_bytes(AddressType::P2PKH,
+                                                 address_hash_from_slice(&row.fee_address)),
+                trade_count: row.trade_count,
+                fee_satoshis: row.fee_satoshis as u64,
+            })
             .collect())
     }
 
-    pub fn trade_offer_utxos(&self, filter: TradeOfferFilter) -> QueryResult<Vec<TradeOffer>> {
+    /// Open offers matching `filter`, only those of `fee_profile` if given.
+    pub fn trade_offer_utxos(&self, filter: TradeOfferFilter, fee_profile: Option<&str>)
+            -> QueryResult<Vec<TradeOffer>> {
         use super::schema::trade_offer as t;
-        type Q = (Vec<u8>, Option<i32>,   Vec<u8>,     i32,          i64,
-                  PgNumeric,                Vec<u8>,              PgNumeric,          bool,
-                  i32);
+        type Q = TradeOfferColumns;
         let s = (tx::hash, t::output_idx, t::input_tx, t::input_idx, t::script_price,
                  t::sell_amount_token_base, t::receiving_address, t::price_per_token, t::is_inverted,
-                 token::decimals);
+                 token::decimals, t::power, t::fee_profile, t::contract_version);
         let tables = trade_offer::table
             .inner_join(tx::table)
             .inner_join(utxo_trade_offer::table.on(tx::id.eq(utxo_trade_offer::tx)))
@@ -512,7 +1861,7 @@ impl Db {
             .select(s);
         let result = match filter {
             TradeOfferFilter::TokenHash(token_hash) => tables
-                .filter(tx::hash.eq(token_hash.to_vec()))
+                .filter(token::hash.eq(token_hash.to_vec()))
                 .load::<Q>(&self.connection)?,
             TradeOfferFilter::ReceivingAddress(address) => tables
                 .filter(trade_offer::receiving_address.eq(address.bytes().to_vec()))
@@ -520,26 +1869,527 @@ impl Db {
         };
         Ok(result
             .into_iter()
-            .filter_map(|(tx_hash, output_idx, input_tx, input_idx, script_price,
-                          sell_amount_token_base, receiving_address, price_per_token, is_inverted,
-                          decimals)| {
-                Some(TradeOffer {
-                    tx: tx_hash_from_slice(&tx_hash),
-                    output_idx,
-                    input_tx: tx_hash_from_slice(&input_tx),
-                    input_idx,
-                    price_per_token: pg_numeric_to_rational(&price_per_token).ok()?,
-                    script_price,
-                    is_inverted,
-                    sell_amount_token: SLPAmount
-                        ::from_numeric_decimals(&sell_amount_token_base, decimals as u32),
-                    receiving_address: Address
-                        ::from_bytes(AddressType::P2PKH, address_hash_from_slice(&receiving_address)),
+            .filter(|row| fee_profile.is_none() || row.11.as_ref().map(String::as_str) == fee_profile)
+            .filter_map(Self::_trade_offer_from_columns)
+            .collect())
+    }
+
+    fn _trade_offer_from_columns((tx_hash, output_idx, input_tx, input_idx, script_price,
+                                  sell_amount_token_base, receiving_address, price_per_token,
+                                  is_inverted, decimals, power, fee_profile, contract_version):
+                                 TradeOfferColumns) -> Option<TradeOffer> {
+        Some(TradeOffer {
+            tx: tx_hash_from_slice(&tx_hash),
+            output_idx,
+            input_tx: tx_hash_from_slice(&input_tx),
+            input_idx,
+            price_per_token: pg_numeric_to_rational(&price_per_token).ok()?,
+            script_price,
+            is_inverted,
+            sell_amount_token: SLPAmount
+                ::from_numeric_decimals(&sell_amount_token_base, decimals as u32),
+            receiving_address: Address
+                ::from_bytes(AddressType::P2PKH, address_hash_from_slice(&receiving_address)),
+            power: power as u8,
+            fee_profile,
+            contract_version: contract_version as i32,
+        })
+    }
+
+    /// The open offer whose contract sits at `outpoint`, if any.
+    pub fn trade_offer_utxo(&self, outpoint: &SpentUtxo) -> QueryResult<Option<TradeOfferUtxo>> {
+        use super::schema::trade_offer as t;
+        let row = trade_offer::table
+            .inner_join(tx::table)
+            .inner_join(utxo_trade_offer::table.on(
+                tx::id.eq(utxo_trade_offer::tx)
+                    .and(t::output_idx.eq(utxo_trade_offer::idx.nullable()))
+            ))
+            .inner_join(tx_output::table.on(
+                tx_output::tx.eq(utxo_trade_offer::tx).and(tx_output::idx.eq(utxo_trade_offer::idx))
+            ))
+            .inner_join(slp_tx::table.on(tx::id.eq(slp_tx::tx)))
+            .inner_join(token::table.on(slp_tx::token.eq(token::id)))
+            .filter(tx::hash.eq(outpoint.tx_hash.to_vec()))
+            .filter(utxo_trade_offer::idx.eq(outpoint.vout))
+            .select((t::input_tx, t::input_idx, t::script_price, t::sell_amount_token_base,
+                     t::receiving_address, t::price_per_token, t::is_inverted, t::power,
+                     t::fee_profile, t::contract_version, token::hash, token::decimals,
+                     tx_output::value_satoshis))
+            .first::<(Vec<u8>, i32, i64, PgNumeric, Vec<u8>, PgNumeric, bool, i16,
+                      Option<String>, i16, Vec<u8>, i32, i64)>(&self.connection)
+            .optional()?;
+        let (input_tx, input_idx, script_price, sell_amount_token_base, receiving_address,
+             price_per_token, is_inverted, power, fee_profile, contract_version, token_hash,
+             decimals, value_satoshis) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let price_per_token = match pg_numeric_to_rational(&price_per_token) {
+            Ok(price_per_token) => price_per_token,
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(TradeOfferUtxo {
+            trade_offer: TradeOffer {
+                tx: outpoint.tx_hash,
+                output_idx: Some(outpoint.vout),
+                input_tx: tx_hash_from_slice(&input_tx),
+                input_idx,
+                price_per_token,
+                script_price,
+                is_inverted,
+                sell_amount_token: SLPAmount::from_numeric_decimals(&sell_amount_token_base,
+                                                                    decimals as u32),
+                receiving_address: Address::from_bytes(AddressType::P2PKH,
+                                                       address_hash_from_slice(&receiving_address)),
+                power: power as u8,
+                fee_profile,
+                contract_version: contract_version as i32,
+            },
+            token_hash: tx_hash_from_slice(&token_hash),
+            value_satoshis: value_satoshis as u64,
+        }))
+    }
+
+    /// Stores raw txs, keeping those already stored.
+    pub fn add_raw_txs(&self, raw_txs: &[([u8; 32], Vec<u8>)]) -> QueryResult<()> {
+        diesel::insert_into(raw_tx::table)
+            .values(raw_txs.iter()
+                .map(|(tx_hash, raw)| models::RawTx { hash: tx_hash.to_vec(), raw: raw.clone() })
+                .collect::<Vec<_>>()
+            )
+            .on_conflict_do_nothing()
+            .execute(&self.connection)?;
+        Ok(())
+    }
+
+    /// Those of `tx_hashes` whose raw tx isn't stored yet.
+    pub fn missing_raw_txs(&self, tx_hashes: &[[u8; 32]]) -> QueryResult<Vec<[u8; 32]>> {
+        let stored = raw_tx::table
+            .select(raw_tx::hash)
+            .filter(raw_tx::hash.eq_any(
+                tx_hashes.iter().map(|tx_hash| tx_hash.to_vec()).collect::<Vec<_>>()
+            ))
+            .load::<Vec<u8>>(&self.connection)?
+            .into_iter()
+            .map(|tx_hash| tx_hash_from_slice(&tx_hash))
+            .collect::<HashSet<_>>();
+        Ok(tx_hashes.iter().filter(|tx_hash| !stored.contains(*tx_hash)).cloned().collect())
+    }
+
+    /// Up to `limit` stored raw txs together with their tx row, in the order the tx rows
+    /// were added, starting after tx id `after_tx_id`.
+    pub fn raw_txs(&self, after_tx_id: i64, limit: i64) -> QueryResult<Vec<(models::Tx, Vec<u8>)>> {
+        raw_tx::table
+            .inner_join(tx::table.on(tx::hash.eq(raw_tx::hash)))
+            .filter(tx::id.gt(after_tx_id))
+            .order(tx::id)
+            .limit(limit)
+            .select((tx::all_columns, raw_tx::raw))
+            .load(&self.connection)
+    }
+
+    /// Deletes everything derived from the txs with a stored raw tx, which reindexing can
+    /// restore: outputs, inputs, SLP txs and trade offers (with their states and fees).
+    /// Tx rows, tokens and raw txs are kept, as is the index of txs without a raw tx.
+    pub fn clear_tx_index(&self) -> QueryResult<()> {
+        self.connection.transaction(|| {
+            for table in &["trade_offer", "slp_tx", "tx_input", "tx_output"] {
+                diesel::sql_query(format!("\
+                    DELETE FROM {}
+                    WHERE \"tx\" IN (
+                        SELECT tx.id FROM tx INNER JOIN raw_tx ON (raw_tx.hash = tx.hash)
+                    )
+                ", table)).execute(&self.connection)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn active_addresses(&self) -> QueryResult<Vec<Address>> {
+        Ok(active_address::table
+            .select(active_address::address)
+            .load::<Vec<u8>>(&self.connection)?
+            .into_iter()
+            .map(|address| Address::from_bytes(AddressType::P2PKH, address_hash_from_slice(&address)))
+            .collect())
+    }
+
+    /// Deactivates the address and deletes its UTXOs and update history, so a later resync
+    /// starts from scratch. Its txs are kept, as other subjects may refer to them.
+    pub fn purge_address(&self, address: &Address) -> QueryResult<()> {
+        let address = address.bytes().to_vec();
+        self.connection.transaction(|| {
+            diesel::delete(active_address::table)
+                .filter(active_address::address.eq(address.clone()))
+                .execute(&self.connection)?;
+            diesel::delete(utxo_address::table)
+                .filter(utxo_address::address.eq(address.clone()))
+                .execute(&self.connection)?;
+            diesel::delete(update_history::table)
+                .filter(update_history::subject_type.eq_any(vec![
+                    UpdateSubjectType::AddressHistory as i32,
+                    UpdateSubjectType::AddressUTXOs as i32,
+                ]))
+                .filter(update_history::subject_hash.eq(address))
+                .execute(&self.connection)?;
+            Ok(())
+        })
+    }
+
+    /// Tokens which currently have rows in `utxo_slp`.
+    pub fn utxo_slp_token_hashes(&self) -> QueryResult<Vec<[u8; 32]>> {
+        Ok(token::table
+            .select(token::hash)
+            .filter(diesel::dsl::exists(
+                utxo_slp::table.filter(utxo_slp::token.eq(token::id))
+            ))
+            .load::<Vec<u8>>(&self.connection)?
+            .into_iter()
+            .map(|token_hash| tx_hash_from_slice(&token_hash))
+            .collect())
+    }
+
+    /// Those of `addresses` which are active.
+    pub fn active_addresses_among(&self, addresses: &[Address]) -> QueryResult<HashSet<Address>> {
+        Ok(active_address::table
+            .select(active_address::address)
+            .filter(active_address::address.eq_any(
+                addresses.iter().map(|address| address.bytes().to_vec()).collect::<Vec<_>>()
+            ))
+            .load::<Vec<u8>>(&self.connection)?
+            .into_iter()
+            .map(|address| Address::from_bytes(AddressType::P2PKH, address_hash_from_slice(&address)))
+            .collect())
+    }
+
+    /// The xpubs of the accounts `addresses` were derived from, each with its addresses.
+    pub fn hd_accounts_of(&self, addresses: &[Address]) -> QueryResult<HashMap<String, Vec<Address>>> {
+        let rows = hd_account_address::table
+            .inner_join(hd_account::table.on(hd_account::id.eq(hd_account_address::account)))
+            .select((hd_account::xpub, hd_account_address::address))
+            .filter(hd_account_address::address.eq_any(
+                addresses.iter().map(|address| address.bytes().to_vec()).collect::<Vec<_>>()
+            ))
+            .load::<(String, Vec<u8>)>(&self.connection)?;
+        let mut accounts = HashMap::new();
+        for (xpub, address) in rows {
+            accounts.entry(xpub)
+                .or_insert_with(Vec::new)
+                .push(Address::from_bytes(AddressType::P2PKH, address_hash_from_slice(&address)));
+        }
+        Ok(accounts)
+    }
+
+    /// Appends events to the outbox and returns them with their sequence numbers. Call it in
+    /// the transaction of the change the events describe.
+    pub fn add_outbox_events(&self, events: &[NewOutboxEvent]) -> QueryResult<Vec<OutboxEvent>> {
+        if events.len() == 0 {
+            return Ok(Vec::new());
+        }
+        // writers take turns until commit, so listeners never see a seq before a smaller one
+        diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+            .bind::<diesel::sql_types::BigInt, _>(OUTBOX_LOCK_ID)
+            .execute(&self.connection)?;
+        let mut rows = diesel::insert_into(event_outbox::table)
+            .values(events.iter()
+                .map(|event| models::NewOutboxEvent {
+                    subject: event.subject.key(),
+                    event_type: event.event_type.to_string(),
+                    payload: event.payload.clone(),
                 })
-            })
+                .collect::<Vec<_>>()
+            )
+            .get_results::<models::OutboxEvent>(&self.connection)?;
+        rows.sort_by_key(|row| row.seq);
+        Ok(Self::_outbox_events(rows))
+    }
+
+    /// Up to `limit` events of `subjects` after `after_seq`, oldest first.
+    pub fn outbox_events(&self, subjects: &[EventSubject], after_seq: i64, limit: i64)
+            -> QueryResult<Vec<OutboxEvent>> {
+        let rows = event_outbox::table
+            .filter(event_outbox::subject.eq_any(
+                subjects.iter().map(EventSubject::key).collect::<Vec<_>>()
+            ))
+            .filter(event_outbox::seq.gt(after_seq))
+            .order(event_outbox::seq)
+            .limit(limit)
+            .load::<models::OutboxEvent>(&self.connection)?;
+        Ok(Self::_outbox_events(rows))
+    }
+
+    /// Up to `limit` events of any subject after `after_seq`, oldest first.
+    pub fn outbox_events_after(&self, after_seq: i64, limit: i64) -> QueryResult<Vec<OutboxEvent>> {
+        let rows = event_outbox::table
+            .filter(event_outbox::seq.gt(after_seq))
+            .order(event_outbox::seq)
+            .limit(limit)
+            .load::<models::OutboxEvent>(&self.connection)?;
+        Ok(Self::_outbox_events(rows))
+    }
+
+    /// Builds, appends and notifies the outbox events of `history`, just added with
+    /// `add_tx_history`, for `relevant_addresses`. Call it in the transaction which added the
+    /// history, so the events are stored exactly if the txs are.
+    pub fn add_tx_history_events(&self,
+                                 history: &TxHistory,
+                                 trade_offer_states: &[TradeOfferStateChange],
+                                 relevant_addresses: &HashSet<Address>,
+                                 now: i64) -> slpdexdb_base::Result<()> {
+        let builder = match &self.outbox_event_builder {
+            Some(builder) => builder,
+            None => return Ok(()),
+        };
+        let events = builder.outbox_events(self, history, trade_offer_states, relevant_addresses,
+                                           now)?;
+        if let Some(last_event) = self.add_outbox_events(&events)?.last() {
+            self.notify_outbox(last_event.seq)?;
+        }
+        Ok(())
+    }
+
+    /// Notifies listeners on `OUTBOX_CHANNEL` of events up to `last_seq`. Postgres delivers
+    /// the notification once the surrounding transaction commits.
+    pub fn notify_outbox(&self, last_seq: i64) -> QueryResult<()> {
+        diesel::sql_query("SELECT pg_notify($1, $2)")
+            .bind::<diesel::sql_types::Text, _>(OUTBOX_CHANNEL)
+            .bind::<diesel::sql_types::Text, _>(last_seq.to_string())
+            .execute(&self.connection)?;
+        Ok(())
+    }
+
+    /// Sequence numbers of the oldest and newest event still in the outbox.
+    pub fn outbox_seq_range(&self) -> QueryResult<Option<(i64, i64)>> {
+        use diesel::dsl::{min, max};
+        let (first, last) = event_outbox::table
+            .select((min(event_outbox::seq), max(event_outbox::seq)))
+            .first::<(Option<i64>, Option<i64>)>(&self.connection)?;
+        Ok(first.and_then(|first| Some((first, last?))))
+    }
+
+    /// Deletes events older than `max_age_secs`, keeping the newest so its sequence number
+    /// stays known.
+    pub fn prune_outbox(&self, max_age_secs: i64) -> QueryResult<usize> {
+        use diesel::sql_types::BigInt;
+        diesel::sql_query("\
+            DELETE FROM event_outbox
+            WHERE
+                timestamp < CURRENT_TIMESTAMP - make_interval(secs => $1) AND
+                seq < (SELECT MAX(seq) FROM event_outbox)
+        ").bind::<BigInt, _>(max_age_secs)
+            .execute(&self.connection)
+    }
+
+    fn _outbox_events(rows: Vec<models::OutboxEvent>) -> Vec<OutboxEvent> {
+        rows.into_iter()
+            .filter_map(|row| Some(OutboxEvent {
+                seq: row.seq,
+                subject: EventSubject::from_key(&row.subject)?,
+                event_type: row.event_type,
+                payload: row.payload,
+                timestamp: row.timestamp.timestamp(),
+            }))
+            .collect()
+    }
+
+    /// Queues `task` to run after `delay_secs`. If the same task is already pending, that job
+    /// is kept, with the higher priority and earlier start of both, and returned instead.
+    pub fn enqueue_resync_job(&self, task: &ResyncTask, priority: i32, delay_secs: i64)
+            -> QueryResult<ResyncJob> {
+        use diesel::sql_types::{Integer, BigInt, Binary, Nullable};
+        let rows = diesel::sql_query(format!("\
+            INSERT INTO resync_job (job_type, subject, priority, state, run_after)
+            VALUES ($1, $2, $3, 1, CURRENT_TIMESTAMP + make_interval(secs => $4))
+            ON CONFLICT (job_type, COALESCE(subject, ''::BYTEA)) WHERE state = 1
+            DO UPDATE SET
+                priority = GREATEST(resync_job.priority, EXCLUDED.priority),
+                run_after = LEAST(resync_job.run_after, EXCLUDED.run_after),
+                updated = CURRENT_TIMESTAMP
+            RETURNING {}
+        ", RESYNC_JOB_COLUMNS))
+            .bind::<Integer, _>(task.job_type() as i32)
+            .bind::<Nullable<Binary>, _>(task.subject())
+            .bind::<Integer, _>(priority)
+            .bind::<BigInt, _>(delay_secs)
+            .load::<models::ResyncJobRow>(&self.connection)?;
+        Self::_resync_jobs(rows).into_iter().next().ok_or(diesel::result::Error::NotFound)
+    }
+
+    /// Marks the due pending job of the highest priority as running and returns it. Jobs
+    /// locked by other workers are skipped.
+    pub fn claim_resync_job(&self) -> QueryResult<Option<ResyncJob>> {
+        let rows = diesel::sql_query(format!("\
+            UPDATE resync_job
+            SET state = 2, attempts = attempts + 1, updated = CURRENT_TIMESTAMP
+            WHERE id = (
+                SELECT id FROM resync_job
+                WHERE state = 1 AND run_after <= CURRENT_TIMESTAMP
+                ORDER BY priority DESC, id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING {}
+        ", RESYNC_JOB_COLUMNS))
+            .load::<models::ResyncJobRow>(&self.connection)?;
+        Ok(Self::_resync_jobs(rows).into_iter().next())
+    }
+
+    /// Marks the pending job `id` as running and returns it, due or not. `None` if it isn't
+    /// pending or another worker is claiming it.
+    pub fn claim_resync_job_by_id(&self, id: i32) -> QueryResult<Option<ResyncJob>> {
+        use diesel::sql_types::Integer;
+        let rows = diesel::sql_query(format!("\
+            UPDATE resync_job
+            SET state = 2, attempts = attempts + 1, updated = CURRENT_TIMESTAMP
+            WHERE id = (
+                SELECT id FROM resync_job
+                WHERE id = $1 AND state = 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING {}
+        ", RESYNC_JOB_COLUMNS))
+            .bind::<Integer, _>(id)
+            .load::<models::ResyncJobRow>(&self.connection)?;
+        Ok(Self::_resync_jobs(rows).into_iter().next())
+    }
+
+    pub fn finish_resync_job(&self, id: i32) -> QueryResult<()> {
+        use diesel::sql_types::Integer;
+        diesel::sql_query("\
+            UPDATE resync_job
+            SET state = 3, last_error = NULL, updated = CURRENT_TIMESTAMP
+            WHERE id = $1
+        ").bind::<Integer, _>(id)
+            .execute(&self.connection)?;
+        Ok(())
+    }
+
+    /// Records the error of a running job and reschedules it with exponential backoff, from
+    /// `retry_delay_secs` up to `max_retry_delay_secs`. It fails for good after
+    /// `max_attempts`, or if the same task was queued again meanwhile.
+    pub fn fail_resync_job(&self, id: i32, error: &str, max_attempts: i32, retry_delay_secs: i64,
+                           max_retry_delay_secs: i64) -> QueryResult<Option<ResyncJob>> {
+        use diesel::sql_types::{Integer, BigInt, Text};
+        let rows = diesel::sql_query(format!("\
+            UPDATE resync_job
+            SET
+                state = CASE WHEN attempts >= $3 OR {} THEN 4 ELSE 1 END,
+                run_after = CURRENT_TIMESTAMP + make_interval(
+                    secs => LEAST($4 * POWER(2, GREATEST(attempts - 1, 0)), $5)
+                ),
+                last_error = $2,
+                updated = CURRENT_TIMESTAMP
+            WHERE id = $1
+            RETURNING {}
+        ", RESYNC_JOB_PENDING_DUPLICATE, RESYNC_JOB_COLUMNS))
+            .bind::<Integer, _>(id)
+            .bind::<Text, _>(error)
+            .bind::<Integer, _>(max_attempts)
+            .bind::<BigInt, _>(retry_delay_secs)
+            .bind::<BigInt, _>(max_retry_delay_secs)
+            .load::<models::ResyncJobRow>(&self.connection)?;
+        Ok(Self::_resync_jobs(rows).into_iter().next())
+    }
+
+    /// Puts jobs left running by a stopped worker back into the queue, or fails them if the
+    /// same task is pending already.
+    pub fn requeue_running_resync_jobs(&self) -> QueryResult<usize> {
+        self.connection.transaction(|| {
+            diesel::sql_query(format!("\
+                UPDATE resync_job
+                SET state = 4, last_error = 'interrupted', updated = CURRENT_TIMESTAMP
+                WHERE state = 2 AND {}
+            ", RESYNC_JOB_PENDING_DUPLICATE))
+                .execute(&self.connection)?;
+            diesel::sql_query("\
+                UPDATE resync_job
+                SET state = 1, updated = CURRENT_TIMESTAMP
+                WHERE state = 2
+            ").execute(&self.connection)
+        })
+    }
+
+    /// Queues failed jobs again with fresh attempts; all of them if `id` is `None`. Tasks
+    /// which are pending already are left alone.
+    pub fn retry_resync_jobs(&self, id: Option<i32>) -> QueryResult<usize> {
+        use diesel::sql_types::{Integer, Nullable};
+        diesel::sql_query(format!("\
+            UPDATE resync_job
+            SET state = 1, attempts = 0, run_after = CURRENT_TIMESTAMP, updated = CURRENT_TIMESTAMP
+            WHERE
+                id IN (
+                    SELECT DISTINCT ON (job_type, COALESCE(subject, ''::BYTEA)) id
+                    FROM resync_job
+                    WHERE state = 4 AND ($1 IS NULL OR id = $1)
+                    ORDER BY job_type, COALESCE(subject, ''::BYTEA), id DESC
+                ) AND
+                NOT {}
+        ", RESYNC_JOB_PENDING_DUPLICATE))
+            .bind::<Nullable<Integer>, _>(id)
+            .execute(&self.connection)
+    }
+
+    pub fn resync_job(&self, id: i32) -> QueryResult<Option<ResyncJob>> {
+        use diesel::sql_types::Integer;
+        let rows = diesel::sql_query(format!("SELECT {} FROM resync_job WHERE id = $1",
+                                             RESYNC_JOB_COLUMNS))
+            .bind::<Integer, _>(id)
+            .load::<models::ResyncJobRow>(&self.connection)?;
+        Ok(Self::_resync_jobs(rows).into_iter().next())
+    }
+
+    /// Up to `limit` jobs in `state`, or in any state, newest first.
+    pub fn resync_jobs(&self, state: Option<ResyncJobState>, limit: i64) -> QueryResult<Vec<ResyncJob>> {
+        use diesel::sql_types::{Integer, BigInt, Nullable};
+        let rows = diesel::sql_query(format!("\
+            SELECT {} FROM resync_job
+            WHERE $1 IS NULL OR state = $1
+            ORDER BY id DESC
+            LIMIT $2
+        ", RESYNC_JOB_COLUMNS))
+            .bind::<Nullable<Integer>, _>(state.map(|state| state as i32))
+            .bind::<BigInt, _>(limit)
+            .load::<models::ResyncJobRow>(&self.connection)?;
+        Ok(Self::_resync_jobs(rows))
+    }
+
+    pub fn resync_job_counts(&self) -> QueryResult<Vec<(ResyncJobState, i64)>> {
+        use num_traits::FromPrimitive;
+        Ok(diesel::sql_query("\
+            SELECT state, COUNT(*) AS count FROM resync_job GROUP BY state ORDER BY state
+        ").load::<models::ResyncJobCountRow>(&self.connection)?
+            .into_iter()
+            .filter_map(|row| Some((ResyncJobState::from_i32(row.state)?, row.count)))
             .collect())
     }
 
+    /// Deletes finished jobs older than `max_age_secs`; failed ones stay for inspection.
+    pub fn prune_resync_jobs(&self, max_age_secs: i64) -> QueryResult<usize> {
+        use diesel::sql_types::BigInt;
+        diesel::sql_query("\
+            DELETE FROM resync_job
+            WHERE state = 3 AND updated < CURRENT_TIMESTAMP - make_interval(secs => $1)
+        ").bind::<BigInt, _>(max_age_secs)
+            .execute(&self.connection)
+    }
+
+    fn _resync_jobs(rows: Vec<models::ResyncJobRow>) -> Vec<ResyncJob> {
+        use num_traits::FromPrimitive;
+        rows.into_iter()
+            .filter_map(|row| Some(ResyncJob {
+                id: row.id,
+                task: ResyncTask::from_row(row.job_type, row.subject.as_ref().map(Vec::as_slice))?,
+                priority: row.priority,
+                state: ResyncJobState::from_i32(row.state)?,
+                attempts: row.attempts,
+                run_after: row.run_after,
+                last_error: row.last_error,
+                created: row.created,
+            }))
+            .collect()
+    }
+
     pub fn txs(&self, tx_hashes: impl Iterator<Item=[u8; 32]>)
             -> QueryResult<HashMap<[u8; 32], models::Tx>> {
         Ok(tx::table
@@ -570,50 +2420,103 @@ impl Db {
             .collect())
     }
 
-    pub fn remove_utxos(&self, utxos: &[SpentUtxo]) -> QueryResult<()> {
+    /// Applies the UTXO changes of one batch of txs in a single transaction. Outputs are added
+    /// first, so outputs created and spent within the batch end up removed; spent outputs of
+    /// txs which aren't indexed are skipped.
+    ///
+    /// Returns the tokens whose SLP UTXOs were skipped as the token isn't known yet; `utxo_slp`
+    /// references the token, so their UTXOs are left to a resync of the token.
+    pub fn apply_utxo_delta(&self, remove_utxos: &[SpentUtxo], add_utxos: &[NewUtxo])
+            -> QueryResult<Vec<[u8; 32]>> {
+        self.connection.transaction(|| {
+            let skipped_tokens = self._add_utxos(add_utxos)?;
+            self._remove_utxos(remove_utxos)?;
+            Ok(skipped_tokens)
+        })
+    }
+
+    fn _remove_utxos(&self, utxos: &[SpentUtxo]) -> QueryResult<()> {
+        use diesel::sql_types::{Array, BigInt, Integer};
         let txs = self.txs(utxos.iter().map(|utxo| utxo.tx_hash))?;
-        for utxo in utxos {
-            let tx = &txs[&utxo.tx_hash];
-            diesel::delete(utxo_address::table)
-                .filter(utxo_address::tx.eq(tx.id).and(utxo_address::idx.eq(utxo.vout)))
-                .execute(&self.connection)?;
-            diesel::delete(utxo_trade_offer::table)
-                .filter(utxo_trade_offer::tx.eq(tx.id).and(utxo_trade_offer::idx.eq(utxo.vout)))
+        let (tx_ids, idxs): (Vec<i64>, Vec<i32>) = utxos.iter()
+            .filter_map(|utxo| Some((txs.get(&utxo.tx_hash)?.id, utxo.vout)))
+            .unzip();
+        if tx_ids.len() == 0 { return Ok(()) }
+        for table in &["utxo_address", "utxo_trade_offer", "utxo_slp"] {
+            diesel::sql_query(format!("\
+                DELETE FROM {}
+                WHERE (tx, idx) IN (SELECT * FROM UNNEST($1::BIGINT[], $2::INT[]))
+            ", table))
+                .bind::<Array<BigInt>, _>(tx_ids.clone())
+                .bind::<Array<Integer>, _>(idxs.clone())
                 .execute(&self.connection)?;
         }
         Ok(())
     }
 
-    pub fn add_utxos(&self, utxos: &[NewUtxo]) -> QueryResult<()> {
+    fn _add_utxos(&self, utxos: &[NewUtxo]) -> QueryResult<Vec<[u8; 32]>> {
         let txs = self.txs(utxos.iter().map(|utxo| match utxo {
             NewUtxo::Address {tx_hash, ..} => tx_hash.clone(),
             NewUtxo::TradeOffer {tx_hash, ..} => tx_hash.clone(),
+            NewUtxo::Slp {tx_hash, ..} => tx_hash.clone(),
         }))?;
+        let token_hashes = utxos.iter()
+            .filter_map(|utxo| match utxo {
+                NewUtxo::Slp {token_hash, ..} => Some(token_hash.to_vec()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        let tokens: Vec<(Vec<u8>, i32)> = token::table
+            .select((token::hash, token::id))
+            .filter(token::hash.eq_any(token_hashes.iter().cloned().collect::<Vec<_>>()))
+            .load(&self.connection)?;
+        let token_ids = tokens.into_iter().collect::<HashMap<_, _>>();
+        let skipped_tokens = token_hashes.iter()
+            .filter(|token_hash| !token_ids.contains_key(*token_hash))
+            .map(|token_hash| tx_hash_from_slice(token_hash))
+            .collect::<Vec<_>>();
         diesel::insert_into(utxo_address::table)
             .values(utxos.iter()
                 .filter_map(|utxo| match utxo {
                     NewUtxo::Address {tx_hash, vout, address} => Some(models::UtxoAddress {
-                        tx: txs[tx_hash].id,
+                        tx: txs.get(tx_hash)?.id,
                         idx: *vout,
                         address: Some(address.bytes().to_vec()),
                     }),
-                    NewUtxo::TradeOffer {..} => None,
+                    _ => None,
                 })
                 .collect::<Vec<_>>()
             )
+            .on_conflict_do_nothing()
             .execute(&self.connection)?;
         diesel::insert_into(utxo_trade_offer::table)
             .values(utxos.iter()
                 .filter_map(|utxo| match utxo {
-                    NewUtxo::Address {..} => None,
                     NewUtxo::TradeOffer {tx_hash, vout} => Some(models::Utxo {
-                        tx: txs[tx_hash].id,
+                        tx: txs.get(tx_hash)?.id,
                         idx: *vout,
                     }),
+                    _ => None,
                 })
                 .collect::<Vec<_>>()
             )
+            .on_conflict_do_nothing()
             .execute(&self.connection)?;
-        Ok(())
+        diesel::insert_into(utxo_slp::table)
+            .values(utxos.iter()
+                .filter_map(|utxo| match utxo {
+                    NewUtxo::Slp {tx_hash, vout, address, token_hash} => Some(models::UtxoSlp {
+                        tx: txs.get(tx_hash)?.id,
+                        idx: *vout,
+                        address: address.as_ref().map(|address| address.bytes().to_vec()),
+                        token: *token_ids.get(token_hash.as_ref())?,
+                    }),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+            )
+            .on_conflict_do_nothing()
+            .execute(&self.connection)?;
+        Ok(skipped_tokens)
     }
 }
@@ -1,6 +1,7 @@
 pub struct Endpoint {
     pub bitdb_endpoint_url: String,
     pub slpdb_endpoint_url: String,
+    pub rest_endpoint_url: String,
 }
 
 impl Endpoint {
@@ -8,6 +9,7 @@ impl Endpoint {
         Endpoint {
             bitdb_endpoint_url: "https://bitdb.bch.sx/q/".to_string(),
             slpdb_endpoint_url: "https://slpdb.fountainhead.cash/q/".to_string(),
+            rest_endpoint_url: "https://rest.bitcoin.com/v2/".to_string(),
         }
     }
 }
@@ -14,6 +14,13 @@ mod tx_history;
 mod update_history;
 mod convert;
 mod data;
+mod tx_builder;
+mod quote;
+mod trade_offer_parser;
+mod protocol;
+mod reindex;
+mod resync_job;
+pub mod resync;
 
 pub use db::*;
 pub use endpoint::*;
@@ -23,6 +30,12 @@ pub use tx_source::*;
 pub use tx_history::*;
 pub use update_history::*;
 pub use data::*;
+pub use tx_builder::*;
+pub use quote::*;
+pub use trade_offer_parser::*;
+pub use protocol::*;
+pub use reindex::*;
+pub use resync_job::*;
 
 //use slpdexdb_base::Result;
 
@@ -1,7 +1,7 @@
 use crate::schema::*;
 use slpdexdb_base::BlockHeader;
 use diesel::data_types::PgNumeric;
-use diesel::sql_types::{Binary, BigInt, Numeric, Integer, Nullable};
+use diesel::sql_types::{Binary, BigInt, Numeric, Integer, SmallInt, Bool, Text, Nullable};
 
 
 #[derive(Queryable)]
@@ -103,7 +103,7 @@ pub struct TxInput {
 
 #[derive(Queryable)]
 pub struct TradeOffer {
-    pub id:                     i64, // SERIAL PRIMARY KEY,
+    pub id:                     i32, // SERIAL PRIMARY KEY,
     pub tx:                     i64, // BIGINT REFERENCES tx (id) ON DELETE CASCADE,
     pub output_idx:             Option<i32>, // INT NOT NULL,
     pub input_tx:               Vec<u8>, // BYTEA NOT NULL,
@@ -113,7 +113,11 @@ pub struct TradeOffer {
     pub is_inverted:            bool, // BOOL NOT NULL,
     pub sell_amount_token_base: PgNumeric, // NUMERIC(26) NOT NULL,
     pub receiving_address:      Vec<u8>, // BYTEA NOT NULL,
-    pub spent:                  bool, // BOOL NOT NULL
+    pub power:                  i16, // SMALLINT NOT NULL
+    pub state:                  i16, // SMALLINT NOT NULL
+    pub spent_tx:               Option<Vec<u8>>, // BYTEA
+    pub fee_profile:            Option<String>, // VARCHAR(64)
+    pub contract_version:       i16, // SMALLINT NOT NULL
 }
 
 #[derive(Insertable)]
@@ -127,7 +131,10 @@ pub struct NewTradeOffer {
     pub script_price:           i64, // BIGINT NOT NULL,
     pub is_inverted:            bool, // BOOL NOT NULL,
     pub sell_amount_token_base: PgNumeric, // NUMERIC(26) NOT NULL,
-    pub receiving_address:      Vec<u8>, // BYTEA NOT NULL
+    pub receiving_address:      Vec<u8>, // BYTEA NOT NULL,
+    pub power:                  i16, // SMALLINT NOT NULL
+    pub fee_profile:            Option<String>, // VARCHAR(64)
+    pub contract_version:       i16, // SMALLINT NOT NULL
 }
 
 #[derive(Queryable)]
@@ -162,6 +169,30 @@ pub struct ActiveAddress {
     pub address: Vec<u8>,
 }
 
+#[derive(Queryable)]
+pub struct HdAccount {
+    pub id: i32,
+    pub xpub: String,
+    pub gap_limit: i32,
+}
+
+#[derive(Insertable)]
+#[table_name="hd_account"]
+pub struct NewHdAccount {
+    pub xpub: String,
+    pub gap_limit: i32,
+}
+
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="hd_account_address"]
+pub struct HdAccountAddress {
+    pub account: i32,
+    pub is_change: bool,
+    pub idx: i32,
+    pub address: Vec<u8>,
+}
+
 #[derive(Queryable)]
 #[derive(Insertable)]
 #[table_name="utxo_address"]
@@ -171,6 +202,58 @@ pub struct UtxoAddress {
     pub address: Option<Vec<u8>>,
 }
 
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="utxo_slp"]
+pub struct UtxoSlp {
+    pub tx: i64,
+    pub idx: i32,
+    pub address: Option<Vec<u8>>,
+    pub token: i32,
+}
+
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="raw_tx"]
+pub struct RawTx {
+    pub hash: Vec<u8>, // BYTEA PRIMARY KEY,
+    pub raw:  Vec<u8>, // BYTEA NOT NULL
+}
+
+#[derive(Queryable)]
+pub struct TradeOfferStateHistory {
+    pub id:          i64, // BIGSERIAL PRIMARY KEY,
+    pub trade_offer: i32, // INT NOT NULL REFERENCES trade_offer ("id") ON DELETE CASCADE,
+    pub state:       i16, // SMALLINT NOT NULL,
+    pub tx:          Option<Vec<u8>>, // BYTEA,
+    pub timestamp:   chrono::DateTime<chrono::Utc>, // TIMESTAMP WITH TIME ZONE NOT NULL
+}
+
+#[derive(Insertable)]
+#[table_name="trade_offer_state_history"]
+pub struct NewTradeOfferStateHistory {
+    pub trade_offer: i32,
+    pub state:       i16,
+    pub tx:          Option<Vec<u8>>,
+}
+
+#[derive(Queryable)]
+pub struct OutboxEvent {
+    pub seq: i64,
+    pub subject: String,
+    pub event_type: String,
+    pub payload: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name="event_outbox"]
+pub struct NewOutboxEvent {
+    pub subject: String,
+    pub event_type: String,
+    pub payload: String,
+}
+
 #[derive(Queryable)]
 #[derive(Insertable)]
 #[table_name="utxo_trade_offer"]
@@ -181,40 +264,169 @@ pub struct Utxo {
 
 #[derive(Debug)]
 #[derive(QueryableByName)]
-pub struct TxDeltaInput {
+pub struct TxDeltaRow {
     #[sql_type="BigInt"]
     pub tx_id: i64,
     #[sql_type="Binary"]
     pub tx_hash: Vec<u8>,
+    #[sql_type="Nullable<Integer>"]
+    pub height: Option<i32>,
+    #[sql_type="Integer"]
+    pub sort_height: i32,
     #[sql_type="BigInt"]
     pub timestamp: i64,
-    #[sql_type="Nullable<Numeric>"]
-    pub input_value_satoshis: Option<PgNumeric>,
-    #[sql_type="Nullable<Numeric>"]
-    pub input_value_token_base: Option<PgNumeric>,
     #[sql_type="Nullable<Binary>"]
     pub token_hash: Option<Vec<u8>>,
     #[sql_type="Nullable<Integer>"]
     pub decimals: Option<i32>,
+    #[sql_type="BigInt"]
+    pub delta_satoshis: i64,
+    #[sql_type="Numeric"]
+    pub delta_token_base: PgNumeric,
+    #[sql_type="BigInt"]
+    pub running_satoshis: i64,
+    #[sql_type="Numeric"]
+    pub running_token_base: PgNumeric,
 }
 
 #[derive(Debug)]
 #[derive(QueryableByName)]
-pub struct TxDeltaOutput {
-    #[sql_type="BigInt"]
-    pub tx_id: i64,
+pub struct TradeHistoryRow {
+    #[sql_type="Integer"]
+    pub offer_id: i32,
+    #[sql_type="Bool"]
+    pub is_maker: bool,
     #[sql_type="Binary"]
     pub tx_hash: Vec<u8>,
+    #[sql_type="Nullable<Integer>"]
+    pub output_idx: Option<i32>,
+    #[sql_type="Binary"]
+    pub input_tx: Vec<u8>,
+    #[sql_type="Integer"]
+    pub input_idx: i32,
+    #[sql_type="Numeric"]
+    pub price_per_token: PgNumeric,
     #[sql_type="BigInt"]
-    pub timestamp: i64,
-    #[sql_type="Nullable<Numeric>"]
-    pub output_value_satoshis: Option<PgNumeric>,
-    #[sql_type="Nullable<Numeric>"]
-    pub output_value_token_base: Option<PgNumeric>,
+    pub script_price: i64,
+    #[sql_type="Bool"]
+    pub is_inverted: bool,
+    #[sql_type="Numeric"]
+    pub sell_amount_token_base: PgNumeric,
+    #[sql_type="Binary"]
+    pub receiving_address: Vec<u8>,
+    #[sql_type="SmallInt"]
+    pub power: i16,
+    #[sql_type="Nullable<Text>"]
+    pub fee_profile: Option<String>,
+    #[sql_type="SmallInt"]
+    pub contract_version: i16,
+    #[sql_type="SmallInt"]
+    pub state: i16,
     #[sql_type="Nullable<Binary>"]
-    pub token_hash: Option<Vec<u8>>,
-    #[sql_type="Nullable<Integer>"]
-    pub decimals: Option<i32>,
+    pub spent_tx: Option<Vec<u8>>,
+    #[sql_type="Binary"]
+    pub token_hash: Vec<u8>,
+    #[sql_type="Integer"]
+    pub decimals: i32,
+    #[sql_type="BigInt"]
+    pub created_timestamp: i64,
+    #[sql_type="Nullable<BigInt>"]
+    pub spent_timestamp: Option<i64>,
+    #[sql_type="BigInt"]
+    pub sort_timestamp: i64,
+}
+
+#[derive(Debug)]
+#[derive(QueryableByName)]
+pub struct TradeFeeRow {
+    #[sql_type="Binary"]
+    pub token_hash: Vec<u8>,
+    #[sql_type="Nullable<Text>"]
+    pub symbol: Option<String>,
+    #[sql_type="Text"]
+    pub day: String,
+    #[sql_type="Binary"]
+    pub fee_address: Vec<u8>,
+    #[sql_type="BigInt"]
+    pub trade_count: i64,
+    #[sql_type="BigInt"]
+    pub fee_satoshis: i64,
+}
+
+#[derive(Debug)]
+#[derive(QueryableByName)]
+pub struct TokenHolderRow {
+    #[sql_type="Nullable<Binary>"]
+    pub address: Option<Vec<u8>>,
+    #[sql_type="Integer"]
+    pub output_type: i32,
+    #[sql_type="Numeric"]
+    pub amount: PgNumeric,
+}
+
+#[derive(Debug)]
+#[derive(QueryableByName)]
+pub struct UpdateRegressionRow {
+    #[sql_type="BigInt"]
+    pub id: i64,
+    #[sql_type="Integer"]
+    pub subject_type: i32,
+    #[sql_type="Nullable<Binary>"]
+    pub subject_hash: Option<Vec<u8>>,
+    #[sql_type="Bool"]
+    pub is_confirmed: bool,
+    #[sql_type="Integer"]
+    pub last_height: i32,
+    #[sql_type="Integer"]
+    pub previous_height: i32,
+}
+
+#[derive(Debug)]
+#[derive(QueryableByName)]
+pub struct BlockGapRow {
+    #[sql_type="Integer"]
+    pub from_height: i32,
+    #[sql_type="Integer"]
+    pub to_height: i32,
+}
+
+#[derive(Debug)]
+#[derive(QueryableByName)]
+pub struct TxIdRow {
+    #[sql_type="BigInt"]
+    pub tx: i64,
+}
+
+#[derive(Debug)]
+#[derive(QueryableByName)]
+pub struct ResyncJobRow {
+    #[sql_type="Integer"]
+    pub id: i32,
+    #[sql_type="Integer"]
+    pub job_type: i32,
+    #[sql_type="Nullable<Binary>"]
+    pub subject: Option<Vec<u8>>,
+    #[sql_type="Integer"]
+    pub priority: i32,
+    #[sql_type="Integer"]
+    pub state: i32,
+    #[sql_type="Integer"]
+    pub attempts: i32,
+    #[sql_type="BigInt"]
+    pub run_after: i64,
+    #[sql_type="Nullable<Text>"]
+    pub last_error: Option<String>,
+    #[sql_type="BigInt"]
+    pub created: i64,
+}
+
+#[derive(Debug)]
+#[derive(QueryableByName)]
+pub struct ResyncJobCountRow {
+    #[sql_type="Integer"]
+    pub state: i32,
+    #[sql_type="BigInt"]
+    pub count: i64,
 }
 
 impl Block {
@@ -0,0 +1,317 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use diesel::QueryResult;
+use slpdexdb_base::{SLPDEXConfig, SLPAmount, Result, ErrorKind, TokenError};
+use crate::db::Db;
+use crate::token::Token;
+use crate::tx_history::{HistoricTx, HistoricTxOutput, OutputType, TxType, SLPTxType, TxHistory,
+                        TradeOffer};
+use crate::tx_source::tx_result;
+use cashcontracts::tx_hex_to_hash;
+
+/// What a protocol parser found in a tx, stored by its parser.
+pub trait ProtocolRecord: Any + Debug + Send + Sync {
+    fn lokad_id(&self) -> &[u8];
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// A tx as it's being parsed. Parsers run in registration order and add their records;
+/// later parsers see the records of earlier ones.
+pub struct ParsedTx {
+    pub historic_tx: HistoricTx,
+    pub records: Vec<Arc<dyn ProtocolRecord>>,
+}
+
+/// The token of an SLP tx; `SLPParser` also fills in the tx type and token amounts of
+/// `ParsedTx::historic_tx`.
+#[derive(Debug)]
+pub struct SLPRecord {
+    pub token: Option<Token>,  // only known when parsing raw txs
+}
+
+/// The trade offer an EXCH tx advertises; `Db::add_tx_history` stores it.
+#[derive(Debug)]
+pub struct ExchRecord(pub TradeOffer);
+
+impl ProtocolRecord for SLPRecord {
+    fn lokad_id(&self) -> &[u8] { b"SLP\0" }
+
+    fn as_any(&self) -> &dyn Any { self }
+}
+
+impl ProtocolRecord for ExchRecord {
+    fn lokad_id(&self) -> &[u8] { b"EXCH" }
+
+    fn as_any(&self) -> &dyn Any { self }
+}
+
+/// Parses the txs of one protocol, identified by its lokad id, adding `ProtocolRecord`s
+/// and storing them in its own tables.
+///
+/// A tx which isn't valid for the protocol simply gets no record; errors are for failures
+/// like an unreachable token source, and fail parsing the whole tx.
+pub trait ProtocolParser: Send + Sync {
+    fn lokad_id(&self) -> &[u8];
+
+    /// Parses a tx received from a node.
+    fn parse_tx(&self, _tx: &cashcontracts::Tx, _parsed: &mut ParsedTx,
+                _config: &SLPDEXConfig, _db: &Db) -> Result<()> {
+        Ok(())
+    }
+
+    /// Parses a tx returned by the tx source.
+    fn parse_entry(&self, _entry: &tx_result::TxEntry, _parsed: &mut ParsedTx,
+                   _config: &SLPDEXConfig) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stores this protocol's records, together with the id of their tx.
+    /// Runs in the transaction of `Db::add_tx_history`.
+    fn store(&self, _db: &Db, _records: &[(i64, &dyn ProtocolRecord)]) -> QueryResult<()> {
+        Ok(())
+    }
+}
+
+pub struct SLPParser;
+
+pub struct ExchParser;
+
+impl ProtocolParser for SLPParser {
+    fn lokad_id(&self) -> &[u8] { b"SLP\0" }
+
+    fn parse_tx(&self, tx: &cashcontracts::Tx, parsed: &mut ParsedTx,
+                _config: &SLPDEXConfig, db: &Db) -> Result<()> {
+        let slp_output = match tx.outputs().get(0) {
+            Some(output) => match TxHistory::_process_slp_output(&output.script, db) {
+                Err(ref err) if match err.kind() {
+                    ErrorKind::InvalidSLPOutput(..) => true,
+                    ErrorKind::TokenError(TokenError::UnknownTokenId(_)) => true,
+                    _ => false,
+                } => None,
+                slp_output => slp_output?,
+            },
+            None => None,
+        };
+        let (tx_type, slp_amounts, token) = match slp_output {
+            Some(slp_output) => slp_output,
+            None => return Ok(()),
+        };
+        let historic_tx = &mut parsed.historic_tx;
+        let decimals = token.decimals as u32;
+        for (output_idx, output) in historic_tx.outputs.iter_mut().enumerate() {
+            output.value_token = if output_idx > 0 {
+                slp_amounts.get(output_idx - 1).cloned()
+            } else {
+                None
+            }.unwrap_or(SLPAmount::new(0, decimals));
+        }
+        let burned = slp_amounts.iter()
+            .skip(tx.outputs().len())
+            .map(|amount| {
+                HistoricTxOutput {
+                    value_satoshis: 0,
+                    value_token: *amount,
+                    output: OutputType::Burned,
+                }
+            })
+            .collect::<Vec<_>>();
+        historic_tx.outputs.extend(burned);
+        historic_tx.tx_type = tx_type;
+        parsed.records.push(Arc::new(SLPRecord { token: Some(token) }));
+        Ok(())
+    }
+
+    fn parse_entry(&self, entry: &tx_result::TxEntry, parsed: &mut ParsedTx,
+                   _config: &SLPDEXConfig) -> Result<()> {
+        let slp = match &entry.slp {
+            Some(slp) => slp,
+            None => return Ok(()),
+        };
+        let historic_tx = &mut parsed.historic_tx;
+        let decimals = slp.detail.decimals as u32;
+        for (i, output) in historic_tx.outputs.iter_mut().enumerate().skip(1) {
+            output.value_token = slp.detail.outputs
+                .get(i - 1)
+                .and_then(|output| SLPAmount::from_str_decimals(&output.amount, decimals).ok())
+                .unwrap_or(SLPAmount::new(0, decimals));
+        }
+        let tx_type = tx_hex_to_hash(&slp.detail.token_id)
+            .and_then(|token_hash| Some(TxType::SLP {
+                token_type: slp.detail.version_type,
+                token_hash,
+                slp_type: SLPTxType::from_bytes(slp.detail.transaction_type.as_bytes())?,
+            }));
+        if let Some(tx_type) = tx_type {
+            historic_tx.tx_type = tx_type;
+            parsed.records.push(Arc::new(SLPRecord { token: None }));
+        }
+        Ok(())
+    }
+}
+
+impl ProtocolParser for ExchParser {
+    fn lokad_id(&self) -> &[u8] { b"EXCH" }
+
+    fn parse_tx(&self, tx: &cashcontracts::Tx, parsed: &mut ParsedTx,
+                config: &SLPDEXConfig, _db: &Db) -> Result<()> {
+        let token = match parsed.record::<SLPRecord>() {
+            Some(SLPRecord { token: Some(token) }) => token,
+            _ => return Ok(()),
+        };
+        if let Some(trade_offer) = TradeOffer::from_tx(&parsed.historic_tx, tx, config, token) {
+            parsed.records.push(Arc::new(ExchRecord(trade_offer)));
+        }
+        Ok(())
+    }
+
+    fn parse_entry(&self, entry: &tx_result::TxEntry, parsed: &mut ParsedTx,
+                   config: &SLPDEXConfig) -> Result<()> {
+        if let Some(slp) = &entry.slp {
+            let trade_offer = TradeOffer::from_entry(&parsed.historic_tx, entry, config,
+                                                     slp.detail.decimals as u32);
+            if let Some(trade_offer) = trade_offer {
+                parsed.records.push(Arc::new(ExchRecord(trade_offer)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Protocol parsers keyed by lokad id, run in registration order.
+#[derive(Clone)]
+pub struct ProtocolParsers {
+    parsers: HashMap<Vec<u8>, Arc<dyn ProtocolParser>>,
+    order: Vec<Vec<u8>>,
+}
+
+impl Default for ProtocolParsers {
+    fn default() -> Self {
+        let mut parsers = ProtocolParsers { parsers: HashMap::new(), order: Vec::new() };
+        parsers.register(Arc::new(SLPParser));
+        parsers.register(Arc::new(ExchParser));
+        parsers
+    }
+}
+
+impl ProtocolParsers {
+    /// Adds `parser`, replacing the parser registered for the same lokad id in its place.
+    pub fn register(&mut self, parser: Arc<dyn ProtocolParser>) {
+        let lokad_id = parser.lokad_id().to_vec();
+        if self.parsers.insert(lokad_id.clone(), parser).is_none() {
+            self.order.push(lokad_id);
+        }
+    }
+
+    pub fn get(&self, lokad_id: &[u8]) -> Option<&dyn ProtocolParser> {
+        self.parsers.get(lokad_id).map(|parser| parser.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&dyn ProtocolParser> {
+        self.order.iter().map(move |lokad_id| self.parsers[lokad_id].as_ref())
+    }
+
+    pub fn parse_tx(&self, tx: &cashcontracts::Tx, historic_tx: HistoricTx,
+                    config: &SLPDEXConfig, db: &Db) -> Result<ParsedTx> {
+        let mut parsed = ParsedTx::new(historic_tx);
+        for parser in self.iter() {
+            parser.parse_tx(tx, &mut parsed, config, db)?;
+        }
+        Ok(parsed)
+    }
+
+    pub fn parse_entry(&self, entry: &tx_result::TxEntry, historic_tx: HistoricTx,
+                       config: &SLPDEXConfig) -> Result<ParsedTx> {
+        let mut parsed = ParsedTx::new(historic_tx);
+        for parser in self.iter() {
+            parser.parse_entry(entry, &mut parsed, config)?;
+        }
+        Ok(parsed)
+    }
+}
+
+impl ParsedTx {
+    fn new(historic_tx: HistoricTx) -> Self {
+        ParsedTx { historic_tx, records: Vec::new() }
+    }
+
+    /// The first record of type `R`, e.g. one an earlier parser added.
+    pub fn record<R: ProtocolRecord>(&self) -> Option<&R> {
+        self.records.iter().find_map(|record| record.as_any().downcast_ref::<R>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestRecord(u8);
+
+    impl ProtocolRecord for TestRecord {
+        fn lokad_id(&self) -> &[u8] { b"TEST" }
+
+        fn as_any(&self) -> &dyn Any { self }
+    }
+
+    struct TestParser(u8);
+
+    impl ProtocolParser for TestParser {
+        fn lokad_id(&self) -> &[u8] { b"TEST" }
+
+        fn parse_entry(&self, _entry: &tx_result::TxEntry, parsed: &mut ParsedTx,
+                       _config: &SLPDEXConfig) -> Result<()> {
+            parsed.records.push(Arc::new(TestRecord(self.0)));
+            Ok(())
+        }
+    }
+
+    fn historic_tx() -> HistoricTx {
+        HistoricTx {
+            hash: [1u8; 32],
+            height: None,
+            timestamp: 0,
+            tx_type: TxType::Default,
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+
+    fn entry() -> tx_result::TxEntry {
+        serde_json::from_str(&format!(
+            r#"{{"blk": null, "tx": {{"h": "{}"}}, "in": [], "out": [], "slp": null}}"#,
+            hex::encode(&[1u8; 32]),
+        )).unwrap()
+    }
+
+    #[test]
+    fn register_parser() {
+        let mut parsers = ProtocolParsers::default();
+        parsers.register(Arc::new(TestParser(1)));
+        let lokad_ids = parsers.iter().map(|parser| parser.lokad_id()).collect::<Vec<_>>();
+        assert_eq!(lokad_ids, [&b"SLP\0"[..], &b"EXCH"[..], &b"TEST"[..]]);
+        assert!(parsers.get(b"TEST").is_some());
+        assert!(parsers.get(b"NONE").is_none());
+
+        let parsed = parsers.parse_entry(&entry(), historic_tx(),
+                                         &SLPDEXConfig::default()).unwrap();
+        assert_eq!(parsed.records.len(), 1);
+        assert_eq!(parsed.record::<TestRecord>().unwrap().0, 1);
+        assert!(parsed.record::<SLPRecord>().is_none());
+        assert!(parsed.record::<ExchRecord>().is_none());
+    }
+
+    #[test]
+    fn register_parser_replaces_same_lokad_id() {
+        let mut parsers = ProtocolParsers::default();
+        parsers.register(Arc::new(TestParser(1)));
+        parsers.register(Arc::new(TestParser(2)));
+        assert_eq!(parsers.iter().count(), 3);
+        let parsed = parsers.parse_entry(&entry(), historic_tx(),
+                                         &SLPDEXConfig::default()).unwrap();
+        assert_eq!(parsed.records.len(), 1);
+        assert_eq!(parsed.record::<TestRecord>().unwrap().0, 2);
+    }
+}
@@ -0,0 +1,220 @@
+use rug::{Rational, Integer, ops::Pow};
+use slpdexdb_base::{SLPDEXConfig, SLPAmount};
+use crate::token::Token;
+use crate::tx_history::TradeOffer;
+use crate::tx_builder::offer_payment_and_fee;
+
+#[derive(Clone, Copy, Debug)]
+pub enum QuoteTarget {
+    BuyTokens(SLPAmount),
+    SpendSatoshis(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct QuotedOffer {
+    pub trade_offer: TradeOffer,
+    pub payment_satoshis: u64,
+    pub fee_satoshis: u64,
+    pub effective_price: Rational,  // satoshis per token, including fee and dust
+}
+
+#[derive(Clone, Debug)]
+pub struct Quote {
+    pub target: QuoteTarget,
+    pub offers: Vec<QuotedOffer>,
+    pub token_amount: SLPAmount,
+    pub total_satoshis: u64,  // payments, fees and the taker's token output; excludes miner fee
+    pub fee_satoshis: u64,
+    pub average_price: Option<Rational>,
+    pub worst_price: Option<Rational>,
+    pub leftover_token: SLPAmount,  // tokens of a BuyTokens target that couldn't be bought
+    pub leftover_satoshis: u64,  // budget of a SpendSatoshis target that couldn't be spent
+}
+
+impl QuotedOffer {
+    fn new(trade_offer: TradeOffer, token: &Token, config: &SLPDEXConfig) -> Option<Self> {
+        if trade_offer.sell_amount_token.base_amount() <= 0 {
+            return None;
+        }
+        let (payment_satoshis, fee_satoshis) = offer_payment_and_fee(&trade_offer, config).ok()?;
+        let effective_price = price_per_token(payment_satoshis + fee_satoshis,
+                                              trade_offer.sell_amount_token,
+                                              token);
+        Some(QuotedOffer { trade_offer, payment_satoshis, fee_satoshis, effective_price })
+    }
+
+    pub fn cost_satoshis(&self) -> u64 {
+        self.payment_satoshis + self.fee_satoshis
+    }
+}
+
+fn price_per_token(satoshis: u64, amount: SLPAmount, token: &Token) -> Rational {
+    let factor = Integer::from(10u8).pow(token.decimals as u32);
+    Rational::from((Integer::from(satoshis) * factor, Integer::from(amount.base_amount() as u64)))
+}
+
+impl Quote {
+    /// Walks the offers from the cheapest effective price up, skipping offers which would
+    /// overshoot the target, since offers can only be taken as a whole.
+    pub fn plan(offers: Vec<TradeOffer>, token: &Token, target: QuoteTarget,
+                config: &SLPDEXConfig) -> Self {
+        let decimals = token.decimals as u32;
+        let mut candidates = offers.into_iter()
+            .filter_map(|offer| QuotedOffer::new(offer, token, config))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| a.effective_price.cmp(&b.effective_price));
+        let mut quote = Quote {
+            target,
+            offers: Vec::new(),
+            token_amount: SLPAmount::new(0, decimals),
+            total_satoshis: 0,
+            fee_satoshis: 0,
+            average_price: None,
+            worst_price: None,
+            leftover_token: SLPAmount::new(0, decimals),
+            leftover_satoshis: 0,
+        };
+        // the taker's token output is paid once, as soon as any offer is taken
+        let output_dust = config.dust_limit;
+        for candidate in candidates {
+            let dust = if quote.offers.is_empty() { output_dust } else { 0 };
+            let fits = match target {
+                QuoteTarget::BuyTokens(amount) =>
+                    quote.token_amount + candidate.trade_offer.sell_amount_token <= amount,
+                QuoteTarget::SpendSatoshis(budget) =>
+                    quote.total_satoshis + candidate.cost_satoshis() + dust <= budget,
+            };
+            if !fits { continue }
+            quote.token_amount += candidate.trade_offer.sell_amount_token;
+            quote.total_satoshis += candidate.cost_satoshis() + dust;
+            quote.fee_satoshis += candidate.fee_satoshis;
+            quote.worst_price = Some(candidate.effective_price.clone());
+            quote.offers.push(candidate);
+            let is_filled = match target {
+                QuoteTarget::BuyTokens(amount) => quote.token_amount == amount,
+                QuoteTarget::SpendSatoshis(_) => false,
+            };
+            if is_filled { break }
+        }
+        if quote.token_amount.base_amount() > 0 {
+            quote.average_price = Some(price_per_token(quote.total_satoshis, quote.token_amount, token));
+        }
+        match target {
+            QuoteTarget::BuyTokens(amount) => quote.leftover_token = amount - quote.token_amount,
+            QuoteTarget::SpendSatoshis(budget) => quote.leftover_satoshis = budget - quote.total_satoshis,
+        }
+        quote
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cashcontracts::{Address, AddressType};
+
+    fn token() -> Token {
+        Token {
+            hash: [1; 32],
+            decimals: 0,
+            timestamp: 0,
+            version_type: 1,
+            document_uri: None,
+            symbol: None,
+            name: None,
+            document_hash: None,
+            initial_supply: SLPAmount::new(1_000_000, 0),
+            current_supply: SLPAmount::new(1_000_000, 0),
+            block_created_height: 0,
+        }
+    }
+
+    fn offer(id: u8, amount: i128, script_price: i64, is_inverted: bool, contract_version: i32) -> TradeOffer {
+        TradeOffer {
+            tx: [id; 32],
+            output_idx: Some(1),
+            input_tx: [0; 32],
+            input_idx: 0,
+            price_per_token: Rational::from(script_price),
+            script_price,
+            is_inverted,
+            sell_amount_token: SLPAmount::new(amount, 0),
+            receiving_address: Address::from_bytes(AddressType::P2PKH, [id; 20]),
+            power: 0,
+            fee_profile: None,
+            contract_version,
+        }
+    }
+
+    fn offers() -> Vec<TradeOffer> {
+        vec![
+            offer(1, 10, 1000, false, 1),
+            offer(2, 5, 1200, false, 1),
+            offer(3, 20, 1100, false, 1),
+        ]
+    }
+
+    fn taken(quote: &Quote) -> Vec<u8> {
+        quote.offers.iter().map(|offer| offer.trade_offer.tx[0]).collect()
+    }
+
+    #[test]
+    fn buy_tokens_skips_offers_overshooting_the_target() {
+        let config = SLPDEXConfig::default();
+        let quote = Quote::plan(offers(), &token(), QuoteTarget::BuyTokens(SLPAmount::new(15, 0)), &config);
+        assert_eq!(taken(&quote), vec![1, 2]);
+        assert_eq!(quote.token_amount, SLPAmount::new(15, 0));
+        assert_eq!(quote.total_satoshis, 10_000 + 6_000 + config.dust_limit);
+        assert_eq!(quote.fee_satoshis, 0);
+        assert_eq!(quote.worst_price, Some(Rational::from(1200)));
+        assert_eq!(quote.average_price, Some(Rational::from((16_546, 15))));
+        assert_eq!(quote.leftover_token, SLPAmount::new(0, 0));
+    }
+
+    #[test]
+    fn buy_tokens_with_insufficient_liquidity() {
+        let config = SLPDEXConfig::default();
+        let quote = Quote::plan(offers(), &token(), QuoteTarget::BuyTokens(SLPAmount::new(100, 0)), &config);
+        assert_eq!(taken(&quote), vec![1, 3, 2]);
+        assert_eq!(quote.token_amount, SLPAmount::new(35, 0));
+        assert_eq!(quote.total_satoshis, 38_000 + config.dust_limit);
+        assert_eq!(quote.leftover_token, SLPAmount::new(65, 0));
+
+        let quote = Quote::plan(vec![], &token(), QuoteTarget::BuyTokens(SLPAmount::new(100, 0)), &config);
+        assert!(quote.offers.is_empty());
+        assert_eq!(quote.total_satoshis, 0);
+        assert_eq!(quote.average_price, None);
+        assert_eq!(quote.leftover_token, SLPAmount::new(100, 0));
+    }
+
+    #[test]
+    fn spend_satoshis_stays_within_budget() {
+        let config = SLPDEXConfig::default();
+        let quote = Quote::plan(offers(), &token(), QuoteTarget::SpendSatoshis(20_000), &config);
+        assert_eq!(taken(&quote), vec![1, 2]);
+        assert_eq!(quote.total_satoshis, 16_546);
+        assert_eq!(quote.leftover_satoshis, 3_454);
+
+        let quote = Quote::plan(offers(), &token(), QuoteTarget::SpendSatoshis(10_000), &config);
+        assert_eq!(taken(&quote), vec![2]);
+        assert_eq!(quote.total_satoshis, 6_546);
+        assert_eq!(quote.leftover_satoshis, 3_454);
+    }
+
+    #[test]
+    fn payments_round_up_and_respect_dust_and_fees() {
+        let config = SLPDEXConfig::default();
+        let offers = vec![
+            offer(1, 100_001, 100, true, 1),  // 1000.01 satoshis, rounded up
+            offer(2, 1_000, 100, true, 1),  // 10 satoshis, raised to dust
+            offer(3, 10, 1000, false, 2),  // fee of 20 satoshis, raised to dust
+            offer(4, 0, 1000, false, 1),  // empty offers are never taken
+        ];
+        let quote = Quote::plan(offers, &token(), QuoteTarget::BuyTokens(SLPAmount::new(200_000, 0)), &config);
+        let costs = quote.offers.iter()
+            .map(|offer| (offer.trade_offer.tx[0], offer.payment_satoshis, offer.fee_satoshis))
+            .collect::<Vec<_>>();
+        assert_eq!(costs, vec![(1, 1001, 0), (2, 546, 0), (3, 10_000, 546)]);
+        assert_eq!(quote.fee_satoshis, 546);
+        assert_eq!(quote.total_satoshis, 1001 + 546 + 10_546 + config.dust_limit);
+    }
+}
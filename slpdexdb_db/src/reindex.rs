@@ -0,0 +1,68 @@
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use diesel::Connection;
+use slpdexdb_base::{SLPDEXConfig, Result, Error};
+use cashcontracts::{Tx, tx_hash_to_hex};
+use crate::db::Db;
+use crate::tx_history::{TxHistory, TxType};
+use crate::data::tx_hash_from_slice;
+use crate::resync::add_tx_history;
+
+/// Rebuilds the tx index from the stored raw txs, without network access.
+///
+/// Everything derived from the txs with a stored raw tx is deleted and re-derived by parsing
+/// the raw txs in the order their tx rows were added; txs without one keep their index. Then
+/// the UTXO tables of active addresses, trade offers and the tokens which had SLP UTXOs are
+/// rebuilt. As SLP validation needs the tx source, a tx stays SLP only if its stored tx row
+/// was SLP. Returns the number of reindexed txs.
+pub fn reindex_raw_txs(db: &Db, config: &SLPDEXConfig, batch_size: i64) -> Result<usize> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    db.connection().transaction::<_, Error, _>(|| {
+        let slp_token_hashes = db.utxo_slp_token_hashes()?;
+        db.clear_tx_index()?;
+        let mut last_tx_id = 0;
+        let mut num_reindexed = 0;
+        loop {
+            let rows = db.raw_txs(last_tx_id, batch_size)?;
+            last_tx_id = match rows.last() {
+                Some((tx_row, _)) => tx_row.id,
+                None => break,
+            };
+            let (tx_rows, txs): (Vec<_>, Vec<_>) = rows.into_iter()
+                .filter_map(|(tx_row, raw)| {
+                    let mut cursor = io::Cursor::new(&raw);
+                    match Tx::read_from_stream(&mut cursor) {
+                        Ok(tx) => Some((tx_row, tx)),
+                        Err(err) => {
+                            eprintln!("can't parse raw tx {}: {}",
+                                      tx_hash_to_hex(&tx_hash_from_slice(&tx_row.hash)), err);
+                            None
+                        },
+                    }
+                })
+                .unzip();
+            let mut history = TxHistory::from_txs(&txs, 0, config, db)?;
+            for (tx_idx, tx_row) in tx_rows.iter().enumerate() {
+                let historic_tx = &mut history.txs[tx_idx];
+                historic_tx.height = tx_row.height;
+                historic_tx.timestamp = tx_row.timestamp;
+                let was_slp = tx_row.tx_type != TxType::Default.id();
+                if historic_tx.tx_type.token_hash().is_some() && !was_slp {
+                    history.invalidate_slp(tx_idx);
+                }
+            }
+            add_tx_history(db, &history, now)?;
+            num_reindexed += history.txs.len();
+            eprintln!("reindexed {} txs", num_reindexed);
+        }
+        for address in db.active_addresses()? {
+            db.update_utxo_set(&address)?;
+        }
+        db.update_utxo_set_exch()?;
+        for token_hash in slp_token_hashes {
+            db.update_utxo_set_slp(&token_hash)?;
+        }
+        db.update_trade_fees(config)?;
+        Ok(num_reindexed)
+    })
+}
@@ -0,0 +1,325 @@
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+use diesel::Connection;
+use cashcontracts::{Address, tx_hash_to_hex};
+use slpdexdb_base::{Result, Error, SLPDEXConfig, ExtendedPubKey, RECEIVE_CHAIN, CHANGE_CHAIN};
+use crate::db::Db;
+use crate::tx_source::{TxSource, TxFilter, Confirmedness};
+use crate::token_source::TokenSource;
+use crate::token::Token;
+use crate::tx_history::TxHistory;
+use crate::update_history::{UpdateSubject, UpdateSubjectType, UpdateHistory};
+use crate::resync_job::{ResyncTask, PRIORITY_BACKFILL};
+use crate::data::{PendingTake, TradeOfferStateChange};
+
+// how long a relayed taker tx may take to show up at the tx source
+const PENDING_TAKE_GRACE_SECS: i64 = 10 * 60;
+
+/// Resyncs tokens and EXCH offers from the remote sources, then the SLP UTXOs of the tokens
+/// tracked by the SLP UTXO index.
+pub fn resync(db: &Db, config: &SLPDEXConfig) -> Result<()> {
+    resync_tokens(db)?;
+    resync_trade_offers(db, config, true)?;
+    resync_trade_offers(db, config, false)?;
+    check_utxo_set_exch(db)?;
+    db.update_trade_fees(config)?;
+    if config.slp_utxo_index {
+        for token_hash in slp_utxo_token_hashes(db, config)?.iter() {
+            resync_token_utxos(db, config, token_hash)
+                .map_err(|err| eprintln!("token utxo resync failed: {}", err))
+                .unwrap_or(());
+        }
+    }
+    Ok(())
+}
+
+/// The tokens tracked by the SLP UTXO index: the configured ones, or every known token.
+fn slp_utxo_token_hashes(db: &Db, config: &SLPDEXConfig) -> Result<Vec<[u8; 32]>> {
+    if config.slp_utxo_tokens.len() > 0 {
+        Ok(config.slp_utxo_tokens.clone())
+    } else {
+        Ok(db.token_hashes()?)
+    }
+}
+
+pub fn resync_tokens(db: &Db) -> Result<()> {
+    let token_source = TokenSource::new();
+    loop {
+        let current_height = db.header_tip()?.map(|(_, height)| height).unwrap_or(0);
+        let subject = UpdateSubject {
+            subject_type: UpdateSubjectType::Token,
+            hash: None,
+            is_confirmed: true,
+        };
+        let last_update = db.last_update(subject.clone())?
+                .unwrap_or(UpdateHistory::initial(subject));
+        let token_entries = token_source.request_tokens(&last_update.next_filters())?;
+        let tokens = token_entries.into_iter()
+            .filter_map(|token_entry| {
+                Token::from_entry(token_entry).map_err(|err| eprintln!("token error: {}", err)).ok()
+            })
+            .collect::<Vec<_>>();
+        if tokens.len() == 0 {
+            break
+        }
+        for token in tokens.iter() {
+            db.add_tokens(&[token.clone()])?;
+        }
+        db.add_update_history(&UpdateHistory::from_tokens(&tokens, current_height))?;
+    }
+    Ok(())
+}
+
+pub fn resync_trade_offers(db: &Db, config: &SLPDEXConfig, is_confirmed: bool) -> Result<()> {
+    let tx_source = TxSource::new();
+    loop {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let current_height = db.header_tip()?.map(|(_, height)| height).unwrap_or(0);
+        let confirmedness = if is_confirmed { Confirmedness::Confirmed }
+                            else { Confirmedness::Unconfirmed };
+        let subject = UpdateSubject {
+            subject_type: UpdateSubjectType::Exch,
+            hash: None,
+            is_confirmed,
+        };
+        let last_update = db.last_update(subject.clone())?
+                .unwrap_or_else(|| UpdateHistory::initial(subject.clone()));
+        let tx_entries = tx_source.request_txs(&last_update.next_filters(), config, confirmedness)?;
+        let history = TxHistory::from_entries(&tx_entries, timestamp as i64, config, db)?;
+        if history.txs.len() == 0 {
+            break
+        }
+        add_tx_history(db, &history, timestamp as i64)?;
+        store_raw_txs(db, config, &tx_source, &history);
+        db.add_update_history(
+            &UpdateHistory::from_tx_history(&history, subject, current_height)
+        )?;
+    }
+    db.update_utxo_set_exch_incremental()?;
+    Ok(())
+}
+
+/// Adds `history` together with its outbox events for the active addresses it touches.
+pub fn add_tx_history(db: &Db, history: &TxHistory, now: i64)
+        -> Result<Vec<TradeOfferStateChange>> {
+    db.connection().transaction::<_, Error, _>(|| {
+        let trade_offer_states = db.add_tx_history(history)?;
+        let relevant_addresses = db.active_addresses_among(&history.addresses())?;
+        db.add_tx_history_events(history, &trade_offer_states, &relevant_addresses, now)?;
+        Ok(trade_offer_states)
+    })
+}
+
+/// Fetches and stores the raw txs of `history` not stored yet, if enabled. Best-effort:
+/// failures are logged, as the txs are indexed already and only reindexing needs them.
+pub fn store_raw_txs(db: &Db, config: &SLPDEXConfig, tx_source: &TxSource, history: &TxHistory) {
+    if !config.store_raw_txs {
+        return;
+    }
+    let tx_hashes = history.txs.iter().map(|tx| tx.hash).collect::<Vec<_>>();
+    let result = db.missing_raw_txs(&tx_hashes)
+        .map_err(Error::from)
+        .and_then(|missing| {
+            if missing.len() > 0 {
+                db.add_raw_txs(&tx_source.request_raw_txs(&missing)?)?;
+            }
+            Ok(())
+        });
+    if let Err(err) = result {
+        eprintln!("can't store raw txs: {}", err);
+    }
+}
+
+pub fn check_utxo_set_exch(db: &Db) -> Result<()> {
+    let diff = db.check_utxo_set_exch()?;
+    if !diff.is_consistent() {
+        eprintln!("utxo_trade_offer inconsistent ({} missing, {} extra), rebuilding",
+                  diff.missing.len(), diff.extra.len());
+        db.update_utxo_set_exch()?;
+    }
+    Ok(())
+}
+
+/// Pending takes whose taker tx the tx source knows neither mined nor unconfirmed anymore.
+pub fn reverted_pending_takes(db: &Db, config: &SLPDEXConfig, now: i64) -> Result<Vec<PendingTake>> {
+    let pending_takes = db.pending_takes()?;
+    let due = pending_takes.iter()
+        .filter(|take| take.is_reverted(false, now, PENDING_TAKE_GRACE_SECS))
+        .collect::<Vec<_>>();
+    if due.len() == 0 { return Ok(Vec::new()) }
+    let tx_source = TxSource::new();
+    let filters = due.iter()
+        .map(|take| TxFilter::TxHash(take.spent_tx))
+        .collect::<Vec<_>>();
+    let known_txs = tx_source.request_txs(&filters, config, Confirmedness::Both)?
+        .into_iter()
+        .map(|entry| entry.tx.h)
+        .collect::<HashSet<_>>();
+    Ok(due.into_iter()
+        .filter(|take| {
+            take.is_reverted(known_txs.contains(&tx_hash_to_hex(&take.spent_tx)), now,
+                             PENDING_TAKE_GRACE_SECS)
+        })
+        .cloned()
+        .collect())
+}
+
+pub fn resync_address(db: &Db, config: &SLPDEXConfig, address: &Address, is_confirmed: bool) -> Result<()> {
+    loop {
+        let tx_source = TxSource::new();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let current_height = db.header_tip()?.map(|(_, height)| height).unwrap_or(0);
+        let confirmedness = if is_confirmed { Confirmedness::Confirmed }
+                            else { Confirmedness::Unconfirmed };
+        let subject = UpdateSubject {
+            subject_type: UpdateSubjectType::AddressHistory,
+            hash: Some(address.bytes().to_vec()),
+            is_confirmed,
+        };
+        let last_update = db.last_update(subject.clone())?
+            .unwrap_or(UpdateHistory::initial(subject.clone()));
+        let tx_entries = tx_source.request_txs(&last_update.next_filters(), config, confirmedness)?;
+        let history = TxHistory::from_entries(&tx_entries, timestamp as i64, config, db)?;
+        if history.txs.len() > 0 {
+            add_tx_history(db, &history, timestamp as i64)?;
+            store_raw_txs(db, config, &tx_source, &history);
+        }
+        db.add_update_history(
+            &UpdateHistory::from_tx_history(
+                &history,
+                subject,
+                current_height,
+            )
+        )?;
+        if history.txs.len() == 0 {
+            break
+        }
+    }
+    db.update_utxo_set(&address)?;
+    Ok(())
+}
+
+/// Activates and resyncs the addresses of an xpub account, re-deriving the address set after
+/// each pass until the resynced history reveals no further used addresses.
+pub fn resync_account(db: &Db, config: &SLPDEXConfig, xpub: &str) -> Result<Vec<Address>> {
+    let account_key = ExtendedPubKey::from_base58(xpub)?;
+    let account = db.add_hd_account(xpub, config.hd_gap_limit)?;
+    let mut resynced = HashSet::new();
+    loop {
+        let new_addresses = derive_account_addresses(db, &account_key, account, config.hd_gap_limit)?
+            .into_iter()
+            .filter(|address| !resynced.contains(address))
+            .collect::<Vec<_>>();
+        if new_addresses.is_empty() {
+            break
+        }
+        for address in new_addresses {
+            db.set_address_active(&address, true)?;
+            resync_address(db, config, &address, true)?;
+            resync_address(db, config, &address, false)?;
+            resynced.insert(address);
+        }
+    }
+    Ok(db.hd_account_addresses(account)?)
+}
+
+/// Derives addresses of both chains until `gap_limit` consecutive addresses have no history.
+fn derive_account_addresses(db: &Db, account_key: &ExtendedPubKey, account: i32, gap_limit: i32)
+        -> Result<Vec<Address>> {
+    let mut addresses = Vec::new();
+    for &chain in &[RECEIVE_CHAIN, CHANGE_CHAIN] {
+        let chain_key = account_key.derive_child(chain)?;
+        let mut n_unused = 0;
+        let mut idx = 0;
+        while n_unused < gap_limit {
+            let address = chain_key.derive_address(idx)?;
+            db.add_hd_account_address(account, chain == CHANGE_CHAIN, idx as i32, &address)?;
+            if db.is_address_used(&address)? {
+                n_unused = 0;
+            } else {
+                n_unused += 1;
+            }
+            addresses.push(address);
+            idx += 1;
+        }
+    }
+    Ok(addresses)
+}
+
+pub fn resync_token_history(db: &Db, config: &SLPDEXConfig, token_hash: &[u8; 32], is_confirmed: bool) -> Result<()> {
+    let tx_source = TxSource::new();
+    loop {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let current_height = db.header_tip()?.map(|(_, height)| height).unwrap_or(0);
+        let confirmedness = if is_confirmed { Confirmedness::Confirmed }
+                            else { Confirmedness::Unconfirmed };
+        let subject = UpdateSubject {
+            subject_type: UpdateSubjectType::TokenHistory,
+            hash: Some(token_hash.to_vec()),
+            is_confirmed,
+        };
+        let last_update = db.last_update(subject.clone())?
+            .unwrap_or_else(|| UpdateHistory::initial(subject.clone()));
+        let tx_entries = tx_source.request_txs(&last_update.next_filters(), config, confirmedness)?;
+        let history = TxHistory::from_entries(&tx_entries, timestamp as i64, config, db)?;
+        if history.txs.len() == 0 {
+            break
+        }
+        add_tx_history(db, &history, timestamp as i64)?;
+        store_raw_txs(db, config, &tx_source, &history);
+        db.add_update_history(
+            &UpdateHistory::from_tx_history(&history, subject, current_height)
+        )?;
+    }
+    Ok(())
+}
+
+pub fn resync_token_utxos(db: &Db, config: &SLPDEXConfig, token_hash: &[u8; 32]) -> Result<()> {
+    resync_token_history(db, config, token_hash, true)?;
+    resync_token_history(db, config, token_hash, false)?;
+    db.update_utxo_set_slp(token_hash)?;
+    Ok(())
+}
+
+/// Refreshes the current supply of a known token from the token source.
+pub fn resync_token_stats(db: &Db, token_hash: &[u8; 32]) -> Result<()> {
+    let token_entries = TokenSource::new().request_tokens(&[TxFilter::TokenId(*token_hash)])?;
+    for token_entry in token_entries {
+        db.update_token_supply(&Token::from_entry(token_entry)?)?;
+    }
+    Ok(())
+}
+
+/// Runs the resync of a job from the `resync_job` queue.
+pub fn run_resync_task(db: &Db, config: &SLPDEXConfig, task: &ResyncTask) -> Result<()> {
+    match task {
+        ResyncTask::Tokens => resync_tokens(db),
+        ResyncTask::Exch => {
+            resync_trade_offers(db, config, true)?;
+            resync_trade_offers(db, config, false)?;
+            check_utxo_set_exch(db)?;
+            db.update_trade_fees(config)?;
+            Ok(())
+        },
+        ResyncTask::Address(address) => {
+            resync_address(db, config, address, true)?;
+            resync_address(db, config, address, false)?;
+            Ok(())
+        },
+        ResyncTask::TokenUtxos(token_hash) => resync_token_utxos(db, config, token_hash),
+        ResyncTask::TokenStats(token_hash) => resync_token_stats(db, token_hash),
+        ResyncTask::Account(xpub) => resync_account(db, config, xpub).map(|_| ()),
+    }
+}
+
+/// Queues what `resync` does as backfill jobs, tokens first.
+pub fn enqueue_resync(db: &Db, config: &SLPDEXConfig) -> Result<()> {
+    db.enqueue_resync_job(&ResyncTask::Tokens, PRIORITY_BACKFILL, 0)?;
+    db.enqueue_resync_job(&ResyncTask::Exch, PRIORITY_BACKFILL, 0)?;
+    if config.slp_utxo_index {
+        for token_hash in slp_utxo_token_hashes(db, config)? {
+            db.enqueue_resync_job(&ResyncTask::TokenUtxos(token_hash), PRIORITY_BACKFILL, 0)?;
+        }
+    }
+    Ok(())
+}
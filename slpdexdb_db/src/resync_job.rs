@@ -0,0 +1,152 @@
+use cashcontracts::{Address, tx_hash_to_hex};
+use crate::data::{tx_hash_from_slice, address_from_output_type};
+use crate::tx_history::OutputType;
+
+/// Address and account activations requested by clients, which wait for the resync.
+pub const PRIORITY_INTERACTIVE: i32 = 100;
+/// Follow-up work of indexed txs, e.g. refreshing token stats.
+pub const PRIORITY_DEFAULT: i32 = 50;
+/// Startup resyncs and backfills of all tokens.
+pub const PRIORITY_BACKFILL: i32 = 0;
+
+#[derive(Copy, Clone, FromPrimitive, Debug, PartialEq, Eq)]
+pub enum ResyncJobType {
+    Tokens = 1,
+    Exch = 2,
+    Address = 3,
+    TokenUtxos = 4,
+    TokenStats = 5,
+    Account = 6,
+}
+
+#[derive(Copy, Clone, FromPrimitive, Debug, PartialEq, Eq)]
+pub enum ResyncJobState {
+    Pending = 1,
+    Running = 2,
+    Done = 3,
+    Failed = 4,  // gave up after the max number of attempts
+}
+
+/// What a job of the `resync_job` queue resyncs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResyncTask {
+    Tokens,  // metadata of new tokens
+    Exch,  // EXCH offers, then the offer UTXO set and trade fees
+    Address(Address),
+    TokenUtxos([u8; 32]),
+    TokenStats([u8; 32]),  // current supply
+    Account(String),  // xpub of an HD account, whose addresses are derived and resynced
+}
+
+impl ResyncTask {
+    pub fn job_type(&self) -> ResyncJobType {
+        match self {
+            ResyncTask::Tokens => ResyncJobType::Tokens,
+            ResyncTask::Exch => ResyncJobType::Exch,
+            ResyncTask::Address(_) => ResyncJobType::Address,
+            ResyncTask::TokenUtxos(_) => ResyncJobType::TokenUtxos,
+            ResyncTask::TokenStats(_) => ResyncJobType::TokenStats,
+            ResyncTask::Account(_) => ResyncJobType::Account,
+        }
+    }
+
+    /// Address subjects are the `OutputType` id of the address followed by its hash.
+    pub fn subject(&self) -> Option<Vec<u8>> {
+        match self {
+            ResyncTask::Tokens | ResyncTask::Exch => None,
+            ResyncTask::Address(address) => {
+                let mut subject = vec![OutputType::Address(address.clone()).id() as u8];
+                subject.extend_from_slice(address.bytes());
+                Some(subject)
+            },
+            ResyncTask::TokenUtxos(token_hash) |
+            ResyncTask::TokenStats(token_hash) => Some(token_hash.to_vec()),
+            ResyncTask::Account(xpub) => Some(xpub.as_bytes().to_vec()),
+        }
+    }
+
+    /// `None` for unknown job types and malformed subjects.
+    pub fn from_row(job_type: i32, subject: Option<&[u8]>) -> Option<Self> {
+        use num_traits::FromPrimitive;
+        let token_hash = || subject.filter(|subject| subject.len() == 32).map(tx_hash_from_slice);
+        Some(match ResyncJobType::from_i32(job_type)? {
+            ResyncJobType::Tokens => ResyncTask::Tokens,
+            ResyncJobType::Exch => ResyncTask::Exch,
+            ResyncJobType::Address => {
+                let subject = subject.filter(|subject| subject.len() == 21)?;
+                ResyncTask::Address(address_from_output_type(subject[0] as i32, &subject[1..])?)
+            },
+            ResyncJobType::TokenUtxos => ResyncTask::TokenUtxos(token_hash()?),
+            ResyncJobType::TokenStats => ResyncTask::TokenStats(token_hash()?),
+            ResyncJobType::Account => ResyncTask::Account(String::from_utf8(subject?.to_vec()).ok()?),
+        })
+    }
+}
+
+impl std::fmt::Display for ResyncTask {
+    fn fmt<'a>(&self, f: &mut std::fmt::Formatter<'a>) -> Result<(), std::fmt::Error> {
+        match self {
+            ResyncTask::Tokens => write!(f, "tokens"),
+            ResyncTask::Exch => write!(f, "exch"),
+            ResyncTask::Address(address) => write!(f, "address {}", address.cash_addr()),
+            ResyncTask::TokenUtxos(token_hash) => write!(f, "token {}", tx_hash_to_hex(token_hash)),
+            ResyncTask::TokenStats(token_hash) => write!(f, "token-stats {}", tx_hash_to_hex(token_hash)),
+            ResyncTask::Account(xpub) => write!(f, "account {}", xpub),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ResyncJob {
+    pub id: i32,
+    pub task: ResyncTask,
+    pub priority: i32,
+    pub state: ResyncJobState,
+    pub attempts: i32,
+    pub run_after: i64,
+    pub last_error: Option<String>,
+    pub created: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cashcontracts::AddressType;
+
+    #[test]
+    fn resync_task_row_round_trip() {
+        let tasks = vec![
+            ResyncTask::Tokens,
+            ResyncTask::Exch,
+            ResyncTask::Address(Address::from_bytes(AddressType::P2PKH, [7; 20])),
+            ResyncTask::Address(Address::from_bytes(AddressType::P2SH, [8; 20])),
+            ResyncTask::TokenUtxos([1; 32]),
+            ResyncTask::TokenStats([2; 32]),
+            ResyncTask::Account("xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWb\
+                                 WMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ".to_string()),
+        ];
+        for task in tasks {
+            let subject = task.subject();
+            let row_task = ResyncTask::from_row(task.job_type() as i32,
+                                                subject.as_ref().map(Vec::as_slice));
+            assert_eq!(row_task, Some(task));
+        }
+    }
+
+    #[test]
+    fn resync_task_unknown_row() {
+        assert_eq!(ResyncTask::from_row(99, None), None);
+        assert_eq!(ResyncTask::from_row(ResyncJobType::Address as i32, None), None);
+        assert_eq!(ResyncTask::from_row(ResyncJobType::Account as i32, Some(&[0xff, 0xfe])), None);
+    }
+
+    #[test]
+    fn resync_task_wrong_subject_length() {
+        let address = ResyncJobType::Address as i32;
+        assert_eq!(ResyncTask::from_row(address, Some(&[7; 20])), None);
+        assert_eq!(ResyncTask::from_row(address, Some(&[3; 22])), None);
+        assert_eq!(ResyncTask::from_row(address, Some(&[9; 21])), None);  // unknown address type
+        assert_eq!(ResyncTask::from_row(ResyncJobType::TokenUtxos as i32, Some(&[1; 31])), None);
+        assert_eq!(ResyncTask::from_row(ResyncJobType::TokenStats as i32, Some(&[2; 33])), None);
+    }
+}
@@ -123,12 +123,10 @@ impl TokenSource {
                 "sort" => sort,
             },
         });
-        println!("{}", query_json);
         let query_b64 = base64::encode(&query_json);
         let text = reqwest
             ::get(&format!("{}{}", self.endpoint.slpdb_endpoint_url, query_b64))?
             .text()?;
-        println!("{}", text);
         let result: token_result::TokenResult = serde_json::from_str(&text).unwrap();
         Ok(result.t)
     }
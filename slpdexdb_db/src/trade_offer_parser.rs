@@ -0,0 +1,89 @@
+use cashcontracts::{Op, OpCodeType, Address, AdvancedTradeOffer};
+use slpdexdb_base::{SLPDEXConfig, Result, ErrorKind, TradeOfferError};
+use crate::tx_history::{TradeOffer, ScriptPrice};
+
+/// Decodes the EXCH pushes of one contract version and rebuilds the contract they commit to.
+///
+/// An offer's scriptSig starts with the lokad id, followed by the version as OP_1..OP_16,
+/// then power, price and receiving address.
+pub trait TradeOfferParser: Sync {
+    fn version(&self) -> i32;
+
+    fn version_op(&self) -> Op;
+
+    /// Fee address and divisor the contract pays the DEX fee with, if it has one.
+    fn fee(&self, config: &SLPDEXConfig) -> Option<(Address, u64)>;
+
+    fn decode_price(&self, slp_decimals: i32, power_bytes: &[u8], price_bytes: &[u8])
+            -> Result<ScriptPrice> {
+        TradeOffer::decode_price(slp_decimals, power_bytes, price_bytes)
+    }
+
+    fn contract(&self,
+                value: u64,
+                price: &ScriptPrice,
+                token_hash: &[u8; 32],
+                token_type: u8,
+                sell_amount_token: u64,
+                config: &SLPDEXConfig,
+                receiving_address: &Address) -> AdvancedTradeOffer {
+        let fee = self.fee(config);
+        AdvancedTradeOffer {
+            value,
+            lokad_id: config.exch_lokad.as_bytes().to_vec(),
+            version: self.version() as u8,
+            power: price.power,
+            is_inverted: price.is_inverted,
+            token_id: token_hash.clone(),
+            token_type,
+            sell_amount_token,
+            price: price.script_price,
+            dust_amount: config.dust_limit,
+            address: receiving_address.clone(),
+            fee_address: fee.as_ref().map(|(fee_address, _)| fee_address.clone()),
+            fee_divisor: fee.as_ref().map(|(_, fee_divisor)| *fee_divisor),
+            spend_params: None,
+        }
+    }
+}
+
+/// Legacy offers, whose contracts predate the DEX fee.
+pub struct ExchV1;
+
+/// Offers whose contracts pay `fee_address` a `1/fee_divisor` share of the payment.
+pub struct ExchV2;
+
+impl TradeOfferParser for ExchV1 {
+    fn version(&self) -> i32 { 1 }
+
+    fn version_op(&self) -> Op { Op::Code(OpCodeType::Op1) }
+
+    fn fee(&self, _config: &SLPDEXConfig) -> Option<(Address, u64)> { None }
+}
+
+impl TradeOfferParser for ExchV2 {
+    fn version(&self) -> i32 { 2 }
+
+    fn version_op(&self) -> Op { Op::Code(OpCodeType::Op2) }
+
+    fn fee(&self, config: &SLPDEXConfig) -> Option<(Address, u64)> {
+        Some((config.fee_address.clone(), config.fee_divisor))
+    }
+}
+
+static TRADE_OFFER_PARSERS: &[&dyn TradeOfferParser] = &[
+    &ExchV1,
+    &ExchV2,
+];
+
+/// All recognized contract versions, oldest first.
+pub fn trade_offer_parsers() -> &'static [&'static dyn TradeOfferParser] {
+    TRADE_OFFER_PARSERS
+}
+
+pub fn trade_offer_parser(version: i32) -> Result<&'static dyn TradeOfferParser> {
+    TRADE_OFFER_PARSERS.iter()
+        .cloned()
+        .find(|parser| parser.version() == version)
+        .ok_or_else(|| ErrorKind::InvalidTradeOffer(TradeOfferError::UnknownVersion(version)).into())
+}
@@ -0,0 +1,580 @@
+use byteorder::{LittleEndian, BigEndian, WriteBytesExt};
+use cashcontracts::{Address, AddressType, double_sha256};
+use slpdexdb_base::{SLPDEXConfig, SLPAmount, Result, ErrorKind, TxBuildError};
+use rug::Rational;
+use crate::data::{Utxo, TradeOfferUtxo};
+use crate::token::Token;
+use crate::tx_history::{TradeOffer, ScriptPrice};
+use crate::trade_offer_parser::trade_offer_parser;
+
+pub const SIGHASH_ALL_FORKID: u32 = 0x41;
+const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+// signature (with sighash byte) and compressed pubkey, including their push ops
+const P2PKH_SIG_SIZE: usize = 1 + 72 + 1 + 33;
+// covenant contracts additionally push the sighash preimage next to the redeem script
+const CONTRACT_PREIMAGE_SIZE: usize = 3 + 400;
+
+#[derive(Clone, Debug)]
+pub struct UnsignedInput {
+    pub tx_hash: [u8; 32],
+    pub vout: u32,
+    pub value_satoshis: u64,
+    pub address: Address,
+    pub script_code: Vec<u8>,  // P2PKH script, or the redeem script of a P2SH input
+    pub script_sig_prefix: Vec<u8>,  // pushes preceding signature and pubkey
+    pub estimated_unlock_size: usize,  // bytes the signer appends to the scriptSig
+    pub sequence: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct UnsignedOutput {
+    pub value_satoshis: u64,
+    pub script: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UnsignedTx {
+    pub version: i32,
+    pub inputs: Vec<UnsignedInput>,
+    pub outputs: Vec<UnsignedOutput>,
+    pub lock_time: u32,
+}
+
+/// What a wallet needs to sign one input with SIGHASH_ALL | SIGHASH_FORKID.
+#[derive(Clone, Debug)]
+pub struct SigningData {
+    pub input_idx: usize,
+    pub preimage: Vec<u8>,
+    pub sighash: [u8; 32],
+    pub sighash_type: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct BuiltTx {
+    pub tx: UnsignedTx,
+    pub fee: u64,
+    pub signing_data: Vec<SigningData>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BuiltTradeOffer {
+    pub built_tx: BuiltTx,
+    pub price: ScriptPrice,
+    pub contract_address: Address,
+}
+
+/// A funding coin; only P2PKH utxos can be spent by the wallet.
+#[derive(Clone, Debug)]
+pub struct FundingUtxo {
+    pub utxo: Utxo,
+    pub address: Address,
+}
+
+#[derive(Clone, Debug)]
+pub struct BuiltAcceptOffers {
+    pub built_tx: BuiltTx,
+    pub token_amount: SLPAmount,
+    pub payments: Vec<OfferPayment>,
+}
+
+/// What the taker pays for one offer.
+#[derive(Clone, Debug)]
+pub struct OfferPayment {
+    pub offer_tx: [u8; 32],
+    pub offer_vout: i32,
+    pub payment_satoshis: u64,
+    pub fee_satoshis: u64,
+}
+
+pub struct AcceptOffersParams {
+    pub token: Token,
+    pub offers: Vec<TradeOfferUtxo>,
+    pub receiving_address: Address,  // where the taker gets the tokens
+    pub change_address: Address,
+    pub funding: Vec<FundingUtxo>,
+}
+
+pub struct TradeOfferParams {
+    pub token: Token,
+    pub sell_amount: SLPAmount,
+    pub price_per_token: Rational,
+    pub receiving_address: Address,
+    pub change_address: Address,
+    pub funding: Vec<FundingUtxo>,
+}
+
+fn write_var_int(vec: &mut Vec<u8>, n: u64) {
+    if n < 0xfd {
+        vec.push(n as u8);
+    } else if n <= 0xffff {
+        vec.push(0xfd);
+        vec.write_u16::<LittleEndian>(n as u16).unwrap();
+    } else if n <= 0xffff_ffff {
+        vec.push(0xfe);
+        vec.write_u32::<LittleEndian>(n as u32).unwrap();
+    } else {
+        vec.push(0xff);
+        vec.write_u64::<LittleEndian>(n).unwrap();
+    }
+}
+
+fn write_var_bytes(vec: &mut Vec<u8>, bytes: &[u8]) {
+    write_var_int(vec, bytes.len() as u64);
+    vec.extend_from_slice(bytes);
+}
+
+pub fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    if data.len() < 0x4c {
+        script.push(data.len() as u8);
+    } else if data.len() <= 0xff {
+        script.push(0x4c);
+        script.push(data.len() as u8);
+    } else {
+        script.push(0x4d);
+        script.write_u16::<LittleEndian>(data.len() as u16).unwrap();
+    }
+    script.extend_from_slice(data);
+}
+
+pub fn p2pkh_script(address: &Address) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9];
+    push_data(&mut script, address.bytes());
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+pub fn p2sh_script(address: &Address) -> Vec<u8> {
+    let mut script = vec![0xa9];
+    push_data(&mut script, address.bytes());
+    script.push(0x87);
+    script
+}
+
+pub fn address_script(address: &Address) -> Vec<u8> {
+    if address.addr_type() == AddressType::P2SH {
+        p2sh_script(address)
+    } else {
+        p2pkh_script(address)
+    }
+}
+
+pub fn slp_send_script(token_type: u8, token_hash: &[u8; 32], amounts: &[u64]) -> Vec<u8> {
+    let mut script = vec![0x6a];
+    push_data(&mut script, b"SLP\0");
+    push_data(&mut script, &[token_type]);
+    push_data(&mut script, b"SEND");
+    // token ids are pushed in display order
+    push_data(&mut script, &token_hash.iter().rev().cloned().collect::<Vec<_>>());
+    for amount in amounts {
+        let mut amount_bytes = Vec::new();
+        amount_bytes.write_u64::<BigEndian>(*amount).unwrap();
+        push_data(&mut script, &amount_bytes);
+    }
+    script
+}
+
+/// EXCH pushes the offer parser expects at the start of an input's scriptSig.
+pub fn exch_script_sig_prefix(config: &SLPDEXConfig, price: &ScriptPrice,
+                              receiving_address: &Address) -> Vec<u8> {
+    let mut script = Vec::new();
+    push_data(&mut script, config.exch_lokad.as_bytes());
+    script.push(0x50 + config.exch_version as u8);  // OP_1..OP_16
+    push_data(&mut script, &[price.power, price.is_inverted as u8]);
+    push_data(&mut script, &price.script_price.to_be_bytes());
+    push_data(&mut script, receiving_address.bytes());
+    script
+}
+
+impl UnsignedTx {
+    pub fn new() -> Self {
+        UnsignedTx { version: 2, inputs: Vec::new(), outputs: Vec::new(), lock_time: 0 }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut vec = Vec::new();
+        vec.write_i32::<LittleEndian>(self.version).unwrap();
+        write_var_int(&mut vec, self.inputs.len() as u64);
+        for input in self.inputs.iter() {
+            vec.extend_from_slice(&input.tx_hash);
+            vec.write_u32::<LittleEndian>(input.vout).unwrap();
+            write_var_bytes(&mut vec, &input.script_sig_prefix);
+            vec.write_u32::<LittleEndian>(input.sequence).unwrap();
+        }
+        write_var_int(&mut vec, self.outputs.len() as u64);
+        for output in self.outputs.iter() {
+            vec.write_u64::<LittleEndian>(output.value_satoshis).unwrap();
+            write_var_bytes(&mut vec, &output.script);
+        }
+        vec.write_u32::<LittleEndian>(self.lock_time).unwrap();
+        vec
+    }
+
+    /// Size once every input carries its unlocking data.
+    pub fn estimated_signed_size(&self) -> usize {
+        self.serialize().len() + self.inputs.iter()
+            .map(|input| input.estimated_unlock_size + 2)  // scriptSig length may grow
+            .sum::<usize>()
+    }
+
+    pub fn total_input(&self) -> u64 {
+        self.inputs.iter().map(|input| input.value_satoshis).sum()
+    }
+
+    pub fn total_output(&self) -> u64 {
+        self.outputs.iter().map(|output| output.value_satoshis).sum()
+    }
+
+    /// BIP143 preimage, as used by Bitcoin Cash with SIGHASH_FORKID.
+    pub fn sighash_preimage(&self, input_idx: usize, sighash_type: u32) -> Vec<u8> {
+        let mut prevouts = Vec::new();
+        let mut sequences = Vec::new();
+        for input in self.inputs.iter() {
+            prevouts.extend_from_slice(&input.tx_hash);
+            prevouts.write_u32::<LittleEndian>(input.vout).unwrap();
+            sequences.write_u32::<LittleEndian>(input.sequence).unwrap();
+        }
+        let mut outputs = Vec::new();
+        for output in self.outputs.iter() {
+            outputs.write_u64::<LittleEndian>(output.value_satoshis).unwrap();
+            write_var_bytes(&mut outputs, &output.script);
+        }
+        let input = &self.inputs[input_idx];
+        let mut preimage = Vec::new();
+        preimage.write_i32::<LittleEndian>(self.version).unwrap();
+        preimage.extend_from_slice(&double_sha256(&prevouts));
+        preimage.extend_from_slice(&double_sha256(&sequences));
+        preimage.extend_from_slice(&input.tx_hash);
+        preimage.write_u32::<LittleEndian>(input.vout).unwrap();
+        write_var_bytes(&mut preimage, &input.script_code);
+        preimage.write_u64::<LittleEndian>(input.value_satoshis).unwrap();
+        preimage.write_u32::<LittleEndian>(input.sequence).unwrap();
+        preimage.extend_from_slice(&double_sha256(&outputs));
+        preimage.write_u32::<LittleEndian>(self.lock_time).unwrap();
+        preimage.write_u32::<LittleEndian>(sighash_type).unwrap();
+        preimage
+    }
+
+    pub fn signing_data(&self) -> Vec<SigningData> {
+        (0..self.inputs.len())
+            .map(|input_idx| {
+                let preimage = self.sighash_preimage(input_idx, SIGHASH_ALL_FORKID);
+                SigningData {
+                    input_idx,
+                    sighash: double_sha256(&preimage),
+                    preimage,
+                    sighash_type: SIGHASH_ALL_FORKID,
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn unsigned_input(funding: &FundingUtxo) -> Result<UnsignedInput> {
+    if funding.address.addr_type() != AddressType::P2PKH {
+        return Err(ErrorKind::TxBuild(
+            TxBuildError::UnsupportedInput(funding.address.cash_addr().to_string())
+        ).into());
+    }
+    Ok(UnsignedInput {
+        tx_hash: funding.utxo.tx_hash,
+        vout: funding.utxo.vout as u32,
+        value_satoshis: funding.utxo.value_satoshis,
+        address: funding.address.clone(),
+        script_code: p2pkh_script(&funding.address),
+        script_sig_prefix: Vec::new(),
+        estimated_unlock_size: P2PKH_SIG_SIZE,
+        sequence: SEQUENCE_FINAL,
+    })
+}
+
+fn token_available(token: &Token, funding: &[FundingUtxo]) -> Result<SLPAmount> {
+    let mut token_available = SLPAmount::new(0, token.decimals as u32);
+    for funding in funding.iter() {
+        match funding.utxo.token_hash {
+            Some(token_hash) if token_hash != token.hash => {
+                return Err(ErrorKind::TxBuild(
+                    TxBuildError::WrongToken(cashcontracts::tx_hash_to_hex(&funding.utxo.tx_hash))
+                ).into());
+            },
+            Some(_) => token_available += funding.utxo.value_token,
+            None => {},
+        }
+    }
+    Ok(token_available)
+}
+
+/// Satoshis the offer's contract demands for all of its tokens.
+pub fn offer_payment(offer: &TradeOffer) -> Result<u64> {
+    script_payment(offer.sell_amount_token.base_amount() as u64,
+                   offer.script_price as u64,
+                   offer.is_inverted)
+}
+
+/// Satoshis the contract demands for `amount` base tokens at `script_price`.
+pub fn script_payment(amount: u64, script_price: u64, is_inverted: bool) -> Result<u64> {
+    let payment = if is_inverted {
+        script_price.checked_sub(1)
+            .and_then(|rounding| amount.checked_add(rounding))
+            .map(|amount| amount / script_price)
+    } else {
+        amount.checked_mul(script_price)
+    };
+    payment.ok_or_else(|| {
+        ErrorKind::TxBuild(TxBuildError::PaymentOutOfRange { amount, script_price }).into()
+    })
+}
+
+/// Payment to the maker and DEX fee for taking an offer, both at least dust.
+/// The fee follows the fee profile the offer's contract committed to; it's zero for
+/// contract versions without a fee.
+pub fn offer_payment_and_fee(offer: &TradeOffer, config: &SLPDEXConfig) -> Result<(u64, u64)> {
+    let config = config.with_fee_profile(offer.fee_profile.as_ref().map(String::as_str))?;
+    let payment_satoshis = offer_payment(offer)?.max(config.dust_limit);
+    let fee = trade_offer_parser(offer.contract_version).ok()
+        .and_then(|parser| parser.fee(&config));
+    let fee_satoshis = match fee {
+        Some((_, fee_divisor)) => (payment_satoshis / fee_divisor).max(config.dust_limit),
+        None => 0,
+    };
+    Ok((payment_satoshis, fee_satoshis))
+}
+
+/// Appends a change output if it's worth more than dust and checks the fee is covered.
+pub fn finish_tx(mut tx: UnsignedTx, change_address: &Address,
+                 config: &SLPDEXConfig) -> Result<BuiltTx> {
+    let fee_per_byte = config.tx_fee_per_byte;
+    let available = tx.total_input();
+    let mut change_tx = tx.clone();
+    change_tx.outputs.push(UnsignedOutput {
+        value_satoshis: 0,
+        script: p2pkh_script(change_address),
+    });
+    let change_fee = change_tx.estimated_signed_size() as u64 * fee_per_byte;
+    let needed = tx.total_output() + change_fee;
+    let fee = if available >= needed + config.dust_limit {
+        change_tx.outputs.last_mut().unwrap().value_satoshis = available - needed;
+        tx = change_tx;
+        change_fee
+    } else {
+        let fee = tx.estimated_signed_size() as u64 * fee_per_byte;
+        let needed = tx.total_output() + fee;
+        if available < needed {
+            return Err(ErrorKind::TxBuild(TxBuildError::InsufficientFunds { needed, available }).into());
+        }
+        available - tx.total_output()
+    };
+    Ok(BuiltTx { signing_data: tx.signing_data(), tx, fee })
+}
+
+/// Builds the tx which moves `sell_amount` tokens into an EXCH contract output.
+///
+/// Outputs: 0 SLP SEND, 1 contract, 2 token change (if any), then BCH change (if above dust).
+pub fn build_trade_offer(params: &TradeOfferParams, config: &SLPDEXConfig) -> Result<BuiltTradeOffer> {
+    let token = &params.token;
+    let price = TradeOffer::encode_price(token.decimals, &params.price_per_token)?;
+    let token_available = token_available(token, &params.funding)?;
+    if token_available < params.sell_amount || params.sell_amount.base_amount() <= 0 {
+        return Err(ErrorKind::TxBuild(TxBuildError::InsufficientTokens {
+            needed: params.sell_amount.to_string(),
+            available: token_available.to_string(),
+        }).into());
+    }
+    let token_change = token_available - params.sell_amount;
+    let contract = trade_offer_parser(config.exch_version)?
+        .contract(config.dust_limit,
+                  &price,
+                  &token.hash,
+                  token.version_type as u8,
+                  params.sell_amount.base_amount() as u64,
+                  config,
+                  &params.receiving_address);
+    let contract_address = Address::from_bytes(
+        AddressType::P2SH,
+        cashcontracts::hash160(&contract.script().to_vec()),
+    );
+    let mut tx = UnsignedTx::new();
+    for funding in params.funding.iter() {
+        tx.inputs.push(unsigned_input(funding)?);
+    }
+    if let Some(input) = tx.inputs.first_mut() {
+        input.script_sig_prefix = exch_script_sig_prefix(config, &price, &params.receiving_address);
+    }
+    let mut amounts = vec![params.sell_amount.base_amount() as u64];
+    if token_change.base_amount() > 0 {
+        amounts.push(token_change.base_amount() as u64);
+    }
+    tx.outputs.push(UnsignedOutput {
+        value_satoshis: 0,
+        script: slp_send_script(token.version_type as u8, &token.hash, &amounts),
+    });
+    tx.outputs.push(UnsignedOutput {
+        value_satoshis: config.dust_limit,
+        script: p2sh_script(&contract_address),
+    });
+    if token_change.base_amount() > 0 {
+        tx.outputs.push(UnsignedOutput {
+            value_satoshis: config.dust_limit,
+            script: p2pkh_script(&params.change_address),
+        });
+    }
+    let built_tx = finish_tx(tx, &params.change_address, config)?;
+    Ok(BuiltTradeOffer { built_tx, price, contract_address })
+}
+
+/// Builds the tx which takes the given offers.
+///
+/// The contract inputs come first, in offer order; their scriptSigs are completed by the
+/// signer with the contract's spend parameters and the redeem script from `script_code`.
+/// Outputs: 0 SLP SEND, 1 tokens to the taker, then per offer the payment to its
+/// `receiving_address` and, if its contract version has one, the DEX fee to the
+/// `fee_address` of the offer's fee profile, then BCH change (if above dust).
+pub fn build_accept_offers(params: &AcceptOffersParams, config: &SLPDEXConfig)
+        -> Result<BuiltAcceptOffers> {
+    let token = &params.token;
+    let mut token_amount = token_available(token, &params.funding)?;
+    let mut tx = UnsignedTx::new();
+    let mut payments = Vec::new();
+    let mut payment_outputs = Vec::new();
+    for offer_utxo in params.offers.iter() {
+        let offer = &offer_utxo.trade_offer;
+        if offer_utxo.token_hash != token.hash {
+            return Err(ErrorKind::TxBuild(
+                TxBuildError::WrongToken(cashcontracts::tx_hash_to_hex(&offer.tx))
+            ).into());
+        }
+        let price = ScriptPrice {
+            script_price: offer.script_price as u32,
+            price_per_token: offer.price_per_token.clone(),
+            power: offer.power,
+            is_inverted: offer.is_inverted,
+        };
+        let offer_config = config.with_fee_profile(offer.fee_profile.as_ref().map(String::as_str))?;
+        let parser = trade_offer_parser(offer.contract_version)?;
+        let redeem_script = parser.contract(offer_utxo.value_satoshis,
+                                            &price,
+                                            &token.hash,
+                                            token.version_type as u8,
+                                            offer.sell_amount_token.base_amount() as u64,
+                                            &offer_config,
+                                            &offer.receiving_address).script().to_vec();
+        tx.inputs.push(UnsignedInput {
+            tx_hash: offer.tx,
+            vout: offer.output_idx.unwrap_or(1) as u32,
+            value_satoshis: offer_utxo.value_satoshis,
+            address: Address::from_bytes(AddressType::P2SH, cashcontracts::hash160(&redeem_script)),
+            estimated_unlock_size: P2PKH_SIG_SIZE + CONTRACT_PREIMAGE_SIZE + 3 + redeem_script.len(),
+            script_code: redeem_script,
+            script_sig_prefix: Vec::new(),
+            sequence: SEQUENCE_FINAL,
+        });
+        token_amount += offer.sell_amount_token;
+        let (payment_satoshis, fee_satoshis) = offer_payment_and_fee(offer, config)?;
+        payment_outputs.push(UnsignedOutput {
+            value_satoshis: payment_satoshis,
+            script: p2pkh_script(&offer.receiving_address),
+        });
+        if let Some((fee_address, _)) = parser.fee(&offer_config) {
+            payment_outputs.push(UnsignedOutput {
+                value_satoshis: fee_satoshis,
+                script: address_script(&fee_address),
+            });
+        }
+        payments.push(OfferPayment {
+            offer_tx: offer.tx,
+            offer_vout: offer.output_idx.unwrap_or(1),
+            payment_satoshis,
+            fee_satoshis,
+        });
+    }
+    for funding in params.funding.iter() {
+        tx.inputs.push(unsigned_input(funding)?);
+    }
+    tx.outputs.push(UnsignedOutput {
+        value_satoshis: 0,
+        script: slp_send_script(token.version_type as u8, &token.hash,
+                                &[token_amount.base_amount() as u64]),
+    });
+    tx.outputs.push(UnsignedOutput {
+        value_satoshis: config.dust_limit,
+        script: p2pkh_script(&params.receiving_address),
+    });
+    tx.outputs.extend(payment_outputs);
+    let built_tx = finish_tx(tx, &params.change_address, config)?;
+    Ok(BuiltAcceptOffers { built_tx, token_amount, payments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx_hash(hex_str: &str) -> [u8; 32] {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&hex::decode(hex_str).unwrap());
+        hash
+    }
+
+    #[test]
+    fn script_payment_rounds_inverted_prices_up() {
+        assert_eq!(script_payment(1000, 546, false).unwrap(), 546_000);
+        assert_eq!(script_payment(1000, 100, true).unwrap(), 10);
+        assert_eq!(script_payment(1001, 100, true).unwrap(), 11);
+        assert_eq!(script_payment(0, 100, true).unwrap(), 0);
+    }
+
+    #[test]
+    fn script_payment_out_of_range() {
+        assert!(script_payment(std::u64::MAX, 2, false).is_err());
+        assert!(script_payment(std::u64::MAX, 2, true).is_err());
+        assert!(script_payment(1000, 0, true).is_err());
+        assert_eq!(script_payment(std::u64::MAX, 1, true).unwrap(), std::u64::MAX);
+    }
+
+    // native P2WPKH example of BIP143, second input
+    #[test]
+    fn sighash_preimage_bip143_vector() {
+        let input = |tx_hash_hex: &str, vout: u32, value_satoshis: u64, script_code: &str, sequence: u32| {
+            UnsignedInput {
+                tx_hash: tx_hash(tx_hash_hex),
+                vout,
+                value_satoshis,
+                address: Address::from_bytes(AddressType::P2PKH, [0; 20]),
+                script_code: hex::decode(script_code).unwrap(),
+                script_sig_prefix: Vec::new(),
+                estimated_unlock_size: P2PKH_SIG_SIZE,
+                sequence,
+            }
+        };
+        let tx = UnsignedTx {
+            version: 1,
+            inputs: vec![
+                input("fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f", 0,
+                      625_000_000, "", 0xffff_ffee),
+                input("ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a", 1,
+                      600_000_000, "76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac", 0xffff_ffff),
+            ],
+            outputs: vec![
+                UnsignedOutput {
+                    value_satoshis: 112_340_000,
+                    script: hex::decode("76a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac").unwrap(),
+                },
+                UnsignedOutput {
+                    value_satoshis: 223_450_000,
+                    script: hex::decode("76a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac").unwrap(),
+                },
+            ],
+            lock_time: 17,
+        };
+        assert_eq!(
+            hex::encode(tx.sighash_preimage(1, 0x01)),
+            "0100000096b827c8483d4e9b96712b6713a7b68d6e8003a781feba36c31143470b4efd37\
+             52b0a642eea2fb7ae638c36f6252b6750293dbe574a806984b8e4d8548339a3b\
+             ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a01000000\
+             1976a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac\
+             0046c32300000000\
+             ffffffff\
+             863ef3e1a92afbfdb97f31ad0fc7683ee943e9abcf2501590ff8f6551f47e5e5\
+             11000000\
+             01000000",
+        );
+    }
+}
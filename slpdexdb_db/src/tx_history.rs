@@ -1,17 +1,24 @@
 use crate::tx_source::{tx_result, TxSource, TxFilter, Confirmedness};
-use slpdexdb_base::{SLPDEXConfig, SLPAmount, Result, Error, ErrorKind, SLPError, TokenError, TradeOfferError};
+use slpdexdb_base::{SLPDEXConfig, SLPAmount, Result, Error, ErrorKind, SLPError, TokenError, TradeOfferError,
+                    TxBuildError, BurnReason, SLPInputCheck};
 use crate::token::Token;
 use crate::db::Db;
+use crate::data::{SpentUtxo, NewUtxo};
+use crate::trade_offer_parser::{TradeOfferParser, trade_offer_parser, trade_offer_parsers};
+use crate::protocol::{ParsedTx, ProtocolRecord, ExchRecord};
 use byteorder::{BigEndian, ReadBytesExt};
 use std::io;
 use std::collections::{HashSet, HashMap};
+use std::sync::Arc;
 use cashcontracts::{Output, AddressType, Address, tx_hash_to_hex, tx_hex_to_hash};
 use rug::Rational;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct TxHistory {
     pub txs: Vec<HistoricTx>,
     pub trade_offers: HashMap<usize, TradeOffer>,
+    pub records: HashMap<usize, Vec<Arc<dyn ProtocolRecord>>>,  // by protocol parsers, incl. SLP and EXCH
+    pub unverified: HashSet<usize>,  // txs no node has validated yet, e.g. submitted ones
 }
 
 #[derive(Clone, Debug)]
@@ -75,6 +82,9 @@ pub struct TradeOffer {
     pub is_inverted: bool,
     pub sell_amount_token: SLPAmount,
     pub receiving_address: Address,
+    pub power: u8,
+    pub fee_profile: Option<String>,  // fee profile the contract matched, `None` if no match or no fee
+    pub contract_version: i32,
 }
 
 impl SLPTxType {
@@ -152,9 +162,9 @@ impl TxHistory {
 
     pub fn from_entries(entries: &[tx_result::TxEntry],
                         now: i64,
-                        config: &SLPDEXConfig) -> Self {
-        let mut historic_txs = Vec::with_capacity(entries.len());
-        let mut trade_offers = HashMap::new();
+                        config: &SLPDEXConfig,
+                        db: &Db) -> Result<Self> {
+        let mut history = TxHistory::default();
         for entry in entries.iter() {
             let inputs = entry.inputs.iter()
                 .map(|input| {
@@ -166,22 +176,10 @@ impl TxHistory {
                 })
                 .collect::<Vec<_>>();
             let outputs = entry.outputs.iter()
-                .enumerate()
-                .map(|(i, output)| {
+                .map(|output| {
                     HistoricTxOutput {
                         value_satoshis: output.e.v,
-                        value_token: match &entry.slp {
-                            Some(slp) if i > 0 => {
-                                let decimals = slp.detail.decimals as u32;
-                                slp.detail.outputs
-                                    .get(i - 1)
-                                    .and_then(|output|
-                                        SLPAmount::from_str_decimals(&output.amount, decimals).ok()
-                                    )
-                                    .unwrap_or(SLPAmount::new(0, decimals))
-                            },
-                            _ => SLPAmount::new(0, 0),
-                        },
+                        value_token: SLPAmount::new(0, 0),
                         output: if output.b0 == (tx_result::StackItem::Op {op: 0x6a}) {
                             OutputType::OpReturn
                         } else {
@@ -194,29 +192,54 @@ impl TxHistory {
                 hash: cashcontracts::tx_hex_to_hash(&entry.tx.h).unwrap(),
                 height: entry.blk.as_ref().map(|blk| blk.i),
                 timestamp: entry.blk.as_ref().map(|blk| blk.t as i64).unwrap_or(now),
-                tx_type: entry.slp.as_ref()
-                    .and_then(|slp| Some(TxType::SLP {
-                        token_type: slp.detail.version_type,
-                        token_hash: tx_hex_to_hash(&slp.detail.token_id)?,
-                        slp_type: SLPTxType::from_bytes(slp.detail.transaction_type.as_bytes())?,
-                    }))
-                    .unwrap_or(TxType::Default),
+                tx_type: TxType::Default,
                 inputs,
                 outputs,
             };
-            entry.slp.as_ref().and_then(|slp| {
-                trade_offers.insert(
-                    historic_txs.len(),
-                    TradeOffer::from_entry(&historic_tx, entry, config, slp.detail.decimals as u32)?,
-                );
-                Some(())
-            });
-            historic_txs.push(historic_tx);
+            history._push(db.protocol_parsers().parse_entry(entry, historic_tx, config)?);
+        }
+        Ok(history)
+    }
+
+    fn _push(&mut self, parsed: ParsedTx) {
+        let idx = self.txs.len();
+        if let Some(ExchRecord(trade_offer)) = parsed.record::<ExchRecord>() {
+            self.trade_offers.insert(idx, trade_offer.clone());
+        }
+        if parsed.records.len() > 0 {
+            self.records.insert(idx, parsed.records);
+        }
+        self.txs.push(parsed.historic_tx);
+    }
+
+    /// Indices of `txs` ordered by height (unconfirmed last), with txs spending other txs of the
+    /// same height after them. Neither SLPDB nor the node report a tx's position in its block,
+    /// so txs are inserted in this order and `tx.id` stands in for it.
+    pub fn insertion_order(&self) -> Vec<usize> {
+        fn visit(idx: usize, txs: &[HistoricTx], indices: &HashMap<[u8; 32], usize>,
+                 visited: &mut [bool], order: &mut Vec<usize>) {
+            if visited[idx] { return; }
+            visited[idx] = true;
+            for input in txs[idx].inputs.iter() {
+                if let Some(&parent_idx) = indices.get(&input.output_tx) {
+                    if txs[parent_idx].height == txs[idx].height {
+                        visit(parent_idx, txs, indices, visited, order);
+                    }
+                }
+            }
+            order.push(idx);
         }
-        TxHistory {
-            txs: historic_txs,
-            trade_offers,
+        let indices = self.txs.iter().enumerate()
+            .map(|(idx, tx)| (tx.hash, idx))
+            .collect::<HashMap<_, _>>();
+        let mut by_height = (0..self.txs.len()).collect::<Vec<_>>();
+        by_height.sort_by_key(|&idx| self.txs[idx].height.unwrap_or(std::i32::MAX));
+        let mut visited = vec![false; self.txs.len()];
+        let mut order = Vec::with_capacity(self.txs.len());
+        for idx in by_height {
+            visit(idx, &self.txs, &indices, &mut visited, &mut order);
         }
+        order
     }
 
     pub fn _process_input_script(script: &cashcontracts::Script) -> OutputType {
@@ -327,9 +350,9 @@ impl TxHistory {
         }
     }
 
-    pub fn from_txs(txs: &[cashcontracts::Tx], now: i64, config: &SLPDEXConfig, db: &Db) -> Self {
-        let mut historic_txs = Vec::new();
-        let mut trade_offers = HashMap::new();
+    pub fn from_txs(txs: &[cashcontracts::Tx], now: i64, config: &SLPDEXConfig, db: &Db)
+            -> Result<Self> {
+        let mut history = TxHistory::default();
         for tx in txs.iter() {
             let inputs = tx.inputs().iter()
                 .map(|input| {
@@ -340,69 +363,26 @@ impl TxHistory {
                     }
                 })
                 .collect::<Vec<_>>();
-            let (tx_type, slp_amounts, token) = tx.outputs()
-                .get(0)
-                .and_then(|output| {
-                    match Self::_process_slp_output(&output.script, db) {
-                        Ok(slp_output) => slp_output,
-                        Err(err) => {
-                            eprintln!("Invalid SLP output: {} in {}", err, tx_hash_to_hex(&tx.hash()));
-                            None
-                        },
-                    }
-                })
-                .map(|(tx_type, slp_amounts, token)| {
-                    (tx_type, slp_amounts, Some(token))
-                })
-                .unwrap_or((TxType::Default, vec![], None));
-            let outputs = tx.outputs().iter().enumerate()
-                .map(|(output_idx, output)| {
+            let outputs = tx.outputs().iter()
+                .map(|output| {
                     HistoricTxOutput {
                         value_satoshis: output.value,
-                        value_token: if output_idx > 0 {
-                            slp_amounts.get(output_idx - 1).cloned()
-                        } else {
-                            None
-                        }.unwrap_or(SLPAmount::new(
-                            0,
-                            token.as_ref().map(|token| token.decimals as u32).unwrap_or(0),
-                        )),
+                        value_token: SLPAmount::new(0, 0),
                         output: Self::_process_output_script(&output.script),
                     }
                 })
-                .chain(
-                    slp_amounts.iter().skip(tx.outputs().len()).map(|amount| {
-                        HistoricTxOutput {
-                            value_satoshis: 0,
-                            value_token: *amount,
-                            output: OutputType::Burned,
-                        }
-                    })
-                )
                 .collect::<Vec<_>>();
             let historic_tx = HistoricTx {
                 hash: tx.hash(),
                 height: None,
                 timestamp: now,
-                tx_type,
+                tx_type: TxType::Default,
                 inputs,
                 outputs,
             };
-            let trade_offer = match &historic_tx.tx_type {
-                TxType::SLP { .. } => token.and_then(
-                    |token| TradeOffer::from_tx(&historic_tx, tx, config, &token)
-                ),
-                _ => None,
-            };
-            if let Some(trade_offer) = trade_offer {
-                trade_offers.insert(historic_txs.len(), trade_offer);
-            }
-            historic_txs.push(historic_tx);
-        }
-        TxHistory {
-            txs: historic_txs,
-            trade_offers,
+            history._push(db.protocol_parsers().parse_tx(tx, historic_tx, config, db)?);
         }
+        Ok(history)
     }
 
     pub fn _fetch_token(token_hash: &[u8; 32], db: &Db) -> Result<Token> {
@@ -411,7 +391,6 @@ impl TxHistory {
             None => {
                 let mut token_entries = crate::token_source::TokenSource::new()
                     .request_tokens(&[TxFilter::TokenId(token_hash.clone())])?;
-                println!("token entry: {:?}", token_entries);
                 if token_entries.len() == 0 {
                     return Err(
                         ErrorKind::TokenError(
@@ -420,13 +399,82 @@ impl TxHistory {
                     )
                 }
                 let token = Token::from_entry(token_entries.remove(0))?;
-                println!("new token: {:?}", token);
                 db.add_tokens(&[token.clone()])?;
                 Ok(token)
             },
         }
     }
 
+    /// The addresses the txs spend from or send to, with duplicates.
+    pub fn addresses(&self) -> Vec<Address> {
+        self.txs.iter()
+            .flat_map(|tx| {
+                tx.outputs.iter()
+                    .map(|output| &output.output)
+                    .chain(tx.inputs.iter().map(|input| &input.output))
+                    .filter_map(|output| match output {
+                        OutputType::Address(address) => Some(address.clone()),
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    /// UTXO rows spent and created by the txs, for `Db::apply_utxo_delta`. Address outputs
+    /// are always added, inputs only removed if they spend from one of `relevant_addresses`,
+    /// a trade offer or an indexed SLP tx.
+    pub fn utxo_delta(&self, relevant_addresses: &HashSet<Address>, config: &SLPDEXConfig)
+            -> (Vec<SpentUtxo>, Vec<NewUtxo>) {
+        let mut remove_utxos = Vec::new();
+        let mut add_utxos = Vec::new();
+        for (idx, tx) in self.txs.iter().enumerate() {
+            let trade_offer = self.trade_offers.get(&idx);
+            let slp_token_hash = tx.tx_type.token_hash()
+                .filter(|token_hash| config.is_slp_utxo_indexed(token_hash));
+            for input in tx.inputs.iter() {
+                match &input.output {
+                    OutputType::Address(address) if relevant_addresses.contains(address) => {},
+                    _ if trade_offer.is_some() || slp_token_hash.is_some() => {},
+                    _ => continue,
+                }
+                remove_utxos.push(SpentUtxo {
+                    tx_hash: input.output_tx.clone(),
+                    vout: input.output_idx,
+                })
+            }
+            if let Some(token_hash) = slp_token_hash {
+                for (output_idx, output) in tx.outputs.iter().enumerate() {
+                    if output.value_token.base_amount() <= 0 { continue }
+                    add_utxos.push(NewUtxo::Slp {
+                        tx_hash: tx.hash.clone(),
+                        vout: output_idx as i32,
+                        address: output.output.address().cloned(),
+                        token_hash: token_hash.clone(),
+                    });
+                }
+            }
+            for (output_idx, output) in tx.outputs.iter().enumerate() {
+                match &output.output {
+                    OutputType::Address(address) => {
+                        add_utxos.push(NewUtxo::Address {
+                            tx_hash: tx.hash.clone(),
+                            vout: output_idx as i32,
+                            address: address.clone(),
+                        });
+                    },
+                    _ if trade_offer.is_some() => {
+                        add_utxos.push(NewUtxo::TradeOffer {
+                            tx_hash: tx.hash.clone(),
+                            vout: output_idx as i32,
+                        });
+                    },
+                    _ => {},
+                }
+            }
+        }
+        (remove_utxos, add_utxos)
+    }
+
     pub fn validate_slp(&mut self, tx_source: &TxSource, _db: &Db, config: &SLPDEXConfig)
             -> Result<()> {
         let tx_to_check = self.txs.iter()
@@ -448,17 +496,12 @@ impl TxHistory {
             .into_iter()
             .map(|validity| (cashcontracts::tx_hex_to_hash(&validity.tx.h).unwrap(), validity))
             .collect::<HashMap<_, _>>();
-        for validity in validity_map.values() {
-            println!("{}", serde_json::to_string(validity).unwrap_or(".".to_string()));
-        }
         for i in 0..self.txs.len() {
             let tx = &mut self.txs[i];
             let (token_hash, token_type) = match &tx.tx_type {
                 TxType::SLP {token_hash, token_type, ..} => (token_hash, token_type),
                 TxType::Default => continue,
             };
-            println!("validating {}", cashcontracts::tx_hash_to_hex(&tx.hash));
-            println!("token found: ");
             let decimals = tx.outputs.iter()
                 .map(|output| output.value_token.decimals())
                 .next();
@@ -480,25 +523,191 @@ impl TxHistory {
                     Some(SLPAmount::from_str_decimals(&slp_output.amount, decimals?).ok()?)
                 })
                 .sum::<SLPAmount>();
-            println!("input sum: {}", input_sum);
-            println!("output sum: {}", output_sum);
             if input_sum < output_sum {
-                tx.tx_type = TxType::Default;
-                tx.outputs.iter_mut().for_each(|output| {
-                    output.value_token = SLPAmount::new(0, 0);
-                });
-                self.trade_offers.remove(&i);
+                self.invalidate_slp(i);
             }
         }
         Ok(())
     }
+
+    /// Turns tx `tx_idx` into a plain BCH tx, e.g. because its SLP inputs don't cover it.
+    pub fn invalidate_slp(&mut self, tx_idx: usize) {
+        let tx = &mut self.txs[tx_idx];
+        tx.tx_type = TxType::Default;
+        tx.outputs.iter_mut().for_each(|output| {
+            output.value_token = SLPAmount::new(0, 0);
+        });
+        self.trade_offers.remove(&tx_idx);
+    }
+
+    /// Breaks down which inputs of tx `tx_idx` would be burned. Uses the SLP validity of the
+    /// spent txs, falling back to stored output values for txs the source doesn't know. Inputs
+    /// spending outputs neither knows can't be verified and count as burned.
+    pub fn check_burns(&self, tx_idx: usize, tx_source: &TxSource, db: &Db, config: &SLPDEXConfig)
+            -> Result<Vec<SLPInputCheck>> {
+        let tx = &self.txs[tx_idx];
+        let outpoints = tx.inputs.iter()
+            .map(|input| SpentUtxo { tx_hash: input.output_tx, vout: input.output_idx })
+            .collect::<Vec<_>>();
+        let stored_utxos = db.stored_outputs(&outpoints)?.into_iter()
+            .map(|funding| ((funding.utxo.tx_hash, funding.utxo.vout), funding.utxo))
+            .collect::<HashMap<_, _>>();
+        let tx_to_check = tx.inputs.iter()
+            .map(|input| input.output_tx)
+            .collect::<HashSet<_>>().into_iter()
+            .map(TxFilter::TxHash)
+            .collect::<Vec<_>>();
+        let validity_map = if tx_to_check.len() > 0 {
+            tx_source
+                .request_slp_tx_validity(&tx_to_check, config, Confirmedness::Both)?
+                .into_iter()
+                .map(|validity| (cashcontracts::tx_hex_to_hash(&validity.tx.h).unwrap(), validity))
+                .collect::<HashMap<_, _>>()
+        } else {
+            HashMap::new()
+        };
+        // spent txs neither the SLP validity nor the index cover; found ones carry no tokens
+        let unknown_txs = tx.inputs.iter()
+            .filter(|input| !validity_map.contains_key(&input.output_tx) &&
+                            !stored_utxos.contains_key(&(input.output_tx, input.output_idx)))
+            .map(|input| input.output_tx)
+            .collect::<HashSet<_>>();
+        let known_txs = if unknown_txs.len() > 0 {
+            let filters = unknown_txs.into_iter().map(TxFilter::TxHash).collect::<Vec<_>>();
+            tx_source.request_txs(&filters, config, Confirmedness::Both)?
+                .into_iter()
+                .filter_map(|entry| tx_hex_to_hash(&entry.tx.h))
+                .collect::<HashSet<_>>()
+        } else {
+            HashSet::new()
+        };
+        let spent_outputs = tx.inputs.iter()
+            .map(|input| match validity_map.get(&input.output_tx) {
+                Some(validity) if validity.slp.valid => {
+                    let detail = &validity.slp.detail;
+                    let decimals = detail.decimals as u32;
+                    let value_token = if input.output_idx > 0 {
+                        detail.outputs.get((input.output_idx - 1) as usize)
+                            .and_then(|output| {
+                                SLPAmount::from_str_decimals(&output.amount, decimals).ok()
+                            })
+                    } else {
+                        None
+                    };
+                    Some(SpentOutput {
+                        token_hash: tx_hex_to_hash(&detail.token_id),
+                        token_type: Some(detail.version_type),
+                        value_token: value_token.unwrap_or(SLPAmount::new(0, decimals)),
+                        is_baton: detail.baton_vout == Some(input.output_idx),
+                    })
+                },
+                _ => match stored_utxos.get(&(input.output_tx, input.output_idx)) {
+                    Some(utxo) => Some(SpentOutput {
+                        token_hash: utxo.token_hash,
+                        token_type: None,
+                        value_token: utxo.value_token,
+                        is_baton: false,
+                    }),
+                    None if validity_map.contains_key(&input.output_tx) ||
+                            known_txs.contains(&input.output_tx) => Some(SpentOutput::without_token()),
+                    None => None,
+                },
+            })
+            .collect::<Vec<_>>();
+        Ok(Self::_check_inputs(tx, &spent_outputs))
+    }
+
+    /// Burn checks of the inputs of `tx`, given the outputs they spend; `None` if unknown.
+    fn _check_inputs(tx: &HistoricTx, spent_outputs: &[Option<SpentOutput>]) -> Vec<SLPInputCheck> {
+        let (tx_token_hash, tx_token_type, tx_slp_type) = match &tx.tx_type {
+            TxType::SLP {token_hash, token_type, slp_type} =>
+                (Some(token_hash), Some(*token_type), Some(slp_type)),
+            TxType::Default => (None, None, None),
+        };
+        let mut checks = tx.inputs.iter().zip(spent_outputs.iter()).enumerate()
+            .map(|(input_idx, (input, spent_output))| {
+                let spent_output = match spent_output {
+                    Some(spent_output) => spent_output,
+                    None => return SLPInputCheck {
+                        input_idx,
+                        tx_hash: input.output_tx,
+                        vout: input.output_idx,
+                        token_hash: None,
+                        value_token: SLPAmount::new(0, 0),
+                        is_baton: false,
+                        burn: Some(BurnReason::UnknownInput),
+                    },
+                };
+                let SpentOutput { token_hash, token_type, value_token, is_baton } = *spent_output;
+                let carries_token = value_token.base_amount() > 0 || is_baton;
+                let burn = match (tx_token_hash, tx_slp_type) {
+                    _ if !carries_token => None,
+                    (Some(tx_token_hash), Some(slp_type)) => {
+                        if token_hash.as_ref() != Some(tx_token_hash) ||
+                                (token_type.is_some() && token_type != tx_token_type) {
+                            Some(BurnReason::WrongToken)
+                        } else {
+                            match slp_type {
+                                SLPTxType::Mint if is_baton => None,
+                                SLPTxType::Send if !is_baton => None,
+                                _ if is_baton => Some(BurnReason::MintBaton),
+                                _ => Some(BurnReason::NoSend),
+                            }
+                        }
+                    },
+                    _ if is_baton => Some(BurnReason::MintBaton),
+                    _ => Some(BurnReason::NoSend),
+                };
+                SLPInputCheck {
+                    input_idx,
+                    tx_hash: input.output_tx,
+                    vout: input.output_idx,
+                    token_hash: token_hash.filter(|_| carries_token),
+                    value_token,
+                    is_baton,
+                    burn,
+                }
+            })
+            .collect::<Vec<_>>();
+        if let Some(SLPTxType::Send) = tx_slp_type {
+            let input_sum = checks.iter()
+                .filter(|check| check.burn.is_none() && !check.is_baton)
+                .map(|check| check.value_token.base_amount())
+                .sum::<i128>();
+            let output_sum = tx.outputs.iter()
+                .map(|output| output.value_token.base_amount())
+                .sum::<i128>();
+            if output_sum < input_sum {
+                checks.iter_mut()
+                    .filter(|check| check.burn.is_none() && check.token_hash.is_some())
+                    .for_each(|check| check.burn = Some(BurnReason::Underspent));
+            }
+        }
+        checks
+    }
+}
+
+/// Token content of an output spent by a checked tx.
+#[derive(Clone, Copy, Debug)]
+struct SpentOutput {
+    token_hash: Option<[u8; 32]>,
+    token_type: Option<i32>,  // `None` if only known from the index
+    value_token: SLPAmount,
+    is_baton: bool,
 }
 
-struct _Price {
-    script_price: u32,
-    price_per_token: Rational,
-    power: u8,
-    is_inverted: bool,
+impl SpentOutput {
+    fn without_token() -> Self {
+        SpentOutput { token_hash: None, token_type: None, value_token: SLPAmount::new(0, 0), is_baton: false }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScriptPrice {
+    pub script_price: u32,
+    pub price_per_token: Rational,
+    pub power: u8,
+    pub is_inverted: bool,
 }
 
 impl TradeOffer {
@@ -515,14 +724,23 @@ impl TradeOffer {
         1_000_000_000,
     ];
 
-    fn _decode_price(slp_decimals: i32, power_bytes: &[u8], price_bytes: &[u8]) -> Result<_Price> {
+    fn _factor(slp_decimals: i32) -> Result<u64> {
+        if slp_decimals < 0 {
+            return Err(ErrorKind::InvalidTradeOffer(TradeOfferError::InvalidDecimals(slp_decimals)).into());
+        }
+        Self::_FACTORS.get(slp_decimals as usize).cloned().ok_or_else(|| {
+            ErrorKind::InvalidTradeOffer(TradeOfferError::InvalidDecimals(slp_decimals)).into()
+        })
+    }
+
+    pub fn decode_price(slp_decimals: i32, power_bytes: &[u8], price_bytes: &[u8]) -> Result<ScriptPrice> {
         let is_inverted = power_bytes.get(1) == Some(&1);
         let script_price = io::Cursor::new(price_bytes)
             .read_u32::<BigEndian>()
             .map_err(|_| Error::from(ErrorKind::InvalidTradeOffer(
                 TradeOfferError::InvalidPrice(price_bytes.to_vec())
             )))?;
-        let factor = Self::_FACTORS[slp_decimals as usize];
+        let factor = Self::_factor(slp_decimals)?;
         let factor_rational = rug::Rational::from((factor, 1));
         let price_per_token = if is_inverted {
             if script_price == 0 {
@@ -534,7 +752,7 @@ impl TradeOffer {
         } else {
             rug::Rational::from((script_price, 1)) * factor_rational
         };
-        Ok(_Price {
+        Ok(ScriptPrice {
             script_price,
             price_per_token,
             power: *power_bytes.get(0).ok_or_else(|| ErrorKind::InvalidTradeOffer(
@@ -544,42 +762,96 @@ impl TradeOffer {
         })
     }
 
-    fn _contract_hash(output: &HistoricTxOutput,
-                      price: &_Price,
+    /// Reverse of `decode_price`: finds the script price closest to `price_per_token`
+    /// (satoshis per token), inverting it if a token base unit is worth less than a satoshi.
+    pub fn encode_price(slp_decimals: i32, price_per_token: &Rational) -> Result<ScriptPrice> {
+        let out_of_range = || Error::from(ErrorKind::TxBuild(
+            TxBuildError::PriceOutOfRange(price_per_token.to_string())
+        ));
+        if *price_per_token <= 0 {
+            return Err(out_of_range());
+        }
+        let factor = Self::_factor(slp_decimals)?;
+        let price_base = price_per_token.clone() / rug::Rational::from((factor, 1));
+        let is_inverted = price_base < 1;
+        let script_price = if is_inverted { price_base.recip() } else { price_base };
+        let script_price = script_price.round().numer().to_u32().ok_or_else(out_of_range)?;
+        if script_price == 0 {
+            return Err(out_of_range());
+        }
+        let power_bytes = [0, is_inverted as u8];
+        Self::decode_price(slp_decimals, &power_bytes, &script_price.to_be_bytes())
+    }
+
+    /// Searches the outputs for the P2SH address of the contract `parser` rebuilds, trying
+    /// every fee profile of `config` if the contract has a fee. Returns the output index,
+    /// its token amount and the matched fee profile.
+    fn _find_contract(outputs: &[HistoricTxOutput],
+                      parser: &dyn TradeOfferParser,
+                      price: &ScriptPrice,
                       tx_type: &TxType,
                       config: &SLPDEXConfig,
-                      receiving_address: &cashcontracts::Address) -> Option<SLPAmount> {
+                      receiving_address: &cashcontracts::Address)
+            -> Option<(i32, SLPAmount, Option<String>)> {
         let (token_hash, token_type) = match tx_type {
             TxType::SLP {token_hash, token_type, ..} => (token_hash, *token_type),
             TxType::Default => return None,
         };
-        let address = output.output.address()?;
-        if address.addr_type() == AddressType::P2SH {
-            let hash = cashcontracts::hash160(
-                &cashcontracts::AdvancedTradeOffer {
-                    value: output.value_satoshis,
-                    lokad_id: b"EXCH".to_vec(),
-                    version: 2,
-                    power: price.power,
-                    is_inverted: price.is_inverted,
-                    token_id: token_hash.clone(),
-                    token_type: token_type as u8,
-                    sell_amount_token: output.value_token.base_amount() as u64,
-                    price: price.script_price,
-                    dust_amount: config.dust_limit,
-                    address: receiving_address.clone(),
-                    fee_address: Some(config.fee_address.clone()),
-                    fee_divisor: Some(config.fee_divisor.clone()),
-                    spend_params: None,
-                }.script().to_vec()
-            );
-            if address.bytes() == &hash {
-                Some(output.value_token)
-            } else {
-                None
+        let fee_profiles: Vec<Option<String>> = match parser.fee(config) {
+            Some(_) => config.all_fee_profiles().into_iter().map(|profile| Some(profile.name)).collect(),
+            None => vec![None],
+        };
+        outputs.iter().enumerate().find_map(|(output_idx, output)| {
+            let address = output.output.address()?;
+            if address.addr_type() != AddressType::P2SH {
+                return None;
             }
-        } else {
-            None
+            fee_profiles.iter().find_map(|fee_profile| {
+                let profile_config = config
+                    .with_fee_profile(fee_profile.as_ref().map(String::as_str)).ok()?;
+                let hash = cashcontracts::hash160(
+                    &parser.contract(output.value_satoshis,
+                                     price,
+                                     token_hash,
+                                     token_type as u8,
+                                     output.value_token.base_amount() as u64,
+                                     &profile_config,
+                                     receiving_address).script().to_vec()
+                );
+                if address.bytes() == &hash {
+                    Some((output_idx as i32, output.value_token, fee_profile.clone()))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    fn _new(tx: [u8; 32],
+            input_tx: [u8; 32],
+            input_idx: i32,
+            parser: &dyn TradeOfferParser,
+            price: ScriptPrice,
+            receiving_address: Address,
+            contract_vals: Option<(i32, SLPAmount, Option<String>)>,
+            decimals: u32) -> Self {
+        let (output_idx, sell_amount_token, fee_profile) = match contract_vals {
+            Some((output_idx, amount, fee_profile)) => (Some(output_idx), amount, fee_profile),
+            None => (None, SLPAmount::new(0, decimals), None),
+        };
+        TradeOffer {
+            tx,
+            output_idx,
+            input_tx,
+            input_idx,
+            power: price.power,
+            price_per_token: price.price_per_token,
+            is_inverted: price.is_inverted,
+            script_price: price.script_price as i64,
+            sell_amount_token,
+            receiving_address,
+            fee_profile,
+            contract_version: parser.version(),
         }
     }
 
@@ -589,50 +861,41 @@ impl TradeOffer {
                       decimals: u32)
             -> Option<Self> {
         entry.inputs.iter().find_map(|input| {
-            if input.b0 == tx_result::StackItem::Str(base64::encode("EXCH")) &&
-                    input.b1 == (tx_result::StackItem::Op {op: 0x52}) {
-                let price = entry.slp.as_ref()
-                    .and_then(|slp| {
-                        Self::_decode_price(
-                            slp.detail.decimals,
-                            &base64::decode(input.b2.get_str()?).ok()?,
-                            &base64::decode(input.b3.get_str()?).ok()?,
-                        ).map_err(|err| {
-                            eprintln!("Trade offer error {}", err);
-                        }).ok()
-                    })?;
-                let receiving_address = Address::from_slice(
-                    AddressType::P2PKH,
-                    &base64::decode(input.b4.get_str()?).ok()?,
-                )?;
-                let output_idx: i32 = 1;
-                let contract_vals = tx.outputs.get(output_idx as usize)
-                    .and_then(|output: &HistoricTxOutput| {
-                        Some((
-                            output_idx,
-                            Self::_contract_hash(output,
-                                                 &price,
-                                                 &tx.tx_type,
-                                                 config,
-                                                 &receiving_address)?,
-                        ))
-                    });
-                Some(TradeOffer {
-                    tx: cashcontracts::tx_hex_to_hash(&entry.tx.h).unwrap(),
-                    output_idx: contract_vals.map(|(idx, _)| idx),
-                    input_tx: cashcontracts::tx_hex_to_hash(&input.e.h).unwrap(),
-                    input_idx: input.e.i,
-                    price_per_token: price.price_per_token,
-                    is_inverted: price.is_inverted,
-                    script_price: price.script_price as i64,
-                    sell_amount_token: contract_vals
-                        .map(|(_, amount)| amount)
-                        .unwrap_or(SLPAmount::new(0, decimals)),
-                    receiving_address,
-                })
-            } else {
-                None
+            if input.b0 != tx_result::StackItem::Str(config.exch_lokad_b64.clone()) {
+                return None;
             }
+            let parser = match input.b1 {
+                tx_result::StackItem::Op {op} => trade_offer_parser(op - 0x50).ok()?,
+                _ => return None,
+            };
+            let price = entry.slp.as_ref()
+                .and_then(|slp| {
+                    parser.decode_price(
+                        slp.detail.decimals,
+                        &base64::decode(input.b2.get_str()?).ok()?,
+                        &base64::decode(input.b3.get_str()?).ok()?,
+                    ).map_err(|err| {
+                        eprintln!("Trade offer error {}", err);
+                    }).ok()
+                })?;
+            let receiving_address = Address::from_slice(
+                AddressType::P2PKH,
+                &base64::decode(input.b4.get_str()?).ok()?,
+            )?;
+            let contract_vals = Self::_find_contract(&tx.outputs,
+                                                     parser,
+                                                     &price,
+                                                     &tx.tx_type,
+                                                     config,
+                                                     &receiving_address);
+            Some(Self::_new(cashcontracts::tx_hex_to_hash(&entry.tx.h).unwrap(),
+                            cashcontracts::tx_hex_to_hash(&input.e.h).unwrap(),
+                            input.e.i,
+                            parser,
+                            price,
+                            receiving_address,
+                            contract_vals,
+                            decimals))
         })
     }
 
@@ -640,8 +903,7 @@ impl TradeOffer {
                    tx: &cashcontracts::Tx,
                    config: &SLPDEXConfig,
                    token: &Token) -> Option<Self> {
-        use cashcontracts::{Op::*, OpCodeType::*};
-        println!("validating trade offer {}", tx_hash_to_hex(&historic_tx.hash));
+        use cashcontracts::Op::*;
         if let TxType::Default = &historic_tx.tx_type {
             return None
         }
@@ -649,46 +911,35 @@ impl TradeOffer {
             let ops = input.script.ops();
             if ops.len() < 5 { return None; }
             match &input.script.ops()[..5] {
-                &[Push(ref exch), Code(Op2), Push(ref power), Push(ref price), Push(ref address)]
+                &[Push(ref exch), ref version_op, Push(ref power), Push(ref price), Push(ref address)]
                         if exch.as_slice() == config.exch_lokad.as_bytes() => {
-                    let price = Self::_decode_price(token.decimals, power, price)
+                    let parser = trade_offer_parsers().iter()
+                        .cloned()
+                        .find(|parser| parser.version_op() == *version_op)?;
+                    let price = parser.decode_price(token.decimals, power, price)
                         .map_err(|err| {
                             eprintln!("Trade offer error {}", err);
                         }).ok()?;
-                    println!("succeed price decoding");
                     let receiving_address = Address::from_slice(
                         AddressType::P2PKH,
                         address,
                     )?;
-                    println!("succeed address decoding");
-                    let output_idx: i32 = 1;
-                    let contract_vals = historic_tx.outputs.get(output_idx as usize)
-                        .and_then(|output: &HistoricTxOutput| {
-                            Some((
-                                output_idx,
-                                Self::_contract_hash(output,
-                                                     &price,
-                                                     &historic_tx.tx_type,
-                                                     config,
-                                                     &receiving_address)?,
-                            ))
-                        });
-                    println!("contract vals {:?}", contract_vals);
-                    Some(TradeOffer {
-                        tx: historic_tx.hash.clone(),
-                        output_idx: contract_vals.map(|(idx, _)| idx),
-                        input_tx: input.outpoint.tx_hash.clone(),
-                        input_idx: input.outpoint.vout as i32,
-                        price_per_token: price.price_per_token,
-                        is_inverted: price.is_inverted,
-                        script_price: price.script_price as i64,
-                        sell_amount_token: contract_vals
-                            .map(|(_, amount)| amount)
-                            .unwrap_or(SLPAmount::new(0, token.decimals as u32)),
-                        receiving_address,
-                    })
+                    let contract_vals = Self::_find_contract(&historic_tx.outputs,
+                                                             parser,
+                                                             &price,
+                                                             &historic_tx.tx_type,
+                                                             config,
+                                                             &receiving_address);
+                    Some(Self::_new(historic_tx.hash.clone(),
+                                    input.outpoint.tx_hash.clone(),
+                                    input.outpoint.vout as i32,
+                                    parser,
+                                    price,
+                                    receiving_address,
+                                    contract_vals,
+                                    token.decimals as u32))
                 }
-                _ => { println!("bad stack {}", input.script); None }
+                _ => None,
             }
         })
     }
@@ -739,3 +990,273 @@ impl std::fmt::Display for OutputType {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slpdexdb_base::DEFAULT_FEE_PROFILE;
+    use crate::trade_offer_parser::{ExchV1, ExchV2};
+
+    fn historic_tx(hash: u8, height: Option<i32>, spends: &[u8]) -> HistoricTx {
+        HistoricTx {
+            hash: [hash; 32],
+            height,
+            timestamp: 0,
+            tx_type: TxType::Default,
+            inputs: spends.iter()
+                .map(|&output_tx| HistoricTxInput {
+                    output_tx: [output_tx; 32],
+                    output_idx: 0,
+                    output: OutputType::Unknown,
+                })
+                .collect(),
+            outputs: vec![],
+        }
+    }
+
+    #[test]
+    fn insertion_order_follows_height_and_spends() {
+        let history = TxHistory {
+            txs: vec![
+                historic_tx(1, None, &[]),
+                historic_tx(2, Some(101), &[3]),
+                historic_tx(3, Some(101), &[4]),
+                historic_tx(4, Some(101), &[]),
+                historic_tx(5, Some(100), &[]),
+                historic_tx(6, None, &[1]),
+            ],
+            ..TxHistory::default()
+        };
+        assert_eq!(history.insertion_order(), vec![4, 3, 2, 1, 0, 5]);
+    }
+
+    fn send_tx(token_hash: [u8; 32], input_count: u8, output_amounts: &[i128]) -> HistoricTx {
+        let mut tx = historic_tx(9, None, &(1..=input_count).collect::<Vec<_>>());
+        tx.tx_type = TxType::SLP { token_hash, token_type: 1, slp_type: SLPTxType::Send };
+        tx.outputs = output_amounts.iter()
+            .map(|&amount| HistoricTxOutput {
+                value_satoshis: 546,
+                value_token: SLPAmount::new(amount, 0),
+                output: OutputType::Unknown,
+            })
+            .collect();
+        tx
+    }
+
+    fn token_output(token_hash: [u8; 32], amount: i128) -> Option<SpentOutput> {
+        Some(SpentOutput {
+            token_hash: Some(token_hash),
+            token_type: Some(1),
+            value_token: SLPAmount::new(amount, 0),
+            is_baton: false,
+        })
+    }
+
+    fn burns(checks: &[SLPInputCheck]) -> Vec<Option<String>> {
+        checks.iter().map(|check| check.burn.as_ref().map(|burn| format!("{:?}", burn))).collect()
+    }
+
+    #[test]
+    fn check_inputs_of_valid_send() {
+        let tx = send_tx([7; 32], 3, &[0, 70, 30]);
+        let spent_outputs = [token_output([7; 32], 60), token_output([7; 32], 40),
+                             Some(SpentOutput::without_token())];
+        let checks = TxHistory::_check_inputs(&tx, &spent_outputs);
+        assert_eq!(burns(&checks), vec![None, None, None]);
+        assert_eq!(checks[0].token_hash, Some([7; 32]));
+        assert_eq!(checks[2].token_hash, None);
+    }
+
+    #[test]
+    fn check_inputs_of_burning_txs() {
+        let tx = send_tx([7; 32], 2, &[0, 90]);
+        let checks = TxHistory::_check_inputs(&tx, &[token_output([7; 32], 60), token_output([7; 32], 40)]);
+        assert_eq!(burns(&checks), vec![Some("Underspent".to_string()), Some("Underspent".to_string())]);
+
+        let tx = send_tx([7; 32], 2, &[0, 60]);
+        let checks = TxHistory::_check_inputs(&tx, &[token_output([7; 32], 60), token_output([8; 32], 40)]);
+        assert_eq!(burns(&checks), vec![None, Some("WrongToken".to_string())]);
+
+        let mut tx = historic_tx(9, None, &[1]);
+        tx.outputs = vec![];
+        let checks = TxHistory::_check_inputs(&tx, &[token_output([7; 32], 60)]);
+        assert_eq!(burns(&checks), vec![Some("NoSend".to_string())]);
+
+        let baton = Some(SpentOutput { is_baton: true, ..SpentOutput::without_token() });
+        let checks = TxHistory::_check_inputs(&tx, &[baton]);
+        assert_eq!(burns(&checks), vec![Some("MintBaton".to_string())]);
+    }
+
+    #[test]
+    fn check_inputs_flags_unknown_inputs() {
+        let tx = send_tx([7; 32], 2, &[0, 60]);
+        let checks = TxHistory::_check_inputs(&tx, &[token_output([7; 32], 60), None]);
+        assert_eq!(burns(&checks), vec![None, Some("UnknownInput".to_string())]);
+
+        let tx = historic_tx(9, None, &[1]);
+        let checks = TxHistory::_check_inputs(&tx, &[None]);
+        assert_eq!(burns(&checks), vec![Some("UnknownInput".to_string())]);
+    }
+
+    #[test]
+    fn price_round_trip() {
+        let price = TradeOffer::encode_price(8, &Rational::from(1000)).unwrap();
+        assert!(price.is_inverted);
+        assert_eq!(price.script_price, 100_000);
+        assert_eq!(price.price_per_token, 1000);
+        let decoded = TradeOffer::decode_price(8, &[price.power, 1], &price.script_price.to_be_bytes())
+            .unwrap();
+        assert_eq!(decoded.price_per_token, 1000);
+
+        let price = TradeOffer::encode_price(0, &Rational::from(546)).unwrap();
+        assert!(!price.is_inverted);
+        assert_eq!(price.script_price, 546);
+        let decoded = TradeOffer::decode_price(0, &[price.power, 0], &price.script_price.to_be_bytes())
+            .unwrap();
+        assert_eq!(decoded.price_per_token, 546);
+    }
+
+    #[test]
+    fn encode_price_rounds_to_nearest_script_price() {
+        let price = TradeOffer::encode_price(2, &Rational::from((3, 7))).unwrap();
+        assert!(price.is_inverted);
+        assert_eq!(price.script_price, 233);
+        assert_eq!(price.price_per_token, Rational::from((100, 233)));
+    }
+
+    #[test]
+    fn price_out_of_range() {
+        assert!(TradeOffer::encode_price(0, &Rational::from(0)).is_err());
+        assert!(TradeOffer::encode_price(0, &Rational::from(-5)).is_err());
+        assert!(TradeOffer::encode_price(0, &Rational::from(1u64 << 33)).is_err());
+        assert!(TradeOffer::decode_price(0, &[0, 1], &0u32.to_be_bytes()).is_err());
+        assert!(TradeOffer::decode_price(0, &[0, 0], &[0, 1]).is_err());
+    }
+
+    #[test]
+    fn price_invalid_decimals() {
+        assert!(TradeOffer::encode_price(10, &Rational::from(1000)).is_err());
+        assert!(TradeOffer::encode_price(-1, &Rational::from(1000)).is_err());
+        assert!(TradeOffer::decode_price(10, &[0, 0], &1u32.to_be_bytes()).is_err());
+        assert!(TradeOffer::decode_price(9, &[0, 0], &1u32.to_be_bytes()).is_ok());
+    }
+
+    // Vectors built from the EXCH v1 format (lokad, OP_1, power, price, receiving address in
+    // the funding input's scriptSig; fee-less contract), not taken from mainnet txs.
+    const V1_TOKEN_HASH: [u8; 32] = [0x5a; 32];
+    const V1_OFFER_TX: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+    const V1_FUNDING_TX: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+
+    fn v1_receiving_address() -> Address {
+        Address::from_cash_addr(
+            "bitcoincash:qq5lzj2p3kznpdsm06ms7la9g6d8hezkkg4mgq9rdh".to_string()
+        ).unwrap()
+    }
+
+    /// An offer tx selling 250 base units of a 2 decimals token at 1000 sats per base unit,
+    /// with change at output 1 and the contract of `parser` at output 2.
+    fn v1_offer_tx(parser: &dyn TradeOfferParser, config: &SLPDEXConfig) -> HistoricTx {
+        let price = TradeOffer::decode_price(2, &[0, 0], &1000u32.to_be_bytes()).unwrap();
+        let contract = parser.contract(546, &price, &V1_TOKEN_HASH, 1, 250, config,
+                                       &v1_receiving_address());
+        let contract_hash = cashcontracts::hash160(&contract.script().to_vec());
+        let output = |value_token, output| HistoricTxOutput {
+            value_satoshis: 546,
+            value_token: SLPAmount::new(value_token, 2),
+            output,
+        };
+        HistoricTx {
+            hash: cashcontracts::tx_hex_to_hash(V1_OFFER_TX).unwrap(),
+            height: Some(600000),
+            timestamp: 1568000000,
+            tx_type: TxType::SLP {
+                token_hash: V1_TOKEN_HASH,
+                token_type: 1,
+                slp_type: SLPTxType::Send,
+            },
+            inputs: vec![],
+            outputs: vec![
+                output(0, OutputType::OpReturn),
+                output(750, OutputType::Address(v1_receiving_address())),
+                output(250, OutputType::Address(
+                    Address::from_slice(AddressType::P2SH, &contract_hash).unwrap()
+                )),
+            ],
+        }
+    }
+
+    /// The SLPDB entry of `v1_offer_tx`, advertising the offer with version op `version_op`.
+    fn v1_offer_entry(version_op: i32) -> tx_result::TxEntry {
+        serde_json::from_str(&format!(r#"{{
+            "blk": {{"t": 1568000000, "i": 600000}},
+            "tx": {{"h": "{offer_tx}"}},
+            "in": [{{
+                "e": {{"h": "{funding_tx}", "i": 3, "a": null}},
+                "b0": "{lokad}",
+                "b1": {{"op": {version_op}}},
+                "b2": "{power}",
+                "b3": "{price}",
+                "b4": "{address}"
+            }}],
+            "out": [],
+            "slp": {{
+                "valid": true,
+                "detail": {{
+                    "decimals": 2,
+                    "tokenIdHex": "{token_id}",
+                    "transactionType": "SEND",
+                    "versionType": 1,
+                    "outputs": []
+                }}
+            }}
+        }}"#,
+            offer_tx = V1_OFFER_TX,
+            funding_tx = V1_FUNDING_TX,
+            lokad = base64::encode("EXCH"),
+            version_op = version_op,
+            power = base64::encode(&[0u8, 0]),
+            price = base64::encode(&1000u32.to_be_bytes()),
+            address = base64::encode(v1_receiving_address().bytes()),
+            token_id = tx_hash_to_hex(&V1_TOKEN_HASH),
+        )).unwrap()
+    }
+
+    #[test]
+    fn exch_v1_offer_from_entry() {
+        let config = SLPDEXConfig::default();
+        let tx = v1_offer_tx(&ExchV1, &config);
+        let offer = TradeOffer::from_entry(&tx, &v1_offer_entry(0x51), &config, 2).unwrap();
+        assert_eq!(offer.contract_version, 1);
+        assert_eq!(offer.output_idx, Some(2));
+        assert_eq!(offer.fee_profile, None);
+        assert_eq!(offer.sell_amount_token.base_amount(), 250);
+        assert_eq!(offer.script_price, 1000);
+        assert_eq!(offer.price_per_token, 100_000);
+        assert!(!offer.is_inverted);
+        assert_eq!(offer.receiving_address, v1_receiving_address());
+        assert_eq!(offer.input_tx, cashcontracts::tx_hex_to_hash(V1_FUNDING_TX).unwrap());
+        assert_eq!(offer.input_idx, 3);
+    }
+
+    #[test]
+    fn exch_v1_contract_not_matched_as_v2() {
+        let config = SLPDEXConfig::default();
+        // a v1 contract advertised as v2 has no matching output, the fee changes the script
+        let tx = v1_offer_tx(&ExchV1, &config);
+        let offer = TradeOffer::from_entry(&tx, &v1_offer_entry(0x52), &config, 2).unwrap();
+        assert_eq!(offer.contract_version, 2);
+        assert_eq!(offer.output_idx, None);
+
+        let tx = v1_offer_tx(&ExchV2, &config);
+        let offer = TradeOffer::from_entry(&tx, &v1_offer_entry(0x52), &config, 2).unwrap();
+        assert_eq!(offer.output_idx, Some(2));
+        assert_eq!(offer.fee_profile, Some(DEFAULT_FEE_PROFILE.to_string()));
+    }
+
+    #[test]
+    fn exch_unknown_version_not_parsed() {
+        let config = SLPDEXConfig::default();
+        let tx = v1_offer_tx(&ExchV1, &config);
+        assert!(TradeOffer::from_entry(&tx, &v1_offer_entry(0x53), &config, 2).is_none());
+    }
+}
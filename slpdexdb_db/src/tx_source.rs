@@ -2,6 +2,7 @@ use cashcontracts::{Address, tx_hash_to_hex};
 use json::{JsonValue, object, array};
 use slpdexdb_base::SLPDEXConfig;
 use crate::endpoint::Endpoint;
+use crate::trade_offer_parser::trade_offer_parsers;
 
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -119,6 +120,8 @@ pub mod tx_result {
         #[serde(rename = "versionType")]
         pub version_type: i32,
         pub outputs: Vec<TxSLPOutput>,
+        #[serde(rename = "batonVout", default)]
+        pub baton_vout: Option<i32>,
     }
     #[derive(Serialize, Deserialize, Debug)]
     pub struct TxSLPOutput {
@@ -176,7 +179,11 @@ impl TxFilter {
                 match filter {
                     TxFilter::Exch => vec![
                         ("in.b0", JsonValue::String(config.exch_lokad_b64.to_string())),
-                        ("in.b1", object!{"op" => 0x50 + config.exch_version}),
+                        ("in.b1", object!{"$in" => JsonValue::Array(
+                            trade_offer_parsers().iter()
+                                .map(|parser| object!{"op" => 0x50 + parser.version()})
+                                .collect()
+                        )}),
                     ],
                     TxFilter::TokenId(token_id) => vec![
                         ("slp.detail.tokenIdHex", JsonValue::String(tx_hash_to_hex(token_id)))
@@ -310,7 +317,6 @@ impl TxSource {
             query["r"] = object!{"f" => "[.[] | {tx: .tx, slp: .slp} ]"};
         }
         let query_json = json::stringify(query);
-        println!("{}", query_json);
         let query_b64 = base64::encode(&query_json);
         reqwest::get(&format!("{}{}", endpoint_url, query_b64))?.text()
         //println!("{}", text);
@@ -370,6 +376,33 @@ impl TxSource {
         Ok(results)
     }
 
+    /// Raw bytes of the given txs; the tx sources above only return decoded txs.
+    pub fn request_raw_txs(&self, tx_hashes: &[[u8; 32]]) -> slpdexdb_base::Result<Vec<([u8; 32], Vec<u8>)>> {
+        const MAX_TXIDS_PER_REQUEST: usize = 20;
+        let client = reqwest::Client::new();
+        let mut raw_txs = Vec::with_capacity(tx_hashes.len());
+        for chunk in tx_hashes.chunks(MAX_TXIDS_PER_REQUEST) {
+            let query = object!{
+                "txids" => JsonValue::Array(
+                    chunk.iter().map(|tx_hash| JsonValue::String(tx_hash_to_hex(tx_hash))).collect()
+                ),
+                "verbose" => false,
+            };
+            let result_json = client
+                .post(&format!("{}rawtransactions/getRawTransaction", self.endpoint.rest_endpoint_url))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(json::stringify(query))
+                .send()?
+                .text()?;
+            let raw_hexes = serde_json::from_str::<Vec<String>>(&result_json)
+                .map_err(|err| format!("unexpected raw tx response: {}", err))?;
+            for (tx_hash, raw_hex) in chunk.iter().zip(raw_hexes) {
+                raw_txs.push((*tx_hash, hex::decode(&raw_hex)?));
+            }
+        }
+        Ok(raw_txs)
+    }
+
     pub fn request_slp_tx_validity(&self, filters: &[TxFilter], config: &SLPDEXConfig,
                                    confirmedness: Confirmedness)
             -> reqwest::Result<Vec<tx_result::TxSLPValidity>> {
@@ -12,6 +12,8 @@ pub enum UpdateSubjectType {
     AddressHistory = 3,
     AddressUTXOs = 4,
     TokenStats = 5,
+    TokenHistory = 6,
+    ExchUtxos = 7,
 }
 
 #[derive(Clone, Debug)]
@@ -59,6 +61,12 @@ impl UpdateHistory {
                 );
                 filters.push(TxFilter::Address(Address::from_bytes(AddressType::P2PKH, address_hash)));
             },
+            TokenHistory => {
+                let token_hash = tx_hash_from_slice(
+                    self.subject.hash.as_ref().expect("Subject hash must be present for TokenHistory")
+                );
+                filters.push(TxFilter::TokenId(token_hash));
+            },
             _ => unimplemented!(),
         };
         filters
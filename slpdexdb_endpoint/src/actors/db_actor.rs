@@ -11,9 +11,8 @@ pub struct DbActor {
 }
 
 impl DbActor {
-    pub fn create() -> Result<Addr<Self>, Error> {
-        let connection_str = std::env::var("DATABASE_URL").expect("DATABASE_URL");
-        let connection = PgConnection::establish(&connection_str)?;
+    pub fn create(database_url: &str) -> Result<Addr<Self>, Error> {
+        let connection = PgConnection::establish(database_url)?;
         let db = Db::new(connection);
         Ok(DbActor::start(DbActor { db }))
     }
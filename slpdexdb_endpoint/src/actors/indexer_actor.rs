@@ -0,0 +1,211 @@
+use actix::prelude::*;
+use std::convert::identity;
+use std::sync::{Mutex, Arc};
+use std::time::Duration;
+use slpdexdb_base::{Error, ErrorKind, TxRejection, SLPDEXConfig};
+use slpdexdb_db::Db;
+use cashcontracts::{Address, Tx, tx_hash_to_hex};
+use slpdexdb_node::actors::IncomingMsg;
+use slpdexdb_node::messages::TxMessage;
+use crate::msg::{ActivateAddress, DeactivateAddress, ResyncAddress, ActivateAccount, ResyncAccount,
+                 ProcessTransactions, CheckUtxoSetExch, IndexTx, SubmittedTx, CheckSubmittedTx, RelayTx,
+                 SetPeers, RunResyncJob, CheckPendingTakes};
+use crate::actors::{ResyncActor, PeersActor};
+
+const UTXO_SET_EXCH_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PENDING_TAKE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const OUTBOX_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RESYNC_JOB_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const RESYNC_JOB_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RESYNC_JOB_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Writes of the indexer: txs from peers and submitted ones, and activating addresses.
+pub struct IndexerActor {
+    db: Arc<Mutex<Db>>,
+    config: SLPDEXConfig,
+    resync: Addr<ResyncActor>,
+    peers: Option<Addr<PeersActor>>,
+}
+
+impl IndexerActor {
+    pub fn start_with(db: Arc<Mutex<Db>>,
+                      config: SLPDEXConfig,
+                      resync: Addr<ResyncActor>) -> Addr<Self> {
+        Self::start(IndexerActor { db, config, resync, peers: None })
+    }
+
+    /// Runs queued resync jobs one message at a time, so txs aren't held up by a backlog,
+    /// and polls again once none is due.
+    fn _run_resync_jobs(&mut self, ctx: &mut Context<Self>) {
+        self.resync.send(RunResyncJob)
+            .from_err()
+            .and_then(identity)
+            .into_actor(self)
+            .then(|result: Result<_, Error>, _act, ctx| {
+                let delay = match result {
+                    Ok(Some(_)) => Duration::from_secs(0),
+                    Ok(None) => RESYNC_JOB_POLL_INTERVAL,
+                    Err(err) => {
+                        eprintln!("running resync job failed: {}", err);
+                        RESYNC_JOB_POLL_INTERVAL
+                    },
+                };
+                ctx.run_later(delay, |act, ctx| act._run_resync_jobs(ctx));
+                actix::fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for IndexerActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(UTXO_SET_EXCH_CHECK_INTERVAL, |act, _ctx| {
+            act.resync.do_send(CheckUtxoSetExch);
+        });
+        ctx.run_interval(PENDING_TAKE_CHECK_INTERVAL, |act, _ctx| {
+            act.resync.do_send(CheckPendingTakes);
+        });
+        ctx.run_interval(OUTBOX_PRUNE_INTERVAL, |act, _ctx| {
+            act.db.lock().unwrap().prune_outbox(act.config.event_outbox_retention_secs)
+                .map_err(|err| eprintln!("pruning the outbox failed: {}", err))
+                .unwrap_or(0);
+        });
+        ctx.run_interval(RESYNC_JOB_PRUNE_INTERVAL, |act, _ctx| {
+            act.db.lock().unwrap().prune_resync_jobs(RESYNC_JOB_RETENTION_SECS)
+                .map_err(|err| eprintln!("pruning resync jobs failed: {}", err))
+                .unwrap_or(0);
+        });
+        self._run_resync_jobs(ctx);
+    }
+}
+
+impl Handler<IncomingMsg<TxMessage>> for IndexerActor {
+    type Result = Response<(), Error>;
+
+    fn handle(&mut self, msg: IncomingMsg<TxMessage>, _ctx: &mut Self::Context) -> Self::Result {
+        let tx = msg.0.tx.clone();
+        Response::fut(
+            self.resync
+                .send(ProcessTransactions {
+                    db: self.db.clone(),
+                    txs: vec![tx],
+                    force_index: false,
+                    config: self.config.clone(),
+                })
+                .from_err()
+                .and_then(identity)
+        )
+    }
+}
+
+impl Handler<SetPeers> for IndexerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetPeers, _ctx: &mut Self::Context) -> Self::Result {
+        self.peers = Some(msg.0);
+    }
+}
+
+impl Handler<IndexTx> for IndexerActor {
+    type Result = Response<SubmittedTx, Error>;
+
+    fn handle(&mut self, msg: IndexTx, _ctx: &mut Self::Context) -> Self::Result {
+        let IndexTx { raw_tx, allow_burn } = msg;
+        let raw_tx = match hex::decode(raw_tx.trim()) {
+            Ok(raw_tx) => raw_tx,
+            Err(_) => return Response::reply(Err(ErrorKind::TxRejected(TxRejection::InvalidHex).into())),
+        };
+        let mut stream = std::io::Cursor::new(&raw_tx);
+        let tx = match Tx::read_from_stream(&mut stream) {
+            Ok(ref _tx) if (stream.position() as usize) < raw_tx.len() => return Response::reply(Err(
+                ErrorKind::TxRejected(TxRejection::Malformed("trailing bytes".to_string())).into()
+            )),
+            Ok(tx) => tx,
+            Err(err) => return Response::reply(Err(
+                ErrorKind::TxRejected(TxRejection::Malformed(err.to_string())).into()
+            )),
+        };
+        let tx_hash = tx.hash();
+        let peers = match self.peers.clone() {
+            Some(peers) => peers,
+            None => return Response::reply(Err(ErrorKind::TxRejected(TxRejection::NoPeers).into())),
+        };
+        let resync = self.resync.clone();
+        let process = ProcessTransactions {
+            db: self.db.clone(),
+            txs: vec![tx.clone()],
+            force_index: true,
+            config: self.config.clone(),
+        };
+        Response::fut(
+            self.resync
+                .send(CheckSubmittedTx(tx.clone()))
+                .from_err()
+                .and_then(identity)
+                .and_then(move |inputs| {
+                    let burns = inputs.iter().any(|input| input.burn.is_some());
+                    if burns && !allow_burn {
+                        return Err(ErrorKind::TxRejected(TxRejection::BurnsTokens(inputs)).into())
+                    }
+                    Ok(inputs)
+                })
+                // relayed before it's indexed, so a tx no node takes never touches the index
+                .and_then(move |inputs| {
+                    peers.send(RelayTx(tx))
+                        .from_err()
+                        .and_then(move |num_relayed| {
+                            if num_relayed == 0 {
+                                eprintln!("no peers to relay {}", tx_hash_to_hex(&tx_hash));
+                                return Err(ErrorKind::TxRejected(TxRejection::NoPeers).into())
+                            }
+                            Ok(inputs)
+                        })
+                })
+                .and_then(move |inputs| {
+                    resync.send(process)
+                        .from_err()
+                        .and_then(identity)
+                        .map(|_| inputs)
+                })
+                .map(move |inputs| SubmittedTx { tx_hash, inputs })
+        )
+    }
+}
+
+impl Handler<ActivateAddress> for IndexerActor {
+    type Result = Response<(), Error>;
+
+    fn handle(&mut self, msg: ActivateAddress, _ctx: &mut Self::Context) -> Self::Result {
+        let ActivateAddress(address) = msg;
+        let resync = self.resync.clone();
+        Response::fut(
+            futures::future::result(self.db.lock().unwrap().set_address_active(&address, true)).from_err()
+                .and_then(move |_| resync.send(ResyncAddress(address)).from_err())
+                .and_then(identity)
+        )
+    }
+}
+
+impl Handler<ActivateAccount> for IndexerActor {
+    type Result = Response<Vec<Address>, Error>;
+
+    fn handle(&mut self, msg: ActivateAccount, _ctx: &mut Self::Context) -> Self::Result {
+        let ActivateAccount(xpub) = msg;
+        Response::fut(
+            self.resync.send(ResyncAccount(xpub))
+                .from_err()
+                .and_then(identity)
+        )
+    }
+}
+
+impl Handler<DeactivateAddress> for IndexerActor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeactivateAddress, _ctx: &mut Self::Context) -> Self::Result {
+        let DeactivateAddress(address) = msg;
+        Ok(self.db.lock().unwrap().set_address_active(&address, false)?)
+    }
+}
@@ -1,6 +1,7 @@
 mod db_actor;
 mod resync_actor;
 mod tx_actor;
+mod indexer_actor;
 mod peers_actor;
 mod ws_actor;
 pub mod broadcast_actor;
@@ -8,5 +9,6 @@ pub mod broadcast_actor;
 pub use db_actor::*;
 pub use resync_actor::*;
 pub use tx_actor::*;
+pub use indexer_actor::*;
 pub use peers_actor::*;
 pub use ws_actor::*;
@@ -2,26 +2,27 @@ use actix::prelude::*;
 use tokio_tcp::TcpStream;
 use std::convert::identity;
 use slpdexdb_base::Error;
-use slpdexdb_node::actors::{NodeActor, IncomingMsg};
+use slpdexdb_node::actors::{NodeActor, IncomingMsg, OutgoingMsg};
+use slpdexdb_node::message::NodeMessage;
 use slpdexdb_node::DbActor;
 use slpdexdb_node::msg::Subscribe;
 use slpdexdb_node::messages::TxMessage;
 
 
-use crate::actors::TxActor;
-use crate::msg::ConnectToPeer;
+use crate::actors::IndexerActor;
+use crate::msg::{ConnectToPeer, RelayTx};
 
 
 pub struct PeersActor {
-    tx_actor: Addr<TxActor>,
+    indexer: Addr<IndexerActor>,
     db_actor: Addr<DbActor>,
     nodes: Vec<Addr<NodeActor>>,
 }
 
 impl PeersActor {
-    pub fn new(tx_actor: Addr<TxActor>, db_actor: Addr<DbActor>) -> Self {
+    pub fn new(indexer: Addr<IndexerActor>, db_actor: Addr<DbActor>) -> Self {
         PeersActor {
-            tx_actor,
+            indexer,
             db_actor,
             nodes: Vec::new(),
         }
@@ -67,10 +68,22 @@ impl Handler<PeerConnected> for PeersActor {
     }
 }
 
+impl Handler<RelayTx> for PeersActor {
+    type Result = usize;
+
+    fn handle(&mut self, msg: RelayTx, _ctx: &mut Self::Context) -> Self::Result {
+        let packet = TxMessage { tx: msg.0 }.packet();
+        self.nodes.retain(|node| node.connected());
+        self.nodes.iter()
+            .filter(|node| node.try_send(OutgoingMsg(packet.clone())).is_ok())
+            .count()
+    }
+}
+
 impl Handler<IncomingMsg<TxMessage>> for PeersActor {
     type Result = Response<(), Error>;
 
     fn handle(&mut self, msg: IncomingMsg<TxMessage>, _ctx: &mut Self::Context) -> Self::Result {
-        Response::fut(self.tx_actor.send(msg).from_err().and_then(identity))
+        Response::fut(self.indexer.send(msg).from_err().and_then(identity))
     }
 }
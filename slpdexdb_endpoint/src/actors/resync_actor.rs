@@ -1,113 +1,62 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::sync::Arc;
 use std::collections::HashSet;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use actix::prelude::*;
-use cashcontracts::Address;
-use slpdexdb_base::{Error, SLPDEXConfig};
-use slpdexdb_db::{Db, TxSource, TokenSource, UpdateSubject, UpdateSubjectType, UpdateHistory, TxHistory, Token, OutputType, Confirmedness};
-use crate::msg::{ResyncAddress, ProcessTransactions, NewTransactions};
+use diesel::Connection;
+use cashcontracts::{Address, tx_hash_to_hex};
+use slpdexdb_base::{Error, ErrorKind, TxRejection, SLPInputCheck, SLPDEXConfig, ExtendedPubKey};
+use slpdexdb_db::{Db, TxSource, TxHistory, ResyncJob, ResyncJobState, ResyncTask,
+                  resync, PRIORITY_INTERACTIVE, PRIORITY_DEFAULT};
+use crate::msg::{ResyncAddress, ResyncAccount, ResyncToken, CheckUtxoSetExch, ProcessTransactions,
+                 CheckSubmittedTx, RunResyncJob, CheckPendingTakes};
 
-
-fn _resync(db: &Db, config: &SLPDEXConfig) -> Result<(), Error> {
-    _resync_tokens(db)?;
-    _resync_trade_offers(db, config, true)?;
-    _resync_trade_offers(db, config, false)?;
-    Ok(())
-}
-
-fn _resync_tokens(db: &Db) -> Result<(), Error> {
-    let token_source = TokenSource::new();
-    loop {
-        let current_height = db.header_tip()?.map(|(_, height)| height).unwrap_or(0);
-        let subject = UpdateSubject {
-            subject_type: UpdateSubjectType::Token,
-            hash: None,
-            is_confirmed: true,
-        };
-        let last_update = db.last_update(subject.clone())?
-                .unwrap_or(UpdateHistory::initial(subject));
-        let token_entries = token_source.request_tokens(&last_update.next_filters())?;
-        let tokens = token_entries.into_iter()
-            .filter_map(|token_entry| {
-                Token::from_entry(token_entry).map_err(|err| eprintln!("token error: {}", err)).ok()
-            })
-            .collect::<Vec<_>>();
-        if tokens.len() == 0 {
-            break
-        }
-        for token in tokens.iter() {
-            println!("try adding token {:?}", token);
-            println!("document_uri: {:?}", token.document_uri.as_ref().map(|x| hex::encode(x.as_bytes())));
-            db.add_tokens(&[token.clone()])?;
-        }
-        db.add_update_history(&UpdateHistory::from_tokens(&tokens, current_height))?;
-    }
-    Ok(())
-}
-
-fn _resync_trade_offers(db: &Db, config: &SLPDEXConfig, is_confirmed: bool) -> Result<(), Error> {
-    let tx_source = TxSource::new();
-    loop {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        let current_height = db.header_tip()?.map(|(_, height)| height).unwrap_or(0);
-        let confirmedness = if is_confirmed { Confirmedness::Confirmed }
-                            else { Confirmedness::Unconfirmed };
-        let subject = UpdateSubject {
-            subject_type: UpdateSubjectType::Exch,
-            hash: None,
-            is_confirmed,
-        };
-        let last_update = db.last_update(subject.clone())?
-                .unwrap_or_else(|| UpdateHistory::initial(subject.clone()));
-        let tx_entries = tx_source.request_txs(&last_update.next_filters(), config, confirmedness)?;
-        let history = TxHistory::from_entries(&tx_entries, timestamp as i64, config);
-        if history.txs.len() == 0 {
-            break
+/// Rejects submitted txs whose SLP output doesn't parse.
+fn _check_submitted_slp_outputs(txs: &[cashcontracts::Tx], db: &Db) -> Result<(), Error> {
+    for tx in txs {
+        if let Some(output) = tx.outputs().get(0) {
+            TxHistory::_process_slp_output(&output.script, db)
+                .map_err(|err| ErrorKind::TxRejected(TxRejection::InvalidSLP(err.to_string())))?;
         }
-        db.add_tx_history(&history)?;
-        db.add_update_history(
-            &UpdateHistory::from_tx_history(&history, subject, current_height)
-        )?;
     }
-    db.update_utxo_set_exch()?;
     Ok(())
 }
 
-fn _resync_address(db: &Db, config: &SLPDEXConfig, address: &Address, is_confirmed: bool) -> Result<(), Error> {
-    loop {
-        let tx_source = TxSource::new();
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        let current_height = db.header_tip()?.map(|(_, height)| height).unwrap_or(0);
-        let confirmedness = if is_confirmed { Confirmedness::Confirmed }
-                            else { Confirmedness::Unconfirmed };
-        let subject = UpdateSubject {
-            subject_type: UpdateSubjectType::AddressHistory,
-            hash: Some(address.bytes().to_vec()),
-            is_confirmed,
-        };
-        let last_update = db.last_update(subject.clone())?
-            .unwrap_or(UpdateHistory::initial(subject.clone()));
-        println!("last update: {}", last_update);
-        let tx_entries = tx_source.request_txs(&last_update.next_filters(), config, confirmedness)?;
-        let history = TxHistory::from_entries(&tx_entries, timestamp as i64, config);
-        if history.txs.len() > 0 {
-            db.add_tx_history(&history)?;
+/// Rejects submitted txs which validation downgraded or whose EXCH input isn't a valid offer.
+fn _check_submitted_history(txs: &[cashcontracts::Tx], was_slp: &[bool], history: &TxHistory,
+                            config: &SLPDEXConfig) -> Result<(), Error> {
+    use cashcontracts::Op;
+    for (idx, (tx, historic_tx)) in txs.iter().zip(history.txs.iter()).enumerate() {
+        if was_slp[idx] && historic_tx.tx_type.token_hash().is_none() {
+            return Err(ErrorKind::TxRejected(TxRejection::InsufficientSLPInputs).into());
         }
-        db.add_update_history(
-            &UpdateHistory::from_tx_history(
-                &history,
-                subject,
-                current_height,
-            )
-        )?;
-        if history.txs.len() == 0 {
-            break
+        let has_exch_input = tx.inputs().iter().any(|input| {
+            match input.script.ops().get(0) {
+                Some(Op::Push(lokad)) => lokad.as_slice() == config.exch_lokad.as_bytes(),
+                _ => false,
+            }
+        });
+        if !has_exch_input { continue }
+        match history.trade_offers.get(&idx) {
+            None => return Err(ErrorKind::TxRejected(TxRejection::InvalidTradeOffer(
+                "EXCH input can't be decoded".to_string()
+            )).into()),
+            Some(trade_offer) if trade_offer.output_idx.is_none() => {
+                return Err(ErrorKind::TxRejected(TxRejection::InvalidTradeOffer(
+                    "no output matches the offer's contract".to_string()
+                )).into())
+            },
+            Some(_) => {},
         }
     }
-    db.update_utxo_set(&address)?;
     Ok(())
 }
 
+// bursts of txs of a token coalesce into one refresh of its stats
+const TOKEN_STATS_DELAY_SECS: i64 = 10 * 60;
+// how often and how long to wait for an interactive job another worker is running
+const INTERACTIVE_JOB_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const INTERACTIVE_JOB_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
 pub struct ResyncActor {
     db: Db,
     config: SLPDEXConfig,
@@ -117,29 +66,204 @@ impl ResyncActor {
     pub fn new(db: Db, config: SLPDEXConfig) -> Self {
         ResyncActor { db, config }
     }
+
+    /// Claims and runs the next due job; failures are recorded in the queue for a retry.
+    fn _run_next_job(&self) -> Result<Option<ResyncJob>, Error> {
+        match self.db.claim_resync_job()? {
+            Some(job) => self._run_job(job),
+            None => Ok(None),
+        }
+    }
+
+    /// Runs a claimed job and records its outcome.
+    fn _run_job(&self, mut job: ResyncJob) -> Result<Option<ResyncJob>, Error> {
+        match resync::run_resync_task(&self.db, &self.config, &job.task) {
+            Ok(()) => {
+                self.db.finish_resync_job(job.id)?;
+                job.state = ResyncJobState::Done;
+                Ok(Some(job))
+            },
+            Err(err) => {
+                eprintln!("resync job {} ({}) failed: {}", job.id, job.task, err);
+                Ok(self.db.fail_resync_job(job.id, &err.to_string(),
+                                           self.config.resync_job_max_attempts,
+                                           self.config.resync_job_retry_delay_secs,
+                                           self.config.resync_job_max_retry_delay_secs)?)
+            },
+        }
+    }
+
+    /// Queues `task` as interactive job, or joins the queued one, and runs just that job. If
+    /// another worker runs it already, waits for it to finish.
+    fn _run_interactive_job(&self, task: &ResyncTask) -> Result<ResyncJob, Error> {
+        let job = self.db.enqueue_resync_job(task, PRIORITY_INTERACTIVE, 0)?;
+        let job_failed = |job: ResyncJob, reason: &str| -> Error {
+            ErrorKind::ResyncJobFailed(job.id, job.last_error.unwrap_or_else(|| reason.to_string()))
+                .into()
+        };
+        let started = Instant::now();
+        loop {
+            let current = self.db.resync_job(job.id)?
+                .ok_or_else(|| ErrorKind::ResyncJobFailed(job.id, "job vanished".to_string()))?;
+            match current.state {
+                ResyncJobState::Done => return Ok(current),
+                ResyncJobState::Failed => return Err(job_failed(current, "job failed")),
+                // failed meanwhile, the queue retries it after the backoff
+                ResyncJobState::Pending if current.attempts != job.attempts => {
+                    return Err(job_failed(current, "job failed"));
+                },
+                ResyncJobState::Pending => {
+                    if let Some(claimed) = self.db.claim_resync_job_by_id(job.id)? {
+                        return match self._run_job(claimed)? {
+                            Some(ran) if ran.state == ResyncJobState::Done => Ok(ran),
+                            Some(ran) => Err(job_failed(ran, "job failed")),
+                            None => Err(ErrorKind::ResyncJobFailed(
+                                job.id, "job vanished".to_string(),
+                            ).into()),
+                        };
+                    }
+                },
+                ResyncJobState::Running => {},
+            }
+            if started.elapsed() >= INTERACTIVE_JOB_TIMEOUT {
+                return Err(ErrorKind::ResyncJobFailed(
+                    job.id, "timed out waiting for another worker".to_string(),
+                ).into());
+            }
+            thread::sleep(INTERACTIVE_JOB_POLL_INTERVAL);
+        }
+    }
+
+    /// Queues a resync of tokens whose SLP UTXOs were skipped as the token wasn't known yet.
+    fn _enqueue_skipped_tokens(&self, token_hashes: &[[u8; 32]]) -> Result<(), Error> {
+        if token_hashes.len() == 0 { return Ok(()) }
+        self.db.enqueue_resync_job(&ResyncTask::Tokens, PRIORITY_DEFAULT, 0)?;
+        for token_hash in token_hashes {
+            eprintln!("utxo_slp: skipped UTXOs of unknown token {}, queueing its resync",
+                      tx_hash_to_hex(token_hash));
+            self.db.enqueue_resync_job(&ResyncTask::TokenUtxos(*token_hash), PRIORITY_DEFAULT, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Queues a refresh of the stats of the tokens of `history`.
+    fn _enqueue_token_stats(&self, history: &TxHistory) -> Result<(), Error> {
+        let token_hashes = history.txs.iter()
+            .filter_map(|tx| tx.tx_type.token_hash().cloned())
+            .collect::<HashSet<_>>();
+        for token_hash in token_hashes {
+            self.db.enqueue_resync_job(&ResyncTask::TokenStats(token_hash), PRIORITY_DEFAULT,
+                                       TOKEN_STATS_DELAY_SECS)?;
+        }
+        Ok(())
+    }
 }
 
 impl Actor for ResyncActor {
     type Context = SyncContext<Self>;
 
     fn started(&mut self, _ctx: &mut Self::Context) {
-        _resync(&self.db, &self.config)
-            .map_err(|err| eprintln!("resync failed: {}", err))
+        self.db.requeue_running_resync_jobs()
+            .map_err(|err| eprintln!("requeueing resync jobs failed: {}", err))
+            .unwrap_or(0);
+        resync::enqueue_resync(&self.db, &self.config)
+            .map_err(|err| eprintln!("queueing resync failed: {}", err))
             .unwrap_or(());
     }
 }
 
+impl Handler<RunResyncJob> for ResyncActor {
+    type Result = Result<Option<ResyncJob>, Error>;
+
+    fn handle(&mut self, _msg: RunResyncJob, _ctx: &mut Self::Context) -> Self::Result {
+        self._run_next_job()
+    }
+}
+
 impl Handler<ResyncAddress> for ResyncActor {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: ResyncAddress, _ctx: &mut Self::Context) -> Self::Result {
-        let address = msg.0;
-        _resync_address(&self.db, &self.config, &address, true)?;
-        _resync_address(&self.db, &self.config, &address, false)?;
+        self._run_interactive_job(&ResyncTask::Address(msg.0))?;
         Ok(())
     }
 }
 
+impl Handler<ResyncAccount> for ResyncActor {
+    type Result = Result<Vec<Address>, Error>;
+
+    fn handle(&mut self, msg: ResyncAccount, _ctx: &mut Self::Context) -> Self::Result {
+        // fail right away rather than on every attempt of the job
+        ExtendedPubKey::from_base58(&msg.0)?;
+        let job = self._run_interactive_job(&ResyncTask::Account(msg.0.clone()))?;
+        let account = self.db.hd_account(&msg.0)?
+            .ok_or_else(|| ErrorKind::ResyncJobFailed(job.id, "account vanished".to_string()))?;
+        Ok(self.db.hd_account_addresses(account)?)
+    }
+}
+
+impl Handler<CheckUtxoSetExch> for ResyncActor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _msg: CheckUtxoSetExch, _ctx: &mut Self::Context) -> Self::Result {
+        resync::check_utxo_set_exch(&self.db)
+    }
+}
+
+impl Handler<CheckPendingTakes> for ResyncActor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _msg: CheckPendingTakes, _ctx: &mut Self::Context) -> Self::Result {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        for take in resync::reverted_pending_takes(&self.db, &self.config, timestamp)? {
+            self.db.connection().transaction::<_, Error, _>(|| {
+                let (change, relevant_addresses) =
+                    match self.db.revert_pending_take(&take, timestamp)? {
+                        Some(reverted) => reverted,
+                        None => return Ok(()),
+                    };
+                if let Some(token_hash) = change.token_hash {
+                    if self.config.is_slp_utxo_indexed(&token_hash) {
+                        self.db.update_utxo_set_slp(&token_hash)?;
+                    }
+                }
+                self.db.add_tx_history_events(&TxHistory::default(), &[change],
+                                              &relevant_addresses, timestamp)?;
+                Ok(())
+            })?;
+        }
+        self.db.update_utxo_set_exch_incremental()?;
+        Ok(())
+    }
+}
+
+impl Handler<ResyncToken> for ResyncActor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ResyncToken, _ctx: &mut Self::Context) -> Self::Result {
+        self.db.enqueue_resync_job(&ResyncTask::TokenUtxos(msg.0), PRIORITY_DEFAULT, 0)?;
+        Ok(())
+    }
+}
+
+impl Handler<CheckSubmittedTx> for ResyncActor {
+    type Result = Result<Vec<SLPInputCheck>, Error>;
+
+    fn handle(&mut self, msg: CheckSubmittedTx, _ctx: &mut Self::Context) -> Self::Result {
+        let tx_source = TxSource::new();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let txs = [msg.0];
+        _check_submitted_slp_outputs(&txs, &self.db)?;
+        let mut history = TxHistory::from_txs(&txs, timestamp, &self.config, &self.db)?;
+        let was_slp = history.txs.iter()
+            .map(|tx| tx.tx_type.token_hash().is_some())
+            .collect::<Vec<_>>();
+        history.validate_slp(&tx_source, &self.db, &self.config)?;
+        _check_submitted_history(&txs, &was_slp, &history, &self.config)?;
+        history.check_burns(0, &tx_source, &self.db, &self.config)
+    }
+}
+
 impl Handler<ProcessTransactions> for ResyncActor {
     type Result = Result<(), Error>;
 
@@ -147,38 +271,57 @@ impl Handler<ProcessTransactions> for ResyncActor {
         let tx_source = TxSource::new();
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
         let db = msg.db.lock().unwrap();
-        let mut history = TxHistory::from_txs(&msg.txs, timestamp, &msg.config, &*db);
-        let addresses = history.txs.iter()
-            .flat_map(|tx| {
-                tx.outputs.iter()
-                    .map(|output| output.output.clone())
-                    .chain(tx.inputs.iter().map(|input| input.output.clone()))
-                    .filter_map(|output| match output {
-                        OutputType::Address(address) => Some(address),
-                        _ => None,
-                    })
-            })
-            .collect::<Vec<_>>();
-        let subscribers_addresses = &msg.subscribers.lock().unwrap().subscribers_address;
-        let relevant_addresses = addresses.into_iter()
-            .filter(|address| subscribers_addresses.contains_key(address))
-            .collect::<HashSet<_>>();
-        if history.trade_offers.len() == 0 && relevant_addresses.len() == 0 {
+        if msg.force_index {
+            _check_submitted_slp_outputs(&msg.txs, &*db)?;
+        }
+        let mut history = TxHistory::from_txs(&msg.txs, timestamp, &msg.config, &*db)?;
+        if msg.force_index {
+            history.unverified = (0..history.txs.len()).collect();
+        }
+        // active rather than subscribed, so clients resuming later find their events
+        let relevant_addresses = db.active_addresses_among(&history.addresses())?;
+        let has_indexed_slp = history.txs.iter()
+            .filter_map(|tx| tx.tx_type.token_hash())
+            .any(|token_hash| msg.config.is_slp_utxo_indexed(token_hash));
+        if history.trade_offers.len() == 0 && relevant_addresses.len() == 0 && !has_indexed_slp &&
+                !msg.force_index && !db.spends_trade_offers(&history)? {
             return Ok(())
         }
+        let was_slp = history.txs.iter()
+            .map(|tx| tx.tx_type.token_hash().is_some())
+            .collect::<Vec<_>>();
         history.validate_slp(&tx_source, &*db, &msg.config)?;
-        db.add_tx_history(&history)?;
-        println!("txs valid.");
-        let new_transactions = NewTransactions {
-            now: timestamp,
-            subscribers: msg.subscribers.clone(),
-            tx_history: Arc::new(history),
-            db: msg.db.clone(),
-            relevant_addresses: Arc::new(relevant_addresses),
-        };
-        for broadcast in msg.broadcasts.iter() {
-            broadcast.do_send(new_transactions.clone()).unwrap();  // TODO: handle error
+        if msg.force_index {
+            _check_submitted_history(&msg.txs, &was_slp, &history, &msg.config)?;
         }
+        let (remove_utxos, add_utxos) = history.utxo_delta(&relevant_addresses, &msg.config);
+        // listeners are notified on commit, so subscribers only see state the db holds
+        let (trade_offer_states, skipped_tokens) = db.connection().transaction::<_, Error, _>(|| {
+            let trade_offer_states = db.add_tx_history(&history)?;
+            let skipped_tokens = db.apply_utxo_delta(&remove_utxos, &add_utxos)?;
+            db.add_tx_history_events(&history, &trade_offer_states, &relevant_addresses,
+                                     timestamp)?;
+            Ok((trade_offer_states, skipped_tokens))
+        })?;
+        self._enqueue_skipped_tokens(&skipped_tokens)
+            .map_err(|err| eprintln!("queueing token resyncs failed: {}", err))
+            .unwrap_or(());
+        if msg.config.store_raw_txs {
+            db.add_raw_txs(&msg.txs.iter()
+                .map(|tx| {
+                    let mut raw = Vec::new();
+                    tx.write_to_stream(&mut raw).unwrap();
+                    (tx.hash(), raw)
+                })
+                .collect::<Vec<_>>()
+            ).unwrap_or_else(|err| eprintln!("can't store raw txs: {}", err));
+        }
+        if trade_offer_states.len() > 0 {
+            db.update_trade_fees(&msg.config)?;
+        }
+        self._enqueue_token_stats(&history)
+            .map_err(|err| eprintln!("queueing token stats failed: {}", err))
+            .unwrap_or(());
         Ok(())
     }
 }
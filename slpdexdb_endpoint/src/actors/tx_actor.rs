@@ -1,81 +1,109 @@
 use actix::prelude::*;
 use std::collections::{HashSet, HashMap};
-use std::convert::identity;
-use slpdexdb_base::{Error, SLPDEXConfig};
-use slpdexdb_db::{Db, Utxo, TxDelta, TradeOffer};
-use slpdexdb_node::actors::IncomingMsg;
-use slpdexdb_node::messages::TxMessage;
-use crate::msg::{ActivateAddress, DeactivateAddress, ResyncAddress, FetchAddressUtxos,
-                 FetchAddressTxDeltas, FetchTradeOfferUtxos, SubscribeToEvent, UnsubscribeFromEvent,
-                 TxEvent, NewTransactions, ProcessTransactions};
-use crate::actors::ResyncActor;
-use crate::actors::broadcast_actor::{UpdateDbUtxosActor, BroadcastAddressUtxosActor,
-                                     BroadcastTradeOfferUtxosActor, BroadcastTxHistoryActor,
-                                     BroadcastActor};
+use slpdexdb_base::{Error, ErrorKind, TokenError, TxBuildError, SLPDEXConfig, SLPAmount};
+use slpdexdb_base::convert_numeric::str_to_rational;
+use slpdexdb_db::{Db, Utxo, TxDeltaPage, TradeOffer, TokenDistribution, AddressBalance,
+                  BuiltTradeOffer, TradeOfferParams, build_trade_offer, SpentUtxo, FundingUtxo,
+                  BuiltAcceptOffers, AcceptOffersParams, build_accept_offers, Quote, QuoteTarget,
+                  TradeOfferFilter, TradeOfferState, TradeHistoryPage, EventSubject};
+use cashcontracts::tx_hash_to_hex;
+use json::JsonValue;
+use crate::msg::{ActivateAddress, ActivateAccount,
+                 FetchAddressUtxos,
+                 FetchAddressBalance,
+                 FetchTokenUtxos, FetchTokenDistribution, FetchAddressTxDeltas, FetchTradeOfferUtxos, SubscribeToEvent, UnsubscribeFromEvent,
+                 TxEvent, NewTransactions, BuildTradeOffer,
+                 AcceptTradeOffers, FetchQuote, SubmitTx,
+                 FetchTradeOfferStates, FetchAddressTradeHistory,
+                 FetchOutboxEvents, OutboxReplay, FetchLastOutboxSeq, OutboxNotified};
+use crate::actors::broadcast_actor::BroadcastActor;
+use crate::indexer_client::IndexerClient;
 
 use cashcontracts::Address;
 use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant};
+
+const MAX_REPLAY_EVENTS: i64 = 10_000;  // beyond this, a fresh snapshot is cheaper
+const TOKEN_DISTRIBUTION_CACHE_SIZE: usize = 256;
+const TOKEN_DISTRIBUTION_TTL: Duration = Duration::from_secs(60);  // mempool txs change it too
+const OUTBOX_ROUTE_BATCH_SIZE: i64 = 1000;
 
 pub struct TxSubscribers {
-    pub subscribers_address: HashMap<Address, HashSet<Recipient<TxEvent>>>,
-    pub subscribers_token: HashMap<[u8; 32], HashSet<Recipient<TxEvent>>>,
+    pub subscribers: HashMap<EventSubject, HashSet<Recipient<TxEvent>>>,
+    pub recipient_subjects: HashMap<Recipient<TxEvent>, HashSet<EventSubject>>,
+    pub recipient_addresses: HashMap<Recipient<TxEvent>, HashSet<Address>>,
+}
+
+impl TxSubscribers {
+    fn subscribe(&mut self, subject: EventSubject, recipient: Recipient<TxEvent>) {
+        self.subscribers
+            .entry(subject.clone())
+            .or_insert_with(HashSet::new)
+            .insert(recipient.clone());
+        self.recipient_subjects
+            .entry(recipient)
+            .or_insert_with(HashSet::new)
+            .insert(subject);
+    }
+
+    fn unsubscribe(&mut self, subject: &EventSubject, recipient: &Recipient<TxEvent>) {
+        if let Some(subs) = self.subscribers.get_mut(subject) {
+            subs.remove(recipient);
+            if subs.is_empty() {
+                self.subscribers.remove(subject);
+            }
+        }
+        if let Some(subjects) = self.recipient_subjects.get_mut(recipient) {
+            subjects.remove(subject);
+        }
+    }
 }
 
+/// Queries and subscriptions of an API server; writes are forwarded to the indexer.
 pub struct TxActor {
     db: Arc<Mutex<Db>>,
     config: SLPDEXConfig,
-    resync: Addr<ResyncActor>,
+    indexer: IndexerClient,
     subscribers: Arc<Mutex<TxSubscribers>>,
     broadcasts: Vec<Recipient<NewTransactions>>,
+    routed_seq: i64,  // newest outbox event routed to subscribers
+    token_distributions: HashMap<[u8; 32], (Instant, Arc<TokenDistribution>)>,
 }
 
 impl TxActor {
     pub fn start_with(db: Arc<Mutex<Db>>,
                       config: SLPDEXConfig,
-                      resync: Addr<ResyncActor>) -> Addr<Self> {
-        let broadcast = BroadcastActor::start(BroadcastActor);
+                      indexer: IndexerClient) -> Result<Addr<Self>, Error> {
         let broadcasts = vec![
-            UpdateDbUtxosActor::start(UpdateDbUtxosActor).recipient(),
-            BroadcastAddressUtxosActor::start(BroadcastAddressUtxosActor::new(broadcast.clone())).recipient(),
-            BroadcastTradeOfferUtxosActor::start(BroadcastTradeOfferUtxosActor::new(broadcast.clone())).recipient(),
-            BroadcastTxHistoryActor::start(BroadcastTxHistoryActor::new(broadcast.clone())).recipient(),
+            BroadcastActor::start(BroadcastActor).recipient(),
         ];
-        Self::start(TxActor {
-            db, config, resync,
+        let routed_seq = db.lock().unwrap().outbox_seq_range()?
+            .map(|(_, last)| last)
+            .unwrap_or(0);
+        Ok(Self::start(TxActor {
+            db, config, indexer,
             subscribers: Arc::new(Mutex::new(TxSubscribers {
-                subscribers_address: HashMap::new(),
-                subscribers_token: HashMap::new(),
+                subscribers: HashMap::new(),
+                recipient_subjects: HashMap::new(),
+                recipient_addresses: HashMap::new(),
             })),
             broadcasts,
-        })
+            routed_seq,
+            token_distributions: HashMap::new(),
+        }))
     }
 }
 
 impl Actor for TxActor {
     type Context = Context<Self>;
-
-    fn started(&mut self, _ctx: &mut Self::Context) {
-        //self.node.do_send(Subscribe::Tx(ctx.address().recipient()));
-    }
 }
 
-impl Handler<IncomingMsg<TxMessage>> for TxActor {
-    type Result = Response<(), Error>;
+impl Handler<SubmitTx> for TxActor {
+    type Result = Response<JsonValue, Error>;
 
-    fn handle(&mut self, msg: IncomingMsg<TxMessage>, _ctx: &mut Self::Context) -> Self::Result {
-        let tx = msg.0.tx.clone();
-        Response::fut(
-            self.resync
-                .send(ProcessTransactions {
-                    db: self.db.clone(),
-                    subscribers: self.subscribers.clone(),
-                    txs: vec![tx],
-                    config: self.config.clone(),
-                    broadcasts: self.broadcasts.clone(),
-                })
-                .from_err()
-                .and_then(identity)
-        )
+    fn handle(&mut self, msg: SubmitTx, _ctx: &mut Self::Context) -> Self::Result {
+        let SubmitTx { raw_tx, allow_burn } = msg;
+        Response::fut(self.indexer.submit_tx(raw_tx, allow_burn))
     }
 }
 
@@ -84,21 +112,16 @@ impl Handler<ActivateAddress> for TxActor {
 
     fn handle(&mut self, msg: ActivateAddress, _ctx: &mut Self::Context) -> Self::Result {
         let ActivateAddress(address) = msg;
-        let resync = self.resync.clone();
-        Response::fut(
-            futures::future::result(self.db.lock().unwrap().set_address_active(&address, true)).from_err()
-                .and_then(move |_| resync.send(ResyncAddress(address)).from_err())
-                .and_then(identity)
-        )
+        Response::fut(self.indexer.activate_address(&address))
     }
 }
 
-impl Handler<DeactivateAddress> for TxActor {
-    type Result = Result<(), Error>;
+impl Handler<ActivateAccount> for TxActor {
+    type Result = Response<Vec<Address>, Error>;
 
-    fn handle(&mut self, msg: DeactivateAddress, _ctx: &mut Self::Context) -> Self::Result {
-        let DeactivateAddress(address) = msg;
-        Ok(self.db.lock().unwrap().set_address_active(&address, false)?)
+    fn handle(&mut self, msg: ActivateAccount, _ctx: &mut Self::Context) -> Self::Result {
+        let ActivateAccount(xpub) = msg;
+        Response::fut(self.indexer.activate_account(&xpub))
     }
 }
 
@@ -106,17 +129,82 @@ impl Handler<FetchAddressUtxos> for TxActor {
     type Result = Result<Vec<Utxo>, Error>;
 
     fn handle(&mut self, msg: FetchAddressUtxos, _ctx: &mut Self::Context) -> Self::Result {
-        let FetchAddressUtxos(address) = msg;
-        Ok(self.db.lock().unwrap().utxos_address(&address)?)
+        let FetchAddressUtxos(addresses) = msg;
+        Ok(self.db.lock().unwrap().utxos_address(&addresses)?)
+    }
+}
+
+impl Handler<FetchAddressBalance> for TxActor {
+    type Result = Result<AddressBalance, Error>;
+
+    fn handle(&mut self, msg: FetchAddressBalance, _ctx: &mut Self::Context) -> Self::Result {
+        let FetchAddressBalance(addresses) = msg;
+        Ok(self.db.lock().unwrap().address_balance(&addresses)?)
+    }
+}
+
+impl Handler<FetchTokenUtxos> for TxActor {
+    type Result = Result<Vec<Utxo>, Error>;
+
+    fn handle(&mut self, msg: FetchTokenUtxos, _ctx: &mut Self::Context) -> Self::Result {
+        let FetchTokenUtxos(token_hash) = msg;
+        Ok(self.db.lock().unwrap().utxos_slp_token(&token_hash)?)
+    }
+}
+
+impl Handler<FetchTokenDistribution> for TxActor {
+    type Result = Result<Option<Arc<TokenDistribution>>, Error>;
+
+    fn handle(&mut self, msg: FetchTokenDistribution, _ctx: &mut Self::Context) -> Self::Result {
+        let FetchTokenDistribution(token_hash) = msg;
+        if !self.config.is_slp_utxo_indexed(&token_hash) {
+            return Err(ErrorKind::SlpUtxoIndexDisabled(tx_hash_to_hex(&token_hash)).into());
+        }
+        let height = self.db.lock().unwrap().header_tip()?.map(|(_, height)| height).unwrap_or(0);
+        let now = Instant::now();
+        let is_fresh = |(cached, distribution): &(Instant, Arc<TokenDistribution>)| {
+            distribution.height == height && now.duration_since(*cached) < TOKEN_DISTRIBUTION_TTL
+        };
+        // cached distributions are recomputed once a new block arrives or they expire
+        match self.token_distributions.get(&token_hash) {
+            Some(entry) if is_fresh(entry) => return Ok(Some(entry.1.clone())),
+            _ => {},
+        }
+        let holder_amounts = self.db.lock().unwrap().token_holder_amounts(&token_hash)?;
+        // shares are computed without holding the db lock
+        let distribution = holder_amounts
+            .map(|(token, amounts)| Arc::new(TokenDistribution::new(token, height, amounts)));
+        if let Some(distribution) = &distribution {
+            self.token_distributions.retain(|_, entry| is_fresh(entry));
+            if self.token_distributions.len() >= TOKEN_DISTRIBUTION_CACHE_SIZE {
+                let oldest = self.token_distributions.iter()
+                    .min_by_key(|(_, (cached, _))| *cached)
+                    .map(|(token_hash, _)| *token_hash);
+                if let Some(oldest) = oldest {
+                    self.token_distributions.remove(&oldest);
+                }
+            }
+            self.token_distributions.insert(token_hash, (now, distribution.clone()));
+        }
+        Ok(distribution)
     }
 }
 
 impl Handler<FetchAddressTxDeltas> for TxActor {
-    type Result = Result<Vec<TxDelta>, Error>;
+    type Result = Result<TxDeltaPage, Error>;
 
     fn handle(&mut self, msg: FetchAddressTxDeltas, _ctx: &mut Self::Context) -> Self::Result {
-        let FetchAddressTxDeltas(address) = msg;
-        Ok(self.db.lock().unwrap().address_tx_deltas(&address)?)
+        let FetchAddressTxDeltas(addresses, query) = msg;
+        Ok(self.db.lock().unwrap().address_tx_deltas(&addresses, &query)?)
+    }
+}
+
+impl Handler<FetchAddressTradeHistory> for TxActor {
+    type Result = Result<TradeHistoryPage, Error>;
+
+    fn handle(&mut self, msg: FetchAddressTradeHistory, _ctx: &mut Self::Context) -> Self::Result {
+        let FetchAddressTradeHistory { addresses, cursor, limit } = msg;
+        Ok(self.db.lock().unwrap().address_trade_history(&addresses, cursor, limit, &self.config)?)
     }
 }
 
@@ -126,21 +214,28 @@ impl Handler<SubscribeToEvent> for TxActor {
     fn handle(&mut self, msg: SubscribeToEvent, _ctx: &mut Self::Context) -> Self::Result {
         let mut subscribers = self.subscribers.lock().unwrap();
         match msg {
-            SubscribeToEvent::Address(address, recipient) => {
-                subscribers.subscribers_address
-                    .entry(address)
+            SubscribeToEvent::Addresses(subject, addresses, recipient) => {
+                subscribers.subscribe(subject, recipient.clone());
+                subscribers.recipient_addresses
+                    .entry(recipient)
                     .or_insert_with(HashSet::new)
-                    .insert(recipient);
+                    .extend(addresses);
             },
             SubscribeToEvent::Tokens(token_hashes, recipient) => {
-                for (_, subs) in subscribers.subscribers_token.iter_mut() {
-                    subs.remove(&recipient);
+                let token_subjects = subscribers.recipient_subjects.get(&recipient)
+                    .into_iter()
+                    .flatten()
+                    .filter(|subject| match subject {
+                        EventSubject::Token(_) => true,
+                        _ => false,
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for subject in token_subjects.iter() {
+                    subscribers.unsubscribe(subject, &recipient);
                 }
                 for token_hash in token_hashes {
-                    subscribers.subscribers_token
-                        .entry(token_hash)
-                        .or_insert_with(HashSet::new)
-                        .insert(recipient.clone());
+                    subscribers.subscribe(EventSubject::Token(token_hash), recipient.clone());
                 }
             },
         };
@@ -153,18 +248,180 @@ impl Handler<UnsubscribeFromEvent> for TxActor {
     fn handle(&mut self, msg: UnsubscribeFromEvent, _ctx: &mut Self::Context) -> Self::Result {
         let mut subscribers = self.subscribers.lock().unwrap();
         match &msg {
-            UnsubscribeFromEvent::Address(address, recipient) => {
-                subscribers.subscribers_address.get_mut(address).map(|subs| subs.remove(recipient));
+            UnsubscribeFromEvent::All(recipient) => {
+                let subjects = subscribers.recipient_subjects.remove(recipient).unwrap_or_default();
+                for subject in subjects.iter() {
+                    subscribers.unsubscribe(subject, recipient);
+                }
+                subscribers.recipient_addresses.remove(recipient);
             },
         }
     }
 }
 
+impl Handler<FetchOutboxEvents> for TxActor {
+    type Result = Result<OutboxReplay, Error>;
+
+    fn handle(&mut self, msg: FetchOutboxEvents, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.lock().unwrap();
+        let (first, last) = match db.outbox_seq_range()? {
+            Some(range) => range,
+            None => return Ok(OutboxReplay::SnapshotRequired),
+        };
+        // pruned events or a sequence number from another database can't be replayed
+        if msg.since > last || msg.since + 1 < first {
+            return Ok(OutboxReplay::SnapshotRequired);
+        }
+        let events = db.outbox_events(&msg.subjects, msg.since, MAX_REPLAY_EVENTS + 1)?;
+        if events.len() as i64 > MAX_REPLAY_EVENTS {
+            return Ok(OutboxReplay::SnapshotRequired);
+        }
+        Ok(OutboxReplay::Events(events))
+    }
+}
+
+impl Handler<OutboxNotified> for TxActor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _msg: OutboxNotified, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.lock().unwrap();
+        loop {
+            let events = db.outbox_events_after(self.routed_seq, OUTBOX_ROUTE_BATCH_SIZE)?;
+            self.routed_seq = match events.last() {
+                Some(last_event) => last_event.seq,
+                None => break,
+            };
+            let num_events = events.len() as i64;
+            let new_transactions = NewTransactions {
+                events: events.into_iter().map(Arc::new).collect(),
+                db: self.db.clone(),
+                subscribers: self.subscribers.clone(),
+            };
+            for broadcast in self.broadcasts.iter() {
+                // subscribers that miss events catch up by resuming with their last seq
+                if let Err(err) = broadcast.do_send(new_transactions.clone()) {
+                    eprintln!("outbox broadcast error: {}", err);
+                }
+            }
+            if num_events < OUTBOX_ROUTE_BATCH_SIZE {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Handler<FetchLastOutboxSeq> for TxActor {
+    type Result = Result<i64, Error>;
+
+    fn handle(&mut self, _msg: FetchLastOutboxSeq, _ctx: &mut Self::Context) -> Self::Result {
+        Ok(self.db.lock().unwrap().outbox_seq_range()?.map(|(_, last)| last).unwrap_or(0))
+    }
+}
+
+impl Handler<BuildTradeOffer> for TxActor {
+    type Result = Result<BuiltTradeOffer, Error>;
+
+    fn handle(&mut self, msg: BuildTradeOffer, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.lock().unwrap();
+        let token = db.token(&msg.token_hash)?
+            .ok_or_else(|| ErrorKind::TokenError(
+                TokenError::UnknownTokenId(tx_hash_to_hex(&msg.token_hash))
+            ))?;
+        let funding = _funding_utxos(&db, &msg.funding)?;
+        let params = TradeOfferParams {
+            sell_amount: SLPAmount::from_str_decimals(&msg.sell_amount, token.decimals as u32)?,
+            price_per_token: str_to_rational(&msg.price_per_token)?,
+            token,
+            receiving_address: msg.receiving_address,
+            change_address: msg.change_address,
+            funding,
+        };
+        build_trade_offer(&params, &self.config)
+    }
+}
+
+impl Handler<AcceptTradeOffers> for TxActor {
+    type Result = Result<BuiltAcceptOffers, Error>;
+
+    fn handle(&mut self, msg: AcceptTradeOffers, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.lock().unwrap();
+        let offers = msg.offers.iter()
+            .map(|outpoint| {
+                db.trade_offer_utxo(outpoint)?.ok_or_else(|| _unknown_utxo(outpoint))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let token_hash = match offers.first() {
+            Some(offer) => offer.token_hash,
+            None => return Err(ErrorKind::TxBuild(TxBuildError::UnknownUtxo("no offers".to_string())).into()),
+        };
+        let token = db.token(&token_hash)?
+            .ok_or_else(|| ErrorKind::TokenError(
+                TokenError::UnknownTokenId(tx_hash_to_hex(&token_hash))
+            ))?;
+        let params = AcceptOffersParams {
+            token,
+            offers,
+            receiving_address: msg.receiving_address,
+            change_address: msg.change_address,
+            funding: _funding_utxos(&db, &msg.funding)?,
+        };
+        build_accept_offers(&params, &self.config)
+    }
+}
+
+impl Handler<FetchQuote> for TxActor {
+    type Result = Result<Quote, Error>;
+
+    fn handle(&mut self, msg: FetchQuote, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.lock().unwrap();
+        let token = db.token(&msg.token_hash)?
+            .ok_or_else(|| ErrorKind::TokenError(
+                TokenError::UnknownTokenId(tx_hash_to_hex(&msg.token_hash))
+            ))?;
+        let target = match (msg.buy_amount, msg.spend_satoshis) {
+            (Some(buy_amount), _) => QuoteTarget::BuyTokens(
+                SLPAmount::from_str_decimals(&buy_amount, token.decimals as u32)?
+            ),
+            (None, Some(spend_satoshis)) => QuoteTarget::SpendSatoshis(spend_satoshis),
+            (None, None) => return Err(ErrorKind::TxBuild(TxBuildError::MissingQuoteTarget).into()),
+        };
+        let offers = db.trade_offer_utxos(TradeOfferFilter::TokenHash(msg.token_hash),
+                                          msg.fee_profile.as_ref().map(String::as_str))?;
+        Ok(Quote::plan(offers, &token, target, &self.config))
+    }
+}
+
+fn _unknown_utxo(outpoint: &SpentUtxo) -> Error {
+    ErrorKind::TxBuild(TxBuildError::UnknownUtxo(
+        format!("{}:{}", tx_hash_to_hex(&outpoint.tx_hash), outpoint.vout)
+    )).into()
+}
+
+fn _funding_utxos(db: &Db, outpoints: &[SpentUtxo]) -> Result<Vec<FundingUtxo>, Error> {
+    let funding = db.funding_utxos(outpoints)?;
+    if let Some(unknown) = outpoints.iter().find(|outpoint| {
+        !funding.iter().any(|funding| funding.utxo.tx_hash == outpoint.tx_hash &&
+                                      funding.utxo.vout == outpoint.vout)
+    }) {
+        return Err(_unknown_utxo(unknown));
+    }
+    Ok(funding)
+}
+
+impl Handler<FetchTradeOfferStates> for TxActor {
+    type Result = Result<Vec<(TradeOfferState, Option<[u8; 32]>, i64)>, Error>;
+
+    fn handle(&mut self, msg: FetchTradeOfferStates, _ctx: &mut Self::Context) -> Self::Result {
+        Ok(self.db.lock().unwrap().trade_offer_state_history(&msg.0)?)
+    }
+}
+
 impl Handler<FetchTradeOfferUtxos> for TxActor {
     type Result = Result<Vec<TradeOffer>, Error>;
 
     fn handle(&mut self, msg: FetchTradeOfferUtxos, _ctx: &mut Self::Context) -> Self::Result {
-        let FetchTradeOfferUtxos(filter) = msg;
-        Ok(self.db.lock().unwrap().trade_offer_utxos(filter)?)
+        let FetchTradeOfferUtxos(filter, fee_profile) = msg;
+        Ok(self.db.lock().unwrap().trade_offer_utxos(filter, fee_profile.as_ref().map(String::as_str))?)
     }
 }
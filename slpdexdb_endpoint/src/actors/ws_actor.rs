@@ -2,21 +2,311 @@ use actix::prelude::*;
 use cashcontracts::{Address, tx_hash_to_hex, tx_hex_to_hash};
 use std::convert::identity;
 use actix_web_actors::ws;
-use slpdexdb_base::{Error, convert_numeric};
+use slpdexdb_base::{Error, ErrorKind, TxRejection, SLPInputCheck, convert_numeric};
+use slpdexdb_db::{Utxo, SpentUtxo, TxDelta, TxDeltaQuery, TxDeltaKind, AddressBalance,
+                  TradeHistoryEntry, TradeRole, TradeOffer, TradeOfferStateChange, EventSubject,
+                  OutboxEvent};
 use serde::Deserialize;
 use json::{object, JsonValue, stringify};
 use std::sync::Arc;
 use crate::actors::TxActor;
-use crate::msg::{ActivateAddress, FetchAddressUtxos, FetchAddressTxDeltas, SubscribeToEvent,
-                 UnsubscribeFromEvent, TxEvent};
+use futures::future::{self, Future};
+use crate::msg::{ActivateAddress, FetchAddressUtxos, FetchAddressTxDeltas, FetchAddressBalance,
+                 SubscribeToEvent,
+                 UnsubscribeFromEvent, TxEvent, SubmitTx, SubmittedTx,
+                 FetchAddressTradeHistory, FetchOutboxEvents, OutboxReplay, FetchLastOutboxSeq};
 
 
+const TX_HISTORY_PAGE_SIZE: i64 = 100;
+pub const TRADE_HISTORY_PAGE_SIZE: i64 = 100;
+
 #[derive(Deserialize)]
 #[serde(tag = "type")]
 pub enum WsIncomingMessage {
     ListenToTokens {
         #[serde(rename = "tokenIdsHex")]
         token_ids_hex: Vec<String>,
+        since: Option<i64>,  // replays the tokens' events after this sequence number
+    },
+    FetchTxHistory {
+        cursor: Option<String>,
+        limit: Option<i64>,
+        #[serde(rename = "tokenIdHex")]
+        token_id_hex: Option<String>,
+        #[serde(default)]
+        kind: TxHistoryKind,
+        #[serde(rename = "minHeight")]
+        min_height: Option<i32>,
+        #[serde(rename = "maxHeight")]
+        max_height: Option<i32>,
+        #[serde(rename = "minTimestamp")]
+        min_timestamp: Option<i64>,
+        #[serde(rename = "maxTimestamp")]
+        max_timestamp: Option<i64>,
+    },
+    FetchTradeHistory {
+        cursor: Option<String>,
+        limit: Option<i64>,
+    },
+    SubmitTx {
+        #[serde(rename = "rawTx")]
+        raw_tx: String,
+        #[serde(rename = "allowBurn", default)]
+        allow_burn: bool,
+    },
+}
+
+pub struct SubmitTxResult(pub Result<JsonValue, Error>);
+
+impl Message for SubmitTxResult {
+    type Result = ();
+}
+
+/// Steps of resuming a stream; live events are buffered until `Resumed`.
+pub enum StreamControl {
+    SnapshotRequired,
+    Snapshot { seq: i64 },
+    Resumed {
+        replayed: Vec<OutboxEvent>,
+        last_seq: i64,  // the replay or snapshot covers all events up to here
+    },
+}
+
+impl Message for StreamControl {
+    type Result = ();
+}
+
+#[derive(Deserialize)]
+pub enum TxHistoryKind {
+    All,
+    #[serde(rename = "BCH")]
+    Bch,
+    #[serde(rename = "SLP")]
+    Slp,
+}
+
+impl Default for TxHistoryKind {
+    fn default() -> Self { TxHistoryKind::All }
+}
+
+pub fn error_json(request: &str, message: &str) -> JsonValue {
+    object!{
+        "type" => "Error",
+        "request" => request,
+        "message" => message,
+    }
+}
+
+pub fn account_json(xpub: &str, addresses: &[Address]) -> JsonValue {
+    object!{
+        "type" => "Account",
+        "xpub" => xpub,
+        "addresses" => JsonValue::Array(
+            addresses.iter()
+                .map(|address| address.cash_addr().to_string().into())
+                .collect()
+        ),
+    }
+}
+
+pub fn balance_json(balance: &AddressBalance) -> JsonValue {
+    object!{
+        "type" => "Balance",
+        "addresses" => JsonValue::Array(
+            balance.addresses.iter()
+                .map(|address| address.cash_addr().to_string().into())
+                .collect()
+        ),
+        "satoshisConfirmed" => balance.satoshis_confirmed,
+        "satoshisUnconfirmed" => balance.satoshis_unconfirmed,
+        "tokens" => JsonValue::Array(
+            balance.tokens.iter()
+                .map(|token_balance| object!{
+                    "tokenIdHex" => tx_hash_to_hex(&token_balance.token.hash),
+                    "symbol" => token_balance.token.symbol.clone(),
+                    "name" => token_balance.token.name.clone(),
+                    "decimals" => token_balance.token.decimals,
+                    "confirmed" => format!("{}", token_balance.confirmed),
+                    "confirmedBase" => token_balance.confirmed.base_amount().to_string(),
+                    "unconfirmed" => format!("{}", token_balance.unconfirmed),
+                    "unconfirmedBase" => token_balance.unconfirmed.base_amount().to_string(),
+                })
+                .collect()
+        ),
+    }
+}
+
+/// The client-facing outcome of a submitted tx; `None` if the error isn't the tx's fault.
+pub fn submit_result_json(result: &Result<SubmittedTx, Error>) -> Option<JsonValue> {
+    match result {
+        Ok(submitted) => Some(object!{
+            "type" => "TxAccepted",
+            "txid" => tx_hash_to_hex(&submitted.tx_hash),
+            "burnsTokens" => submitted.inputs.iter().any(|input| input.burn.is_some()),
+            "inputs" => input_checks_json(&submitted.inputs),
+        }),
+        Err(err) => match err.kind() {
+            ErrorKind::TxRejected(rejection) => Some(object!{
+                "type" => "TxRejected",
+                "reason" => rejection.reason(),
+                "detail" => rejection.detail(),
+                "inputs" => match rejection {
+                    TxRejection::BurnsTokens(inputs) => input_checks_json(inputs),
+                    _ => JsonValue::Null,
+                },
+            }),
+            _ => None,
+        },
+    }
+}
+
+pub fn trade_history_json(trade: &TradeHistoryEntry) -> JsonValue {
+    let trade_offer = &trade.trade_offer;
+    object!{
+        "role" => match trade.role {
+            TradeRole::Maker => "Maker",
+            TradeRole::Taker => "Taker",
+        },
+        "state" => format!("{:?}", trade.state),
+        "tokenIdHex" => tx_hash_to_hex(&trade.token_hash),
+        "offerTx" => tx_hash_to_hex(&trade_offer.tx),
+        "offerVout" => trade_offer.output_idx,
+        "spentTx" => trade.spent_tx.map(|tx| tx_hash_to_hex(&tx)),
+        "pricePerToken" => format!("{}", convert_numeric::PrettyRational(
+            trade_offer.price_per_token.clone()
+        )),
+        "amountToken" => format!("{}", trade_offer.sell_amount_token),
+        "amountTokenBase" => trade_offer.sell_amount_token.base_amount().to_string(),
+        "paymentSatoshis" => trade.payment_satoshis,
+        "feeSatoshis" => trade.fee_satoshis,
+        "receivingAddress" => trade_offer.receiving_address.cash_addr(),
+        "feeProfile" => trade_offer.fee_profile.clone(),
+        "contractVersion" => trade_offer.contract_version,
+        "createdTimestamp" => trade.created_timestamp,
+        "spentTimestamp" => trade.spent_timestamp,
+    }
+}
+
+pub fn trade_offer_json(trade_offer: &TradeOffer, token_hash: &[u8; 32]) -> JsonValue {
+    object!{
+        "tx" => tx_hash_to_hex(&trade_offer.tx),
+        "outputVout" => trade_offer.output_idx,
+        "inputTx" => tx_hash_to_hex(&trade_offer.input_tx),
+        "inputVout" => trade_offer.input_idx,
+        "pricePerToken" => format!("{}", convert_numeric::PrettyRational(
+            trade_offer.price_per_token.clone()
+        )),
+        "scriptPrice" => trade_offer.script_price.to_string(),
+        "isInverted" => trade_offer.is_inverted,
+        "sellAmountTokenBase" => trade_offer.sell_amount_token.base_amount().to_string(),
+        "receivingAddress" => trade_offer.receiving_address.cash_addr(),
+        "feeProfile" => trade_offer.fee_profile.clone(),
+        "contractVersion" => trade_offer.contract_version,
+        "tokenIdHex" => tx_hash_to_hex(token_hash),
+    }
+}
+
+fn input_checks_json(inputs: &[SLPInputCheck]) -> JsonValue {
+    JsonValue::Array(
+        inputs.iter()
+            .map(|input| object!{
+                "inputIdx" => input.input_idx,
+                "tx" => tx_hash_to_hex(&input.tx_hash),
+                "vout" => input.vout,
+                "tokenIdHex" => input.token_hash.map(|token| tx_hash_to_hex(&token)),
+                "valueToken" => format!("{}", input.value_token),
+                "valueTokenBase" => input.value_token.base_amount().to_string(),
+                "isBaton" => input.is_baton,
+                "burn" => input.burn.as_ref().map(|burn| format!("{:?}", burn)),
+            })
+            .collect()
+    )
+}
+
+pub fn address_utxo_json(add_utxos: &[Utxo], remove_utxos: &[SpentUtxo]) -> JsonValue {
+    object!{
+        "type" => "AddressUtxo",
+        "addUtxos" => JsonValue::Array(
+            add_utxos.iter()
+                .map(|utxo| object!{
+                    "tx" => tx_hash_to_hex(&utxo.tx_hash),
+                    "vout" => utxo.vout,
+                    "valueSatoshis" => utxo.value_satoshis,
+                    "valueToken" => format!("{}", utxo.value_token),
+                    "valueTokenBase" => utxo.value_token.base_amount().to_string(),
+                    "tokenIdHex" => utxo.token_hash.map(|token| tx_hash_to_hex(&token)),
+                })
+                .collect()
+        ),
+        "removeUtxos" => spent_utxos_json(remove_utxos),
+    }
+}
+
+pub fn trade_offer_utxo_json(token_hash: &[u8; 32], add_utxos: &[TradeOffer],
+                             remove_utxos: &[SpentUtxo]) -> JsonValue {
+    object!{
+        "type" => "TradeOfferUtxo",
+        "addUtxos" => JsonValue::Array(
+            add_utxos.iter()
+                .map(|trade_offer| trade_offer_json(trade_offer, token_hash))
+                .collect()
+        ),
+        "removeUtxos" => spent_utxos_json(remove_utxos),
+    }
+}
+
+/// A `TxHistory` message. In the messages pushed for newly indexed txs, `runningBalanceSatoshis`
+/// and `runningBalanceToken` are null, as their position in the history isn't settled yet;
+/// `FetchTxHistory` pages have them.
+pub fn tx_history_json(tx_deltas: &[TxDelta]) -> JsonValue {
+    object!{
+        "type" => "TxHistory",
+        "addTxHistory" => JsonValue::Array(tx_deltas.iter().map(tx_delta_json).collect()),
+    }
+}
+
+pub fn trade_offer_state_json(token_hash: &[u8; 32], changes: &[TradeOfferStateChange]) -> JsonValue {
+    object!{
+        "type" => "TradeOfferState",
+        "tokenIdHex" => tx_hash_to_hex(token_hash),
+        "changes" => JsonValue::Array(
+            changes.iter()
+                .map(|change| object!{
+                    "tx" => tx_hash_to_hex(&change.offer_tx),
+                    "outputVout" => change.offer_vout,
+                    "state" => format!("{:?}", change.state),
+                    "spentTx" => change.spent_tx.map(|tx| tx_hash_to_hex(&tx)),
+                    "timestamp" => change.timestamp,
+                })
+                .collect()
+        ),
+    }
+}
+
+fn spent_utxos_json(utxos: &[SpentUtxo]) -> JsonValue {
+    JsonValue::Array(
+        utxos.iter()
+            .map(|utxo| object!{
+                "tx" => tx_hash_to_hex(&utxo.tx_hash),
+                "vout" => utxo.vout,
+            })
+            .collect()
+    )
+}
+
+fn tx_delta_json(tx_delta: &TxDelta) -> JsonValue {
+    object!{
+        "tx" => tx_hash_to_hex(&tx_delta.tx_hash),
+        "deltaSatoshis" => tx_delta.delta_satoshis,
+        "deltaToken" => format!("{}", tx_delta.delta_token),
+        "deltaTokenBase" => tx_delta.delta_token.base_amount().to_string(),
+        "tokenIdHex" => tx_delta.token_hash.map(|token| tx_hash_to_hex(&token)),
+        "timestamp" => tx_delta.timestamp,
+        "height" => tx_delta.height,
+        "isConfirmed" => tx_delta.is_confirmed(),
+        "runningBalanceSatoshis" => tx_delta.running_balance_satoshis,
+        "runningBalanceToken" => tx_delta.running_balance_token
+            .map(|balance| format!("{}", balance)),
     }
 }
 
@@ -24,48 +314,191 @@ impl Message for WsIncomingMessage {
     type Result = Result<(), Error>;
 }
 
+pub enum WsSubject {
+    Address(Address),
+    Account {
+        xpub: String,
+        addresses: Vec<Address>,  // activated by ActivateAccount, updated as it grows
+    },
+}
+
 pub struct WsActor {
-    address: Address,
+    subject: WsSubject,
     tx: Addr<TxActor>,
+    since: Option<i64>,
+    last_seq: i64,  // of the last event sent
+    replay_buffer: Option<Vec<Arc<OutboxEvent>>>,
 }
 
 impl WsActor {
-    pub fn new(address: Address, tx: Addr<TxActor>) -> Self {
-        WsActor { address, tx }
+    pub fn new(address: Address, since: Option<i64>, tx: Addr<TxActor>) -> Self {
+        WsActor::with_subject(WsSubject::Address(address), since, tx)
+    }
+
+    pub fn new_account(xpub: String, addresses: Vec<Address>, since: Option<i64>,
+                       tx: Addr<TxActor>) -> Self {
+        WsActor::with_subject(WsSubject::Account { xpub, addresses }, since, tx)
+    }
+
+    fn with_subject(subject: WsSubject, since: Option<i64>, tx: Addr<TxActor>) -> Self {
+        WsActor { subject, tx, since, last_seq: 0, replay_buffer: Some(Vec::new()) }
+    }
+
+    fn addresses(&self) -> Vec<Address> {
+        match &self.subject {
+            WsSubject::Address(address) => vec![address.clone()],
+            WsSubject::Account { addresses, .. } => addresses.clone(),
+        }
+    }
+
+    fn event_subject(&self) -> EventSubject {
+        match &self.subject {
+            WsSubject::Address(address) => EventSubject::Address(address.clone()),
+            WsSubject::Account { xpub, .. } => EventSubject::Account(xpub.clone()),
+        }
+    }
+
+    /// Sends a live event, unless a replay already did.
+    fn _send_stored(&mut self, event: &OutboxEvent, ctx: &mut <Self as Actor>::Context) {
+        if event.seq <= self.last_seq { return }
+        self._send_event(event, ctx);
     }
+
+    fn _send_event(&mut self, event: &OutboxEvent, ctx: &mut <Self as Actor>::Context) {
+        match json::parse(&event.payload) {
+            Ok(mut payload) => {
+                payload["seq"] = event.seq.into();
+                ctx.text(stringify(payload));
+                self.last_seq = self.last_seq.max(event.seq);
+            },
+            Err(err) => eprintln!("invalid payload of event {}: {}", event.seq, err),
+        }
+    }
+}
+
+/// Replays the events of `subjects` after `since`, or runs `snapshot` if they're gone.
+fn _resume<F>(tx: Addr<TxActor>, own_address: Addr<WsActor>, subjects: Vec<EventSubject>,
+              since: i64, snapshot: F) -> Box<dyn Future<Item=(), Error=Error>>
+        where F: FnOnce() -> Box<dyn Future<Item=(), Error=Error>> + 'static {
+    Box::new(
+        tx.send(FetchOutboxEvents { subjects, since })
+            .from_err()
+            .and_then(identity)
+            .and_then(move |replay| -> Box<dyn Future<Item=(), Error=Error>> {
+                match replay {
+                    OutboxReplay::Events(events) => {
+                        let last_seq = events.last().map(|event| event.seq).unwrap_or(since);
+                        Box::new(own_address.send(StreamControl::Resumed {
+                            replayed: events,
+                            last_seq,
+                        }).from_err())
+                    },
+                    OutboxReplay::SnapshotRequired => Box::new(
+                        own_address.send(StreamControl::SnapshotRequired)
+                            .from_err()
+                            .and_then(move |_| snapshot())
+                    ),
+                }
+            })
+    )
+}
+
+/// Sends the current UTXOs, balance and latest tx history of `addresses`, all covering at
+/// least the events up to the announced sequence number.
+fn _snapshot(tx: Addr<TxActor>, own_address: Addr<WsActor>, addresses: Vec<Address>)
+        -> Box<dyn Future<Item=(), Error=Error>> {
+    let address2 = addresses.clone();
+    let address3 = addresses.clone();
+    let tx2 = tx.clone();
+    let tx3 = tx.clone();
+    let tx4 = tx.clone();
+    let own_address2 = own_address.clone();
+    let own_address3 = own_address.clone();
+    let own_address4 = own_address.clone();
+    let own_address5 = own_address.clone();
+    Box::new(
+        tx.send(FetchLastOutboxSeq)
+            .from_err()
+            .and_then(identity)
+            .and_then(move |seq| {
+                own_address.send(StreamControl::Snapshot { seq }).from_err().map(move |_| seq)
+            })
+            .and_then(move |seq| {
+                tx2.send(FetchAddressUtxos(addresses))
+                    .from_err()
+                    .and_then(identity)
+                    .map(move |utxos| (seq, utxos))
+            })
+            .and_then(move |(seq, utxos)| own_address2.send(
+                TxEvent::AddressUtxoDelta { add_utxos: Arc::new(utxos),
+                                            remove_utxos: Arc::new(vec![]) }
+            ).from_err().map(move |_| seq))
+            .and_then(move |seq| {
+                tx3.send(FetchAddressBalance(address2))
+                    .from_err()
+                    .and_then(identity)
+                    .map(move |balance| (seq, balance))
+            })
+            .and_then(move |(seq, balance)| own_address3.send(
+                TxEvent::BalanceChanged { balance: Arc::new(balance) }
+            ).from_err().map(move |_| seq))
+            .and_then(move |seq| {
+                let query = TxDeltaQuery {
+                    limit: Some(TX_HISTORY_PAGE_SIZE),
+                    ..TxDeltaQuery::default()
+                };
+                tx4.send(FetchAddressTxDeltas(address3, query))
+                    .from_err()
+                    .and_then(identity)
+                    .map(move |page| (seq, page))
+            })
+            .and_then(move |(seq, page)| own_address4.send(
+                TxEvent::AddressTxDeltaPage { page: Arc::new(page) }
+            ).from_err().map(move |_| seq))
+            .and_then(move |seq| own_address5.send(
+                StreamControl::Resumed { replayed: vec![], last_seq: seq }
+            ).from_err())
+    )
 }
 
 impl Actor for WsActor {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let address = self.address.clone();
-        let address2 = self.address.clone();
-        let address3 = self.address.clone();
+        let addresses = self.addresses();
+        let addresses2 = self.addresses();
+        let subject = self.event_subject();
+        let since = self.since;
         let tx = self.tx.clone();
         let tx2 = self.tx.clone();
         let tx3 = self.tx.clone();
         let own_address = ctx.address();
         let own_address2 = ctx.address();
         let own_address3 = ctx.address();
+        let activate: Box<dyn Future<Item=(), Error=Error>> = match &self.subject {
+            WsSubject::Address(address) => Box::new(
+                self.tx.send(ActivateAddress(address.clone())).from_err().and_then(identity)
+            ),
+            WsSubject::Account { xpub, addresses } => {
+                ctx.text(stringify(account_json(xpub, addresses)));
+                Box::new(future::ok(()))
+            },
+        };
+        // subscribed first, so events stored during the replay or snapshot are buffered
         Arbiter::spawn(
-            self.tx.send(ActivateAddress(self.address.clone())).from_err().and_then(identity)
-                .and_then(move |_| {
-                    tx.send(FetchAddressUtxos(address)).from_err().and_then(identity)
-                })
-                .and_then(move |utxos| own_address.send(
-                    TxEvent::AddressUtxoDelta { add_utxos: Arc::new(utxos),
-                                                remove_utxos: Arc::new(vec![]) }
-                ).from_err())
-                .and_then(move |_| {
-                    tx2.send(FetchAddressTxDeltas(address2)).from_err().and_then(identity)
-                })
-                .and_then(move |tx_deltas| own_address2.send(
-                    TxEvent::AddressNewTxDeltas { tx_deltas: Arc::new(tx_deltas) }
-                ).from_err())
+            activate
                 .and_then(move |_| {
-                    tx3.send(SubscribeToEvent::Address(address3, own_address3.recipient()))
+                    tx.send(SubscribeToEvent::Addresses(subject.clone(), addresses,
+                                                        own_address.recipient()))
                         .from_err()
+                        .map(move |_| subject)
+                })
+                .and_then(move |subject| {
+                    let snapshot = move || _snapshot(tx2, own_address2, addresses2);
+                    match since {
+                        Some(since) => _resume(tx3, own_address3, vec![subject], since, snapshot),
+                        None => snapshot(),
+                    }
                 })
                 .map_err(|err| eprintln!("Error: {}", err))
         )
@@ -74,7 +507,6 @@ impl Actor for WsActor {
 
 impl StreamHandler<ws::Message, ws::ProtocolError> for WsActor {
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
-        println!("WS: {:?}", msg);
         match msg {
             ws::Message::Ping(msg) => {
                 ctx.pong(&msg);
@@ -90,8 +522,7 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WsActor {
             },
             ws::Message::Binary(_bin) => {},
             ws::Message::Close(_) => {
-                self.tx.do_send(UnsubscribeFromEvent::Address(self.address.clone(),
-                                                              ctx.address().recipient()));
+                self.tx.do_send(UnsubscribeFromEvent::All(ctx.address().recipient()));
                 ctx.stop();
             }
             ws::Message::Nop => (),
@@ -104,83 +535,43 @@ impl Handler<TxEvent> for WsActor {
 
     fn handle(&mut self, msg: TxEvent, ctx: &mut Self::Context) -> Self::Result {
         match msg {
+            TxEvent::Stored(event) => {
+                match &mut self.replay_buffer {
+                    Some(replay_buffer) => replay_buffer.push(event),
+                    None => self._send_stored(&event, ctx),
+                }
+            },
             TxEvent::AddressUtxoDelta { add_utxos, remove_utxos } => {
-                ctx.text(stringify(
-                    object!{
-                        "type" => "AddressUtxo",
-                        "addUtxos" => JsonValue::Array(
-                            add_utxos.iter()
-                                .map(|utxo| object!{
-                                    "tx" => tx_hash_to_hex(&utxo.tx_hash),
-                                    "vout" => utxo.vout,
-                                    "valueSatoshis" => utxo.value_satoshis,
-                                    "valueToken" => format!("{}", utxo.value_token),
-                                    "valueTokenBase" => utxo.value_token.base_amount().to_string(),
-                                    "tokenIdHex" => utxo.token_hash.map(|token| tx_hash_to_hex(&token)),
-                                })
-                                .collect()
-                        ),
-                        "removeUtxos" => JsonValue::Array(
-                            remove_utxos.iter()
-                                .map(|utxo| object!{
-                                    "tx" => tx_hash_to_hex(&utxo.tx_hash),
-                                    "vout" => utxo.vout,
-                                })
-                                .collect()
-                        ),
-                    }
-                ))
+                ctx.text(stringify(address_utxo_json(&add_utxos, &remove_utxos)))
+            },
+            TxEvent::BalanceChanged { balance } => {
+                ctx.text(stringify(balance_json(&balance)))
             },
-            TxEvent::TradeOfferUtxoDelta { token_hash, add_utxos, remove_utxos } => {
+            TxEvent::AccountAddresses { addresses: new_addresses } => {
+                if let WsSubject::Account { xpub, addresses } = &mut self.subject {
+                    *addresses = new_addresses.as_ref().clone();
+                    ctx.text(stringify(account_json(xpub, addresses)));
+                }
+            },
+            TxEvent::AddressTradeHistoryPage { page } => {
                 ctx.text(stringify(
                     object!{
-                        "type" => "TradeOfferUtxo",
-                        "addUtxos" => JsonValue::Array(
-                            add_utxos.iter()
-                                .map(|trade_offer| object!{
-                                    "tx" => tx_hash_to_hex(&trade_offer.tx),
-                                    "outputVout" => trade_offer.output_idx,
-                                    "inputTx" => tx_hash_to_hex(&trade_offer.input_tx),
-                                    "inputVout" => trade_offer.input_idx,
-                                    "pricePerToken" => format!("{}", convert_numeric::PrettyRational(
-                                        trade_offer.price_per_token.clone()
-                                    )),
-                                    "scriptPrice" => trade_offer.script_price.to_string(),
-                                    "isInverted" => trade_offer.is_inverted,
-                                    "sellAmountTokenBase" => trade_offer.sell_amount_token
-                                        .base_amount().to_string(),
-                                    "receivingAddress" => trade_offer.receiving_address.cash_addr(),
-                                    "tokenIdHex" => tx_hash_to_hex(&token_hash),
-                                })
-                                .collect()
-                        ),
-                        "removeUtxos" => JsonValue::Array(
-                            remove_utxos.iter()
-                                .map(|utxo| object!{
-                                    "tx" => tx_hash_to_hex(&utxo.tx_hash),
-                                    "vout" => utxo.vout,
-                                })
-                                .collect()
+                        "type" => "TradeHistoryPage",
+                        "trades" => JsonValue::Array(
+                            page.trades.iter().map(trade_history_json).collect()
                         ),
+                        "nextCursor" => page.next_cursor.map(|cursor| cursor.to_string()),
                     }
                 ))
             },
-            TxEvent::AddressNewTxDeltas { tx_deltas } => {
+            TxEvent::AddressTxDeltaPage { page } => {
                 ctx.text(stringify(
                     object!{
                         "type" => "TxHistory",
                         "addTxHistory" => JsonValue::Array(
-                            tx_deltas.iter()
-                                .map(|tx_delta| object!{
-                                    "tx" => tx_hash_to_hex(&tx_delta.tx_hash),
-                                    "deltaSatoshis" => tx_delta.delta_satoshis,
-                                    "deltaToken" => format!("{}", tx_delta.delta_token),
-                                    "deltaTokenBase" => tx_delta.delta_token.base_amount().to_string(),
-                                    "tokenIdHex" => tx_delta.token_hash.map(|token| tx_hash_to_hex(&token)),
-                                    "timestamp" => tx_delta.timestamp,
-                                })
-                                .collect()
+                            page.tx_deltas.iter().map(tx_delta_json).collect()
                         ),
+                        "nextCursor" => page.next_cursor.map(|cursor| cursor.to_string()),
                     }
                 ))
             },
@@ -189,22 +580,167 @@ impl Handler<TxEvent> for WsActor {
     }
 }
 
+impl Handler<StreamControl> for WsActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: StreamControl, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            StreamControl::SnapshotRequired => {
+                ctx.text(stringify(object!{ "type" => "SnapshotRequired" }))
+            },
+            StreamControl::Snapshot { seq } => {
+                ctx.text(stringify(object!{ "type" => "Snapshot", "seq" => seq }))
+            },
+            StreamControl::Resumed { replayed, last_seq } => {
+                for event in replayed.iter() {
+                    self._send_event(event, ctx);
+                }
+                self.last_seq = self.last_seq.max(last_seq);
+                for event in self.replay_buffer.take().unwrap_or_default() {
+                    if event.seq > last_seq {
+                        self._send_event(&event, ctx);
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Handler<SubmitTxResult> for WsActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubmitTxResult, ctx: &mut Self::Context) -> Self::Result {
+        match msg.0 {
+            Ok(json) => ctx.text(stringify(json)),
+            Err(err) => {
+                eprintln!("submit error: {}", err);
+                ctx.text(stringify(error_json("SubmitTx", &err.to_string())));
+            },
+        }
+    }
+}
+
 impl Handler<WsIncomingMessage> for WsActor {
     type Result = Response<(), Error>;
 
     fn handle(&mut self, msg: WsIncomingMessage, ctx: &mut Self::Context) -> Self::Result {
         match msg {
-            WsIncomingMessage::ListenToTokens { token_ids_hex } => {
-                println!("subscribe to {:?}", token_ids_hex);
+            WsIncomingMessage::ListenToTokens { token_ids_hex, since } => {
                 let token_hashes = token_ids_hex.iter()
                     .filter_map(|token_hash| tx_hex_to_hash(token_hash))
-                    .collect();
+                    .collect::<Vec<_>>();
+                let subjects = token_hashes.iter()
+                    .cloned()
+                    .map(EventSubject::Token)
+                    .collect::<Vec<_>>();
+                let subscribe = self.tx
+                    .send(SubscribeToEvent::Tokens(token_hashes, ctx.address().recipient()))
+                    .from_err();
+                let since = match since {
+                    Some(since) => since,
+                    None => return Response::fut(subscribe),
+                };
+                if self.replay_buffer.is_none() {
+                    self.replay_buffer = Some(Vec::new());
+                }
+                let tx = self.tx.clone();
+                let tx2 = self.tx.clone();
+                let own_address = ctx.address();
+                let own_address2 = ctx.address();
+                // offers are fetched over HTTP, so a token snapshot only skips to the latest event
+                let snapshot = move || -> Box<dyn Future<Item=(), Error=Error>> {
+                    Box::new(
+                        tx2.send(FetchLastOutboxSeq)
+                            .from_err()
+                            .and_then(identity)
+                            .and_then(move |seq| own_address2.send(
+                                StreamControl::Resumed { replayed: vec![], last_seq: seq }
+                            ).from_err())
+                    )
+                };
+                Response::fut(
+                    subscribe.and_then(move |_| _resume(tx, own_address, subjects, since, snapshot))
+                )
+            },
+            WsIncomingMessage::FetchTxHistory { cursor, limit, token_id_hex, kind, min_height,
+                                                max_height, min_timestamp, max_timestamp } => {
+                let cursor = match cursor.map(|cursor| cursor.parse()) {
+                    Some(Ok(cursor)) => Some(cursor),
+                    Some(Err(_)) => {
+                        ctx.text(stringify(error_json("FetchTxHistory", "invalid cursor")));
+                        return Response::reply(Ok(()));
+                    },
+                    None => None,
+                };
+                let token_hash = match token_id_hex.map(|token_id_hex| tx_hex_to_hash(&token_id_hex)) {
+                    Some(Some(token_hash)) => Some(token_hash),
+                    Some(None) => {
+                        ctx.text(stringify(error_json("FetchTxHistory", "invalid token id")));
+                        return Response::reply(Ok(()));
+                    },
+                    None => None,
+                };
+                let query = TxDeltaQuery {
+                    cursor,
+                    limit: Some(limit.unwrap_or(TX_HISTORY_PAGE_SIZE).max(1).min(TX_HISTORY_PAGE_SIZE)),
+                    token_hash,
+                    kind: match kind {
+                        TxHistoryKind::All => TxDeltaKind::All,
+                        TxHistoryKind::Bch => TxDeltaKind::Bch,
+                        TxHistoryKind::Slp => TxDeltaKind::Slp,
+                    },
+                    min_height,
+                    max_height,
+                    min_timestamp,
+                    max_timestamp,
+                };
+                let own_address = ctx.address();
                 Response::fut(
                     self.tx
-                        .send(SubscribeToEvent::Tokens(token_hashes, ctx.address().recipient()))
+                        .send(FetchAddressTxDeltas(self.addresses(), query))
                         .from_err()
+                        .and_then(identity)
+                        .and_then(move |page| own_address.send(
+                            TxEvent::AddressTxDeltaPage { page: Arc::new(page) }
+                        ).from_err())
+                        .and_then(identity)
                 )
-            }
+            },
+            WsIncomingMessage::FetchTradeHistory { cursor, limit } => {
+                let cursor = match cursor.map(|cursor| cursor.parse()) {
+                    Some(Ok(cursor)) => Some(cursor),
+                    Some(Err(_)) => {
+                        ctx.text(stringify(error_json("FetchTradeHistory", "invalid cursor")));
+                        return Response::reply(Ok(()));
+                    },
+                    None => None,
+                };
+                let own_address = ctx.address();
+                Response::fut(
+                    self.tx
+                        .send(FetchAddressTradeHistory {
+                            addresses: self.addresses(),
+                            cursor,
+                            limit: limit.unwrap_or(TRADE_HISTORY_PAGE_SIZE)
+                                .max(1).min(TRADE_HISTORY_PAGE_SIZE),
+                        })
+                        .from_err()
+                        .and_then(identity)
+                        .and_then(move |page| own_address.send(
+                            TxEvent::AddressTradeHistoryPage { page: Arc::new(page) }
+                        ).from_err())
+                        .and_then(identity)
+                )
+            },
+            WsIncomingMessage::SubmitTx { raw_tx, allow_burn } => {
+                let own_address = ctx.address();
+                Response::fut(
+                    self.tx
+                        .send(SubmitTx { raw_tx, allow_burn })
+                        .from_err()
+                        .and_then(move |result| own_address.send(SubmitTxResult(result)).from_err())
+                )
+            },
         }
     }
 }
@@ -0,0 +1,446 @@
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use futures::future::{self, Future};
+use cashcontracts::{Address, tx_hash_to_hex, tx_hex_to_hash};
+use json::{object, JsonValue, stringify};
+use serde::{Serialize, Deserialize};
+use std::convert::identity;
+use slpdexdb_base::ErrorKind;
+use slpdexdb_base::convert_numeric::PrettyRational;
+use slpdexdb_db::{SpentUtxo, BuiltTx, TradeOfferFilter};
+use rug::Rational;
+use crate::actors::{TxActor, balance_json, trade_history_json,
+                    trade_offer_json, TRADE_HISTORY_PAGE_SIZE};
+use crate::msg::{FetchTokenDistribution, FetchAddressBalance, BuildTradeOffer, AcceptTradeOffers,
+                 FetchQuote, SubmitTx, FetchTradeOfferStates, FetchAddressTradeHistory,
+                 FetchTradeOfferUtxos};
+
+const DEFAULT_TOP_HOLDERS: usize = 100;
+
+type ApiResponse = Box<dyn Future<Item=HttpResponse, Error=actix_web::Error>>;
+
+fn json_response(json: JsonValue) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(stringify(json))
+}
+
+fn bad_request(msg: &str) -> ApiResponse {
+    Box::new(future::ok(HttpResponse::BadRequest().body(msg.to_string())))
+}
+
+/// Invalid requests are the client's fault, everything else is ours.
+fn error_response(err: slpdexdb_base::Error) -> actix_web::Error {
+    match err.kind() {
+        ErrorKind::TxBuild(_) | ErrorKind::NumericError(_) | ErrorKind::TokenError(_) |
+        ErrorKind::SlpUtxoIndexDisabled(_) =>
+            actix_web::error::ErrorBadRequest(err.to_string()),
+        _ => actix_web::error::ErrorInternalServerError(err.to_string()),
+    }
+}
+
+fn built_tx_json(built_tx: &BuiltTx) -> JsonValue {
+    object!{
+        "txHex" => hex::encode(built_tx.tx.serialize()),
+        "fee" => built_tx.fee,
+        "inputs" => JsonValue::Array(
+            built_tx.signing_data.iter()
+                .map(|signing_data| {
+                    let input = &built_tx.tx.inputs[signing_data.input_idx];
+                    object!{
+                        "tx" => tx_hash_to_hex(&input.tx_hash),
+                        "vout" => input.vout,
+                        "valueSatoshis" => input.value_satoshis,
+                        "address" => input.address.cash_addr().to_string(),
+                        "scriptCodeHex" => hex::encode(&input.script_code),
+                        "scriptSigPrefixHex" => hex::encode(&input.script_sig_prefix),
+                        "sighashType" => signing_data.sighash_type,
+                        "preimageHex" => hex::encode(&signing_data.preimage),
+                        "sighashHex" => hex::encode(&signing_data.sighash),
+                    }
+                })
+                .collect()
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OutpointRequest {
+    tx: String,
+    vout: i32,
+}
+
+fn parse_outpoints(outpoints: &[OutpointRequest]) -> Option<Vec<SpentUtxo>> {
+    outpoints.iter()
+        .map(|outpoint| Some(SpentUtxo {
+            tx_hash: tx_hex_to_hash(&outpoint.tx)?,
+            vout: outpoint.vout,
+        }))
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct TokenHoldersQuery {
+    top: Option<usize>,
+}
+
+pub fn token_holders(path: web::Path<(String,)>,
+                     query: web::Query<TokenHoldersQuery>,
+                     tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    let token_hash = match tx_hex_to_hash(&path.0) {
+        Some(token_hash) => token_hash,
+        None => return bad_request("invalid token id"),
+    };
+    let top = query.top.unwrap_or(DEFAULT_TOP_HOLDERS);
+    Box::new(
+        tx.send(FetchTokenDistribution(token_hash))
+            .from_err()
+            .and_then(identity)
+            .map(move |distribution| {
+                let distribution = match distribution {
+                    Some(distribution) => distribution,
+                    None => return HttpResponse::NotFound().finish(),
+                };
+                json_response(object!{
+                    "tokenIdHex" => tx_hash_to_hex(&distribution.token.hash),
+                    "symbol" => distribution.token.symbol.clone(),
+                    "decimals" => distribution.token.decimals,
+                    "height" => distribution.height,
+                    "circulatingSupply" => format!("{}", distribution.circulating_supply),
+                    "holderCount" => distribution.holder_count(),
+                    "topConcentration" => format!(
+                        "{:.8}", PrettyRational(distribution.top_concentration(top)),
+                    ),
+                    "holders" => JsonValue::Array(
+                        distribution.holders.iter()
+                            .take(top)
+                            .map(|holder| object!{
+                                "address" => holder.address.as_ref()
+                                    .map(|address| address.cash_addr().to_string()),
+                                "amount" => format!("{}", holder.amount),
+                                "amountBase" => holder.amount.base_amount().to_string(),
+                                "share" => format!("{:.8}", PrettyRational(holder.share.clone())),
+                            })
+                            .collect()
+                    ),
+                })
+            })
+            .map_err(error_response)
+    )
+}
+
+pub fn address_balance(path: web::Path<(String,)>,
+                       tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    let address = match Address::from_cash_addr(path.0.clone()) {
+        Ok(address) => address,
+        Err(_) => return bad_request("invalid address"),
+    };
+    Box::new(
+        tx.send(FetchAddressBalance(vec![address]))
+            .from_err()
+            .and_then(identity)
+            .map(|balance| json_response(balance_json(&balance)))
+            .map_err(|err: slpdexdb_base::Error| {
+                actix_web::error::ErrorInternalServerError(err.to_string())
+            })
+    )
+}
+
+#[derive(Deserialize)]
+pub struct TradeHistoryQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+pub fn address_trades(path: web::Path<(String,)>,
+                      query: web::Query<TradeHistoryQuery>,
+                      tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    let address = match Address::from_cash_addr(path.0.clone()) {
+        Ok(address) => address,
+        Err(_) => return bad_request("invalid address"),
+    };
+    let cursor = match &query.cursor {
+        Some(cursor) => match cursor.parse() {
+            Ok(cursor) => Some(cursor),
+            Err(_) => return bad_request("invalid cursor"),
+        },
+        None => None,
+    };
+    Box::new(
+        tx.send(FetchAddressTradeHistory {
+            addresses: vec![address],
+            cursor,
+            limit: query.limit.unwrap_or(TRADE_HISTORY_PAGE_SIZE).max(1).min(TRADE_HISTORY_PAGE_SIZE),
+        })
+            .from_err()
+            .and_then(identity)
+            .map(|page| json_response(object!{
+                "trades" => JsonValue::Array(page.trades.iter().map(trade_history_json).collect()),
+                "nextCursor" => page.next_cursor.map(|cursor| cursor.to_string()),
+            }))
+            .map_err(error_response)
+    )
+}
+
+#[derive(Deserialize)]
+pub struct BuildTradeOfferRequest {
+    #[serde(rename = "tokenIdHex")]
+    token_id_hex: String,
+    amount: String,
+    #[serde(rename = "pricePerToken")]
+    price_per_token: String,
+    #[serde(rename = "receivingAddress")]
+    receiving_address: String,
+    #[serde(rename = "changeAddress")]
+    change_address: Option<String>,
+    utxos: Vec<OutpointRequest>,
+}
+
+pub fn build_trade_offer(request: web::Json<BuildTradeOfferRequest>,
+                         tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    let token_hash = match tx_hex_to_hash(&request.token_id_hex) {
+        Some(token_hash) => token_hash,
+        None => return bad_request("invalid token id"),
+    };
+    let receiving_address = match Address::from_cash_addr(request.receiving_address.clone()) {
+        Ok(address) => address,
+        Err(_) => return bad_request("invalid receiving address"),
+    };
+    let change_address = match &request.change_address {
+        Some(change_address) => match Address::from_cash_addr(change_address.clone()) {
+            Ok(address) => address,
+            Err(_) => return bad_request("invalid change address"),
+        },
+        None => receiving_address.clone(),
+    };
+    let funding = match parse_outpoints(&request.utxos) {
+        Some(funding) => funding,
+        None => return bad_request("invalid utxo"),
+    };
+    Box::new(
+        tx.send(BuildTradeOffer {
+            token_hash,
+            sell_amount: request.amount.clone(),
+            price_per_token: request.price_per_token.clone(),
+            receiving_address,
+            change_address,
+            funding,
+        })
+            .from_err()
+            .and_then(identity)
+            .map(|offer| {
+                let mut json = built_tx_json(&offer.built_tx);
+                json["contractAddress"] = offer.contract_address.cash_addr().to_string().into();
+                json["scriptPrice"] = offer.price.script_price.into();
+                json["isInverted"] = offer.price.is_inverted.into();
+                json["power"] = offer.price.power.into();
+                json["pricePerToken"] = format!(
+                    "{}", PrettyRational(offer.price.price_per_token.clone())
+                ).into();
+                json_response(json)
+            })
+            .map_err(error_response)
+    )
+}
+
+#[derive(Deserialize)]
+pub struct AcceptTradeOffersRequest {
+    offers: Vec<OutpointRequest>,
+    #[serde(rename = "receivingAddress")]
+    receiving_address: String,
+    #[serde(rename = "changeAddress")]
+    change_address: Option<String>,
+    utxos: Vec<OutpointRequest>,
+}
+
+pub fn accept_trade_offers(request: web::Json<AcceptTradeOffersRequest>,
+                           tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    let receiving_address = match Address::from_cash_addr(request.receiving_address.clone()) {
+        Ok(address) => address,
+        Err(_) => return bad_request("invalid receiving address"),
+    };
+    let change_address = match &request.change_address {
+        Some(change_address) => match Address::from_cash_addr(change_address.clone()) {
+            Ok(address) => address,
+            Err(_) => return bad_request("invalid change address"),
+        },
+        None => receiving_address.clone(),
+    };
+    let (offers, funding) = match (parse_outpoints(&request.offers), parse_outpoints(&request.utxos)) {
+        (Some(offers), Some(funding)) => (offers, funding),
+        _ => return bad_request("invalid utxo"),
+    };
+    Box::new(
+        tx.send(AcceptTradeOffers { offers, receiving_address, change_address, funding })
+            .from_err()
+            .and_then(identity)
+            .map(|accept| {
+                let mut json = built_tx_json(&accept.built_tx);
+                json["tokenAmount"] = format!("{}", accept.token_amount).into();
+                json["tokenAmountBase"] = accept.token_amount.base_amount().to_string().into();
+                json["payments"] = JsonValue::Array(
+                    accept.payments.iter()
+                        .map(|payment| object!{
+                            "offerTx" => tx_hash_to_hex(&payment.offer_tx),
+                            "offerVout" => payment.offer_vout,
+                            "paymentSatoshis" => payment.payment_satoshis,
+                            "feeSatoshis" => payment.fee_satoshis,
+                        })
+                        .collect()
+                );
+                json_response(json)
+            })
+            .map_err(error_response)
+    )
+}
+
+#[derive(Deserialize)]
+pub struct QuoteQuery {
+    buy: Option<String>,
+    spend: Option<u64>,
+    profile: Option<String>,
+}
+
+pub fn token_quote(path: web::Path<(String,)>,
+                   query: web::Query<QuoteQuery>,
+                   tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    let token_hash = match tx_hex_to_hash(&path.0) {
+        Some(token_hash) => token_hash,
+        None => return bad_request("invalid token id"),
+    };
+    if query.buy.is_some() == query.spend.is_some() {
+        return bad_request("expected exactly one of buy or spend");
+    }
+    Box::new(
+        tx.send(FetchQuote {
+            token_hash,
+            buy_amount: query.buy.clone(),
+            spend_satoshis: query.spend,
+            fee_profile: query.profile.clone(),
+        })
+            .from_err()
+            .and_then(identity)
+            .map(move |quote| {
+                let pretty_price = |price: &Option<Rational>| {
+                    price.as_ref().map(|price| format!("{}", PrettyRational(price.clone())))
+                };
+                json_response(object!{
+                    "tokenIdHex" => tx_hash_to_hex(&token_hash),
+                    "tokenAmount" => format!("{}", quote.token_amount),
+                    "tokenAmountBase" => quote.token_amount.base_amount().to_string(),
+                    "totalSatoshis" => quote.total_satoshis,
+                    "feeSatoshis" => quote.fee_satoshis,
+                    "averagePrice" => pretty_price(&quote.average_price),
+                    "worstPrice" => pretty_price(&quote.worst_price),
+                    "leftoverToken" => format!("{}", quote.leftover_token),
+                    "leftoverSatoshis" => quote.leftover_satoshis,
+                    "offers" => JsonValue::Array(
+                        quote.offers.iter()
+                            .map(|offer| object!{
+                                "tx" => tx_hash_to_hex(&offer.trade_offer.tx),
+                                "vout" => offer.trade_offer.output_idx,
+                                "sellAmountToken" => format!("{}", offer.trade_offer.sell_amount_token),
+                                "pricePerToken" => format!(
+                                    "{}", PrettyRational(offer.trade_offer.price_per_token.clone())
+                                ),
+                                "effectivePrice" => format!(
+                                    "{}", PrettyRational(offer.effective_price.clone())
+                                ),
+                                "paymentSatoshis" => offer.payment_satoshis,
+                                "feeSatoshis" => offer.fee_satoshis,
+                                "feeProfile" => offer.trade_offer.fee_profile.clone(),
+                            })
+                            .collect()
+                    ),
+                })
+            })
+            .map_err(error_response)
+    )
+}
+
+#[derive(Deserialize)]
+pub struct OrderBookQuery {
+    profile: Option<String>,
+}
+
+/// Open offers of a token; those of one fee profile if `profile` is given, otherwise all.
+pub fn token_offers(path: web::Path<(String,)>,
+                    query: web::Query<OrderBookQuery>,
+                    tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    let token_hash = match tx_hex_to_hash(&path.0) {
+        Some(token_hash) => token_hash,
+        None => return bad_request("invalid token id"),
+    };
+    Box::new(
+        tx.send(FetchTradeOfferUtxos(TradeOfferFilter::TokenHash(token_hash), query.profile.clone()))
+            .from_err()
+            .and_then(identity)
+            .map(move |offers| {
+                json_response(JsonValue::Array(
+                    offers.iter()
+                        .map(|trade_offer| trade_offer_json(trade_offer, &token_hash))
+                        .collect()
+                ))
+            })
+            .map_err(error_response)
+    )
+}
+
+pub fn trade_offer_states(path: web::Path<(String, i32)>,
+                          tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    let outpoint = match tx_hex_to_hash(&path.0) {
+        Some(tx_hash) => SpentUtxo { tx_hash, vout: path.1 },
+        None => return bad_request("invalid tx"),
+    };
+    Box::new(
+        tx.send(FetchTradeOfferStates(outpoint))
+            .from_err()
+            .and_then(identity)
+            .map(|states| {
+                if states.len() == 0 {
+                    return HttpResponse::NotFound().finish()
+                }
+                let (state, _, _) = states[states.len() - 1];
+                json_response(object!{
+                    "state" => format!("{:?}", state),
+                    "history" => JsonValue::Array(
+                        states.iter()
+                            .map(|(state, spent_tx, timestamp)| object!{
+                                "state" => format!("{:?}", state),
+                                "spentTx" => spent_tx.map(|tx| tx_hash_to_hex(&tx)),
+                                "timestamp" => *timestamp,
+                            })
+                            .collect()
+                    ),
+                })
+            })
+            .map_err(error_response)
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubmitTxRequest {
+    #[serde(rename = "rawTx")]
+    pub raw_tx: String,
+    #[serde(rename = "allowBurn", default)]
+    pub allow_burn: bool,
+}
+
+pub fn submit_tx(request: web::Json<SubmitTxRequest>,
+                 tx: web::Data<Addr<TxActor>>) -> ApiResponse {
+    Box::new(
+        tx.send(SubmitTx { raw_tx: request.raw_tx.clone(), allow_burn: request.allow_burn })
+            .from_err()
+            .and_then(|result| {
+                match result {
+                    Ok(ref json) if json["type"] == "TxAccepted" => Ok(json_response(json.clone())),
+                    Ok(json) => Ok(
+                        HttpResponse::BadRequest()
+                            .content_type("application/json")
+                            .body(stringify(json))
+                    ),
+                    Err(err) => Err(error_response(err)),
+                }
+            })
+    )
+}
@@ -0,0 +1,123 @@
+//! Read-only queries and subscriptions; any number of these can run against one indexer.
+
+use std::sync::{Arc, Mutex};
+use actix::prelude::*;
+
+use actix_web::{middleware, web, App, HttpResponse, HttpRequest, HttpServer};
+use actix_web_actors::ws;
+
+use cashcontracts::{Address};
+use slpdexdb_endpoint::{api, events, connect_db};
+use slpdexdb_endpoint::actors::{TxActor, WsActor};
+use slpdexdb_endpoint::config::ApiConfig;
+use slpdexdb_endpoint::indexer_client::IndexerClient;
+use slpdexdb_endpoint::msg::ActivateAccount;
+use futures::Future;
+use serde::Deserialize;
+
+/// Last sequence number a reconnecting client has seen.
+#[derive(Deserialize)]
+struct ResumeQuery {
+    since: Option<i64>,
+}
+
+fn index(r: HttpRequest,
+         stream: web::Payload,
+         path: web::Path<(String,)>,
+         query: web::Query<ResumeQuery>,
+         tx: web::Data<Addr<TxActor>>) -> Result<HttpResponse, actix_web::Error> {
+    let address_str = &path.0;
+    eprintln!("connect to address {}", address_str);
+    let address = match Address::from_cash_addr(address_str.clone()) {
+        Ok(address) => address,
+        Err(_) => return Ok(HttpResponse::BadRequest().body("invalid address")),
+    };
+    ws::start(WsActor::new(address, query.since, tx.get_ref().clone()), &r, stream)
+}
+
+fn index_account(r: HttpRequest,
+                 stream: web::Payload,
+                 path: web::Path<(String,)>,
+                 query: web::Query<ResumeQuery>,
+                 tx: web::Data<Addr<TxActor>>)
+        -> Box<dyn Future<Item=HttpResponse, Error=actix_web::Error>> {
+    let xpub = path.0.clone();
+    eprintln!("connect to account {}", xpub);
+    let since = query.since;
+    let tx = tx.get_ref().clone();
+    Box::new(
+        tx.send(ActivateAccount(xpub.clone()))
+            .from_err()
+            .and_then(std::convert::identity)
+            .map_err(|err: slpdexdb_base::Error| {
+                actix_web::error::ErrorBadRequest(err.to_string())
+            })
+            .and_then(move |addresses| {
+                ws::start(WsActor::new_account(xpub, addresses, since, tx), &r, stream)
+            })
+    )
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("RUST_LOG", "actix_server=info,actix_web=info");
+    env_logger::init();
+    let config = ApiConfig::from_env();
+    let sys = actix::System::new("slpdexdb-api");
+    let tx_addr = TxActor::start_with(Arc::new(Mutex::new(connect_db(&config.database_url))),
+                                      config.slpdex.clone(),
+                                      IndexerClient::new(config.indexer_url.clone(),
+                                                         config.indexer_secret.clone()))?;
+    events::spawn_outbox_listener(config.database_url.clone(), tx_addr.clone().recipient());
+
+    HttpServer::new(move || {
+        App::new()
+            .wrap(middleware::Logger::default())
+            .data(tx_addr.clone())
+            .service(
+                web::resource("/ws/{address}").route(web::get().to(index))
+            )
+            .service(
+                web::resource("/ws/account/{xpub}").route(web::get().to_async(index_account))
+            )
+            .service(
+                web::resource("/token/{token_id}/holders")
+                    .route(web::get().to_async(api::token_holders))
+            )
+            .service(
+                web::resource("/token/{token_id}/quote")
+                    .route(web::get().to_async(api::token_quote))
+            )
+            .service(
+                web::resource("/token/{token_id}/offers")
+                    .route(web::get().to_async(api::token_offers))
+            )
+            .service(
+                web::resource("/address/{address}/balance")
+                    .route(web::get().to_async(api::address_balance))
+            )
+            .service(
+                web::resource("/address/{address}/trades")
+                    .route(web::get().to_async(api::address_trades))
+            )
+            .service(
+                web::resource("/trade-offer/build")
+                    .route(web::post().to_async(api::build_trade_offer))
+            )
+            .service(
+                web::resource("/trade-offer/accept")
+                    .route(web::post().to_async(api::accept_trade_offers))
+            )
+            .service(
+                web::resource("/trade-offer/{tx}/{vout}/states")
+                    .route(web::get().to_async(api::trade_offer_states))
+            )
+            .service(
+                web::resource("/tx/submit")
+                    .route(web::post().to_async(api::submit_tx))
+            )
+    })
+        .bind(&config.bind_addr)?
+        .start();
+    sys.run()?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! P2P sync, resync, validation and all writes; runs once per database.
+
+use std::sync::{Arc, Mutex};
+use actix::prelude::*;
+
+use actix_web::{middleware, web, App, HttpServer};
+
+use slpdexdb_endpoint::{indexer_api, connect_db};
+use slpdexdb_endpoint::indexer_api::IndexerSecret;
+use slpdexdb_endpoint::actors::{IndexerActor, ResyncActor, PeersActor, DbActor};
+use slpdexdb_endpoint::config::IndexerConfig;
+use slpdexdb_endpoint::msg::{ConnectToPeer, SetPeers};
+use futures::Future;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("RUST_LOG", "actix_server=info,actix_web=info");
+    env_logger::init();
+    let config = IndexerConfig::from_env();
+    if config.secret.is_none() && !config.is_loopback_bind() {
+        return Err(format!("INDEXER_SECRET is required to bind to {}", config.bind_addr).into());
+    }
+    actix::System::run(move || {
+        let resync_config = config.clone();
+        let resync_addr = SyncArbiter::start(1, move || {
+            ResyncActor::new(connect_db(&resync_config.database_url), resync_config.slpdex.clone())
+        });
+        let db_addr = DbActor::create(&config.database_url).unwrap();
+        let db_addr = slpdexdb_node::DbActor::start(slpdexdb_node::DbActor {
+            add_header_query: db_addr.clone().recipient(),
+            header_tip_query: db_addr.recipient(),
+        });
+        let indexer_addr = IndexerActor::start_with(
+            Arc::new(Mutex::new(connect_db(&config.database_url))),
+            config.slpdex.clone(),
+            resync_addr,
+        );
+        let peers_addr = PeersActor::start(PeersActor::new(indexer_addr.clone(), db_addr));
+        indexer_addr.do_send(SetPeers(peers_addr.clone()));
+
+        Arbiter::spawn(
+            peers_addr.send(ConnectToPeer { socket_addr: config.peer_addr })
+                .from_err()
+                .and_then(|x| x)
+                .map_err(|err: slpdexdb_base::Error| {eprintln!("{}", err);})
+        );
+
+        let secret = config.secret.clone();
+        HttpServer::new(move || {
+            App::new()
+                .wrap(middleware::Logger::default())
+                .data(indexer_addr.clone())
+                .data(IndexerSecret(secret.clone()))
+                .service(
+                    web::resource("/tx/submit")
+                        .route(web::post().to_async(indexer_api::submit_tx))
+                )
+                .service(
+                    web::resource("/address/{address}/activate")
+                        .route(web::post().to_async(indexer_api::activate_address))
+                )
+                .service(
+                    web::resource("/account/{xpub}/activate")
+                        .route(web::post().to_async(indexer_api::activate_account))
+                )
+        })
+            .bind(&config.bind_addr).unwrap()
+            .start();
+    })?;
+    Ok(())
+}
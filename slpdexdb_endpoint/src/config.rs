@@ -0,0 +1,63 @@
+use std::net::{self, ToSocketAddrs};
+use std::str::FromStr;
+use slpdexdb_base::SLPDEXConfig;
+
+const DEFAULT_PEER_ADDR: &str = "100.1.209.114:8333";
+const DEFAULT_INDEXER_BIND_ADDR: &str = "127.0.0.1:7502";
+const DEFAULT_INDEXER_URL: &str = "http://127.0.0.1:7502";
+const DEFAULT_PORT: &str = "7501";
+
+/// Settings of `slpdexdb-indexer`.
+#[derive(Clone)]
+pub struct IndexerConfig {
+    pub database_url: String,
+    pub peer_addr: net::SocketAddr,  // node txs are received from and relayed to
+    pub bind_addr: String,  // internal API for the API servers, not to be exposed publicly
+    pub secret: Option<String>,  // required from the API servers if set
+    pub slpdex: SLPDEXConfig,
+}
+
+/// Settings of `slpdexdb-api`.
+#[derive(Clone)]
+pub struct ApiConfig {
+    pub database_url: String,
+    pub bind_addr: String,
+    pub indexer_url: String,  // where submitted txs and address activations are forwarded to
+    pub indexer_secret: Option<String>,
+    pub slpdex: SLPDEXConfig,
+}
+
+impl IndexerConfig {
+    pub fn from_env() -> IndexerConfig {
+        let peer_addr = std::env::var("PEER_ADDR").unwrap_or(DEFAULT_PEER_ADDR.to_string());
+        IndexerConfig {
+            database_url: std::env::var("DATABASE_URL").expect("DATABASE_URL"),
+            peer_addr: net::SocketAddr::from_str(&peer_addr).expect("PEER_ADDR must be ip:port"),
+            bind_addr: std::env::var("INDEXER_BIND_ADDR")
+                .unwrap_or(DEFAULT_INDEXER_BIND_ADDR.to_string()),
+            secret: std::env::var("INDEXER_SECRET").ok(),
+            slpdex: SLPDEXConfig::from_env(),
+        }
+    }
+
+    /// Whether `bind_addr` only accepts connections from this machine.
+    pub fn is_loopback_bind(&self) -> bool {
+        match self.bind_addr.to_socket_addrs() {
+            Ok(addrs) => addrs.into_iter().all(|addr| addr.ip().is_loopback()),
+            Err(_) => false,
+        }
+    }
+}
+
+impl ApiConfig {
+    pub fn from_env() -> ApiConfig {
+        let port = std::env::var("PORT").unwrap_or(DEFAULT_PORT.to_string());
+        ApiConfig {
+            database_url: std::env::var("DATABASE_URL").expect("DATABASE_URL"),
+            bind_addr: std::env::var("API_BIND_ADDR").unwrap_or(format!("127.0.0.1:{}", port)),
+            indexer_url: std::env::var("INDEXER_URL").unwrap_or(DEFAULT_INDEXER_URL.to_string()),
+            indexer_secret: std::env::var("INDEXER_SECRET").ok(),
+            slpdex: SLPDEXConfig::from_env(),
+        }
+    }
+}
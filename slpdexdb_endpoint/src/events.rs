@@ -0,0 +1,356 @@
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::Duration;
+use actix::Recipient;
+use cashcontracts::Address;
+use fallible_iterator::FallibleIterator;
+use json::stringify;
+use slpdexdb_base::{Error, SLPAmount};
+use slpdexdb_db::{Db, OutputType, Utxo, SpentUtxo, TxDelta, TxHistory, TradeOffer, TradeOfferState,
+                  TradeOfferStateChange, EventSubject, NewOutboxEvent, OutboxEventBuilder,
+                  OUTBOX_CHANNEL};
+use crate::actors::{address_utxo_json, trade_offer_utxo_json, tx_history_json,
+                    trade_offer_state_json};
+use crate::msg::OutboxNotified;
+
+pub const ADDRESS_UTXO: &str = "AddressUtxo";
+pub const TRADE_OFFER_UTXO: &str = "TradeOfferUtxo";
+pub const TX_HISTORY: &str = "TxHistory";
+pub const TRADE_OFFER_STATE: &str = "TradeOfferState";
+
+// missed notifications, e.g. while reconnecting, are caught up after this
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(30);
+const LISTEN_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Builds the outbox events of txs indexed through a `Db` it's injected into.
+pub struct OutboxEvents;
+
+impl OutboxEventBuilder for OutboxEvents {
+    fn outbox_events(&self,
+                     db: &Db,
+                     history: &TxHistory,
+                     trade_offer_states: &[TradeOfferStateChange],
+                     relevant_addresses: &HashSet<Address>,
+                     now: i64) -> Result<Vec<NewOutboxEvent>, Error> {
+        outbox_events(db, history, trade_offer_states, relevant_addresses, now)
+    }
+}
+
+/// The outbox events of newly indexed txs, for each address in `relevant_addresses`, the
+/// accounts those belong to and each token with trade offer changes.
+///
+/// Runs in the ingest transaction, after `Db::add_tx_history`, so the events are stored
+/// exactly if the txs are.
+pub fn outbox_events(db: &Db,
+                     history: &TxHistory,
+                     trade_offer_states: &[TradeOfferStateChange],
+                     relevant_addresses: &HashSet<Address>,
+                     now: i64) -> Result<Vec<NewOutboxEvent>, Error> {
+    let (address_add_utxos, address_remove_utxos) = _address_utxos(history, relevant_addresses);
+    let address_tx_deltas = _address_tx_deltas(db, history, relevant_addresses, now)?;
+    let (token_add_utxos, token_remove_utxos) = _token_trade_offers(history);
+    let mut token_state_changes = HashMap::new();
+    for change in trade_offer_states.iter() {
+        if let Some(token_hash) = change.token_hash {
+            token_state_changes
+                .entry(token_hash)
+                .or_insert_with(Vec::new)
+                .push(change.clone());
+        }
+    }
+
+    let trade_participants = trade_offer_states.iter()
+        .flat_map(|change| _trade_participants(history, change))
+        .filter(|address| relevant_addresses.contains(address))
+        .collect::<Vec<_>>();
+    let addresses = address_add_utxos.keys()
+        .chain(address_remove_utxos.keys())
+        .chain(address_tx_deltas.keys())
+        .chain(trade_participants.iter())
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mut subjects = addresses.iter()
+        .map(|address| (EventSubject::Address(address.clone()), vec![address.clone()]))
+        .collect::<Vec<_>>();
+    subjects.extend(
+        db.hd_accounts_of(&addresses)?
+            .into_iter()
+            .map(|(xpub, addresses)| (EventSubject::Account(xpub), addresses))
+    );
+
+    let mut events = Vec::new();
+    for (subject, addresses) in subjects {
+        // an account sees one event for all its addresses
+        let add_utxos = addresses.iter()
+            .flat_map(|address| address_add_utxos.get(address))
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        let remove_utxos = addresses.iter()
+            .flat_map(|address| address_remove_utxos.get(address))
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        if add_utxos.len() > 0 || remove_utxos.len() > 0 {
+            events.push(NewOutboxEvent {
+                subject: subject.clone(),
+                event_type: ADDRESS_UTXO,
+                payload: stringify(address_utxo_json(&add_utxos, &remove_utxos)),
+            });
+        }
+        let tx_deltas = _net_tx_deltas(
+            addresses.iter()
+                .flat_map(|address| address_tx_deltas.get(address))
+                .flatten()
+        );
+        if tx_deltas.len() > 0 {
+            events.push(NewOutboxEvent {
+                subject: subject.clone(),
+                event_type: TX_HISTORY,
+                payload: stringify(tx_history_json(&tx_deltas)),
+            });
+        }
+        // makers and takers hear about their trades
+        for (token_hash, changes) in token_state_changes.iter() {
+            let changes = changes.iter()
+                .filter(|change| {
+                    _trade_participants(history, change).iter()
+                        .any(|participant| addresses.contains(participant))
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            if changes.len() > 0 {
+                events.push(NewOutboxEvent {
+                    subject: subject.clone(),
+                    event_type: TRADE_OFFER_STATE,
+                    payload: stringify(trade_offer_state_json(token_hash, &changes)),
+                });
+            }
+        }
+    }
+
+    let tokens = token_add_utxos.keys()
+        .chain(token_remove_utxos.keys())
+        .chain(token_state_changes.keys())
+        .cloned()
+        .collect::<HashSet<_>>();
+    for token_hash in tokens {
+        let add_utxos = token_add_utxos.get(&token_hash).map(Vec::as_slice).unwrap_or(&[]);
+        let remove_utxos = token_remove_utxos.get(&token_hash).map(Vec::as_slice).unwrap_or(&[]);
+        if add_utxos.len() > 0 || remove_utxos.len() > 0 {
+            events.push(NewOutboxEvent {
+                subject: EventSubject::Token(token_hash),
+                event_type: TRADE_OFFER_UTXO,
+                payload: stringify(trade_offer_utxo_json(&token_hash, add_utxos, remove_utxos)),
+            });
+        }
+        if let Some(changes) = token_state_changes.get(&token_hash) {
+            events.push(NewOutboxEvent {
+                subject: EventSubject::Token(token_hash),
+                event_type: TRADE_OFFER_STATE,
+                payload: stringify(trade_offer_state_json(&token_hash, changes)),
+            });
+        }
+    }
+    Ok(events)
+}
+
+/// Listens on `OUTBOX_CHANNEL` in its own thread and notifies `recipient` of new events,
+/// whichever process stored them. Reconnects if the connection is lost.
+pub fn spawn_outbox_listener(connection_str: String, recipient: Recipient<OutboxNotified>) {
+    thread::spawn(move || loop {
+        if let Err(err) = _listen_outbox(&connection_str, &recipient) {
+            eprintln!("outbox listener failed: {}", err);
+        }
+        thread::sleep(LISTEN_RECONNECT_DELAY);
+    });
+}
+
+fn _listen_outbox(connection_str: &str, recipient: &Recipient<OutboxNotified>)
+        -> Result<(), postgres::Error> {
+    let connection = postgres::Connection::connect(connection_str, postgres::TlsMode::None)?;
+    connection.execute(&format!("LISTEN {}", OUTBOX_CHANNEL), &[])?;
+    let notifications = connection.notifications();
+    let mut notifications = notifications.timeout_iter(LISTEN_TIMEOUT);
+    loop {
+        // on timeout as well, as notifications sent while disconnected are lost
+        notifications.next()?;
+        if recipient.do_send(OutboxNotified).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn _address_utxos(history: &TxHistory, relevant_addresses: &HashSet<Address>)
+        -> (HashMap<Address, Vec<Utxo>>, HashMap<Address, Vec<SpentUtxo>>) {
+    let mut address_add_utxos = HashMap::new();
+    let mut address_remove_utxos = HashMap::new();
+    for tx in history.txs.iter() {
+        for input in tx.inputs.iter() {
+            if let OutputType::Address(address) = &input.output {
+                if !relevant_addresses.contains(address) { continue; }
+                address_remove_utxos
+                    .entry(address.clone())
+                    .or_insert_with(Vec::new)
+                    .push(SpentUtxo {
+                        tx_hash: input.output_tx.clone(),
+                        vout: input.output_idx,
+                    });
+            }
+        }
+        for (output_idx, output) in tx.outputs.iter().enumerate() {
+            if let OutputType::Address(ref address) = output.output {
+                if !relevant_addresses.contains(address) { continue; }
+                address_add_utxos
+                    .entry(address.clone())
+                    .or_insert_with(Vec::new)
+                    .push(Utxo {
+                        tx_hash: tx.hash.clone(),
+                        vout: output_idx as i32,
+                        token_hash: tx.tx_type.token_hash().cloned(),
+                        value_satoshis: output.value_satoshis,
+                        value_token: output.value_token,
+                    });
+            }
+        }
+    }
+    (address_add_utxos, address_remove_utxos)
+}
+
+/// The maker of the offer of `change`, plus the input addresses of the tx taking it, if that
+/// tx is in `history`.
+fn _trade_participants(history: &TxHistory, change: &TradeOfferStateChange) -> Vec<Address> {
+    let mut participants = vec![change.receiving_address.clone()];
+    match change.state {
+        TradeOfferState::PendingTake | TradeOfferState::Filled => {},
+        _ => return participants,
+    }
+    let taker_tx = history.txs.iter().find(|tx| Some(tx.hash) == change.spent_tx);
+    for input in taker_tx.iter().flat_map(|tx| tx.inputs.iter()) {
+        if let OutputType::Address(address) = &input.output {
+            if !participants.contains(address) {
+                participants.push(address.clone());
+            }
+        }
+    }
+    participants
+}
+
+fn _token_trade_offers(history: &TxHistory)
+        -> (HashMap<[u8; 32], Vec<TradeOffer>>, HashMap<[u8; 32], Vec<SpentUtxo>>) {
+    let mut token_add_utxos = HashMap::new();
+    let mut token_remove_utxos = HashMap::new();
+    for (idx, tx) in history.txs.iter().enumerate() {
+        let (trade_offer, token_hash) =
+            match (history.trade_offers.get(&idx), tx.tx_type.token_hash()) {
+                (Some(trade_offer), Some(token_hash)) => (trade_offer, token_hash.clone()),
+                _ => continue,
+            };
+        if trade_offer.output_idx.is_some() {
+            token_add_utxos
+                .entry(token_hash.clone())
+                .or_insert_with(Vec::new)
+                .push(trade_offer.clone());
+        }
+        token_remove_utxos
+            .entry(token_hash)
+            .or_insert_with(Vec::new)
+            .push(SpentUtxo {
+                tx_hash: trade_offer.input_tx,
+                vout: trade_offer.input_idx,
+            });
+    }
+    (token_add_utxos, token_remove_utxos)
+}
+
+fn _address_tx_deltas(db: &Db, history: &TxHistory, relevant_addresses: &HashSet<Address>,
+                      now: i64) -> Result<HashMap<Address, Vec<TxDelta>>, Error> {
+    let input_txs = db.tx_outputs(
+        history.txs.iter().flat_map(|tx| {
+            tx.inputs.iter().filter_map(|input| {
+                if relevant_addresses.contains(input.output.address()?) {
+                    Some(input.output_tx.clone())
+                } else {
+                    None
+                }
+            })
+        })
+    )?;
+    let mut address_tx_deltas = HashMap::new();
+    for tx in history.txs.iter() {
+        let decimals = tx.outputs.iter()
+            .map(|output| output.value_token.decimals())
+            .next()
+            .unwrap_or(0);
+        let mut tx_deltas = HashMap::new();
+        for input in tx.inputs.iter() {
+            input.output.address()
+                .filter(|address| relevant_addresses.contains(address))
+                .and_then(|address| {
+                    let input_output = input_txs.get(&(input.output_tx, input.output_idx))?;
+                    let (a_satoshis, a_token) = (input_output.value_satoshis,
+                                                  SLPAmount::from_numeric_decimals(
+                                                      &input_output.value_token_base,
+                                                      decimals,
+                                                  ));
+                    tx_deltas
+                        .entry(address.clone())
+                        .and_modify(|b: &mut (i64, SLPAmount)| {
+                            b.0 -= a_satoshis;
+                            b.1 -= a_token;
+                        })
+                        .or_insert((-a_satoshis, -a_token));
+                    Some(())
+                });
+        }
+        for output in tx.outputs.iter() {
+            output.output.address()
+                .filter(|address| relevant_addresses.contains(address))
+                .map(|address| {
+                    let a_satoshis = output.value_satoshis as i64;
+                    let a_token = output.value_token;
+                    tx_deltas
+                        .entry(address.clone())
+                        .and_modify(|b: &mut (i64, SLPAmount)| {
+                            b.0 += a_satoshis;
+                            b.1 += a_token;
+                        })
+                        .or_insert((a_satoshis, a_token));
+                });
+        }
+        for (address, (delta_satoshis, delta_token)) in tx_deltas {
+            address_tx_deltas
+                .entry(address)
+                .or_insert_with(Vec::new)
+                .push(TxDelta {
+                    tx_hash: tx.hash.clone(),
+                    token_hash: tx.tx_type.token_hash().cloned(),
+                    timestamp: now,
+                    height: tx.height,
+                    delta_satoshis,
+                    delta_token,
+                    // pushed deltas carry no running balances, see `tx_history_json`
+                    running_balance_satoshis: None,
+                    running_balance_token: None,
+                })
+        }
+    }
+    Ok(address_tx_deltas)
+}
+
+/// Deltas of the same tx are netted, so transfers within an account cancel out.
+fn _net_tx_deltas<'a>(tx_deltas: impl Iterator<Item=&'a TxDelta>) -> Vec<TxDelta> {
+    let mut merged_tx_deltas = Vec::<TxDelta>::new();
+    for tx_delta in tx_deltas {
+        match merged_tx_deltas.iter_mut().find(|d| d.tx_hash == tx_delta.tx_hash) {
+            Some(merged) => {
+                merged.delta_satoshis += tx_delta.delta_satoshis;
+                merged.delta_token += tx_delta.delta_token;
+            },
+            None => merged_tx_deltas.push(tx_delta.clone()),
+        }
+    }
+    merged_tx_deltas
+}
@@ -0,0 +1,113 @@
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::future::{self, Future};
+use cashcontracts::Address;
+use json::{object, JsonValue, stringify};
+use std::convert::identity;
+use crate::actors::{IndexerActor, submit_result_json};
+use crate::api::SubmitTxRequest;
+use crate::msg::{IndexTx, ActivateAddress, ActivateAccount};
+
+// Internal routes of the indexer, requested by the API servers through `IndexerClient`.
+
+type ApiResponse = Box<dyn Future<Item=HttpResponse, Error=actix_web::Error>>;
+
+pub const SECRET_HEADER: &str = "X-Indexer-Secret";
+
+/// Secret the API servers have to send in `SECRET_HEADER`; `None` accepts every request.
+pub struct IndexerSecret(pub Option<String>);
+
+fn _unauthorized(request: &HttpRequest, secret: &IndexerSecret) -> Option<ApiResponse> {
+    let secret = secret.0.as_ref()?;
+    let given = request.headers().get(SECRET_HEADER)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    // compares every byte, so the time taken doesn't reveal the matching prefix
+    let is_equal = given.len() == secret.len() &&
+        given.iter().zip(secret.as_bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+    if is_equal {
+        return None;
+    }
+    Some(Box::new(future::ok(HttpResponse::Unauthorized().body("invalid indexer secret"))))
+}
+
+fn json_response(json: JsonValue) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(stringify(json))
+}
+
+fn error_response(err: slpdexdb_base::Error) -> actix_web::Error {
+    actix_web::error::ErrorInternalServerError(err.to_string())
+}
+
+pub fn submit_tx(http_request: HttpRequest,
+                 request: web::Json<SubmitTxRequest>,
+                 indexer: web::Data<Addr<IndexerActor>>,
+                 secret: web::Data<IndexerSecret>) -> ApiResponse {
+    if let Some(response) = _unauthorized(&http_request, &secret) {
+        return response;
+    }
+    let SubmitTxRequest { raw_tx, allow_burn } = request.into_inner();
+    Box::new(
+        indexer.send(IndexTx { raw_tx, allow_burn })
+            .from_err()
+            .and_then(|result| {
+                match (submit_result_json(&result), result) {
+                    (Some(json), Ok(_)) => Ok(json_response(json)),
+                    (Some(json), Err(_)) => Ok(
+                        HttpResponse::BadRequest()
+                            .content_type("application/json")
+                            .body(stringify(json))
+                    ),
+                    (None, Err(err)) => Err(error_response(err)),
+                    // submit_result_json describes every accepted tx, so this is our bug
+                    (None, Ok(_)) => Ok(
+                        HttpResponse::InternalServerError().body("accepted tx without result")
+                    ),
+                }
+            })
+    )
+}
+
+pub fn activate_address(request: HttpRequest,
+                        path: web::Path<(String,)>,
+                        indexer: web::Data<Addr<IndexerActor>>,
+                        secret: web::Data<IndexerSecret>) -> ApiResponse {
+    if let Some(response) = _unauthorized(&request, &secret) {
+        return response;
+    }
+    let address = match Address::from_cash_addr(path.0.clone()) {
+        Ok(address) => address,
+        Err(_) => return Box::new(future::ok(HttpResponse::BadRequest().body("invalid address"))),
+    };
+    Box::new(
+        indexer.send(ActivateAddress(address))
+            .from_err()
+            .and_then(identity)
+            .map(|_| json_response(object!{}))
+            .map_err(error_response)
+    )
+}
+
+pub fn activate_account(request: HttpRequest,
+                        path: web::Path<(String,)>,
+                        indexer: web::Data<Addr<IndexerActor>>,
+                        secret: web::Data<IndexerSecret>) -> ApiResponse {
+    if let Some(response) = _unauthorized(&request, &secret) {
+        return response;
+    }
+    Box::new(
+        indexer.send(ActivateAccount(path.0.clone()))
+            .from_err()
+            .and_then(identity)
+            .map(|addresses| json_response(object!{
+                "addresses" => JsonValue::Array(
+                    addresses.iter()
+                        .map(|address| address.cash_addr().to_string().into())
+                        .collect()
+                ),
+            }))
+            .map_err(error_response)
+    )
+}
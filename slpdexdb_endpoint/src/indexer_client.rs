@@ -0,0 +1,82 @@
+use actix_web::client::Client;
+use actix_web::http::StatusCode;
+use futures::future::Future;
+use json::JsonValue;
+use serde::Serialize;
+use cashcontracts::Address;
+use slpdexdb_base::{Error, ErrorKind};
+use crate::api::SubmitTxRequest;
+use crate::indexer_api::SECRET_HEADER;
+
+/// Requests writes from the indexer's internal API, see `indexer_api`.
+pub struct IndexerClient {
+    url: String,
+    secret: Option<String>,
+    client: Client,
+}
+
+fn _request_error(err: impl std::fmt::Display) -> Error {
+    ErrorKind::IndexerRequest(err.to_string()).into()
+}
+
+impl IndexerClient {
+    pub fn new(url: String, secret: Option<String>) -> Self {
+        IndexerClient { url, secret, client: Client::default() }
+    }
+
+    /// Posts to `path`; yields the JSON body of responses with one of `statuses`.
+    fn post(&self, path: &str, body: &impl Serialize, statuses: &'static [StatusCode])
+            -> Box<dyn Future<Item=JsonValue, Error=Error>> {
+        let mut request = self.client.post(format!("{}{}", self.url, path));
+        if let Some(secret) = &self.secret {
+            request = request.header(SECRET_HEADER, secret.clone());
+        }
+        Box::new(
+            request
+                .send_json(body)
+                .map_err(_request_error)
+                .and_then(move |mut response| {
+                    let status = response.status();
+                    response.body()
+                        .map_err(_request_error)
+                        .and_then(move |body| {
+                            let body = String::from_utf8_lossy(&body);
+                            if !statuses.contains(&status) {
+                                return Err(_request_error(format!("{}: {}", status, body)));
+                            }
+                            json::parse(&body).map_err(_request_error)
+                        })
+                })
+        )
+    }
+
+    /// The `TxAccepted` or `TxRejected` JSON of the submitted tx.
+    pub fn submit_tx(&self, raw_tx: String, allow_burn: bool)
+            -> Box<dyn Future<Item=JsonValue, Error=Error>> {
+        self.post("/tx/submit", &SubmitTxRequest { raw_tx, allow_burn },
+                  &[StatusCode::OK, StatusCode::BAD_REQUEST])
+    }
+
+    pub fn activate_address(&self, address: &Address) -> Box<dyn Future<Item=(), Error=Error>> {
+        Box::new(
+            self.post(&format!("/address/{}/activate", address.cash_addr()), &(), &[StatusCode::OK])
+                .map(|_| ())
+        )
+    }
+
+    /// Yields the derived addresses of the account.
+    pub fn activate_account(&self, xpub: &str) -> Box<dyn Future<Item=Vec<Address>, Error=Error>> {
+        Box::new(
+            self.post(&format!("/account/{}/activate", xpub), &(), &[StatusCode::OK])
+                .and_then(|json| {
+                    json["addresses"].members()
+                        .map(|address| {
+                            let address = address.as_str().unwrap_or_default().to_string();
+                            Address::from_cash_addr(address)
+                                .map_err(|_| _request_error("invalid address from indexer"))
+                        })
+                        .collect::<Result<Vec<_>, Error>>()
+                })
+        )
+    }
+}
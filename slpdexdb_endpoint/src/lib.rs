@@ -0,0 +1,17 @@
+pub mod actors;
+pub mod api;
+pub mod config;
+pub mod events;
+pub mod indexer_api;
+pub mod indexer_client;
+pub mod msg;
+
+use std::sync::Arc;
+use diesel::prelude::*;
+use slpdexdb_db::Db;
+
+/// A `Db` which writes the outbox events of the txs it indexes.
+pub fn connect_db(database_url: &str) -> Db {
+    let connection = PgConnection::establish(database_url).unwrap();
+    Db::new(connection).with_outbox_event_builder(Arc::new(events::OutboxEvents))
+}
@@ -1,12 +1,15 @@
 use actix::prelude::*;
 use cashcontracts::Address;
-use slpdexdb_base::Error;
+use slpdexdb_base::{Error, SLPInputCheck};
 use std::net;
 use slpdexdb_base::SLPDEXConfig;
-use slpdexdb_db::{Db, Utxo, SpentUtxo, TxDelta, TradeOfferFilter, TradeOffer, TxHistory};
-use std::collections::{HashSet, HashMap};
+use slpdexdb_db::{Db, Utxo, SpentUtxo, TradeOfferFilter, TradeOffer, TokenDistribution,
+                  TxDeltaQuery, TxDeltaPage, AddressBalance, BuiltTradeOffer, BuiltAcceptOffers,
+                  Quote, TradeOfferState, TradeHistoryCursor, TradeHistoryPage, EventSubject,
+                  OutboxEvent, ResyncJob};
 use std::sync::{Arc, Mutex};
-use crate::actors::TxSubscribers;
+use json::JsonValue;
+use crate::actors::{TxSubscribers, PeersActor};
 
 
 pub struct ConnectToPeer {
@@ -29,32 +32,197 @@ impl Message for DeactivateAddress {
     type Result = Result<(), Error>;
 }
 
+/// Resyncs the address as an interactive job of the `resync_job` queue and waits for it.
 pub struct ResyncAddress(pub Address);
 
 impl Message for ResyncAddress {
     type Result = Result<(), Error>;
 }
 
-pub struct FetchTradeOfferUtxos(pub TradeOfferFilter);
+/// Registers an xpub account, derives its receive and change addresses up to the gap limit
+/// and resyncs them; yields all derived addresses.
+pub struct ActivateAccount(pub String);
+
+impl Message for ActivateAccount {
+    type Result = Result<Vec<Address>, Error>;
+}
+
+pub struct ResyncAccount(pub String);
+
+impl Message for ResyncAccount {
+    type Result = Result<Vec<Address>, Error>;
+}
+
+/// Queues a resync of the token's history and SLP UTXOs.
+pub struct ResyncToken(pub [u8; 32]);
+
+impl Message for ResyncToken {
+    type Result = Result<(), Error>;
+}
+
+/// Runs the next due job of the `resync_job` queue; yields it with its new state, or `None`
+/// if no job is due.
+pub struct RunResyncJob;
+
+impl Message for RunResyncJob {
+    type Result = Result<Option<ResyncJob>, Error>;
+}
+
+pub struct CheckUtxoSetExch;
+
+impl Message for CheckUtxoSetExch {
+    type Result = Result<(), Error>;
+}
+
+/// Opens pending offers again whose taker tx vanished.
+pub struct CheckPendingTakes;
+
+impl Message for CheckPendingTakes {
+    type Result = Result<(), Error>;
+}
+
+pub struct BuildTradeOffer {
+    pub token_hash: [u8; 32],
+    pub sell_amount: String,
+    pub price_per_token: String,
+    pub receiving_address: Address,
+    pub change_address: Address,
+    pub funding: Vec<SpentUtxo>,
+}
+
+impl Message for BuildTradeOffer {
+    type Result = Result<BuiltTradeOffer, Error>;
+}
+
+pub struct AcceptTradeOffers {
+    pub offers: Vec<SpentUtxo>,
+    pub receiving_address: Address,
+    pub change_address: Address,
+    pub funding: Vec<SpentUtxo>,
+}
+
+impl Message for AcceptTradeOffers {
+    type Result = Result<BuiltAcceptOffers, Error>;
+}
+
+/// Either `buy_amount` (decimal token amount) or `spend_satoshis` is set.
+pub struct FetchQuote {
+    pub token_hash: [u8; 32],
+    pub buy_amount: Option<String>,
+    pub spend_satoshis: Option<u64>,
+    pub fee_profile: Option<String>,  // `None` merges the offers of all fee profiles
+}
+
+impl Message for FetchQuote {
+    type Result = Result<Quote, Error>;
+}
+
+/// Validates, relays and then indexes a raw tx. Txs burning tokens are rejected unless
+/// `allow_burn`, and any tx while no peer is connected.
+pub struct IndexTx {
+    pub raw_tx: String,
+    pub allow_burn: bool,
+}
+
+pub struct SubmittedTx {
+    pub tx_hash: [u8; 32],
+    pub inputs: Vec<SLPInputCheck>,
+}
+
+impl Message for IndexTx {
+    type Result = Result<SubmittedTx, Error>;
+}
+
+/// Has the indexer index a raw tx; yields the `TxAccepted` or `TxRejected` JSON.
+pub struct SubmitTx {
+    pub raw_tx: String,
+    pub allow_burn: bool,
+}
+
+impl Message for SubmitTx {
+    type Result = Result<JsonValue, Error>;
+}
+
+/// Validates the SLP data of a submitted tx without indexing it, and breaks down which of its
+/// inputs it burns.
+pub struct CheckSubmittedTx(pub cashcontracts::Tx);
+
+impl Message for CheckSubmittedTx {
+    type Result = Result<Vec<SLPInputCheck>, Error>;
+}
+
+/// Sends a tx to the connected nodes; yields how many of them it was handed to. The P2P
+/// protocol doesn't acknowledge txs, so this is as close to acceptance as relaying gets.
+pub struct RelayTx(pub cashcontracts::Tx);
+
+impl Message for RelayTx {
+    type Result = usize;
+}
+
+pub struct SetPeers(pub Addr<PeersActor>);
+
+impl Message for SetPeers {
+    type Result = ();
+}
+
+/// Open offers matching the filter, only those of the given fee profile if any.
+pub struct FetchTradeOfferUtxos(pub TradeOfferFilter, pub Option<String>);
 
 impl Message for FetchTradeOfferUtxos {
     type Result = Result<Vec<TradeOffer>, Error>;
 }
 
-pub struct FetchAddressUtxos(pub Address);
+/// Transitions (state, spending tx, timestamp) of the offer at the given contract outpoint.
+pub struct FetchTradeOfferStates(pub SpentUtxo);
+
+impl Message for FetchTradeOfferStates {
+    type Result = Result<Vec<(TradeOfferState, Option<[u8; 32]>, i64)>, Error>;
+}
+
+pub struct FetchAddressUtxos(pub Vec<Address>);
 
 impl Message for FetchAddressUtxos {
     type Result = Result<Vec<Utxo>, Error>;
 }
 
-pub struct FetchAddressTxDeltas(pub Address);
+pub struct FetchAddressBalance(pub Vec<Address>);
+
+impl Message for FetchAddressBalance {
+    type Result = Result<AddressBalance, Error>;
+}
+
+pub struct FetchTokenUtxos(pub [u8; 32]);
+
+impl Message for FetchTokenUtxos {
+    type Result = Result<Vec<Utxo>, Error>;
+}
+
+pub struct FetchTokenDistribution(pub [u8; 32]);
+
+impl Message for FetchTokenDistribution {
+    type Result = Result<Option<Arc<TokenDistribution>>, Error>;
+}
+
+pub struct FetchAddressTxDeltas(pub Vec<Address>, pub TxDeltaQuery);
 
 impl Message for FetchAddressTxDeltas {
-    type Result = Result<Vec<TxDelta>, Error>;
+    type Result = Result<TxDeltaPage, Error>;
+}
+
+pub struct FetchAddressTradeHistory {
+    pub addresses: Vec<Address>,
+    pub cursor: Option<TradeHistoryCursor>,
+    pub limit: i64,
+}
+
+impl Message for FetchAddressTradeHistory {
+    type Result = Result<TradeHistoryPage, Error>;
 }
 
 pub enum SubscribeToEvent {
-    Address(Address, Recipient<TxEvent>),
+    /// An address or account subject; balances sent along its UTXO deltas cover `Vec<Address>`.
+    Addresses(EventSubject, Vec<Address>, Recipient<TxEvent>),
+    /// Replaces the recipient's token subscriptions.
     Tokens(Vec<[u8; 32]>, Recipient<TxEvent>),
 }
 
@@ -63,26 +231,56 @@ impl Message for SubscribeToEvent {
 }
 
 pub enum UnsubscribeFromEvent {
-    Address(Address, Recipient<TxEvent>),
+    All(Recipient<TxEvent>),
 }
 
 impl Message for UnsubscribeFromEvent {
     type Result = ();
 }
 
+/// Events of the outbox after `since` for `subjects`.
+pub struct FetchOutboxEvents {
+    pub subjects: Vec<EventSubject>,
+    pub since: i64,
+}
+
+pub enum OutboxReplay {
+    Events(Vec<OutboxEvent>),
+    SnapshotRequired,  // `since` was pruned or too far behind
+}
+
+impl Message for FetchOutboxEvents {
+    type Result = Result<OutboxReplay, Error>;
+}
+
+/// Sequence number of the newest outbox event, 0 if there is none.
+pub struct FetchLastOutboxSeq;
+
+impl Message for FetchLastOutboxSeq {
+    type Result = Result<i64, Error>;
+}
+
 #[derive(Clone)]
 pub enum TxEvent {
+    /// An outbox event of a subscribed subject.
+    Stored(Arc<OutboxEvent>),
+    /// The UTXOs of a snapshot.
     AddressUtxoDelta {
         add_utxos: Arc<Vec<Utxo>>,
         remove_utxos: Arc<Vec<SpentUtxo>>,
     },
-    TradeOfferUtxoDelta {
-        token_hash: [u8; 32],
-        add_utxos: Arc<Vec<TradeOffer>>,
-        remove_utxos: Arc<Vec<SpentUtxo>>,
+    AddressTxDeltaPage {
+        page: Arc<TxDeltaPage>,
+    },
+    AddressTradeHistoryPage {
+        page: Arc<TradeHistoryPage>,
+    },
+    BalanceChanged {
+        balance: Arc<AddressBalance>,
     },
-    AddressNewTxDeltas {
-        tx_deltas: Arc<Vec<TxDelta>>,
+    /// All addresses of a subscribed account, sent when it gained some.
+    AccountAddresses {
+        addresses: Arc<Vec<Address>>,
     },
 }
 
@@ -90,36 +288,19 @@ impl Message for TxEvent {
     type Result = Result<(), Error>;
 }
 
-type SyncTxSubscribers = Arc<Mutex<TxSubscribers>>;
+/// Sent by the outbox listener when new events may have been committed.
+pub struct OutboxNotified;
 
-pub enum TxBroadcastEvent {
-    AddressUtxoDelta {
-        add_utxos: HashMap<Address, Vec<Utxo>>,
-        remove_utxos: HashMap<Address, Vec<SpentUtxo>>,
-        subscribers: SyncTxSubscribers,
-    },
-    TradeOfferUtxoDelta {
-        add_utxos: HashMap<[u8; 32], Vec<TradeOffer>>,
-        remove_utxos: HashMap<[u8; 32], Vec<SpentUtxo>>,
-        subscribers: SyncTxSubscribers,
-    },
-    AddressNewTxDeltas {
-        tx_deltas: HashMap<Address, Vec<TxDelta>>,
-        subscribers: SyncTxSubscribers,
-    },
-}
-
-impl Message for TxBroadcastEvent {
+impl Message for OutboxNotified {
     type Result = Result<(), Error>;
 }
 
+/// Committed outbox events, to be routed to this instance's subscribers.
 #[derive(Clone)]
 pub struct NewTransactions {
-    pub now: i64,
+    pub events: Vec<Arc<OutboxEvent>>,
     pub db: Arc<Mutex<Db>>,
-    pub tx_history: Arc<TxHistory>,
-    pub relevant_addresses: Arc<HashSet<Address>>,
-    pub subscribers: SyncTxSubscribers,
+    pub subscribers: Arc<Mutex<TxSubscribers>>,
 }
 
 impl Message for NewTransactions {
@@ -128,10 +309,9 @@ impl Message for NewTransactions {
 
 pub struct ProcessTransactions {
     pub txs: Vec<cashcontracts::Tx>,
+    pub force_index: bool,  // submitted by a client: validate strictly and index even if irrelevant
     pub db: Arc<Mutex<Db>>,
     pub config: SLPDEXConfig,
-    pub subscribers: Arc<Mutex<TxSubscribers>>,
-    pub broadcasts: Vec<Recipient<NewTransactions>>,
 }
 
 impl Message for ProcessTransactions {